@@ -34,7 +34,7 @@ rm -r "$X86_64_GUI_PACKAGE_FOLDER/test/results" || true
 
 # Build without GUI
 
-"$CARGO_BINARY" build --target=x86_64-unknown-linux-gnu --release --no-default-features --features static_ssl
+"$CARGO_BINARY" build --target=x86_64-unknown-linux-gnu --release --no-default-features --features static_ssl,fft
 
 rm -r "$X86_64_NOGUI_PACKAGE_FOLDER" || true
 mkdir -p "$X86_64_NOGUI_PACKAGE_FOLDER"
@@ -61,7 +61,7 @@ rm -r "$ARMV7_GUI_PACKAGE_FOLDER/test/results" || true
 
 # Build without GUI for Raspberry Pi
 
-"$CARGO_BINARY" build --target=armv7-unknown-linux-gnueabihf --release --no-default-features --features static_ssl
+"$CARGO_BINARY" build --target=armv7-unknown-linux-gnueabihf --release --no-default-features --features static_ssl,fft
 
 rm -r "$ARMV7_NOGUI_PACKAGE_FOLDER" || true
 mkdir -p "$ARMV7_NOGUI_PACKAGE_FOLDER"
@@ -1,16 +1,23 @@
 //! Manages configurations from commandline and settings file
 
-use directories;
+#[cfg(not(target_arch = "wasm32"))] use directories;
 use toml;
+use png;
 use serde::Deserialize;
 
-// For std::fs::File.read_to_string()
-use std::io::prelude::*;
+// For std::fs::File.read_to_string(), only used with the settings file
+#[cfg(not(target_arch = "wasm32"))] use std::io::prelude::*;
 
-use noaa_apt::Contrast;
-use err;
+use noaa_apt::{Contrast, DemodMethod, OutputChannels};
+use processing::Palette;
+use telemetry;
+#[cfg(not(target_arch = "wasm32"))] use err;
 
 /// How to launch the program.
+///
+/// Created only once when starting, so I don't care about `Decode` being much
+/// bigger than the rest.
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug)]
 pub enum Mode {
     /// Open GUI.
@@ -35,11 +42,91 @@ pub struct DecodeSettings {
     /// Output filename.
     pub output_filename: String,
 
+    /// Filename for an additional image without contrast adjustment, if any.
+    pub raw_output_filename: Option<String>,
+
+    /// Filename for a bar chart of the image histogram, if any.
+    pub histogram_filename: Option<String>,
+
+    /// Filename for the demodulated signal as CSV, if any.
+    pub csv_filename: Option<String>,
+
+    /// Maximum width and height of a thumbnail saved next to the output
+    /// image, if any.
+    pub thumbnail_size: Option<usize>,
+
+    /// Width and height to fit the output images on, keeping the aspect
+    /// ratio and filling the rest with black, if any.
+    pub output_size: Option<(usize, usize)>,
+
+    /// Compression level of the PNG images written, see
+    /// `sink::PngWriter::set_compression()`.
+    pub compression: png::Compression,
+
+    /// Whether to save the SNR of each line as CSV next to the output image.
+    pub line_snr: bool,
+
+    /// Whether to decode a rough image quickly, see
+    /// `noaa_apt::preview_settings()`.
+    pub preview: bool,
+
+    /// APT channels to save on the output images.
+    pub output_channels: OutputChannels,
+
     /// Whether to sync frames.
     pub sync: bool,
 
-    /// Contrast adjustment method.
-    pub contrast_adjustment: Contrast,
+    /// Sync frames with lower confidence are discarded, from -1 to 1. Minus
+    /// infinity by default, so every sync frame is kept.
+    pub sync_threshold: f32,
+
+    /// Whether to correct the input sample rate by measuring the carrier
+    /// frequency.
+    pub correct_rate: bool,
+
+    /// Demodulation method.
+    pub demod_method: DemodMethod,
+
+    /// Color palette for the output image, grayscale if `None`.
+    pub palette: Option<Palette>,
+
+    /// Whether to remove the start and end of the recording without signal.
+    pub trim: bool,
+
+    /// Decode only these lines, from the first to the last (not included),
+    /// counted from the start of the recording. The recording is not trimmed
+    /// when set.
+    pub line_range: Option<(usize, usize)>,
+
+    /// Whether to give exactly the same result on every computer, see
+    /// `Context::deterministic`.
+    pub deterministic: bool,
+
+    /// Whether to sum the products on `f64` when filtering, see
+    /// `Context::high_precision`.
+    pub high_precision: bool,
+
+    /// Mu value for mu-law companding before mapping to pixels, if any.
+    pub compand: Option<f32>,
+
+    /// Time constant in seconds of the de-emphasis filter applied after
+    /// demodulating, if any.
+    pub deemphasis: Option<f32>,
+
+    /// Contrast adjustment method for channel A.
+    pub channel_a_contrast: Contrast,
+
+    /// Contrast adjustment method for channel B.
+    pub channel_b_contrast: Contrast,
+
+    /// Lines to average when reading telemetry, see
+    /// `telemetry::read_telemetry()`.
+    pub telemetry_lines: usize,
+
+    /// Whether to move the image by half a line when the telemetry says that
+    /// channels A and B are swapped, see `telemetry::detect_channel_swap()`.
+    /// Only works if some channel uses the telemetry for the contrast.
+    pub fix_swapped_channels: bool,
 
     /// If we are exporting steps to WAV.
     pub export_wav: bool,
@@ -61,8 +148,69 @@ pub struct DecodeSettings {
     /// Cutout frequency in Hz of the resampling filter.
     pub resample_cutout: f32,
 
+    /// Whether to flatten the passband of the resampling filter after
+    /// resampling.
+    pub compensate_droop: bool,
+
     /// Attenuation in positive dB for the demodulation filter.
     pub demodulation_atten: f32,
+
+    /// Cutout frequency in Hz of the demodulation filter, half of
+    /// `noaa_apt::FINAL_RATE` if `None`.
+    pub demodulation_cutout: Option<f32>,
+
+    /// Transition band width in Hz for the demodulation filter, a fifth of the
+    /// cutout frequency if `None`.
+    pub demodulation_delta_freq: Option<f32>,
+}
+
+impl DecodeSettings {
+    /// Settings with the same defaults as the commandline.
+    ///
+    /// The filter parameters come from the standard profile of the default
+    /// settings file, the settings file of the user is not read.
+    pub fn new(input_filename: &str, output_filename: &str) -> DecodeSettings {
+        let profile = default_de_settings().profiles.standard;
+
+        DecodeSettings {
+            input_filename: input_filename.to_string(),
+            output_filename: output_filename.to_string(),
+            raw_output_filename: None,
+            histogram_filename: None,
+            csv_filename: None,
+            thumbnail_size: None,
+            output_size: None,
+            compression: png::Compression::Fast,
+            line_snr: false,
+            preview: false,
+            output_channels: OutputChannels::Both,
+            sync: true,
+            sync_threshold: f32::NEG_INFINITY,
+            correct_rate: false,
+            demod_method: DemodMethod::TwoSample,
+            palette: None,
+            trim: false,
+            line_range: None,
+            deterministic: false,
+            high_precision: false,
+            compand: None,
+            deemphasis: None,
+            channel_a_contrast: Contrast::Percent(0.98),
+            channel_b_contrast: Contrast::Percent(0.98),
+            telemetry_lines: telemetry::DEFAULT_TELEMETRY_LINES,
+            fix_swapped_channels: false,
+            export_wav: false,
+            export_resample_filtered: false,
+            work_rate: profile.work_rate as u32,
+            resample_atten: profile.resample_atten as f32,
+            resample_delta_freq: profile.resample_delta_freq as f32,
+            resample_cutout: profile.resample_cutout as f32,
+            compensate_droop: false,
+            demodulation_atten: profile.demodulation_atten as f32,
+            demodulation_cutout: None,
+            demodulation_delta_freq: None,
+        }
+    }
 }
 
 /// Settings for resampling
@@ -150,6 +298,7 @@ struct DeProfile {
 }
 
 /// Parse `DeSettings` from file
+#[cfg(not(target_arch = "wasm32"))]
 fn parse_from_file(filename: &std::path::PathBuf) -> err::Result<DeSettings> {
     let mut file = std::fs::File::open(filename)?;
     let mut text = String::new();
@@ -157,10 +306,19 @@ fn parse_from_file(filename: &std::path::PathBuf) -> err::Result<DeSettings> {
     Ok(toml::from_str(text.as_str())?)
 }
 
+/// Load `DeSettings` from the default settings file included on the
+/// executable.
+fn default_de_settings() -> DeSettings {
+    toml::from_str(include_str!("default_settings.toml")).expect(
+        "Failed to parse default settings"
+    )
+}
+
 /// Load `DeSettings` from settings file.
 ///
 /// Tries to create the settings file if it's not available and loads the
 /// default settings.
+#[cfg(not(target_arch = "wasm32"))]
 fn load_de_settings() -> DeSettings {
 
     let default_settings_str = include_str!("default_settings.toml");
@@ -190,15 +348,77 @@ fn load_de_settings() -> DeSettings {
                     &filename,
                 );
             }
-            return toml::from_str(default_settings_str).expect(
-                "Failed to parse default settings"
-            )
+            return default_de_settings()
         }
     } else {
         println!("Could not get system settings directory, using default settings");
-        return toml::from_str(default_settings_str).expect(
-            "Failed to parse default settings"
-        )
+        return default_de_settings()
+    }
+}
+
+/// There is no settings file on WebAssembly, load the default settings.
+#[cfg(target_arch = "wasm32")]
+fn load_de_settings() -> DeSettings {
+    default_de_settings()
+}
+
+/// Parse contrast adjustment argument from commandline.
+///
+/// Returns `default` if the argument was not given. Exits if the argument is
+/// invalid.
+fn parse_contrast(argument: &Option<String>, default: Contrast) -> Contrast {
+
+    // See https://stackoverflow.com/questions/48034119/rust-matching-a-optionstring
+    match argument.as_ref().map(|s| s.as_str()) {
+        Some("telemetry") => Contrast::Telemetry,
+        Some("disable") => Contrast::MinMax,
+        Some("98_percent") => Contrast::Percent(0.98),
+        Some("auto") => Contrast::Auto(0.02),
+        None => default,
+        Some(_) => {
+            println!("Invalid contrast adjustment argument");
+            std::process::exit(0);
+        },
+    }
+}
+
+/// Parse line range argument from commandline, like `100-200`.
+///
+/// Returns `None` if the argument is invalid.
+fn parse_line_range(argument: &str) -> Option<(usize, usize)> {
+    let mut parts = argument.splitn(2, '-');
+    let first = parts.next()?.trim().parse().ok()?;
+    let last = parts.next()?.trim().parse().ok()?;
+    if first < last {
+        Some((first, last))
+    } else {
+        None
+    }
+}
+
+/// Parse PNG compression argument from commandline.
+///
+/// Returns `None` if the argument is invalid.
+fn parse_compression(argument: &str) -> Option<png::Compression> {
+    match argument {
+        "fast" => Some(png::Compression::Fast),
+        "default" => Some(png::Compression::Default),
+        "best" => Some(png::Compression::Best),
+        _ => None,
+    }
+}
+
+/// Parse image size argument from commandline, like `1000x1500`.
+///
+/// Returns `None` if the argument is invalid or a side is zero.
+fn parse_size(argument: &str) -> Option<(usize, usize)> {
+    let mut parts = argument.splitn(2, ['x', 'X']);
+    let width = parts.next()?.trim().parse().ok()?;
+    let height = parts.next()?.trim().parse().ok()?;
+    if width > 0 && height > 0 {
+        Some((width, height))
+    } else {
+        None
     }
 }
 
@@ -217,10 +437,34 @@ pub fn get_config() -> (bool, log::Level, Mode) {
     let mut wav_steps = false;
     let mut export_resample_filtered = false;
     let mut sync = true;
+    let mut sync_threshold: f32 = f32::NEG_INFINITY;
+    let mut correct_rate = false;
+    let mut demod_method: Option<String> = None;
+    let mut output_channels: Option<String> = None;
+    let mut palette: Option<String> = None;
+    let mut trim = false;
+    let mut lines: Option<String> = None;
+    let mut deterministic = false;
+    let mut high_precision = false;
+    let mut compensate_droop = false;
+    let mut compand: Option<f32> = None;
+    let mut deemphasis: Option<f32> = None;
     let mut contrast_adjustment: Option<String> = None;
+    let mut contrast_a: Option<String> = None;
+    let mut contrast_b: Option<String> = None;
+    let mut telemetry_lines = telemetry::DEFAULT_TELEMETRY_LINES;
+    let mut fix_swapped_channels = false;
     let mut profile: Option<String> = None;
     let mut print_version = false;
     let mut output_filename: Option<String> = None;
+    let mut raw_output_filename: Option<String> = None;
+    let mut histogram_filename: Option<String> = None;
+    let mut csv_filename: Option<String> = None;
+    let mut thumbnail_size: Option<usize> = None;
+    let mut output_size: Option<String> = None;
+    let mut compression: Option<String> = None;
+    let mut line_snr = false;
+    let mut preview = false;
     let mut resample_output: Option<u32> = None;
     {
         let mut parser = argparse::ArgumentParser::new();
@@ -252,11 +496,88 @@ pub fn get_config() -> (bool, log::Level, Mode) {
             .add_option(&["--no-sync"], argparse::StoreFalse,
             "Disable syncing, useful when the sync frames are noisy and the \
             syncing attempts do more harm than good.");
+        parser.refer(&mut sync_threshold)
+            .add_option(&["--sync-threshold"], argparse::Store,
+            "Discard sync frames with a correlation lower than this value, \
+            between -1 and 1. By default every sync frame is kept.")
+            .metavar("THRESHOLD");
+        parser.refer(&mut correct_rate)
+            .add_option(&["--correct-rate"], argparse::StoreTrue,
+            "Measure the carrier frequency and correct the sample rate of the \
+            recording, useful when the sample rate is slightly wrong and the \
+            image is slanted.");
+        parser.refer(&mut demod_method)
+            .add_option(&["--demod"], argparse::StoreOption,
+            "Demodulation method. Possible values: \"two_sample\" or \
+            \"square_law\". Square law has lower latency but is worse on \
+            noisy recordings. Two sample used by default.")
+            .metavar("METHOD");
+        parser.refer(&mut output_channels)
+            .add_option(&["--channels"], argparse::StoreOption,
+            "APT channels to save on the output images. Possible values: \
+            \"a\", \"b\" or \"both\". Both used by default.")
+            .metavar("CHANNELS");
+        parser.refer(&mut palette)
+            .add_option(&["--palette"], argparse::StoreOption,
+            "Color the output image using a palette, the raw output stays in \
+            grayscale. Possible values: \"thermal\" or \"rainbow\".")
+            .metavar("PALETTE");
+        parser.refer(&mut trim)
+            .add_option(&["--trim"], argparse::StoreTrue,
+            "Remove the start and end of the recording where there is only \
+            noise.");
+        parser.refer(&mut lines)
+            .add_option(&["--lines"], argparse::StoreOption,
+            "Decode only a range of lines, for example \"100-200\" decodes \
+            from line 100 to 199. There are two lines per second, counted from \
+            the start of the recording, disables --trim.")
+            .metavar("FIRST-LAST");
+        parser.refer(&mut deterministic)
+            .add_option(&["--deterministic"], argparse::StoreTrue,
+            "Give exactly the same image on every computer, for regression \
+            testing. Disables SIMD instructions, so it's slower.");
+        parser.refer(&mut high_precision)
+            .add_option(&["--high-precision"], argparse::StoreTrue,
+            "Reduce rounding errors when filtering with long filters, slower.");
+        parser.refer(&mut compensate_droop)
+            .add_option(&["--compensate-droop"], argparse::StoreTrue,
+            "Flatten the passband of the resampling filter, the image gets a \
+            little sharper.");
+        parser.refer(&mut compand)
+            .add_option(&["--compand"], argparse::StoreOption,
+            "Compress the dynamic range using mu-law companding before \
+            mapping to pixels, dark areas get more detail. Bigger values \
+            compress more, try 5.")
+            .metavar("MU");
+        parser.refer(&mut deemphasis)
+            .add_option(&["--deemphasis"], argparse::StoreOption,
+            "Apply a de-emphasis filter with this time constant in \
+            microseconds after demodulating, softens the noise on the image. \
+            Try 100.")
+            .metavar("MICROSECONDS");
         parser.refer(&mut contrast_adjustment)
             .add_option(&["-c", "--contrast"], argparse::StoreOption,
             "Contrast adjustment method for decode. Possible values: \
-            \"98_percent\", \"telemetry\" or \"disable\". 98 Percent used by \
-            default.");
+            \"98_percent\", \"auto\" (narrowest range clipping 2 percent), \
+            \"telemetry\" or \"disable\". 98 Percent used by default.");
+        parser.refer(&mut contrast_a)
+            .add_option(&["--contrast-a"], argparse::StoreOption,
+            "Contrast adjustment method only for channel A, overrides --contrast. \
+            Same values as --contrast.");
+        parser.refer(&mut contrast_b)
+            .add_option(&["--contrast-b"], argparse::StoreOption,
+            "Contrast adjustment method only for channel B, overrides --contrast. \
+            Same values as --contrast.");
+        parser.refer(&mut telemetry_lines)
+            .add_option(&["--telemetry-lines"], argparse::Store,
+            "Lines to average when reading telemetry, more are more stable if \
+            the wedges don't change during the pass. By default a single \
+            telemetry frame, 128 lines.")
+            .metavar("LINES");
+        parser.refer(&mut fix_swapped_channels)
+            .add_option(&["--fix-swapped-channels"], argparse::StoreTrue,
+            "If the telemetry shows channel B before channel A, move the image \
+            by half a line. Only when reading the telemetry for the contrast.");
         parser.refer(&mut profile)
             .add_option(&["-p", "--profile"], argparse::StoreOption,
             "Profile to use, values loaded from settings file. Possible values: \
@@ -264,8 +585,49 @@ pub fn get_config() -> (bool, log::Level, Mode) {
         parser.refer(&mut output_filename)
             .add_option(&["-o", "--output"], argparse::StoreOption,
             "Set output path. When decoding images the default is \
-            './output.png', when resampling the default is './output.wav'.")
+            './output.png', paths ending in '.pgm' or '.ppm' are saved as \
+            PGM/PPM. When resampling the default is './output.wav'.")
+            .metavar("FILENAME");
+        parser.refer(&mut raw_output_filename)
+            .add_option(&["--raw-output"], argparse::StoreOption,
+            "When decoding, also save the image without contrast adjustment \
+            to this path.")
             .metavar("FILENAME");
+        parser.refer(&mut histogram_filename)
+            .add_option(&["--histogram"], argparse::StoreOption,
+            "When decoding, also save a bar chart of the pixel histogram of \
+            the image to this path.")
+            .metavar("FILENAME");
+        parser.refer(&mut csv_filename)
+            .add_option(&["--csv"], argparse::StoreOption,
+            "When decoding, also save the demodulated signal before syncing \
+            to this path as CSV, one sample per line.")
+            .metavar("FILENAME");
+        parser.refer(&mut thumbnail_size)
+            .add_option(&["--thumbnail"], argparse::StoreOption,
+            "When decoding, also save a thumbnail that fits on a square of \
+            this size, next to the output image with '_thumb.png' at the end.")
+            .metavar("SIZE");
+        parser.refer(&mut output_size)
+            .add_option(&["--size"], argparse::StoreOption,
+            "Scale the output images to fit on this size, for example \
+            \"1000x1500\". The aspect ratio is kept and the rest is filled \
+            with black.")
+            .metavar("WIDTHxHEIGHT");
+        parser.refer(&mut compression)
+            .add_option(&["--compression"], argparse::StoreOption,
+            "Compression of the PNG images: \"fast\" (used by default), \
+            \"default\" or \"best\". Slower compression gives smaller files, \
+            the pixels are the same.")
+            .metavar("LEVEL");
+        parser.refer(&mut line_snr)
+            .add_option(&["--line-snr"], argparse::StoreTrue,
+            "When decoding, also save the SNR in dB of each line as CSV, next \
+            to the output image with '_snr.csv' at the end.");
+        parser.refer(&mut preview)
+            .add_option(&["--preview"], argparse::StoreTrue,
+            "Decode a rough image at half the size with less strict filters, \
+            much faster.");
         parser.refer(&mut resample_output)
             .add_option(&["-r", "--resample"], argparse::StoreOption,
             "Resample WAV file to a given sample rate, no APT image will be \
@@ -328,32 +690,113 @@ pub fn get_config() -> (bool, log::Level, Mode) {
         // resample_output option not set, decode WAV file
         } else {
 
-            // See https://stackoverflow.com/questions/48034119/rust-matching-a-optionstring
-            let contrast_adjustment: Contrast = match contrast_adjustment
-                .as_ref()
-                .map(|s| s.as_str())
-            {
-                Some("telemetry") => Contrast::Telemetry,
-                Some("disable") => Contrast::MinMax,
-                Some("98_percent") | None => Contrast::Percent(0.98),
+            let contrast_adjustment: Contrast =
+                parse_contrast(&contrast_adjustment, Contrast::Percent(0.98));
+            let channel_a_contrast: Contrast =
+                parse_contrast(&contrast_a, contrast_adjustment.clone());
+            let channel_b_contrast: Contrast =
+                parse_contrast(&contrast_b, contrast_adjustment);
+
+            let demod_method = match demod_method.as_ref().map(|s| s.as_str()) {
+                Some("two_sample") | None => DemodMethod::TwoSample,
+                Some("square_law") => DemodMethod::SquareLaw,
+                Some(_) => {
+                    println!("Invalid demodulation method argument");
+                    std::process::exit(0);
+                },
+            };
+
+            let output_channels = match output_channels.as_deref() {
+                Some("both") | None => OutputChannels::Both,
+                Some("a") => OutputChannels::A,
+                Some("b") => OutputChannels::B,
                 Some(_) => {
-                    println!("Invalid contrast adjustment argument");
+                    println!("Invalid channels argument");
                     std::process::exit(0);
                 },
             };
 
+            let palette = match palette {
+                Some(name) => match Palette::from_name(name.as_str()) {
+                    Some(palette) => Some(palette),
+                    None => {
+                        println!("Invalid palette argument");
+                        std::process::exit(0);
+                    },
+                },
+                None => None,
+            };
+
+            let line_range = match lines {
+                Some(lines) => match parse_line_range(&lines) {
+                    Some(range) => Some(range),
+                    None => {
+                        println!("Invalid lines argument");
+                        std::process::exit(0);
+                    },
+                },
+                None => None,
+            };
+
+            let output_size = match output_size {
+                Some(size) => match parse_size(&size) {
+                    Some(size) => Some(size),
+                    None => {
+                        println!("Invalid size argument");
+                        std::process::exit(0);
+                    },
+                },
+                None => None,
+            };
+
+            let compression = match compression {
+                Some(level) => match parse_compression(&level) {
+                    Some(compression) => compression,
+                    None => {
+                        println!("Invalid compression argument");
+                        std::process::exit(0);
+                    },
+                },
+                None => png::Compression::Fast,
+            };
+
             let settings = DecodeSettings {
                 input_filename,
                 output_filename: output_filename.unwrap_or("./output.png".to_string()),
+                raw_output_filename,
+                histogram_filename,
+                csv_filename,
+                thumbnail_size,
+                output_size,
+                compression,
+                line_snr,
+                preview,
+                output_channels,
                 export_wav: wav_steps,
                 export_resample_filtered,
                 sync,
-                contrast_adjustment,
+                sync_threshold,
+                correct_rate,
+                demod_method,
+                palette,
+                trim,
+                line_range,
+                deterministic,
+                high_precision,
+                compand,
+                deemphasis: deemphasis.map(|tau| tau * 1e-6),
+                channel_a_contrast,
+                channel_b_contrast,
+                telemetry_lines,
+                fix_swapped_channels,
                 work_rate: profile.work_rate as u32,
                 resample_atten: profile.resample_atten as f32,
                 resample_delta_freq: profile.resample_delta_freq as f32,
                 resample_cutout: profile.resample_cutout as f32,
+                compensate_droop,
                 demodulation_atten: profile.demodulation_atten as f32,
+                demodulation_cutout: None,
+                demodulation_delta_freq: None,
             };
 
             return (check_updates, verbosity, Mode::Decode(settings));
@@ -1,6 +1,6 @@
 //! Contains the Context struct.
 
-use dsp::{Signal, Rate};
+use dsp::{self, Signal, Rate, DotMode};
 use noaa_apt::PX_PER_ROW;
 use err;
 use wav;
@@ -58,6 +58,33 @@ impl<'a> Step<'a> {
     }
 }
 
+/// Part of the process a `DecodeEvent` comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    /// Loading the input file.
+    Read,
+    /// Resampling to the working or output rate.
+    Resample,
+    /// AM demodulation and filtering.
+    Demodulate,
+    /// Syncing and building the image lines.
+    Assemble,
+    /// Writing output files.
+    Write,
+}
+
+/// Progress notification with the stage it belongs to.
+///
+/// The same information given to the UI callback, but meant for programs
+/// using this as a library that want to show a log of each stage without
+/// capturing the `log` output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodeEvent {
+    pub stage: Stage,
+    pub message: String,
+    pub progress: f32,
+}
+
 /// Holds information about each step.
 struct StepMetadata {
     description: &'static str,
@@ -111,6 +138,32 @@ pub struct Context {
     /// so that function checks if this variable is set before doing extra work.
     pub export_resample_filtered: bool,
 
+    /// Give the same results on every computer.
+    ///
+    /// The decoding runs on a single thread and always in the same order, so
+    /// the only thing that can change between computers is whether
+    /// `dsp::dot()` uses SIMD instructions, that depends on the CPU. When set
+    /// the `dsp` functions always use the scalar version, see `dot_mode()`.
+    pub deterministic: bool,
+
+    /// Sum products on `f64` when filtering and resampling.
+    ///
+    /// Each output sample of `dsp::filter()` or the resampling functions is
+    /// the sum of as many products as coefficients, with the longest filters
+    /// that's thousands of `f32` additions and the rounding errors add up.
+    /// When set `dsp::dot()` accumulates on `f64` and rounds only the result,
+    /// it's slower and doesn't use SIMD instructions. Overrides
+    /// `deterministic`.
+    pub high_precision: bool,
+
+    /// Maximum length of the filter designed when resampling.
+    ///
+    /// When the sample rates have no big divisor in common (e.g. 44099Hz to
+    /// 11025Hz) the interpolation factor is huge and so is the filter,
+    /// resampling then takes a lot of memory and time. Over this limit
+    /// `dsp::resample_with_filter()` fails instead.
+    pub max_resample_taps: usize,
+
     /// Private field, if we are exporting to WAV.
     export_wav: bool,
 
@@ -119,15 +172,39 @@ pub struct Context {
 
     /// Callback to notify the UI
     ui_callback: Box<FnMut(f32, String)>,
+
+    /// Optional callback that receives a `DecodeEvent` on every status.
+    event_callback: Option<Box<dyn FnMut(DecodeEvent)>>,
+
+    /// Stage set by the last call to `set_stage()`.
+    stage: Stage,
 }
 
 impl Context {
 
     /// Notify progress
     pub fn status(&mut self, progress: f32, description: String) {
+        if let Some(ref mut callback) = self.event_callback {
+            callback(DecodeEvent {
+                stage: self.stage,
+                message: description.clone(),
+                progress,
+            });
+        }
         (self.ui_callback)(progress, description);
     }
 
+    /// Set stage reported on the next `DecodeEvent`s.
+    pub fn set_stage(&mut self, stage: Stage) {
+        self.stage = stage;
+    }
+
+    /// Receive a `DecodeEvent` on every status notification, besides the UI
+    /// callback.
+    pub fn set_event_callback<F: FnMut(DecodeEvent) + 'static>(&mut self, callback: F) {
+        self.event_callback = Some(Box::new(callback));
+    }
+
     /// Export step.
     pub fn step(&mut self, step: Step) -> err::Result<()> {
         if self.export_wav {
@@ -208,6 +285,17 @@ impl Context {
         Ok(())
     }
 
+    /// How the `dsp` functions should calculate dot products.
+    pub fn dot_mode(&self) -> DotMode {
+        if self.high_precision {
+            DotMode::HighPrecision
+        } else if self.deterministic {
+            DotMode::Deterministic
+        } else {
+            DotMode::Fast
+        }
+    }
+
     /// Create `Context` for a resampling process.
     pub fn resample<F: FnMut(f32, String) + 'static>(
         ui_callback: F,
@@ -248,9 +336,14 @@ impl Context {
             ],
             export_steps: export_wav,
             export_resample_filtered,
+            deterministic: false,
+            high_precision: false,
+            max_resample_taps: dsp::DEFAULT_MAX_RESAMPLE_TAPS,
             export_wav,
             index: 0,
             ui_callback: Box::new(ui_callback),
+            event_callback: None,
+            stage: Stage::Read,
         }
     }
 
@@ -394,9 +487,14 @@ impl Context {
             ],
             export_steps: export_wav,
             export_resample_filtered,
+            deterministic: false,
+            high_precision: false,
+            max_resample_taps: dsp::DEFAULT_MAX_RESAMPLE_TAPS,
             export_wav,
             index: 0,
             ui_callback: Box::new(ui_callback),
+            event_callback: None,
+            stage: Stage::Read,
         }
     }
 }
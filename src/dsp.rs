@@ -5,14 +5,44 @@ use num::ToPrimitive;
 
 pub use frequency::Freq;
 pub use frequency::Rate;
+use frequency::hz_to_norm;
 use err;
+use fft;
 use filters;
 use context::{Context, Step};
 
 
 /// Represents a signal, it's just a `Vec<f32>`.
+///
+/// Functions that reduce a signal to a single value (e.g. `get_max()`,
+/// `misc::percent()`) fail with `err::Error::Internal` when the signal is
+/// empty instead of making up a value. Functions that transform a signal give
+/// an empty signal back.
 pub type Signal = Vec<f32>;
 
+/// Magnitude spectrum, one value for each frequency bin.
+///
+/// Not a `Signal` on purpose, so a spectrum can't end up on `filter()` or
+/// `resample()` by mistake. Derefs to `[f32]` for reading the bins, use
+/// `into_vec()` when you really want the values as a `Signal`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Spectrum(Vec<f32>);
+
+impl Spectrum {
+    /// Take the values out.
+    pub fn into_vec(self) -> Vec<f32> {
+        self.0
+    }
+}
+
+impl std::ops::Deref for Spectrum {
+    type Target = [f32];
+
+    fn deref(&self) -> &[f32] {
+        &self.0
+    }
+}
+
 /// Get biggest sample in signal.
 #[allow(dead_code)]
 pub fn get_max(vector: &Signal) -> err::Result<&f32> {
@@ -49,12 +79,305 @@ pub fn get_min(vector: &Signal) -> err::Result<&f32> {
     Ok(min)
 }
 
+/// Mean of the signal without the biggest and smallest samples.
+///
+/// Discards `trim_fraction` of the samples on each side (so 0.1 keeps the
+/// central 80%), that way a few spikes don't move the result. The fraction is
+/// clamped between 0 and 0.5. If trimming would remove every sample I keep the
+/// middle one or two, which gives the median.
+pub fn trimmed_mean(signal: &Signal, trim_fraction: f32) -> err::Result<f32> {
+    if signal.is_empty() {
+        return Err(err::Error::Internal(
+            "Can't get trimmed mean of a zero length vector".to_string()));
+    }
+
+    let mut sorted = signal.clone();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    let len = sorted.len();
+    let trim = (len as f32 * trim_fraction.clamp(0., 0.5)) as usize;
+    let trim = trim.min((len - 1) / 2);
+    let kept = &sorted[trim..len - trim];
+
+    Ok((kept.iter().map(|x| f64::from(*x)).sum::<f64>() / kept.len() as f64) as f32)
+}
+
+/// Concatenate signals.
+pub fn concat(parts: &[&Signal]) -> Signal {
+    let mut result: Signal =
+        Vec::with_capacity(parts.iter().map(|p| p.len()).sum());
+    for part in parts {
+        result.extend_from_slice(part);
+    }
+    result
+}
+
+/// Add `n` zeros at the start and at the end of the signal.
+pub fn pad_zeros(signal: &Signal, n: usize) -> Signal {
+    let zeros: Signal = vec![0.; n];
+    concat(&[&zeros, signal, &zeros])
+}
+
+/// Add `n` samples at the start and at the end of the signal by reflection.
+///
+/// The signal is mirrored around the first and last samples, without
+/// repeating them: `[1, 2, 3]` padded by 2 gives `[3, 2, 1, 2, 3, 2, 1]`.
+/// Useful to avoid transients when filtering, because the signal continues
+/// smoothly at the edges. Fails if `n` is not smaller than the signal length.
+pub fn pad_reflect(signal: &Signal, n: usize) -> err::Result<Signal> {
+    if n >= signal.len() {
+        return Err(err::Error::Internal(format!(
+            "Can't reflect {} samples on a signal of length {}",
+            n, signal.len())));
+    }
+
+    let start: Signal = signal[1..=n].iter().rev().cloned().collect();
+    let end: Signal = signal[signal.len() - 1 - n .. signal.len() - 1]
+        .iter().rev().cloned().collect();
+
+    Ok(concat(&[&start, signal, &end]))
+}
+
+/// Find peaks on a signal.
+///
+/// Returns the indices of local maxima bigger or equal than `threshold`,
+/// sorted. If two peaks are closer than `min_distance` samples I keep the
+/// strongest one, if they have the same value I keep the first one.
+pub fn find_peaks(signal: &Signal, min_distance: usize, threshold: f32) -> Vec<usize> {
+    // Local maxima over threshold. On plateaus only the first sample counts
+    let mut candidates: Vec<usize> = (0..signal.len())
+        .filter(|&i| signal[i] >= threshold)
+        .filter(|&i| i == 0 || signal[i] > signal[i - 1])
+        .filter(|&i| i == signal.len() - 1 || signal[i] >= signal[i + 1])
+        .collect();
+
+    // Strongest first, stable so ties keep the order
+    candidates.sort_by(|&a, &b| signal[b].partial_cmp(&signal[a])
+        .unwrap_or(::std::cmp::Ordering::Equal));
+
+    let mut peaks: Vec<usize> = Vec::new();
+    for candidate in candidates {
+        let too_close = peaks.iter()
+            .any(|&peak| peak.abs_diff(candidate) < min_distance);
+        if !too_close {
+            peaks.push(candidate);
+        }
+    }
+
+    peaks.sort();
+    peaks
+}
+
+/// Compress the dynamic range of a signal using mu-law companding.
+///
+/// Samples are scaled so the biggest absolute value is one, then mapped with:
+///
+/// ```text
+/// y = sign(x) * ln(1 + mu * |x|) / ln(1 + mu)
+/// ```
+///
+/// And scaled back. Small values get more range than big values, bigger `mu`
+/// means more compression. The peak value stays the same, so `expand()` with
+/// the same `mu` undoes it.
+pub fn compand(signal: &mut Signal, mu: f32) {
+    mu_law(signal, |x| (1. + mu * x).ln() / (1. + mu).ln());
+}
+
+/// Inverse of `compand()`.
+pub fn expand(signal: &mut Signal, mu: f32) {
+    mu_law(signal, |y| ((1. + mu).powf(y) - 1.) / mu);
+}
+
+/// De-emphasis, single pole lowpass filter with time constant `tau` in
+/// seconds.
+///
+/// ```text
+/// y[n] = y[n-1] + a * (x[n] - y[n-1])
+/// ```
+///
+/// Where `a = 1 - exp(-1 / (tau * rate))`. Has unity gain on DC and starts
+/// from zero, so the first samples are attenuated. `pre_emphasis()` with the
+/// same `tau` undoes it.
+pub fn deemphasis(signal: &Signal, rate: Rate, tau: f32) -> Signal {
+    let a = emphasis_coefficient(rate, tau);
+    let mut previous = 0.;
+    signal.iter().map(|x| {
+        previous += a * (x - previous);
+        previous
+    }).collect()
+}
+
+/// Inverse of `deemphasis()`, boosts high frequencies.
+pub fn pre_emphasis(signal: &Signal, rate: Rate, tau: f32) -> Signal {
+    let a = emphasis_coefficient(rate, tau);
+    let mut previous = 0.;
+    signal.iter().map(|y| {
+        let x = (y - (1. - a) * previous) / a;
+        previous = *y;
+        x
+    }).collect()
+}
+
+/// Coefficient of the single pole filter used by `deemphasis()`, a time
+/// constant of zero gives a filter that does nothing.
+fn emphasis_coefficient(rate: Rate, tau: f32) -> f32 {
+    if tau <= 0. {
+        1.
+    } else {
+        1. - (-1. / (tau * rate.get_hz() as f32)).exp()
+    }
+}
+
+/// Apply function to the absolute value of the samples normalized to the
+/// peak, keeping the sign.
+fn mu_law<F: Fn(f32) -> f32>(signal: &mut Signal, function: F) {
+    let peak = signal.iter().fold(0_f32, |peak, x| peak.max(x.abs()));
+    if peak == 0. || !peak.is_finite() {
+        return;
+    }
+
+    for sample in signal.iter_mut() {
+        *sample = sample.signum() * function(sample.abs() / peak) * peak;
+    }
+}
+
+/// Calculate absolute value of the FFT.
+///
+/// Fails if compiled without FFT support, see `fft::fft_forward()`.
+pub fn abs_fft(signal: &Signal) -> err::Result<Spectrum> {
+    Ok(Spectrum(fft::fft_forward(signal)?.iter().map(|x| x.norm()).collect()))
+}
+
+/// Average magnitude spectrum of blocks of the signal.
+///
+/// Uses up to 16 blocks of 2^16 samples distributed along the signal, shorter
+/// blocks if the signal is short. Returns only the positive frequencies, so
+/// the block length used is twice the length of the result.
+fn averaged_spectrum(signal: &Signal) -> err::Result<Spectrum> {
+
+    // Maximum amount of blocks to average, more than enough and avoids
+    // spending time on long recordings
+    let max_blocks = 16;
+
+    // Use shorter blocks if the signal is short
+    let mut block_len: usize = 1 << 16;
+    while block_len > signal.len() && block_len > 64 {
+        block_len >>= 1;
+    }
+    if signal.len() < block_len {
+        return Err(err::Error::Internal(
+            "Signal too short for spectrum estimation".to_string()));
+    }
+
+    // Take blocks distributed along the whole signal
+    let num_blocks = (signal.len() / block_len).min(max_blocks);
+    let step = signal.len() / num_blocks;
+
+    let mut spectrum: Signal = vec![0.; block_len / 2];
+    for i in 0..num_blocks {
+        let block: Signal = signal[i * step .. i * step + block_len].to_vec();
+        for (s, x) in spectrum.iter_mut().zip(abs_fft(&block)?.iter()) {
+            *s += x;
+        }
+    }
+
+    Ok(Spectrum(spectrum))
+}
+
+/// Estimate the frequency offset of a tone.
+///
+/// Returns the difference in Hz between the frequency of the strongest tone
+/// found near `expected` (up to 10% away) and `expected`.
+///
+/// When the sample rate of a recording is wrong, every frequency is moved by
+/// the same ratio, so this is useful for measuring the error. It's simpler than
+/// tracking the frequency because it assumes the offset is constant: the
+/// spectrum of blocks of 2^16 samples are averaged and the peak position is
+/// refined using parabolic interpolation.
+pub fn estimate_freq_offset(signal: &Signal, rate: Rate, expected: f32) -> err::Result<f32> {
+
+    let spectrum = averaged_spectrum(signal).map_err(|e| match e {
+        err::Error::FeatureNotAvailable(_) => e,
+        _ => err::Error::Internal(
+            "Signal too short for frequency offset estimation".to_string()),
+    })?;
+    let block_len = spectrum.len() * 2;
+
+    // Bins to search
+    let hz_per_bin = rate.get_hz() as f32 / block_len as f32;
+    let first = ((expected * 0.9 / hz_per_bin) as usize).max(1);
+    let last = ((expected * 1.1 / hz_per_bin).ceil() as usize).min(spectrum.len() - 2);
+    if first >= last {
+        return Err(err::Error::Internal(format!(
+            "Can't look for {}Hz tone with sample rate {}Hz",
+            expected, rate.get_hz())));
+    }
+
+    let mut peak = first;
+    for i in first..=last {
+        if spectrum[i] > spectrum[peak] {
+            peak = i;
+        }
+    }
+
+    // Parabolic interpolation around the peak
+    let (a, b, c) = (spectrum[peak - 1], spectrum[peak], spectrum[peak + 1]);
+    let denominator = a - 2. * b + c;
+    let offset = if denominator != 0. { 0.5 * (a - c) / denominator } else { 0. };
+
+    let found = (peak as f32 + offset) * hz_per_bin;
+
+    debug!("Expected tone at {}Hz, found at {}Hz", expected, found);
+
+    Ok(found - expected)
+}
+
+/// Correct sample rate given the frequency offset of a tone.
+///
+/// If a tone at `expected` Hz appears at `expected + offset` Hz, the real
+/// sample rate of the signal is different from `rate`. Returns the real one.
+pub fn correct_rate(rate: Rate, expected: f32, offset: f32) -> Rate {
+    Rate::hz((rate.get_hz() as f32 * expected / (expected + offset)).round() as u32)
+}
+
+/// RMS value that `auto_gain()` sets.
+pub const AUTO_GAIN_RMS: f32 = 0.25;
+
+/// Scale signal so its RMS value is `AUTO_GAIN_RMS`.
+///
+/// It's a single global scale, not an AGC. Quiet recordings have very small
+/// sample values and lose precision on the float pipeline, so this should be
+/// done before any filtering. Signals with only zeros are left untouched.
+pub fn auto_gain(signal: &mut Signal) {
+
+    if signal.is_empty() {
+        return;
+    }
+
+    // Accumulate on f64, the sum of squares of a long recording is big
+    let rms = (signal.iter().map(|x| f64::from(*x).powi(2)).sum::<f64>()
+        / signal.len() as f64).sqrt() as f32;
+
+    if rms == 0. || !rms.is_finite() {
+        warn!("Can't normalize gain of signal, RMS is {}", rms);
+        return;
+    }
+
+    let gain = AUTO_GAIN_RMS / rms;
+    debug!("Applying gain of {} to signal", gain);
+
+    for sample in signal.iter_mut() {
+        *sample *= gain;
+    }
+}
+
 /// Filter and then resample.
 ///
 /// Does both things at the same time, so it's faster than calling `filter()`
 /// and then resampling. Make sure that the filter prevents aliasing.
 ///
-/// The filter should have the frequencies referenced to the `input_rate`.
+/// The filter should have the frequencies referenced to the `input_rate`. The
+/// amplitude of the frequencies on the passband is preserved.
 pub fn resample_with_filter(
     context: &mut Context,
     signal: &Signal,
@@ -84,6 +407,7 @@ pub fn resample_with_filter(
         )?;
 
         filt.resample(input_rate, interpolated_rate);
+        check_resample_taps(&filt, input_rate, output_rate, l, m, context.max_resample_taps)?;
         let coeff = filt.design();
 
         context.step(Step::filter("resample_filter", &coeff))?;
@@ -94,13 +418,16 @@ pub fn resample_with_filter(
 
     } else {
 
+        check_resample_taps(&filt, input_rate, output_rate, l, m, context.max_resample_taps)?;
+
         context.step(Step::filter("resample_filter", &filt.design()))?;
 
-        let filtered = &filter(context, &signal, filt)?;
+        let mut filtered = filter(context, &signal, filt)?;
 
         context.step(Step::signal("resample_filtered", &filtered, Some(input_rate)))?;
 
-        result = decimate(filtered, m);
+        decimate(&mut filtered, m);
+        result = filtered;
 
         context.step(Step::signal("resample_decimated", &result, Some(output_rate)))?;
     }
@@ -108,6 +435,33 @@ pub fn resample_with_filter(
     Ok(result)
 }
 
+/// Default for `Context::max_resample_taps`, about 2MB of coefficients.
+pub const DEFAULT_MAX_RESAMPLE_TAPS: usize = 500_000;
+
+/// Fail if the resampling filter is longer than `max_taps`, see
+/// `Context::max_resample_taps`.
+fn check_resample_taps(
+    filt: &impl filters::Filter,
+    input_rate: Rate,
+    output_rate: Rate,
+    l: u32,
+    m: u32,
+    max_taps: usize,
+) -> err::Result<()> {
+    let taps = filt.length();
+
+    if taps > max_taps {
+        return Err(err::Error::RateOverflow(format!(
+            "Can't resample from {}Hz to {}Hz, the rates do not have a big \
+            divisor in common (L/M: {}/{}) and the filter needs {} \
+            coefficients, more than the limit of {}. Try resampling to a \
+            rate with more divisors in common",
+            input_rate.get_hz(), output_rate.get_hz(), l, m, taps, max_taps)));
+    }
+
+    Ok(())
+}
+
 /// Resample signal.
 ///
 /// `delta_w` is the transition band of the lowpass filter to use. `atten` is
@@ -141,6 +495,187 @@ pub fn resample(
     )
 }
 
+/// Length of the equalizer used by `resample_compensated()`.
+const DROOP_COMPENSATION_TAPS: usize = 15;
+
+/// Filter and resample, then flatten the passband of the filter.
+///
+/// Same as `resample_with_filter()` followed by an equalizer that undoes the
+/// droop of the filter between `start` and `end`, in Hz. See
+/// `filters::droop_compensation()`. The equalizer is applied without delay, so
+/// the output is aligned with the one from `resample_with_filter()`.
+pub fn resample_compensated(
+    context: &mut Context,
+    signal: &Signal,
+    input_rate: Rate,
+    output_rate: Rate,
+    filt: impl filters::Filter + Clone,
+    (start, end): (f32, f32),
+) -> err::Result<Signal> {
+
+    let resampled = resample_with_filter(
+        context, signal, input_rate, output_rate, filt.clone())?;
+
+    // Design the filter again at the same rate used when resampling, it
+    // didn't overflow there
+    let gcd = input_rate.get_hz().gcd(&output_rate.get_hz());
+    let filter_rate = Rate::hz(input_rate.get_hz() * (output_rate.get_hz() / gcd));
+    let mut filt = filt;
+    filt.resample(input_rate, filter_rate);
+    let coeff = filt.design();
+
+    let mut equalizer = filters::droop_compensation(
+        &coeff,
+        Freq::hz(start, filter_rate),
+        Freq::hz(end, filter_rate),
+        DROOP_COMPENSATION_TAPS,
+    );
+    filters::Filter::resample(&mut equalizer, filter_rate, output_rate);
+
+    filter_centered(context, &resampled, equalizer)
+}
+
+/// Where `resample()` starts the transition band, as a fraction of the lowest
+/// Nyquist frequency. Used with `resample_to_quality()`.
+pub const RESAMPLE_TRANSITION_START: f32 = 0.9;
+
+/// Resample signal choosing where the transition band starts.
+///
+/// `transition_start` is a fraction of the lowest Nyquist frequency (half of
+/// the smaller rate) and the transition band is `delta_w` wide from there.
+/// `resample()` centers the transition band on the Nyquist frequency, so with
+/// the usual `delta_w` of a fifth of the Nyquist frequency that's the same as
+/// a `transition_start` of `RESAMPLE_TRANSITION_START`, letting a little
+/// aliasing through.
+///
+/// If the transition band ends before the Nyquist frequency there is no
+/// aliasing at all (down to `atten`), but the highest frequencies of the
+/// passband are lost so the image is less sharp.
+pub fn resample_to_quality(
+    context: &mut Context,
+    signal: &Signal,
+    input_rate: Rate,
+    output_rate: Rate,
+    atten: f32,
+    delta_w: Freq,
+    transition_start: f32,
+) -> err::Result<Signal> {
+
+    if transition_start <= 0. || transition_start > 1. {
+        return Err(err::Error::Internal(format!(
+            "Transition start should be between 0 and 1, got {}", transition_start)));
+    }
+
+    let nyquist = std::cmp::min(input_rate.get_hz(), output_rate.get_hz()) as f32 / 2.;
+    let cutout = Freq::hz(transition_start * nyquist, input_rate)
+        + delta_w / 2.;
+
+    resample_with_filter(context, signal, input_rate, output_rate,
+        filters::Lowpass {
+            cutout,
+            atten,
+            delta_w,
+        }
+    )
+}
+
+/// Attenuation in positive dB of the lowpass used by `resample_to_length`.
+const RESAMPLE_TO_LENGTH_ATTEN: f32 = 40.;
+
+/// Resample signal to an exact length.
+///
+/// The ratio `target_len / signal.len()` is approximated by the simplest
+/// fraction `L/M` that lands within one sample of the target, so the filter
+/// stays short even when the lengths have no big divisor in common. Then I
+/// calculate exactly `target_len` samples, the last one can fall just after
+/// the end of the input and end up close to zero.
+///
+/// Unlike `resample_with_filter()` the output is aligned with the input, the
+/// filter delay is compensated.
+pub fn resample_to_length(
+    context: &mut Context,
+    signal: &Signal,
+    target_len: usize,
+) -> err::Result<Signal> {
+
+    if signal.is_empty() || target_len == 0 {
+        return Ok(vec![0.; target_len]);
+    }
+
+    let (l, m) = length_ratio(signal.len() as u64, target_len as u64);
+    let interpolated_rate = match (l * m).to_u32() {
+        Some(rate) => Rate::hz(rate),
+        None => return Err(err::Error::RateOverflow(format!(
+            "Can't resample from {} to {} samples, L/M: {}/{}",
+            signal.len(), target_len, l, m))),
+    };
+
+    debug!("Resampling to length {} by L/M: {}/{}", target_len, l, m);
+
+    // I use the factors as sample rates, only the ratio matters
+    let mut filt = filters::Lowpass {
+        cutout: Freq::pi_rad(std::cmp::min(l, m) as f32 / m as f32),
+        atten: RESAMPLE_TO_LENGTH_ATTEN,
+        delta_w: Freq::pi_rad(std::cmp::min(l, m) as f32 / m as f32 / 10.),
+    };
+    filters::Filter::resample(&mut filt, Rate::hz(m as u32), interpolated_rate);
+    let coeff = filters::Filter::design(&filt);
+
+    context.step(Step::filter("resample_filter", &coeff))?;
+
+    // Filter delay in the interpolated axis
+    let offset = (coeff.len() as u64 - 1) / 2;
+
+    let output: Signal = (0..target_len as u64).map(|j| {
+        let t = j * m; // Position of the output sample in the interpolated axis
+
+        // Input samples inside the filter window centered on t
+        let first = (t.saturating_sub(offset)).div_ceil(l);
+        let last = std::cmp::min((t + offset) / l, signal.len() as u64 - 1);
+
+        (first..=last).map(|n| {
+            signal[n as usize] * coeff[(n * l + offset - t) as usize]
+        }).sum::<f32>() * l as f32
+    }).collect();
+
+    context.step(Step::signal("resample_decimated", &output, None))?;
+
+    Ok(output)
+}
+
+/// Find the simplest `L/M` that resamples `input_len` samples to within one
+/// sample of `target_len`.
+///
+/// Walks the convergents of the continued fraction of `target_len /
+/// input_len`, the last one is the exact ratio so this always finishes.
+fn length_ratio(input_len: u64, target_len: u64) -> (u64, u64) {
+    let (mut num, mut den) = (target_len, input_len);
+    let (mut h, mut h_prev) = (1, 0);
+    let (mut k, mut k_prev) = (0, 1);
+
+    while den != 0 {
+        let a = num / den;
+        let next = (a * h + h_prev, a * k + k_prev);
+        h_prev = h;
+        k_prev = k;
+        h = next.0;
+        k = next.1;
+
+        if h != 0 {
+            let len = (input_len * h + k / 2) / k; // Rounded
+            if len.abs_diff(target_len) <= 1 {
+                return (h, k);
+            }
+        }
+
+        let rem = num - a * den;
+        num = den;
+        den = rem;
+    }
+
+    (h, k)
+}
+
 /// Resample a signal using a given filter.
 ///
 /// Low-level function used by `resample_with_filter`.
@@ -150,6 +685,9 @@ pub fn resample(
 ///
 /// Resamples by expansion by `l`, filtering and then decimation by `m`. The
 /// expansion is equivalent to the insertion of `l-1` zeros between samples.
+/// Only one out of `l` samples is not zero, so after the lowpass filter the
+/// signal is `l` times smaller, the coefficients are multiplied by `l` to keep
+/// the amplitude of the input (see `polyphase()`).
 ///
 /// The given filter coefficients should be designed for the signal after
 /// expansion by `l`, so you might want to divide every frequency by `l` when
@@ -162,6 +700,11 @@ pub fn resample(
 /// Should be careful because it's easy to overflow usize when on 32 bits
 /// systems. Specifically the variables that can overflow are:
 /// `interpolated_len`, `n`, `t`.
+///
+/// The filter window of the last output samples goes past the end of the
+/// input. I keep those samples instead of truncating the output, so the length
+/// doesn't change, but correct their gain with `edge_gain()`, otherwise they
+/// fade to zero.
 #[allow(clippy::many_single_char_names)]
 fn fast_resampling(
     context: &mut Context,
@@ -205,6 +748,11 @@ fn fast_resampling(
     // Filter delay in the n axis, half of filter width
     let offset: u64 = (coeff.len() as u64 - 1) / 2;
 
+    // Each output sample is a dot product between a phase and contiguous
+    // input samples
+    let phases = polyphase(coeff, l);
+    let mode = context.dot_mode();
+
     let mut n: u64; // Current working n
 
     let mut t: u64 = offset; // Like n but fixed to the current output
@@ -226,20 +774,16 @@ fn fast_resampling(
             n = 0;
         }
 
-        // Loop over all n inside the window with input samples and
-        // calculate products
-        let mut sum = 0.;
-        let mut x = n / l; // Current input sample
-        while n <= t + offset {
-            // Check if there is a sample in that index, in case that we
-            // use an index bigger that signal.len()
-            if let Some(sample) = signal.get(x as usize) {
-                // n+offset-t is equal to j
-                sum += coeff[(n + offset - t) as usize] * sample;
-            }
-            x += 1;
-            n += l;
-        }
+        // Multiply all n inside the window with input samples. n+offset-t is
+        // the index of the first coefficient, the phase goes until the end
+        // of the window
+        let x = (n / l) as usize; // First input sample
+        let sum = if x < signal.len() {
+            let phase = &phases[(n + offset - t) as usize];
+            dot(&signal[x..], phase, mode) * edge_gain(phase, signal.len() - x)
+        } else {
+            0.
+        };
 
         if context.export_resample_filtered {
             // Iterate over every sample on the n axis, inefficient because we
@@ -268,37 +812,315 @@ fn fast_resampling(
     Ok(output)
 }
 
-/// Decimate without filtering.
+/// Gain correction for an output sample whose filter window goes past the
+/// end of the input.
+///
+/// Only the first `available` coefficients of the phase get multiplied by an
+/// input sample. I scale the result by the gain of the whole phase divided by
+/// the gain of the coefficients used, like if the missing samples had the
+/// same level as the ones available. The window is always at least half full,
+/// so the correction stays around 2 or below.
+fn edge_gain(phase: &[f32], available: usize) -> f32 {
+    if available >= phase.len() {
+        return 1.;
+    }
+
+    let partial: f32 = phase[..available].iter().sum();
+    if partial.abs() < f32::EPSILON {
+        1.
+    } else {
+        phase.iter().sum::<f32>() / partial
+    }
+}
+
+/// Split filter in L phases for resampling.
+///
+/// Phase p has coefficients p, p + L, p + 2L, etc. Multiplied by L to
+/// compensate the zeros inserted when expanding, otherwise the amplitude of
+/// the signal is divided by L. The decoder didn't notice because the contrast
+/// is adjusted afterwards, but WAV files resampled with `--resample` came out
+/// almost silent and the levels of `stream::DecodeIter` need the right scale.
+fn polyphase(coeff: &Signal, l: u64) -> Vec<Signal> {
+    (0..l as usize)
+        .map(|p| coeff.iter().skip(p).step_by(l as usize).map(|c| c * l as f32).collect())
+        .collect()
+}
+
+/// Resampler for signals received by parts.
+///
+/// Gives exactly the same result as `fast_resampling()` on the whole signal,
+/// no matter how it's split in blocks. Keeps the position of the next output
+/// sample on the n axis (see the documentation of `fast_resampling()`) and the
+/// input samples that are still needed.
+///
+/// Each call to `process()` returns the output samples that can be calculated
+/// with the samples received so far, the rest are returned by `finish()`.
+pub struct Resampler {
+    l: u64,
+    m: u64,
+
+    /// Filter delay in the n axis, half of filter width.
+    offset: u64,
+
+    phases: Vec<Signal>,
+
+    /// Input samples still needed.
+    buffer: Signal,
+
+    /// Index of the first sample of the buffer on the input signal.
+    buffer_start: u64,
+
+    /// Amount of input samples received.
+    received: u64,
+
+    /// Position of the next output sample on the n axis.
+    t: u64,
+
+    dot_mode: DotMode,
+}
+
+impl Resampler {
+
+    /// Create resampler.
+    ///
+    /// The filter should have the frequencies referenced to the `input_rate`.
+    /// Fails if the filter is longer than `max_taps`, usually
+    /// `Context::max_resample_taps`.
+    pub fn new(
+        input_rate: Rate,
+        output_rate: Rate,
+        mut filt: impl filters::Filter,
+        max_taps: usize,
+    ) -> err::Result<Self> {
+
+        if output_rate.get_hz() == 0 {
+            return Err(err::Error::Internal("Can't resample to 0Hz".to_string()));
+        }
+
+        let gcd = input_rate.get_hz().gcd(&output_rate.get_hz());
+        let l = output_rate.get_hz() / gcd; // interpolation factor
+        let m = input_rate.get_hz() / gcd; // decimation factor
+
+        let interpolated_rate = input_rate.checked_mul(l).ok_or_else(||
+            err::Error::RateOverflow(format!(
+                "Can't resample, looks like the sample rates do not have a big \
+                divisor in common. input_rate: {}, output_rate: {}, l: {}, m: {}",
+                input_rate.get_hz(), output_rate.get_hz(), l, m
+            ))
+        )?;
+
+        filt.resample(input_rate, interpolated_rate);
+        check_resample_taps(&filt, input_rate, output_rate, l, m, max_taps)?;
+        let coeff = filt.design();
+        let offset = (coeff.len() as u64 - 1) / 2;
+
+        Ok(Self {
+            l: u64::from(l),
+            m: u64::from(m),
+            offset,
+            phases: polyphase(&coeff, u64::from(l)),
+            buffer: Vec::new(),
+            buffer_start: 0,
+            received: 0,
+            t: offset,
+            dot_mode: DotMode::Fast,
+        })
+    }
+
+    /// Set how the products are summed, `DotMode::Fast` by default.
+    pub fn set_dot_mode(&mut self, mode: DotMode) {
+        self.dot_mode = mode;
+    }
+
+    /// How the products are summed, see `set_dot_mode()`.
+    pub fn dot_mode(&self) -> DotMode {
+        self.dot_mode
+    }
+
+    /// Resample a block of the signal.
+    pub fn process(&mut self, block: &[f32]) -> Signal {
+        let mut output: Signal = Vec::new();
+        self.process_into(block, &mut output);
+        output
+    }
+
+    /// Resample a block of the signal writing the result on `output`.
+    ///
+    /// Same as `process()` but replaces what `output` had before, so the same
+    /// buffer can be used on every call. Once it's big enough for the longest
+    /// output nothing else is allocated on each call, besides the input
+    /// samples kept between calls, at most a filter length. If you know the
+    /// block size, reserve `block_size * output_rate / input_rate + 1` samples
+    /// beforehand.
+    pub fn process_into(&mut self, block: &[f32], output: &mut Signal) {
+        self.buffer.extend_from_slice(block);
+        self.received += block.len() as u64;
+
+        // Calculate while the whole window has samples, the last input
+        // sample used is on n = t + offset
+        self.resample_until(|t, offset, available| t + offset < available, output);
+    }
+
+    /// Return the last output samples.
+    ///
+    /// Their windows are not full, the gain is corrected like on
+    /// `fast_resampling()`.
+    pub fn finish(&mut self) -> Signal {
+        let mut output: Signal = Vec::new();
+        self.finish_into(&mut output);
+        output
+    }
+
+    /// Write the last output samples on `output`, like `finish()`.
+    pub fn finish_into(&mut self, output: &mut Signal) {
+        self.resample_until(|t, _offset, available| t < available, output);
+    }
+
+    /// Calculate output samples while the condition holds. The condition takes
+    /// `t`, the offset and the amount of samples received on the n axis.
+    fn resample_until<F: Fn(u64, u64, u64) -> bool>(&mut self, condition: F, output: &mut Signal) {
+        let (l, offset) = (self.l, self.offset);
+        let available = self.received * l;
+
+        // First input sample needed for the output sample on t
+        let first_sample = |t: u64| if t > offset { (t - offset).div_ceil(l) } else { 0 };
+
+        output.clear();
+        while condition(self.t, offset, available) {
+            let x = first_sample(self.t);
+            let phase = &self.phases[(x * l + offset - self.t) as usize];
+            let start = (x - self.buffer_start) as usize;
+
+            output.push(if start < self.buffer.len() {
+                let available = self.buffer.len() - start;
+                dot(&self.buffer[start..], phase, self.dot_mode) * edge_gain(phase, available)
+            } else {
+                0.
+            });
+
+            self.t += self.m;
+        }
+
+        // Drop samples that are not going to be used anymore
+        let needed = first_sample(self.t).min(self.received);
+        self.buffer.drain(..(needed - self.buffer_start) as usize);
+        self.buffer_start = needed;
+    }
+}
+
+/// Decimate without filtering, in place.
+///
+/// Keeps one of every `m` samples moving them to the start of the signal and
+/// truncating it, so nothing is allocated. Only possible when resampling to a
+/// rate that divides the input rate (`L = 1`), otherwise the output can be
+/// longer than the input. The capacity is kept, call `shrink_to_fit()` if you
+/// need the memory back, but that copies the samples again.
 ///
 /// The signal should be accordingly bandlimited previously to avoid aliasing.
-fn decimate(signal: &Signal, m: u32) -> Signal {
+pub fn decimate(signal: &mut Signal, m: u32) {
 
     let m = m as usize;
 
     debug!("Resampling by decimation, M: {}", m);
 
-    let mut decimated: Signal = Vec::with_capacity(signal.len() / m);
-
-    for i in 0..signal.len() / m {
-        decimated.push(signal[i * m]);
+    let len = signal.len() / m;
+    for i in 0..len {
+        signal[i] = signal[i * m];
     }
+    signal.truncate(len);
 
     debug!("Resampling finished");
-    decimated
+}
+
+/// Attenuation in positive dB used for notch filters.
+const NOTCH_ATTEN: f32 = 40.;
+
+/// Remove a tone using a narrow band-stop filter.
+///
+/// Removes frequencies between `freq - width / 2` and `freq + width / 2` in
+/// Hz, see `filters::Notch`. The delay of the filter is compensated, so the
+/// output is aligned to the input.
+pub fn notch(signal: &Signal, rate: Rate, freq: f32, width: f32) -> Signal {
+    use filters::Filter;
+
+    debug!("Removing {}Hz tone", freq);
+
+    let coeff = filters::Notch {
+        center: Freq::hz(freq, rate),
+        width: Freq::hz(width, rate),
+        atten: NOTCH_ATTEN,
+    }.design();
+
+    // Pad the signal so there is something to convolve at the edges, then
+    // skip the filter delay
+    let delay = (coeff.len() - 1) / 2;
+    convolve(&pad_zeros(signal, delay), &coeff, DotMode::Fast)[2 * delay ..].to_vec()
+}
+
+/// Find and remove the strongest interfering tone.
+///
+/// Looks for the narrowest and strongest spike on the spectrum, outside the
+/// band used by the APT signal (the carrier and its sidebands). If it's at
+/// least 20dB over its surroundings I remove it using `notch()` with the
+/// given width in Hz, otherwise the signal is returned as is.
+pub fn auto_notch(signal: &Signal, rate: Rate, width: f32) -> err::Result<Signal> {
+    use noaa_apt::{CARRIER_FREQ, FINAL_RATE};
+
+    // Spike magnitude compared to the median of the surroundings
+    let min_ratio = 10.;
+    // Bins to each side used as surroundings
+    let neighbours = 64;
+
+    let spectrum = averaged_spectrum(signal)?;
+    let hz_per_bin = rate.get_hz() as f32 / (spectrum.len() * 2) as f32;
+
+    // Sidebands go up to FINAL_RATE / 2 from the carrier
+    let protected_low = (CARRIER_FREQ - FINAL_RATE / 2) as f32;
+    let protected_high = (CARRIER_FREQ + FINAL_RATE / 2) as f32;
+
+    let mut best: Option<(usize, f32)> = None; // (bin, ratio)
+    for i in neighbours .. spectrum.len().saturating_sub(neighbours) {
+        let freq = i as f32 * hz_per_bin;
+        if freq > protected_low - width && freq < protected_high + width {
+            continue;
+        }
+
+        let mut surroundings: Signal = spectrum[i - neighbours .. i + neighbours].to_vec();
+        surroundings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal));
+        let median = surroundings[neighbours];
+        let ratio = if median > 0. { spectrum[i] / median } else { 0. };
+
+        match best {
+            Some((_, r)) if r >= ratio => {},
+            _ => best = Some((i, ratio)),
+        }
+    }
 
+    match best {
+        Some((bin, ratio)) if ratio >= min_ratio => {
+            let freq = bin as f32 * hz_per_bin;
+            info!("Removing interference at {}Hz, {}dB over the noise",
+                freq, 20. * ratio.log10());
+            Ok(notch(signal, rate, freq, width))
+        },
+        _ => {
+            debug!("No interference found");
+            Ok(signal.clone())
+        },
+    }
 }
 
 /// Demodulate AM signal.
 ///
 /// Demodulate from two consecutive samples, by the calculation of:
 ///
-/// ```
+/// ```text
 /// y[i] = sqrt(x[i-1]^2 + x[i]^2 - x[i-1]*x[i]*2*cos(phi)) / sin(phi)
 /// ```
 ///
 /// Where:
 ///
-/// ```
+/// ```text
 /// phi = 2 * pi * (carrier_freq / sampling_freq)
 /// ```
 ///
@@ -314,34 +1136,420 @@ pub fn demodulate(
 
     debug!("Demodulating signal");
 
-    let mut output: Signal = vec![0_f32; signal.len()];
+    let mut output: Signal = Vec::with_capacity(signal.len());
+    Demodulator::new(carrier_freq).process_into(signal, &mut output);
 
-    // Shortcut to 2 * pi * (carrier_freq.get_hz() / sample_rate.get_hz())
-    let phi = 2. * carrier_freq.get_rad();
+    debug!("Demodulation finished");
 
-    let cosphi2 = phi.cos() * 2.;
-    let sinphi = phi.sin();
+    context.step(Step::signal("demodulation_result", &output, None))?;
+    Ok(output)
+}
 
-    let mut curr;
-    let mut curr_sq;
-    let mut prev = signal[0];
-    let mut prev_sq = signal[0].powi(2);
-    for i in 1..signal.len() {
-        curr = signal[i];
-        curr_sq = signal[i].powi(2);
+/// Demodulator for signals received by parts.
+///
+/// Same algorithm as `demodulate()`, which uses it for the whole signal. The
+/// only state needed between blocks is the last sample received, so there is
+/// no delay and the result is the same no matter how the signal is split.
+pub struct Demodulator {
+    /// `2 * cos(phi)`, see `demodulate()`.
+    cosphi2: f32,
+
+    /// `sin(phi)`, see `demodulate()`.
+    sinphi: f32,
+
+    /// Last sample received and its square, `None` before the first one.
+    prev: Option<(f32, f32)>,
+}
+
+impl Demodulator {
+
+    /// Create demodulator for an AM signal with the carrier on `carrier_freq`.
+    pub fn new(carrier_freq: Freq) -> Self {
+        // Shortcut to 2 * pi * (carrier_freq.get_hz() / sample_rate.get_hz())
+        let phi = 2. * carrier_freq.get_rad();
+
+        Self {
+            cosphi2: phi.cos() * 2.,
+            sinphi: phi.sin(),
+            prev: None,
+        }
+    }
+
+    /// Demodulate the next block of samples.
+    ///
+    /// Writes one output sample for each input sample on `output`, replacing
+    /// what it had before. The first sample of the signal gives zero because
+    /// there is no previous sample to use.
+    pub fn process_into(&mut self, signal: &[f32], output: &mut Signal) {
+        output.clear();
+        output.reserve(signal.len());
+
+        for curr in signal {
+            let curr_sq = curr.powi(2);
+
+            output.push(match self.prev {
+                Some((prev, prev_sq)) =>
+                    (prev_sq + curr_sq - (prev * curr * self.cosphi2)).sqrt() / self.sinphi,
+                None => 0.,
+            });
+
+            self.prev = Some((*curr, curr_sq));
+        }
+    }
+}
 
-        output[i] = (prev_sq + curr_sq - (prev * curr * cosphi2)).sqrt() / sinphi;
+/// Analytic signal, as `(real, imaginary)` pairs.
+///
+/// The imaginary part is the signal filtered by a Hilbert transformer (see
+/// `filters::Hilbert`), the real part is the signal delayed by the same amount
+/// as the filter, so both are aligned. The output has the same length as the
+/// input, the first samples are the transient of the filter.
+///
+/// `demodulate()` only gives the magnitude, with this you can also get the
+/// instantaneous phase and frequency. `atten` is the attenuation in positive
+/// dB and `delta_w` the width of the transition bands of the filter.
+pub fn analytic_signal(signal: &Signal, atten: f32, delta_w: Freq) -> Vec<(f32, f32)> {
+    let coeff = filters::Filter::design(&filters::Hilbert { atten, delta_w });
+    let delay = hilbert_delay(atten, delta_w);
+
+    let imaginary = convolve(signal, &coeff, DotMode::Fast);
+
+    imaginary.iter().enumerate().map(|(i, imag)| {
+        let real = if i >= delay { signal[i - delay] } else { 0. };
+        (real, *imag)
+    }).collect()
+}
 
-        prev = curr;
-        prev_sq = curr_sq;
+/// Samples the output of `analytic_signal()` is delayed, half the length of
+/// the Hilbert transformer.
+///
+/// For aligning things like sync frames found on the analytic signal with
+/// the input, without designing the filter again. `demodulate()` has no
+/// delay.
+pub fn hilbert_delay(atten: f32, delta_w: Freq) -> usize {
+    (filters::kaiser_length(atten, delta_w) - 1) / 2
+}
+
+/// Depth of the AM modulation of a signal, from 0 to 1.
+///
+/// Gets the envelope from the analytic signal (see `analytic_signal()`, with
+/// the same `atten` and `delta_w`) and returns `(max - min) / (max + min)`,
+/// skipping the transient of the filter. APT is sent with an index close to
+/// 0.87, much lower values mean a weak signal buried on noise and values near
+/// one usually mean that the recording was clipped. Fails if the signal is
+/// shorter than the transient or there is no carrier at all.
+pub fn modulation_index(signal: &Signal, atten: f32, delta_w: Freq) -> err::Result<f32> {
+    let delay = hilbert_delay(atten, delta_w);
+    if signal.len() <= 2 * delay {
+        return Err(err::Error::Internal(format!(
+            "Can't measure modulation index of a signal with {} samples, \
+            shorter than the transient of the filter ({} samples)",
+            signal.len(), 2 * delay)));
     }
 
+    let analytic = analytic_signal(signal, atten, delta_w);
+
+    let envelope = analytic.iter().skip(2 * delay)
+        .map(|(re, im)| (re.powi(2) + im.powi(2)).sqrt());
+    let (min, max) = envelope.fold((f32::INFINITY, 0_f32), |(min, max), x| {
+        (min.min(x), max.max(x))
+    });
+
+    if max > 0. {
+        Ok((max - min) / (max + min))
+    } else {
+        Err(err::Error::Internal(
+            "Can't measure modulation index, there is no carrier".to_string()))
+    }
+}
+
+/// Samples the output of `square_law_demod()` is delayed with the same
+/// `cutout` and `atten`, half the length of its lowpass filter.
+pub fn square_law_delay(cutout: Freq, atten: f32) -> usize {
+    let lowpass = square_law_filter(cutout, atten);
+    (filters::kaiser_length(lowpass.atten, lowpass.delta_w) - 1) / 2
+}
+
+/// Attenuation in positive dB of the Hilbert transformer used by
+/// `frequency_shift()`.
+const FREQUENCY_SHIFT_ATTEN: f32 = 40.;
+
+/// Width in Hz of the transition bands of the Hilbert transformer used by
+/// `frequency_shift()`.
+const FREQUENCY_SHIFT_DELTA: f32 = 100.;
+
+/// Move every frequency of the signal by `shift` Hz, can be negative.
+///
+/// Multiplying the signal by a cosine gives copies shifted up and down, so I
+/// multiply the analytic signal (see `analytic_signal()`) by a complex
+/// exponential instead and keep the real part, then there is only one copy.
+/// Useful to move a subcarrier somewhere else before demodulating. The delay
+/// of the Hilbert transformer is compensated, frequencies closer than
+/// `FREQUENCY_SHIFT_DELTA` to 0Hz or to the Nyquist frequency get attenuated.
+pub fn frequency_shift(signal: &Signal, rate: Rate, shift: f32) -> Signal {
+    debug!("Shifting signal by {}Hz", shift);
+
+    let delta_w = Freq::hz(FREQUENCY_SHIFT_DELTA, rate);
+    let delay = hilbert_delay(FREQUENCY_SHIFT_ATTEN, delta_w);
+
+    // Pad so the real part of the first output sample is the first input
+    // sample, like on notch()
+    let analytic = analytic_signal(&pad_zeros(signal, delay), FREQUENCY_SHIFT_ATTEN, delta_w);
+
+    let w = std::f64::consts::PI * f64::from(hz_to_norm(shift, rate));
+    analytic[2 * delay..].iter().enumerate().map(|(i, (re, im))| {
+        let phase = (w * i as f64) % (2. * std::f64::consts::PI);
+        re * phase.cos() as f32 - im * phase.sin() as f32
+    }).collect()
+}
+
+/// Lowpass filter used by `square_law_demod()`.
+fn square_law_filter(cutout: Freq, atten: f32) -> filters::Lowpass {
+    filters::Lowpass { cutout, atten, delta_w: cutout / 5. }
+}
+
+/// Demodulate AM signal using a square law detector.
+///
+/// Squares the signal and keeps the low frequencies, that's the square of the
+/// envelope divided by two:
+///
+/// ```text
+/// (A * cos(wt))^2 = A^2 / 2 * (1 + cos(2wt))
+/// ```
+///
+/// Then I undo the square to get the envelope. The lowpass filter should
+/// remove everything around twice the carrier frequency, `cutout` and `atten`
+/// are the parameters of that filter.
+///
+/// The only delay is the one of the lowpass filter and it processes samples
+/// one by one, so it's useful for live decoding. Worse than `demodulate()` on
+/// noisy signals.
+pub fn square_law_demod(
+    context: &mut Context,
+    signal: &Signal,
+    cutout: Freq,
+    atten: f32,
+) -> err::Result<Signal> {
+
+    debug!("Demodulating signal using square law");
+
+    let squared: Signal = signal.iter().map(|x| x.powi(2)).collect();
+
+    let coeff = filters::Filter::design(&square_law_filter(cutout, atten));
+
+    // Clamp negative values from filter ripple before sqrt()
+    let output: Signal = convolve(&squared, &coeff, context.dot_mode()).iter()
+        .map(|x| (2. * x.max(0.)).sqrt()).collect();
+
     debug!("Demodulation finished");
 
     context.step(Step::signal("demodulation_result", &output, None))?;
     Ok(output)
 }
 
+/// Convolve signal with filter coefficients, same length as the input.
+fn convolve(signal: &Signal, coeff: &Signal, mode: DotMode) -> Signal {
+    let mut output: Signal = Vec::with_capacity(signal.len());
+    filter_into(signal, coeff, &mut output, mode);
+    output
+}
+
+/// Filter a signal into a buffer.
+///
+/// Same as `filter()` but takes the coefficients directly and writes the result
+/// on `output`, resized to the length of `signal`. When filtering blocks on a
+/// loop the same buffer can be used every time, so it's allocated only once.
+pub fn filter_into(signal: &Signal, coeff: &Signal, output: &mut Signal, mode: DotMode) {
+    output.clear();
+    output.resize(signal.len(), 0.);
+
+    // Reverse the coefficients so I can use a dot product with contiguous
+    // samples
+    let reversed: Signal = coeff.iter().rev().cloned().collect();
+    let len = coeff.len();
+
+    for i in 1..signal.len() {
+        // Use samples from i - len + 1 to i, the first sample is skipped
+        let start = if i + 1 > len { i + 1 - len } else { 1 };
+        output[i] = dot(&signal[start..=i], &reversed[len - 1 + start - i ..], mode);
+    }
+}
+
+/// How `dot()` sums the products, see `Context::dot_mode()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DotMode {
+    /// Use SIMD instructions if the CPU has them.
+    Fast,
+    /// Never use SIMD instructions, gives the same result on every computer.
+    Deterministic,
+    /// Accumulate on `f64` and round only the result, also deterministic.
+    HighPrecision,
+}
+
+/// Dot product of two slices, stops at the end of the shortest one.
+///
+/// With the `simd` feature and a CPU with AVX, uses SIMD instructions. The
+/// result can differ a little from the scalar version because the products
+/// are summed in a different order (eight partial sums instead of one). See
+/// `DotMode`.
+pub fn dot(a: &[f32], b: &[f32], mode: DotMode) -> f32 {
+    match mode {
+        DotMode::Fast => dot_fast(a, b),
+        DotMode::Deterministic => dot_scalar(a, b),
+        DotMode::HighPrecision => dot_f64(a, b),
+    }
+}
+
+/// Dot product of two slices, with SIMD instructions if available.
+fn dot_fast(a: &[f32], b: &[f32]) -> f32 {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            // Safe because we checked that the CPU supports AVX
+            return unsafe { simd::dot_avx(a, b) };
+        }
+    }
+
+    dot_scalar(a, b)
+}
+
+/// Dot product of two slices without SIMD instructions.
+fn dot_scalar(a: &[f32], b: &[f32]) -> f32 {
+    let mut sum: f32 = 0.;
+    for (x, y) in a.iter().zip(b.iter()) {
+        sum += x * y;
+    }
+    sum
+}
+
+/// Dot product of two slices accumulating on `f64`.
+fn dot_f64(a: &[f32], b: &[f32]) -> f32 {
+    let mut sum: f64 = 0.;
+    for (x, y) in a.iter().zip(b.iter()) {
+        sum += f64::from(*x) * f64::from(*y);
+    }
+    sum as f32
+}
+
+/// SIMD implementations.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod simd {
+    use std::arch::x86_64::*;
+
+    /// Dot product using AVX, eight products at a time.
+    ///
+    /// Unsafe because the CPU must support AVX.
+    #[target_feature(enable = "avx")]
+    pub unsafe fn dot_avx(a: &[f32], b: &[f32]) -> f32 {
+        let len = a.len().min(b.len());
+        let chunks = len / 8;
+
+        let mut acc = _mm256_setzero_ps();
+        for i in 0..chunks {
+            let x = _mm256_loadu_ps(a.as_ptr().add(i * 8));
+            let y = _mm256_loadu_ps(b.as_ptr().add(i * 8));
+            acc = _mm256_add_ps(acc, _mm256_mul_ps(x, y));
+        }
+
+        let mut partial = [0_f32; 8];
+        _mm256_storeu_ps(partial.as_mut_ptr(), acc);
+        let mut sum: f32 = partial.iter().sum();
+
+        for i in chunks * 8 .. len {
+            sum += a[i] * b[i];
+        }
+
+        sum
+    }
+}
+
+/// Resample signal on arbitrary positions.
+///
+/// Each output sample is the input signal linearly interpolated at the
+/// position given, in samples. Useful when the resampling ratio changes over
+/// time. Positions outside the signal give zero.
+pub fn resample_arbitrary(signal: &Signal, positions: &[f64]) -> Signal {
+    positions.iter().map(|position| {
+        if *position < 0. || *position > (signal.len() as f64 - 1.) {
+            return 0.;
+        }
+        let i = position.floor() as usize;
+        let fraction = (position - i as f64) as f32;
+        match signal.get(i + 1) {
+            Some(next) => signal[i] * (1. - fraction) + next * fraction,
+            None => signal[i],
+        }
+    }).collect()
+}
+
+/// Replace NaN and infinite samples with zero.
+///
+/// A single bad sample would spread through every filter and ruin the whole
+/// image. Returns the amount of samples replaced.
+pub fn sanitize(signal: &mut Signal) -> usize {
+    let mut count = 0;
+    for sample in signal.iter_mut().filter(|x| !x.is_finite()) {
+        *sample = 0.;
+        count += 1;
+    }
+    count
+}
+
+/// Autocorrelation for lags from 0 to `max_lag` (not included).
+///
+/// Normalized so lag 0 is 1, has peaks on multiples of the period of periodic
+/// signals. Not divided by the number of samples on each lag, so the peaks get
+/// smaller as the lag grows. Lags longer than the signal and signals without
+/// energy give zero.
+pub fn autocorrelate(signal: &Signal, max_lag: usize) -> Signal {
+    let energy = dot(signal, signal, DotMode::Fast);
+    if energy == 0. {
+        return vec![0.; max_lag];
+    }
+
+    (0..max_lag).map(|lag| {
+        if lag < signal.len() {
+            dot(&signal[lag..], signal, DotMode::Fast) / energy
+        } else {
+            0.
+        }
+    }).collect()
+}
+
+/// Smooth signal averaging each sample with its neighbours.
+///
+/// The window is centered on each sample, on the edges I average only the
+/// samples available. Uses a running sum, so it's fast even for long windows.
+/// A window of zero or one returns the same signal.
+pub fn moving_average(signal: &Signal, window: usize) -> Signal {
+    if window <= 1 {
+        return signal.clone();
+    }
+
+    let before = (window - 1) / 2; // Samples before the center
+    let after = window - 1 - before; // Samples after the center
+
+    // Keep the sum on f64, otherwise rounding errors accumulate on long
+    // signals
+    let mut sum: f64 = signal.iter().take(after).map(|x| f64::from(*x)).sum();
+    let mut output = Vec::with_capacity(signal.len());
+
+    for i in 0..signal.len() {
+        if let Some(x) = signal.get(i + after) {
+            sum += f64::from(*x);
+        }
+        if i > before {
+            sum -= f64::from(signal[i - before - 1]);
+        }
+
+        let start = i.saturating_sub(before);
+        let end = std::cmp::min(i + after + 1, signal.len());
+        output.push((sum / (end - start) as f64) as f32);
+    }
+
+    output
+}
+
 /// Filter a signal.
 pub fn filter(
     context: &mut Context,
@@ -352,17 +1560,37 @@ pub fn filter(
     debug!("Filtering signal");
 
     let coeff = filter.design();
-    let mut output: Signal = vec![0_f32; signal.len()];
+    let output = convolve(signal, &coeff, context.dot_mode());
+
+    debug!("Filtering finished");
+
+    context.step(Step::filter("filter_filter", &coeff))?;
+    context.step(Step::signal("filter_result", &output, None))?;
+    Ok(output)
+}
+
+/// Filter signal without delay.
+///
+/// `filter()` is causal, so the output comes late by half the length of the
+/// filter. Here I shift the output back by that delay so every feature stays
+/// where it was on the input, the tradeoff is that the last `delay` samples
+/// are unknown and I set them to zero. Makes sense for symmetric filters like
+/// the ones on `filters`, which have the same delay for every frequency.
+pub fn filter_centered(
+    context: &mut Context,
+    signal: &Signal,
+    filter: impl filters::Filter
+) -> err::Result<Signal> {
+
+    debug!("Filtering signal without delay");
+
+    let coeff = filter.design();
+    let delay = std::cmp::min((coeff.len() - 1) / 2, signal.len());
+
+    let mut output = convolve(signal, &coeff, context.dot_mode());
+    output.drain(..delay);
+    output.resize(signal.len(), 0.);
 
-    for i in 0..signal.len() {
-        let mut sum: f32 = 0_f32;
-        for j in 0..coeff.len() {
-            if i > j {
-                sum += signal[i - j] * coeff[j];
-            }
-        }
-        output[i] = sum;
-    }
     debug!("Filtering finished");
 
     context.step(Step::filter("filter_filter", &coeff))?;
@@ -375,6 +1603,987 @@ pub fn filter(
 mod tests {
 
     use super::*;
+    use test_util;
+
+    #[test]
+    fn test_concat() {
+        let a: Signal = vec![1., 2.];
+        let b: Signal = vec![];
+        let c: Signal = vec![3.];
+        assert_eq!(concat(&[&a, &b, &c]), vec![1., 2., 3.]);
+        assert_eq!(concat(&[]), Signal::new());
+    }
+
+    #[test]
+    fn test_pad_zeros() {
+        let signal: Signal = vec![1., 2., 3.];
+        assert_eq!(pad_zeros(&signal, 2), vec![0., 0., 1., 2., 3., 0., 0.]);
+        assert_eq!(pad_zeros(&signal, 0), signal);
+    }
+
+    #[test]
+    fn test_pad_reflect() {
+        let signal: Signal = vec![1., 2., 3., 4.];
+        assert_eq!(pad_reflect(&signal, 2).unwrap(),
+                   vec![3., 2., 1., 2., 3., 4., 3., 2.]);
+        assert_eq!(pad_reflect(&signal, 3).unwrap(),
+                   vec![4., 3., 2., 1., 2., 3., 4., 3., 2., 1.]);
+        assert_eq!(pad_reflect(&signal, 0).unwrap(), signal);
+
+        // Not enough samples to reflect
+        assert!(pad_reflect(&signal, 4).is_err());
+        assert!(pad_reflect(&Signal::new(), 0).is_err());
+    }
+
+    #[test]
+    fn test_find_peaks() {
+        let signal: Signal = vec![
+            0., 1., 0., 5., 0., 2., 0., 0., 0., 0., 0., 3., 3., 0., 4., 0., -1., 0.5];
+
+        // Every local maximum
+        assert_eq!(find_peaks(&signal, 0, 0.), vec![1, 3, 5, 11, 14, 17]);
+
+        // Small peaks near bigger ones are discarded
+        assert_eq!(find_peaks(&signal, 3, 0.), vec![3, 11, 14, 17]);
+        assert_eq!(find_peaks(&signal, 4, 0.), vec![3, 14]);
+        assert_eq!(find_peaks(&signal, 20, 0.), vec![3]);
+
+        // Below threshold
+        assert_eq!(find_peaks(&signal, 3, 2.5), vec![3, 11, 14]);
+        assert_eq!(find_peaks(&signal, 3, 10.), Vec::<usize>::new());
+
+        // Ties keep the first peak
+        assert_eq!(find_peaks(&vec![0., 1., 0., 1., 0.], 3, 0.), vec![1]);
+        assert_eq!(find_peaks(&Signal::new(), 3, 0.), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_length_ratio() {
+        assert_eq!(length_ratio(1000, 2000), (2, 1));
+        assert_eq!(length_ratio(1000, 500), (1, 2));
+        assert_eq!(length_ratio(1000, 1000), (1, 1));
+        assert_eq!(length_ratio(1000, 1001), (1, 1));
+        assert_eq!(length_ratio(4000, 4096), (43, 42));
+
+        for (input_len, target_len) in
+                [(1000, 1500), (4001, 4096), (12345, 678), (7, 100_003)].iter() {
+            let (l, m) = length_ratio(*input_len, *target_len);
+            let len = (input_len * l + m / 2) / m;
+            assert!(len.abs_diff(*target_len) <= 1);
+        }
+    }
+
+    #[test]
+    fn test_resample_to_length() {
+        use std::f32::consts::PI;
+
+        let mut context = Context::resample(|_,_| {}, false, false);
+
+        // Slow tone, period of 100 samples
+        let period = 100.;
+        let signal: Signal = (0..4000).map(|i|
+            (2. * PI * i as f32 / period).sin()
+        ).collect();
+
+        for target_len in [9000, 2345, 4000, 4096, 3999, 4001, 1].iter() {
+            let output = resample_to_length(
+                &mut context, &signal, *target_len).unwrap();
+            assert_eq!(output.len(), *target_len);
+
+            // Check the tone away from the edges
+            let ratio = *target_len as f32 / signal.len() as f32;
+            if *target_len > 1000 {
+                let middle = output.iter().enumerate()
+                    .skip(target_len / 4).take(target_len / 2);
+                for (i, sample) in middle {
+                    let expected = (2. * PI * i as f32 / (period * ratio)).sin();
+                    assert!((sample - expected).abs() < 0.05,
+                        "target_len {}, sample {}: {} != {}",
+                        target_len, i, sample, expected);
+                }
+            }
+        }
+
+        assert_eq!(resample_to_length(&mut context, &Signal::new(), 3).unwrap(),
+                   vec![0., 0., 0.]);
+        assert!(resample_to_length(&mut context, &signal, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_resample_compensated() {
+        let mut context = Context::resample(|_,_| {}, false, false);
+        let input_rate = Rate::hz(11025);
+        let output_rate = Rate::hz(12480);
+        let filt = filters::LowpassDcRemoval {
+            cutout: Freq::hz(4800., input_rate),
+            atten: 30.,
+            delta_w: Freq::hz(1000., input_rate),
+        };
+
+        // Amplitude of a tone after resampling, away from the edges
+        let mut amplitude = |freq: f32, compensate: bool| {
+            let signal: Signal = (0..11025).map(|i|
+                (Freq::hz(freq, input_rate).get_rad() * i as f32).sin()
+            ).collect();
+            let output = if compensate {
+                resample_compensated(&mut context, &signal, input_rate,
+                    output_rate, filt.clone(), (1000., 4300.)).unwrap()
+            } else {
+                resample_with_filter(&mut context, &signal, input_rate,
+                    output_rate, filt.clone()).unwrap()
+            };
+            let middle = &output[2000..10000];
+            (middle.iter().map(|x| x * x).sum::<f32>() / middle.len() as f32 * 2.).sqrt()
+        };
+
+        // The passband is flatter
+        let mut ratio = |compensate| {
+            let gains: Vec<f32> = [1200., 2000., 2800., 3600., 4200.].iter()
+                .map(|f| amplitude(*f, compensate))
+                .collect();
+            gains.iter().fold(0., |a: f32, b| a.max(*b))
+                / gains.iter().fold(f32::INFINITY, |a: f32, b| a.min(*b))
+        };
+        let alone = ratio(false);
+        let compensated = ratio(true);
+        assert!(compensated - 1. < (alone - 1.) / 2.,
+                "Compensated: {}, alone: {}", compensated, alone);
+    }
+
+    #[test]
+    fn test_max_resample_taps() {
+        let mut context = Context::resample(|_,_| {}, false, false);
+        let signal: Signal = vec![0.5; 100];
+        let delta_w = Freq::hz(1000., Rate::hz(44099));
+
+        // Coprime rates, L/M: 11025/44099
+        match resample(&mut context, &signal, Rate::hz(44099), Rate::hz(11025), 30., delta_w) {
+            Err(err::Error::RateOverflow(_)) => (),
+            other => panic!("Expected RateOverflow, got {:?}", other),
+        }
+
+        // Common rates are fine unless the limit is really low
+        let delta_w = Freq::hz(1000., Rate::hz(48000));
+        resample(&mut context, &signal, Rate::hz(48000), Rate::hz(12480), 30., delta_w).unwrap();
+        context.max_resample_taps = 50;
+        assert!(resample(&mut context, &signal, Rate::hz(48000), Rate::hz(12480), 30., delta_w).is_err());
+        assert!(resample(&mut context, &signal, Rate::hz(48000), Rate::hz(12000), 30., delta_w).is_err());
+
+        // Same limit when streaming
+        let filter = filters::Lowpass {
+            cutout: Freq::hz(4800., Rate::hz(48000)),
+            atten: 30.,
+            delta_w,
+        };
+        assert!(Resampler::new(Rate::hz(48000), Rate::hz(12480), filter.clone(),
+                               DEFAULT_MAX_RESAMPLE_TAPS).is_ok());
+        assert!(Resampler::new(Rate::hz(48000), Rate::hz(12480), filter, 50).is_err());
+    }
+
+    #[test]
+    fn test_resample_to_quality() {
+        let mut context = Context::resample(|_,_| {}, false, false);
+        let input_rate = Rate::hz(20000);
+        let output_rate = Rate::hz(10000);
+        let delta_w = Freq::hz(1000., input_rate);
+
+        // Tone just above the new Nyquist frequency, everything that is left
+        // after resampling is aliased
+        let signal: Signal = (0..20000).map(|i|
+            (Freq::hz(5300., input_rate).get_rad() * i as f32).sin()
+        ).collect();
+
+        let mut aliased_energy = |transition_start| {
+            let output = resample_to_quality(&mut context, &signal, input_rate,
+                output_rate, 40., delta_w, transition_start).unwrap();
+            output[1000..9000].iter().map(|x| x * x).sum::<f32>()
+        };
+
+        let default = aliased_energy(RESAMPLE_TRANSITION_START);
+        let strict = aliased_energy(0.8);
+        assert!(strict < default / 100.,
+                "Strict: {}, default: {}", strict, default);
+
+        assert!(resample_to_quality(&mut context, &signal, input_rate,
+            output_rate, 40., delta_w, 0.).is_err());
+    }
+
+    #[test]
+    fn test_resample_end() {
+        let mut context = Context::resample(|_,_| {}, false, false);
+
+        // The filter window of the last samples is not full, they should keep
+        // the level of the input anyway
+        for &(input_rate, output_rate) in [(11025, 12480), (12480, 4160), (20800, 12480)].iter() {
+            let signal: Signal = vec![1.; 2000];
+            let resampled = resample(&mut context, &signal, Rate::hz(input_rate),
+                Rate::hz(output_rate), 40., Freq::pi_rad(0.1)).unwrap();
+
+            let tail = &resampled[resampled.len() - 20..];
+            for x in tail.iter() {
+                assert_relative_eq!(*x, 1., max_relative = 0.05);
+            }
+
+            // Same with the streaming resampler
+            let filter = filters::Lowpass {
+                cutout: Freq::hz(2000., Rate::hz(input_rate)),
+                atten: 40.,
+                delta_w: Freq::hz(500., Rate::hz(input_rate)),
+            };
+            let mut resampler = Resampler::new(
+                Rate::hz(input_rate), Rate::hz(output_rate), filter,
+                DEFAULT_MAX_RESAMPLE_TAPS).unwrap();
+            let mut streamed = resampler.process(&signal);
+            streamed.extend(resampler.finish());
+            for x in streamed[streamed.len() - 20..].iter() {
+                assert_relative_eq!(*x, 1., max_relative = 0.05);
+            }
+        }
+    }
+
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    #[test]
+    fn test_frequency_shift() {
+        // One second, so each bin of the spectrum is 1Hz
+        let rate = Rate::hz(11025);
+        let signal: Signal = (0..11025)
+            .map(|i| (Freq::hz(2000., rate).get_rad() * i as f32).cos()).collect();
+
+        for &(shift, expected) in [(400., 2400), (-500., 1500)].iter() {
+            let shifted = frequency_shift(&signal, rate, shift);
+            assert_eq!(shifted.len(), signal.len());
+
+            let spectrum = abs_fft(&shifted).unwrap();
+            let half = &spectrum[..spectrum.len() / 2];
+            let peak = (0..half.len())
+                .max_by(|a, b| half[*a].partial_cmp(&half[*b]).unwrap()).unwrap();
+            assert_eq!(peak, expected);
+
+            // No copy on the other side or left on the original frequency
+            let mirror = (4000. - expected as f32) as usize;
+            assert!(half[mirror] < half[peak] / 100.);
+            assert!(half[2000] < half[peak] / 100.);
+        }
+    }
+
+    #[test]
+    fn test_resample_arbitrary() {
+        let signal: Signal = vec![0., 1., 4., 9.];
+        assert_eq!(
+            resample_arbitrary(&signal, &[0., 0.5, 1., 2.25, 3., 3.5, -0.5]),
+            vec![0., 0.5, 1., 5.25, 9., 0., 0.]
+        );
+        assert_eq!(resample_arbitrary(&Signal::new(), &[0.]), vec![0.]);
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let mut signal: Signal = vec![
+            1., f32::NAN, -2., f32::INFINITY, f32::NEG_INFINITY, 3.];
+        assert_eq!(sanitize(&mut signal), 3);
+        assert_eq!(signal, vec![1., 0., -2., 0., 0., 3.]);
+
+        // Nothing else to replace
+        assert_eq!(sanitize(&mut signal), 0);
+        assert_eq!(sanitize(&mut Signal::new()), 0);
+    }
+
+    #[test]
+    fn test_trimmed_mean() {
+        // Values around 10 with a few big spikes
+        let mut signal: Signal = (0..100).map(|i| 10. + ((i * 7) % 11) as f32 / 10. - 0.5).collect();
+        for i in [3, 40, 77].iter() {
+            signal[*i] = 1000.;
+        }
+        signal[60] = -500.;
+
+        let mean: f32 = signal.iter().sum::<f32>() / signal.len() as f32;
+        assert!((mean - 10.).abs() > 10.);
+        assert!((trimmed_mean(&signal, 0.05).unwrap() - 10.).abs() < 0.1);
+
+        // No trimming is the mean
+        assert_relative_eq!(trimmed_mean(&signal, 0.).unwrap(), mean, max_relative = 1e-5);
+
+        // Trimming everything gives the median
+        assert_eq!(trimmed_mean(&vec![1., 2., 100.], 0.5).unwrap(), 2.);
+        assert_eq!(trimmed_mean(&vec![4., 1., 2., 100.], 0.9).unwrap(), 3.);
+        assert_eq!(trimmed_mean(&vec![7.], 0.5).unwrap(), 7.);
+
+        assert!(trimmed_mean(&Signal::new(), 0.1).is_err());
+    }
+
+    #[test]
+    fn test_empty_signal() {
+        let empty = Signal::new();
+        assert!(get_max(&empty).is_err());
+        assert!(get_min(&empty).is_err());
+        assert!(trimmed_mean(&empty, 0.1).is_err());
+        assert!(estimate_freq_offset(&empty, Rate::hz(12480), 2400.).is_err());
+        assert!(auto_notch(&empty, Rate::hz(12480), 10.).is_err());
+
+        // Transformations give empty signals
+        #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+        assert!(abs_fft(&empty).unwrap().is_empty());
+        assert!(moving_average(&empty, 3).is_empty());
+        assert!(find_peaks(&empty, 3, 0.).is_empty());
+        assert_eq!(dot(&[], &[], DotMode::Fast), 0.);
+
+        let mut signal = empty.clone();
+        auto_gain(&mut signal);
+        assert_eq!(sanitize(&mut signal), 0);
+        assert!(signal.is_empty());
+    }
+
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    #[test]
+    fn test_spectrum() {
+        use std::f32::consts::PI;
+
+        // Tone on bin 100 of blocks of 1024 samples
+        let signal: Signal = (0..4096).map(|i| (2. * PI * 100. * i as f32 / 1024.).sin()).collect();
+
+        let spectrum: Spectrum = abs_fft(&signal[..1024].to_vec()).unwrap();
+        assert_eq!(spectrum.len(), 1024);
+        assert_relative_eq!(spectrum[100], 512., max_relative = 1e-3);
+
+        // Averaging keeps the positive frequencies only
+        let averaged: Spectrum = averaged_spectrum(&signal).unwrap();
+        assert_eq!(averaged.len(), 2048);
+        let peak = averaged.iter().enumerate()
+            .max_by(|a, b| a.1.partial_cmp(b.1).unwrap()).unwrap().0;
+        assert_eq!(peak, 400);
+
+        let values: Signal = spectrum.clone().into_vec();
+        assert_eq!(&values[..], &spectrum[..]);
+    }
+
+    #[cfg(not(any(feature = "fft", feature = "fft_builtin")))]
+    #[test]
+    fn test_fft_not_available() {
+        let signal: Signal = (0..20000).map(|x| (x as f32 * 0.1).sin()).collect();
+        let not_available = |result: err::Result<_>| match result {
+            Err(err::Error::FeatureNotAvailable(features)) =>
+                assert_eq!(features, vec!["fft".to_string()]),
+            _ => panic!("Expected FeatureNotAvailable"),
+        };
+        not_available(abs_fft(&signal).map(|_| ()));
+        not_available(estimate_freq_offset(&signal, Rate::hz(12480), 2400.).map(|_| ()));
+        not_available(auto_notch(&signal, Rate::hz(12480), 10.).map(|_| ()));
+    }
+
+    #[test]
+    fn test_modulation_index() {
+        use std::f32::consts::PI;
+
+        let rate = Rate::hz(11025);
+        let delta_w = Freq::hz(500., rate);
+        let am = |index: f32| -> Signal {
+            (0..2 * 11025).map(|i| {
+                let t = i as f32 / 11025.;
+                (1. + index * (2. * PI * 2. * t).sin()) * (2. * PI * 2400. * t).cos()
+            }).collect()
+        };
+
+        for &index in [0.1, 0.5, 0.87].iter() {
+            let measured = modulation_index(&am(index), 40., delta_w).unwrap();
+            assert!((measured - index).abs() < 0.02, "{} != {}", measured, index);
+        }
+
+        // Only the carrier
+        assert!(modulation_index(&am(0.), 40., delta_w).unwrap() < 0.02);
+
+        assert!(modulation_index(&vec![0.; 5000], 40., delta_w).is_err());
+        assert!(modulation_index(&vec![1.; 10], 40., delta_w).is_err());
+        assert!(modulation_index(&vec![], 40., delta_w).is_err());
+    }
+
+    #[test]
+    fn test_analytic_signal() {
+        // AM signal like the ones from the satellites, on the work rate
+        let rate = Rate::hz(12480);
+        let carrier = Freq::hz(2400., rate);
+        let envelope = |i: usize| 1. + 0.5 * (Freq::hz(10., rate).get_rad() * i as f32).sin();
+        let signal: Signal = (0..12480).map(|i|
+            envelope(i) * (carrier.get_rad() * i as f32).cos()
+        ).collect();
+
+        let analytic = analytic_signal(&signal, 40., Freq::hz(500., rate));
+        assert_eq!(analytic.len(), signal.len());
+
+        // Skip the transient, the real part is delayed by half the filter
+        let delay = hilbert_delay(40., Freq::hz(500., rate));
+        let hilbert = filters::Filter::design(&filters::Hilbert {
+            atten: 40., delta_w: Freq::hz(500., rate) });
+        assert_eq!(delay, hilbert.len() / 2);
+        let magnitude: Signal = analytic[2 * delay..].iter()
+            .map(|(re, im)| (re.powi(2) + im.powi(2)).sqrt()).collect();
+        for (i, x) in magnitude.iter().enumerate() {
+            assert!((x - envelope(i + delay)).abs() < 0.03,
+                "Sample {}: {} != {}", i, x, envelope(i + delay));
+        }
+
+        // Same shape as the output of demodulate() without its carrier ripple,
+        // averaging five carrier periods
+        let mut context = Context::resample(|_,_| {}, false, false);
+        let demodulated = moving_average(
+            &demodulate(&mut context, &signal, carrier).unwrap(), 26);
+        let demodulated = &demodulated[delay..demodulated.len() - delay];
+
+        let mean = |x: &[f32]| x.iter().sum::<f32>() / x.len() as f32;
+        let (mean_a, mean_b) = (mean(&magnitude), mean(demodulated));
+        let (mut cov, mut var_a, mut var_b) = (0., 0., 0.);
+        for (a, b) in magnitude.iter().zip(demodulated.iter()) {
+            cov += (a - mean_a) * (b - mean_b);
+            var_a += (a - mean_a).powi(2);
+            var_b += (b - mean_b).powi(2);
+        }
+        let correlation = cov / (var_a * var_b).sqrt();
+        assert!(correlation > 0.99, "Correlation: {}", correlation);
+    }
+
+    #[test]
+    fn test_filter_centered() {
+        let mut context = Context::resample(|_,_| {}, false, false);
+        let lowpass = filters::Lowpass {
+            cutout: Freq::pi_rad(0.2),
+            atten: 40.,
+            delta_w: Freq::pi_rad(0.05),
+        };
+        let delay = (filters::Filter::design(&lowpass).len() - 1) / 2;
+
+        let mut impulse: Signal = vec![0.; 1000];
+        impulse[500] = 1.;
+
+        let peak = |signal: &Signal| signal.iter().enumerate()
+            .fold((0, 0.), |(i_max, max), (i, x)| if *x > max { (i, *x) } else { (i_max, max) })
+            .0;
+
+        // Causal filter delays the peak, centered keeps it in place
+        let causal = filter(&mut context, &impulse, lowpass.clone()).unwrap();
+        assert_eq!(peak(&causal), 500 + delay);
+
+        let centered = filter_centered(&mut context, &impulse, lowpass.clone()).unwrap();
+        assert_eq!(centered.len(), impulse.len());
+        assert_eq!(peak(&centered), 500);
+        assert_eq!(&centered[..1000 - delay], &causal[delay..]);
+
+        // Lost samples at the tail
+        assert!(centered[1000 - delay..].iter().all(|x| *x == 0.));
+
+        // Shorter than the delay
+        let short = filter_centered(&mut context, &vec![1.; 3], lowpass).unwrap();
+        assert_eq!(short, vec![0.; 3]);
+    }
+
+    #[test]
+    fn test_autocorrelate() {
+        use std::f32::consts::PI;
+
+        // Period of 25 samples
+        let signal: Signal = (0..1000).map(|i| (2. * PI * i as f32 / 25.).sin()).collect();
+        let correlation = autocorrelate(&signal, 110);
+        assert_eq!(correlation.len(), 110);
+        assert_relative_eq!(correlation[0], 1.);
+
+        let peaks = find_peaks(&correlation, 10, 0.5);
+        assert_eq!(peaks, vec![0, 25, 50, 75, 100]);
+        assert!(correlation[12] < -0.9);
+
+        assert_eq!(autocorrelate(&vec![0.; 10], 3), vec![0.; 3]);
+        assert_eq!(autocorrelate(&vec![1., 1.], 4), vec![1., 0.5, 0., 0.]);
+        assert!(autocorrelate(&Signal::new(), 0).is_empty());
+    }
+
+    #[test]
+    fn test_moving_average() {
+        let mut noise = test_util::noise(1);
+        let signal: Signal = (0..1000).map(|_| noise()).collect();
+
+        for window in [2, 3, 10, 51, 999, 1000, 2000].iter() {
+            let before = (window - 1) / 2;
+            let after = window - 1 - before;
+            let naive: Signal = (0..signal.len()).map(|i| {
+                let start = i.saturating_sub(before);
+                let end = std::cmp::min(i + after + 1, signal.len());
+                signal[start..end].iter().sum::<f32>() / (end - start) as f32
+            }).collect();
+
+            let result = moving_average(&signal, *window);
+            assert_eq!(result.len(), signal.len());
+            for (a, b) in result.iter().zip(naive.iter()) {
+                assert_abs_diff_eq!(a, b, epsilon = 1e-5);
+            }
+        }
+
+        assert_eq!(moving_average(&signal, 1), signal);
+        assert_eq!(moving_average(&signal, 0), signal);
+        assert_eq!(moving_average(&vec![1., 3.], 3), vec![2., 2.]);
+        assert_eq!(moving_average(&Signal::new(), 5), Signal::new());
+    }
+
+    #[test]
+    fn test_square_law_demod() {
+        use filters::kaiser_length;
+
+        let rate = Rate::hz(12480);
+        let carrier = Freq::hz(2400., rate);
+        let cutout = Freq::hz(2080., rate);
+        let context = &mut Context::resample(|_,_| {}, false, false); // Dummy context
+
+        // Clean AM signal, the envelope has a 50Hz tone
+        let envelope: Signal = (0..5000)
+            .map(|i| 1. + 0.5 * (Freq::hz(50., rate).get_rad() * i as f32).sin()).collect();
+        let signal: Signal = envelope.iter().enumerate()
+            .map(|(i, x)| x * (carrier.get_rad() * i as f32).cos()).collect();
+
+        let square_law = square_law_demod(context, &signal, cutout, 40.).unwrap();
+        assert_eq!(square_law.len(), signal.len());
+
+        // The other demodulator needs the same lowpass filter afterwards
+        let demodulated = demodulate(context, &signal, carrier).unwrap();
+        let two_sample = filter(
+            context,
+            &demodulated,
+            filters::Lowpass { cutout, atten: 40., delta_w: cutout / 5. },
+        ).unwrap();
+
+        // Skip the start and compensate the filter delay
+        let delay = (kaiser_length(40., cutout / 5.) - 1) / 2;
+        assert_eq!(square_law_delay(cutout, 40.), delay);
+        let lowpass = filters::Filter::design(
+            &filters::Lowpass { cutout, atten: 40., delta_w: cutout / 5. });
+        assert_eq!(delay, lowpass.len() / 2);
+        for i in 1000..signal.len() {
+            assert_relative_eq!(square_law[i], envelope[i - delay], max_relative = 0.01);
+        }
+
+        // Compare with the other demodulator, find the scale factor first
+        let scale = (1000..signal.len()).map(|i| square_law[i] * two_sample[i]).sum::<f32>()
+            / (1000..signal.len()).map(|i| square_law[i].powi(2)).sum::<f32>();
+        for i in 1000..signal.len() {
+            assert_relative_eq!(square_law[i] * scale, two_sample[i], max_relative = 0.05);
+        }
+    }
+
+    /// Amplitude of a tone on a signal, measured on the middle.
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    fn tone_amplitude(signal: &Signal, rate: Rate, freq: f32) -> f32 {
+        use std::f32::consts::PI;
+        let (start, end) = (signal.len() / 4, signal.len() * 3 / 4);
+        let w = PI * hz_to_norm(freq, rate);
+        let (mut re, mut im): (f32, f32) = (0., 0.);
+        for (i, x) in signal.iter().enumerate().take(end).skip(start) {
+            re += x * (w * i as f32).cos();
+            im += x * (w * i as f32).sin();
+        }
+        2. * (re * re + im * im).sqrt() / (end - start) as f32
+    }
+
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    #[test]
+    fn test_notch() {
+        use std::f32::consts::PI;
+
+        let rate = Rate::hz(11025);
+
+        // AM carrier on 2400Hz with some interference on 5000Hz
+        let apt: Signal = (0..11025 * 4).map(|i| {
+            let t = i as f32 / 11025.;
+            (1. + 0.5 * (2. * PI * 3. * t).sin()) * (2. * PI * 2400. * t).sin()
+        }).collect();
+        let signal: Signal = apt.iter().enumerate()
+            .map(|(i, x)| x + 0.5 * (Freq::hz(5000., rate).get_rad() * i as f32).sin())
+            .collect();
+
+        assert_relative_eq!(tone_amplitude(&signal, rate, 5000.), 0.5, max_relative = 0.01);
+
+        for filtered in [
+            notch(&signal, rate, 5000., 100.),
+            auto_notch(&signal, rate, 100.).unwrap(),
+        ].iter() {
+            assert_eq!(filtered.len(), signal.len());
+
+            // More than 30dB of attenuation
+            assert!(tone_amplitude(filtered, rate, 5000.) < 0.5 * 0.03);
+
+            // The carrier is preserved and aligned
+            assert_relative_eq!(tone_amplitude(filtered, rate, 2400.),
+                                tone_amplitude(&apt, rate, 2400.), max_relative = 0.01);
+            for i in 1000..signal.len() - 1000 {
+                assert!((filtered[i] - apt[i]).abs() < 0.05);
+            }
+        }
+
+        // Nothing to remove
+        assert_eq!(auto_notch(&apt, rate, 100.).unwrap(), apt);
+    }
+
+    #[test]
+    fn test_convolve() {
+        let signal: Signal = (0..50).map(|x| (x as f32 * 0.3).sin()).collect();
+        let coeff: Signal = vec![0.5, -0.25, 0.125, 1., 2.];
+
+        let result = convolve(&signal, &coeff, DotMode::Fast);
+        assert_eq!(result.len(), signal.len());
+        for i in 0..signal.len() {
+            let mut expected = 0.;
+            for j in 0..coeff.len() {
+                if i > j {
+                    expected += signal[i - j] * coeff[j];
+                }
+            }
+            assert_relative_eq!(result[i], expected, epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_demodulator_blocks() {
+        let rate = 11025.;
+        let signal: Signal = (0..20000).map(|i| {
+            let t = i as f32 / rate;
+            (1. + 0.5 * (2. * std::f32::consts::PI * 3. * t).sin())
+                * (2. * std::f32::consts::PI * 2400. * t).cos()
+        }).collect();
+        let carrier = Freq::hz(2400., Rate::hz(11025));
+
+        let context = &mut Context::resample(|_,_| {}, false, false);
+        let expected = demodulate(context, &signal, carrier).unwrap();
+
+        let mut demodulator = Demodulator::new(carrier);
+        let mut streamed = Signal::new();
+        let mut block_output = Signal::new();
+        let mut start = 0;
+        for size in [1, 0, 7, 1000, 3, 5000].iter().cycle() {
+            let end = std::cmp::min(start + size, signal.len());
+            demodulator.process_into(&signal[start..end], &mut block_output);
+            assert_eq!(block_output.len(), end - start);
+            streamed.extend_from_slice(&block_output);
+            start = end;
+            if start == signal.len() {
+                break;
+            }
+        }
+
+        assert_eq!(streamed, expected);
+    }
+
+    #[test]
+    fn test_filter_into() {
+        use filters::Filter;
+
+        let signal: Signal = (0..500).map(|x| (x as f32 * 0.3).sin() + (x as f32 * 2.).cos()).collect();
+        let lowpass = filters::Lowpass {
+            cutout: Freq::pi_rad(0.2),
+            atten: 40.,
+            delta_w: Freq::pi_rad(0.1),
+        };
+        let coeff = lowpass.design();
+
+        let context = &mut Context::resample(|_,_| {}, false, false);
+        let expected = filter(context, &signal, lowpass).unwrap();
+
+        // Reuse a buffer that is bigger and then smaller than needed
+        let mut output: Signal = vec![123.; 1000];
+        filter_into(&signal, &coeff, &mut output, DotMode::Fast);
+        assert_eq!(output, expected);
+
+        let capacity = output.capacity();
+        filter_into(&signal[..100].to_vec(), &coeff, &mut output, DotMode::Fast);
+        assert_eq!(output, expected[..100].to_vec());
+        assert_eq!(output.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_high_precision() {
+        use std::f64::consts::PI;
+
+        let mut context = Context::resample(|_,_| {}, false, false);
+
+        let lowpass = filters::Lowpass {
+            cutout: Freq::pi_rad(0.3),
+            atten: 60.,
+            delta_w: Freq::pi_rad(0.0018),
+        };
+        let coeff = filters::Filter::design(&lowpass);
+        assert!(coeff.len() >= 4000);
+
+        let signal: Signal = (0..5000).map(|i|
+            ((i as f64 * 0.1).sin() + 0.3 * (i as f64 * PI * 0.27).cos()) as f32
+        ).collect();
+
+        // Maximum error against the convolution calculated on f64, only where
+        // the filter is full of samples
+        let max_error = |output: &Signal| {
+            (coeff.len()..signal.len()).map(|i| {
+                let reference: f64 = coeff.iter().enumerate()
+                    .map(|(k, c)| f64::from(*c) * f64::from(signal[i - k]))
+                    .sum();
+                (f64::from(output[i]) - reference).abs()
+            }).fold(0., f64::max)
+        };
+
+        context.deterministic = true;
+        let normal = max_error(&filter(&mut context, &signal, lowpass.clone()).unwrap());
+        context.high_precision = true;
+        assert_eq!(context.dot_mode(), DotMode::HighPrecision);
+        let precise = max_error(&filter(&mut context, &signal, lowpass.clone()).unwrap());
+
+        assert!(precise < normal / 4., "Precise: {}, normal: {}", precise, normal);
+        assert!(precise < 1e-6, "Precise: {}", precise);
+    }
+
+    #[test]
+    fn test_dot() {
+        let a: Signal = (0..100).map(|x| (x as f32 * 0.37).sin()).collect();
+        let b: Signal = (0..100).map(|x| (x as f32 * 0.11).cos()).collect();
+
+        for len in [0, 1, 7, 8, 9, 33, 100].iter() {
+            let expected: f32 = a[..*len].iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+            assert_relative_eq!(dot(&a[..*len], &b, DotMode::Fast), expected, epsilon = 1e-5);
+            assert_relative_eq!(dot(&a, &b[..*len], DotMode::Fast), expected, epsilon = 1e-5);
+            assert_eq!(dot_scalar(&a[..*len], &b), dot_scalar(&a, &b[..*len]));
+        }
+    }
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[test]
+    fn test_dot_simd() {
+        if !is_x86_feature_detected!("avx") {
+            return;
+        }
+
+        let a: Signal = (0..1001).map(|x| (x as f32 * 0.37).sin()).collect();
+        let b: Signal = (0..1001).map(|x| (x as f32 * 0.11).cos()).collect();
+
+        for len in [0, 5, 8, 16, 100, 1001].iter() {
+            let scalar = dot_scalar(&a[..*len], &b);
+            let simd = unsafe { simd::dot_avx(&a[..*len], &b) };
+            assert_relative_eq!(simd, scalar, epsilon = 1e-4, max_relative = 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_dot_deterministic() {
+        let a: Signal = (0..1001).map(|x| (x as f32 * 0.37).sin()).collect();
+        let b: Signal = (0..1001).map(|x| (x as f32 * 0.11).cos()).collect();
+
+        assert_eq!(dot(&a, &b, DotMode::Deterministic).to_bits(), dot_scalar(&a, &b).to_bits());
+
+        // Set on the context
+        let mut context = Context::resample(|_,_| {}, false, false);
+        assert_eq!(context.dot_mode(), DotMode::Fast);
+        context.deterministic = true;
+        assert_eq!(context.dot_mode(), DotMode::Deterministic);
+    }
+
+    /// Compare speed of SIMD and scalar dot products, run with
+    /// `cargo test --release --features simd -- --ignored --nocapture`.
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[test]
+    #[ignore]
+    fn bench_dot_simd() {
+        use std::time::Instant;
+
+        if !is_x86_feature_detected!("avx") {
+            return;
+        }
+
+        let a: Signal = (0..1000).map(|x| (x as f32 * 0.37).sin()).collect();
+        let b: Signal = (0..1000).map(|x| (x as f32 * 0.11).cos()).collect();
+
+        let start = Instant::now();
+        let mut scalar = 0.;
+        for _ in 0..100_000 {
+            scalar += dot_scalar(&a, &b);
+        }
+        let scalar_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut simd = 0.;
+        for _ in 0..100_000 {
+            simd += unsafe { simd::dot_avx(&a, &b) };
+        }
+        let simd_time = start.elapsed();
+
+        println!("Scalar: {:?}, SIMD: {:?}, speedup: {:.2}, results: {} {}",
+            scalar_time, simd_time,
+            scalar_time.as_secs_f64() / simd_time.as_secs_f64(), scalar, simd);
+    }
+
+    #[test]
+    fn test_interpolation_gain() {
+        // gcd is 15, L/M: 832/735
+        let input_rate = Rate::hz(11025);
+        let output_rate = Rate::hz(12480);
+        let signal: Signal = vec![0.5; 11025];
+
+        // Passband ripple of the filter, much less than the L = 832 it had
+        // without compensating
+        let ripple = 10_f32.powf(-30. / 20.);
+        let filter = filters::Lowpass {
+            cutout: Freq::hz(4800., input_rate),
+            atten: 30.,
+            delta_w: Freq::hz(1000., input_rate),
+        };
+
+        let mut context = Context::resample(|_,_| {}, false, false);
+        let resampled = resample_with_filter(
+            &mut context, &signal, input_rate, output_rate, filter.clone()).unwrap();
+        for x in resampled[1000..resampled.len() - 1000].iter() {
+            assert_relative_eq!(*x, 0.5, max_relative = ripple);
+        }
+
+        let mut resampler = Resampler::new(
+            input_rate, output_rate, filter, DEFAULT_MAX_RESAMPLE_TAPS).unwrap();
+        let streamed = resampler.process(&signal);
+        for x in streamed[1000..].iter() {
+            assert_relative_eq!(*x, 0.5, max_relative = ripple);
+        }
+    }
+
+    /// Check that the resampler keeps tones on the passband and removes the
+    /// ones that would alias, measuring the tones with the FFT.
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    #[test]
+    fn test_resample_quality() {
+        use std::f32::consts::PI;
+
+        let atten = 40.;
+        let input_rate = Rate::hz(11025);
+        let output_rate = Rate::hz(4160);
+        let delta_w = Freq::hz(200., input_rate);
+
+        // Passband up to 1980Hz and stopband from 2180Hz, the tones on the
+        // stopband alias to 1160Hz and 340Hz after resampling
+        let in_band = [500., 1500.];
+        let out_of_band = [3000., 4500.];
+
+        let signal: Signal = (0..3 * 11025).map(|i| {
+            let t = i as f32 / 11025.;
+            in_band.iter().chain(out_of_band.iter())
+                .map(|f| (2. * PI * f * t).sin()).sum()
+        }).collect();
+
+        let resampled = resample(
+            &mut Context::resample(|_,_| {}, false, false), // Dummy context
+            &signal, input_rate, output_rate, atten, delta_w).unwrap();
+
+        // One second from the middle, so every tone falls on a bin of 1Hz
+        let start = resampled.len() / 2 - 2080;
+        let spectrum = abs_fft(&resampled[start..start + 4160].to_vec()).unwrap();
+        let amplitude = |freq: usize| 2. * spectrum[freq] / 4160.;
+
+        // Passband ripple
+        let ripple = 10_f32.powf(-atten / 20.);
+        for freq in in_band.iter() {
+            assert_relative_eq!(amplitude(*freq as usize), 1., epsilon = 2. * ripple);
+        }
+
+        // Attenuated aliases
+        for freq in out_of_band.iter() {
+            let alias = (*freq as usize) % 4160;
+            let alias = alias.min(4160 - alias);
+            assert!(amplitude(alias) < ripple);
+        }
+    }
+
+    #[test]
+    fn test_compand() {
+        let original: Signal = (-100..=100).map(|x| x as f32 * 0.037).collect();
+
+        let mut signal = original.clone();
+        compand(&mut signal, 255.);
+
+        // Same peak and sign, small values are bigger
+        assert_relative_eq!(signal[0], original[0]);
+        assert_relative_eq!(signal[200], original[200]);
+        assert_eq!(signal[100], 0.);
+        assert!(signal[101] > original[101] * 10.);
+        assert!(signal[99] < original[99] * 10.);
+
+        expand(&mut signal, 255.);
+        for (x, y) in signal.iter().zip(original.iter()) {
+            assert_relative_eq!(x, y, epsilon = 1e-5, max_relative = 1e-4);
+        }
+
+        // Silence is left as is
+        let mut silence: Signal = vec![0.; 10];
+        compand(&mut silence, 255.);
+        assert_eq!(silence, vec![0.; 10]);
+    }
+
+    #[test]
+    fn test_emphasis() {
+        use std::f32::consts::PI;
+
+        let rate = Rate::hz(12480);
+        let tau = 200e-6;
+
+        let original: Signal = (0..2000).map(|i| {
+            let t = i as f32 / 12480.;
+            0.5 + 0.3 * (2. * PI * 1000. * t).sin() + 0.1 * (2. * PI * 3000. * t).sin()
+        }).collect();
+
+        // High frequencies get attenuated, DC stays the same
+        let deemphasized = deemphasis(&original, rate, tau);
+        assert_relative_eq!(deemphasis(&vec![1.; 2000], rate, tau)[1999], 1.);
+        let energy = |s: &Signal| s[1000..].iter().map(|x| (x - 0.5).powi(2)).sum::<f32>();
+        assert!(energy(&deemphasized) < energy(&original) / 2.);
+
+        let restored = pre_emphasis(&deemphasized, rate, tau);
+        for (x, y) in restored.iter().zip(original.iter()) {
+            assert_relative_eq!(x, y, epsilon = 1e-4);
+        }
+        let restored = deemphasis(&pre_emphasis(&original, rate, tau), rate, tau);
+        for (x, y) in restored.iter().zip(original.iter()) {
+            assert_relative_eq!(x, y, epsilon = 1e-4);
+        }
+
+        // Zero time constant does nothing
+        assert_eq!(deemphasis(&original, rate, 0.), original);
+        assert!(deemphasis(&Signal::new(), rate, tau).is_empty());
+    }
+
+    #[test]
+    fn test_auto_gain() {
+        let mut signal: Signal = (0..10000)
+            .map(|x| 0.001 * (x as f32 / 10.).sin() + 0.0005).collect();
+
+        auto_gain(&mut signal);
+
+        let rms = (signal.iter().map(|x| x.powi(2)).sum::<f32>()
+            / signal.len() as f32).sqrt();
+        assert_relative_eq!(rms, AUTO_GAIN_RMS, max_relative = 0.001);
+
+        // Silence is left as is
+        let mut silence: Signal = vec![0.; 100];
+        auto_gain(&mut silence);
+        assert_eq!(silence, vec![0.; 100]);
+    }
+
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    #[test]
+    fn test_estimate_freq_offset() {
+        use std::f32::consts::PI;
+
+        let rate = Rate::hz(11025);
+
+        // Recording where the real sample rate is 0.5% higher than the one
+        // declared. The tone is on 2400Hz but appears lower
+        let real_rate = 11025. * 1.005;
+        let signal: Signal = (0..11025 * 20)
+            .map(|i| (2. * PI * 2400. * (i as f32 / real_rate)).sin()).collect();
+
+        let expected_offset = 2400. * 11025. / real_rate - 2400.;
+        let offset = estimate_freq_offset(&signal, rate, 2400.).unwrap();
+        assert!((offset - expected_offset).abs() < 0.5);
+
+        let corrected = correct_rate(rate, 2400., offset);
+        assert!((corrected.get_hz() as f32 - real_rate).abs() < 3.);
+
+        // Too short
+        assert!(estimate_freq_offset(&vec![0.; 10], rate, 2400.).is_err());
+    }
 
     /// Check that when we use strange resampling rates, the greatest common
     /// divisor between them can be too small and the calculated interpolated
@@ -413,6 +2622,76 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_resampler_blocks() {
+        let input_rate = Rate::hz(11025);
+        let output_rate = Rate::hz(12480);
+        let filter = || filters::Lowpass {
+            cutout: Freq::hz(4000., input_rate),
+            atten: 30.,
+            delta_w: Freq::hz(1000., input_rate),
+        };
+
+        let signal: Signal = (0..20000).map(|x| (x as f32 * 0.1).sin()
+            + 0.3 * (x as f32 * 0.77).cos()).collect();
+
+        // Whole signal at once
+        let gcd = 11025_u32.gcd(&12480);
+        let (l, m) = (12480 / gcd, 11025 / gcd);
+        let mut coeff_filter = filter();
+        filters::Filter::resample(&mut coeff_filter, input_rate, input_rate * l);
+        let expected = fast_resampling(
+            &mut Context::resample(|_,_| {}, false, false), // Dummy context
+            &signal, l, m, &filters::Filter::design(&coeff_filter), input_rate,
+        ).unwrap();
+
+        // Uneven blocks, including empty ones and ones shorter than the filter
+        let sizes = [1, 0, 7, 500, 3, 2000, 0, 1, 4096, 13];
+        let mut resampler = Resampler::new(
+            input_rate, output_rate, filter(), DEFAULT_MAX_RESAMPLE_TAPS).unwrap();
+        let mut result: Signal = Vec::new();
+        let mut position = 0;
+        for size in sizes.iter().cycle() {
+            let end = (position + size).min(signal.len());
+            result.extend(resampler.process(&signal[position..end]));
+            position = end;
+            if position == signal.len() {
+                break;
+            }
+        }
+        result.extend(resampler.finish());
+
+        assert_eq!(result.len(), expected.len());
+        assert_eq!(result, expected);
+
+        // Reusing the same output buffer
+        let mut resampler = Resampler::new(
+            input_rate, output_rate, filter(), DEFAULT_MAX_RESAMPLE_TAPS).unwrap();
+        let mut output: Signal = Vec::with_capacity(4096 * 12480 / 11025 + 1);
+        let capacity = output.capacity();
+        let mut result: Signal = Vec::new();
+        for block in signal.chunks(4096) {
+            resampler.process_into(block, &mut output);
+            result.extend_from_slice(&output);
+        }
+        resampler.finish_into(&mut output);
+        result.extend_from_slice(&output);
+        assert_eq!(output.capacity(), capacity);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_decimate() {
+        let signal: Signal = (0..20).map(|x| x as f32).collect();
+        for &m in [1, 3, 4, 25].iter() {
+            let mut decimated = signal.clone();
+            decimate(&mut decimated, m);
+            let expected: Signal = signal.iter().step_by(m as usize)
+                .take(signal.len() / m as usize).cloned().collect();
+            assert_eq!(decimated, expected);
+        }
+    }
+
     /// Check `fast_resampling()` when the coeffs are longer than the signal.
     ///
     /// I'm checking only for overflows, not checking if the resample is
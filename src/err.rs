@@ -5,6 +5,8 @@ use std::error::Error as StdError;
 use hound;
 use png;
 use log;
+#[cfg(feature = "gif")]
+use gif_codec;
 
 
 /// Uses my custom error type.
@@ -24,6 +26,9 @@ pub enum Error {
     /// About PNG encoding/writing.
     PngWrite(String),
 
+    /// About reading or processing images.
+    ImageProcessing(String),
+
     /// Deserializing errors.
     Deserialize(String),
 
@@ -45,6 +50,7 @@ impl std::fmt::Display for Error {
             Error::Io(ref err) => err.fmt(f),
             Error::WavOpen(ref msg) => f.write_str(msg.as_str()),
             Error::PngWrite(ref msg) => f.write_str(msg.as_str()),
+            Error::ImageProcessing(ref msg) => f.write_str(msg.as_str()),
             Error::Deserialize(ref msg) => f.write_str(msg.as_str()),
             Error::Internal(ref msg) => f.write_str(msg.as_str()),
             Error::RateOverflow(ref msg) => f.write_str(msg.as_str()),
@@ -89,8 +95,53 @@ impl From<png::EncodingError> for Error {
     }
 }
 
+impl From<png::DecodingError> for Error {
+    fn from(err: png::DecodingError) -> Self {
+        match err {
+            png::DecodingError::IoError(io_error) => Error::Io(io_error),
+            _ => Error::ImageProcessing(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "gif")]
+impl From<gif_codec::EncodingError> for Error {
+    fn from(err: gif_codec::EncodingError) -> Self {
+        match err {
+            gif_codec::EncodingError::Io(io_error) => Error::Io(io_error),
+            _ => Error::ImageProcessing(err.to_string()),
+        }
+    }
+}
+
 impl From<toml::de::Error> for Error {
     fn from(err: toml::de::Error) -> Self {
         Error::Deserialize(err.description().to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_from_png_decoding_error() {
+        let not_png: &[u8] = b"This is not a PNG file";
+        let result: Result<()> = png::Decoder::new(not_png).read_info()
+            .map(|_| ()).map_err(Error::from);
+
+        match result {
+            Err(Error::ImageProcessing(msg)) => assert_eq!(msg, "invalid signature"),
+            _ => panic!("Expected ImageProcessing error"),
+        }
+
+        // Reading errors stay as they are
+        let io_error = png::DecodingError::IoError(
+            std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "eof"));
+        match Error::from(io_error) {
+            Error::Io(err) => assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof),
+            _ => panic!("Expected Io error"),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Fast Fourier Transform.
+//!
+//! Everything that needs a spectrum goes through `fft_forward()`, so the rest
+//! of the code doesn't know which implementation is used. With the `fft`
+//! feature I use `rustfft`, with `fft_builtin` (and not `fft`) I use the small
+//! implementation on this file, useful on platforms where `rustfft` doesn't
+//! build. Without any of them spectral functions fail with
+//! `Error::FeatureNotAvailable`.
+
+use num::Complex;
+
+use err;
+
+
+/// Forward FFT of a real signal.
+///
+/// Returns every bin, negative frequencies included, without normalization.
+#[cfg(feature = "fft")]
+pub fn fft_forward(signal: &[f32]) -> err::Result<Vec<Complex<f32>>> {
+    Ok(rustfft_forward(signal))
+}
+
+/// Forward FFT of a real signal.
+///
+/// Returns every bin, negative frequencies included, without normalization.
+#[cfg(all(feature = "fft_builtin", not(feature = "fft")))]
+pub fn fft_forward(signal: &[f32]) -> err::Result<Vec<Complex<f32>>> {
+    Ok(builtin_forward(signal))
+}
+
+/// Forward FFT of a real signal.
+///
+/// Compiled without FFT support, so fails.
+#[cfg(not(any(feature = "fft", feature = "fft_builtin")))]
+pub fn fft_forward(_signal: &[f32]) -> err::Result<Vec<Complex<f32>>> {
+    Err(err::Error::FeatureNotAvailable(vec!["fft".to_string()]))
+}
+
+/// FFT using `rustfft`.
+#[cfg(feature = "fft")]
+fn rustfft_forward(signal: &[f32]) -> Vec<Complex<f32>> {
+    use rustfft::FFTplanner;
+    use rustfft::num_traits::Zero;
+
+    let mut input: Vec<Complex<f32>> = signal.iter()
+        .map(|x| Complex::new(*x, 0.)).collect();
+
+    let mut output: Vec<Complex<f32>> = vec![Complex::zero(); input.len()];
+
+    let mut planner = FFTplanner::new(false); // inverse=false
+    let fft = planner.plan_fft(input.len());
+    fft.process(&mut input, &mut output);
+
+    output
+}
+
+/// FFT without external crates.
+///
+/// Radix 2 when the length is a power of two, otherwise Bluestein's algorithm
+/// that turns the FFT into a convolution I can do with radix 2 FFTs. Not as
+/// fast as `rustfft` but still O(n log n). Calculated with `f64` so the
+/// precision is similar.
+#[cfg(any(test, feature = "fft_builtin"))]
+fn builtin_forward(signal: &[f32]) -> Vec<Complex<f32>> {
+    let input: Vec<Complex<f64>> = signal.iter()
+        .map(|x| Complex::new(f64::from(*x), 0.)).collect();
+
+    let output = if input.len().is_power_of_two() || input.is_empty() {
+        let mut data = input;
+        radix2(&mut data, false);
+        data
+    } else {
+        bluestein(&input)
+    };
+
+    output.iter().map(|x| Complex::new(x.re as f32, x.im as f32)).collect()
+}
+
+/// Complex number with magnitude one and the given angle.
+#[cfg(any(test, feature = "fft_builtin"))]
+fn unit(angle: f64) -> Complex<f64> {
+    Complex::new(angle.cos(), angle.sin())
+}
+
+/// In place iterative radix 2 FFT, the length has to be a power of two.
+#[cfg(any(test, feature = "fft_builtin"))]
+fn radix2(data: &mut [Complex<f64>], inverse: bool) {
+    let n = data.len();
+    if n <= 1 {
+        return;
+    }
+
+    // Bit reversal permutation
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1. } else { -1. };
+    let mut len = 2;
+    while len <= n {
+        let step = unit(sign * 2. * std::f64::consts::PI / len as f64);
+        for start in (0..n).step_by(len) {
+            let mut w = Complex::new(1., 0.);
+            for k in 0..len / 2 {
+                let a = data[start + k];
+                let b = data[start + k + len / 2] * w;
+                data[start + k] = a + b;
+                data[start + k + len / 2] = a - b;
+                w *= step;
+            }
+        }
+        len *= 2;
+    }
+}
+
+/// FFT of any length using Bluestein's algorithm.
+#[cfg(any(test, feature = "fft_builtin"))]
+fn bluestein(input: &[Complex<f64>]) -> Vec<Complex<f64>> {
+    let n = input.len();
+    let m = (2 * n - 1).next_power_of_two();
+
+    // exp(-i pi k^2 / n), k^2 taken modulo 2n so the angle stays small
+    let chirp: Vec<Complex<f64>> = (0..n).map(|k| {
+        let k2 = (k * k) % (2 * n);
+        unit(-std::f64::consts::PI * k2 as f64 / n as f64)
+    }).collect();
+
+    let mut a = vec![Complex::new(0., 0.); m];
+    for k in 0..n {
+        a[k] = input[k] * chirp[k];
+    }
+
+    let mut b = vec![Complex::new(0., 0.); m];
+    b[0] = chirp[0].conj();
+    for k in 1..n {
+        b[k] = chirp[k].conj();
+        b[m - k] = chirp[k].conj();
+    }
+
+    radix2(&mut a, false);
+    radix2(&mut b, false);
+    for (x, y) in a.iter_mut().zip(b.iter()) {
+        *x *= y;
+    }
+    radix2(&mut a, true);
+
+    (0..n).map(|k| a[k] * chirp[k] / m as f64).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Assertions every backend should pass.
+    fn check_backend(forward: fn(&[f32]) -> Vec<Complex<f32>>) {
+        assert!(forward(&[]).is_empty());
+        assert_eq!(forward(&[3.]), vec![Complex::new(3., 0.)]);
+
+        // Known transforms, power of two and other lengths
+        let spectrum = forward(&[1., 2., 3., 4.]);
+        let expected = [(10., 0.), (-2., 2.), (-2., 0.), (-2., -2.)];
+        for (x, (re, im)) in spectrum.iter().zip(expected.iter()) {
+            assert_relative_eq!(x.re, re, epsilon = 1e-5);
+            assert_relative_eq!(x.im, im, epsilon = 1e-5);
+        }
+        let spectrum = forward(&[1.; 7]);
+        assert_relative_eq!(spectrum[0].re, 7., epsilon = 1e-5);
+        assert!(spectrum[1..].iter().all(|x| x.norm() < 1e-5));
+
+        // A tone falls on its bin, on lengths like the ones used for spectra
+        for &n in [1000, 1024, 11025].iter() {
+            let signal: Vec<f32> = (0..n)
+                .map(|i| (2. * std::f32::consts::PI * 50. * i as f32 / n as f32).cos())
+                .collect();
+            let spectrum = forward(&signal);
+            assert_eq!(spectrum.len(), n);
+            assert_relative_eq!(spectrum[50].norm(), n as f32 / 2., max_relative = 1e-3);
+            assert_relative_eq!(spectrum[n - 50].norm(), n as f32 / 2., max_relative = 1e-3);
+            assert!(spectrum[51].norm() < 1e-2 * n as f32);
+        }
+    }
+
+    #[test]
+    fn test_builtin_backend() {
+        check_backend(builtin_forward);
+    }
+
+    #[cfg(feature = "fft")]
+    #[test]
+    fn test_rustfft_backend() {
+        check_backend(rustfft_forward);
+
+        // Both give the same result
+        let signal: Vec<f32> = (0..999).map(|i| ((i * 37) % 101) as f32 - 50.).collect();
+        for (a, b) in rustfft_forward(&signal).iter().zip(builtin_forward(&signal).iter()) {
+            assert!((a - b).norm() < 1e-3, "{} != {}", a, b);
+        }
+    }
+}
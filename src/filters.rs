@@ -2,7 +2,9 @@
 
 use std::f32::consts::PI;
 
-use dsp::{Signal, Rate, Freq};
+use dsp::{self, Signal, Rate, Freq};
+use err;
+use misc;
 
 
 /// Some kind of filter
@@ -12,6 +14,10 @@ pub trait Filter {
 
     /// Resample filter to a new `Rate`.
     fn resample(&mut self, input_rate: Rate, output_rate: Rate);
+
+    /// Number of coefficients that `design()` would give, without designing
+    /// the filter.
+    fn length(&self) -> usize;
 }
 
 /// No filter.
@@ -44,12 +50,52 @@ pub struct LowpassDcRemoval {
     pub delta_w: Freq
 }
 
+/// Band-stop FIR filter for removing a tone, windowed by a kaiser window.
+///
+/// Attenuation in positive decibels. Removes frequencies from
+/// `center - width / 2` to `center + width / 2`, the transition bands are
+/// centered on those edges and are `width / 2` wide. Designed as a bandpass
+/// filter and then inverted.
+#[derive(Clone, PartialEq)]
+pub struct Notch {
+    pub center: Freq,
+    pub width: Freq,
+    pub atten: f32,
+}
+
+/// Symmetric FIR filter with some given gains.
+///
+/// The gains are `(w, gain)` pairs, the filter is designed with least squares
+/// so between and outside those frequencies the gain is whatever fits best.
+/// `taps` is the length, rounded up to an odd number. See
+/// `droop_compensation()`.
+#[derive(Clone, PartialEq)]
+pub struct Equalizer {
+    pub gains: Vec<(Freq, f32)>,
+    pub taps: usize,
+}
+
+/// Hilbert transformer FIR filter, windowed by a kaiser window.
+///
+/// Shifts the phase of every frequency by -90 degrees. Attenuation in positive
+/// decibels. Has transition bands from `0` to `delta_w` and from
+/// `pi - delta_w` to `pi`, the gain falls to zero there.
+#[derive(Clone, PartialEq)]
+pub struct Hilbert {
+    pub atten: f32,
+    pub delta_w: Freq
+}
+
 impl Filter for NoFilter {
     fn design(&self) -> Signal {
         return vec![1.,];
     }
 
     fn resample(&mut self, _input_rate: Rate, _output_rate: Rate) {}
+
+    fn length(&self) -> usize {
+        1
+    }
 }
 
 impl Filter for Lowpass {
@@ -88,6 +134,10 @@ impl Filter for Lowpass {
         self.cutout /= ratio;
         self.delta_w /= ratio;
     }
+
+    fn length(&self) -> usize {
+        kaiser_length(self.atten, self.delta_w)
+    }
 }
 
 impl Filter for LowpassDcRemoval {
@@ -131,6 +181,188 @@ impl Filter for LowpassDcRemoval {
         self.cutout /= ratio;
         self.delta_w /= ratio;
     }
+
+    fn length(&self) -> usize {
+        kaiser_length(self.atten, self.delta_w)
+    }
+}
+
+impl Filter for Hilbert {
+    fn design(&self) -> Signal {
+
+        debug!("Designing Hilbert filter, attenuation: {}dB, delta_w: pi*{}rad/s",
+               self.atten, self.delta_w.get_pi_rad());
+
+        let window = kaiser(self.atten, self.delta_w);
+
+        let m = window.len() as i32;
+
+        // Ideal impulse response is 2 / (n * pi) on odd samples and zero on
+        // even ones
+        let filter: Signal = (-(m - 1) / 2 ..= (m - 1) / 2).map(|n| {
+            if n % 2 == 0 {
+                0.
+            } else {
+                2. / (n as f32 * PI)
+            }
+        }).collect();
+
+        debug!("Hilbert filter design finished");
+
+        product(filter, &window)
+    }
+
+    fn resample(&mut self, input_rate: Rate, output_rate: Rate) {
+        let ratio = output_rate.get_hz() as f32 / input_rate.get_hz() as f32;
+        self.delta_w /= ratio;
+    }
+
+    fn length(&self) -> usize {
+        kaiser_length(self.atten, self.delta_w)
+    }
+}
+
+impl Filter for Notch {
+    fn design(&self) -> Signal {
+
+        debug!("Designing Notch filter, \
+               center: pi*{}rad/s, width: pi*{}rad/s, attenuation: {}dB",
+               self.center.get_pi_rad(), self.width.get_pi_rad(), self.atten);
+
+        // Always odd length
+        let window = kaiser(self.atten, self.width / 2.);
+
+        let low = self.center - self.width / 2.;
+        let high = self.center + self.width / 2.;
+
+        let mut filter: Signal = Vec::with_capacity(window.len());
+
+        let m = window.len() as i32;
+
+        // Bandpass, difference between two lowpass filters
+        for n in -(m - 1) / 2 ..= (m - 1) / 2 {
+            if n == 0 {
+                filter.push(high.get_pi_rad() - low.get_pi_rad());
+            } else {
+                let n = n as f32;
+                filter.push(
+                    (n * PI * high.get_pi_rad()).sin() / (n * PI)
+                    - (n * PI * low.get_pi_rad()).sin() / (n * PI)
+                );
+            }
+        }
+
+        let mut filter = product(filter, &window);
+
+        // Spectral inversion, substract the bandpass from an impulse
+        for x in filter.iter_mut() {
+            *x = -*x;
+        }
+        filter[(m as usize - 1) / 2] += 1.;
+
+        debug!("Notch filter design finished");
+
+        filter
+    }
+
+    fn resample(&mut self, input_rate: Rate, output_rate: Rate) {
+        let ratio = output_rate.get_hz() as f32 / input_rate.get_hz() as f32;
+        self.center /= ratio;
+        self.width /= ratio;
+    }
+
+    fn length(&self) -> usize {
+        kaiser_length(self.atten, self.width / 2.)
+    }
+}
+
+impl Filter for Equalizer {
+    fn design(&self) -> Signal {
+
+        debug!("Designing Equalizer filter, {} gains, {} taps",
+               self.gains.len(), self.taps);
+
+        // The gain of a symmetric filter is a sum of cosines, one for each
+        // pair of coefficients
+        let half = self.taps / 2;
+        let basis: Vec<Vec<f64>> = self.gains.iter().map(|(w, _)| {
+            (0..=half).map(|k| {
+                let factor = if k == 0 { 1. } else { 2. };
+                factor * (f64::from(w.get_rad()) * k as f64).cos()
+            }).collect()
+        }).collect();
+        let target: Vec<f64> = self.gains.iter().map(|(_, g)| f64::from(*g)).collect();
+
+        let weights = match misc::least_squares(&basis, &target) {
+            Ok(weights) => weights,
+            Err(e) => {
+                warn!("Can't design equalizer, using no filter: {}", e);
+                return vec![1.];
+            },
+        };
+
+        debug!("Equalizer filter design finished");
+
+        weights.iter().rev().chain(weights.iter().skip(1))
+            .map(|x| *x as f32)
+            .collect()
+    }
+
+    fn resample(&mut self, input_rate: Rate, output_rate: Rate) {
+        let ratio = output_rate.get_hz() as f32 / input_rate.get_hz() as f32;
+        for (w, _) in self.gains.iter_mut() {
+            *w /= ratio;
+        }
+    }
+
+    fn length(&self) -> usize {
+        self.taps / 2 * 2 + 1
+    }
+}
+
+/// Equalizer that flattens the passband of a filter.
+///
+/// Windowed filters don't have a perfectly flat passband, the gain falls a bit
+/// near the transition band and that softens the image. The equalizer has the
+/// inverse gain of `coeff` from `start` to `end`, scaled so the mean gain on
+/// that band stays the same. Keep `taps` short, otherwise the equalizer also
+/// boosts the start of the transition band and lets noise through.
+pub fn droop_compensation(coeff: &Signal, start: Freq, end: Freq, taps: usize) -> Equalizer {
+    // Points on the band, more than enough for the least squares
+    let points = std::cmp::max(64, 8 * taps);
+    let band: Vec<Freq> = (0..points).map(|i|
+        start + (end - start) * (i as f32 / (points - 1) as f32)
+    ).collect();
+
+    let gains: Vec<f32> = band.iter().map(|w| gain_at(coeff, *w)).collect();
+    let mean = gains.iter().sum::<f32>() / gains.len() as f32;
+
+    Equalizer {
+        gains: band.into_iter().zip(gains).map(|(w, g)| (w, mean / g)).collect(),
+        taps,
+    }
+}
+
+/// Magnitude of the frequency response of a filter on a single frequency.
+pub fn gain_at(coeff: &Signal, w: Freq) -> f32 {
+    let (re, im) = coeff.iter().enumerate().fold((0., 0.), |(re, im), (n, h)| {
+        let phase = w.get_rad() * n as f32;
+        (re + h * phase.cos(), im - h * phase.sin())
+    });
+    (re * re + im * im).sqrt()
+}
+
+/// Length of the Kaiser window designed with these parameters.
+///
+/// Always odd. Useful for knowing the filter length without designing it.
+pub fn kaiser_length(atten: f32, delta_w: Freq) -> usize {
+    // Filter length, we want an odd length
+    let mut length: i32 = ((atten - 8.) / (2.285 * delta_w.get_rad())).ceil() as i32 + 1;
+    if length % 2 == 0 {
+        length += 1;
+    }
+
+    length as usize
 }
 
 /// Design Kaiser window from parameters.
@@ -152,11 +384,7 @@ fn kaiser(atten: f32, delta_w: Freq) -> Signal {
         beta = 0.5842 * (atten - 21.).powf(0.4) + 0.07886 * (atten - 21.);
     }
 
-    // Filter length, we want an odd length
-    let mut length: i32 = ((atten - 8.) / (2.285 * delta_w.get_rad())).ceil() as i32 + 1;
-    if length % 2 == 0 {
-        length += 1;
-    }
+    let length = kaiser_length(atten, delta_w) as i32;
 
     let mut window: Signal = Vec::with_capacity(length as usize);
 
@@ -172,6 +400,64 @@ fn kaiser(atten: f32, delta_w: Freq) -> Signal {
     window
 }
 
+/// Minimum amount of points used when measuring a frequency response.
+const RESPONSE_POINTS: usize = 8192;
+
+/// Magnitude of the frequency response of a filter from 0 to pi rad/s.
+///
+/// Pads the coefficients with zeros before the FFT to get a fine frequency
+/// resolution, otherwise the peaks of the ripple can fall between points.
+/// Returns `(w, magnitude)` pairs.
+fn frequency_response(coeff: &Signal) -> err::Result<Vec<(Freq, f32)>> {
+    let points = std::cmp::max(RESPONSE_POINTS, 8 * coeff.len());
+    let mut padded = coeff.clone();
+    padded.resize(points, 0.);
+
+    Ok(dsp::abs_fft(&padded)?.into_vec().into_iter()
+        .take(points / 2 + 1)
+        .enumerate()
+        .map(|(i, v)| (Freq::pi_rad(2. * i as f32 / points as f32), v))
+        .collect())
+}
+
+/// Maximum deviation from unity gain on the passband.
+///
+/// The passband goes from zero to `cutout`. For the filters designed here,
+/// where the transition band is centered on the cutout frequency, use
+/// `cutout - delta_w / 2`. Fails if there are no coefficients.
+pub fn passband_ripple(coeff: &Signal, cutout: Freq) -> err::Result<f32> {
+    check_coefficients(coeff)?;
+
+    Ok(frequency_response(coeff)?.iter()
+        .filter(|(w, _)| *w < cutout)
+        .map(|(_, v)| (v - 1.).abs())
+        .fold(0., f32::max))
+}
+
+/// Minimum attenuation on the stopband in positive dB.
+///
+/// The stopband goes from `cutout + delta_w / 2` to pi rad/s, as on the
+/// filters designed here. Fails if there are no coefficients.
+pub fn stopband_attenuation(coeff: &Signal, cutout: Freq, delta_w: Freq) -> err::Result<f32> {
+    check_coefficients(coeff)?;
+
+    let max = frequency_response(coeff)?.iter()
+        .filter(|(w, _)| *w > cutout + delta_w / 2.)
+        .map(|(_, v)| *v)
+        .fold(0., f32::max);
+
+    Ok(-20. * max.log10())
+}
+
+/// Fail if there are no filter coefficients to measure.
+fn check_coefficients(coeff: &Signal) -> err::Result<()> {
+    if coeff.is_empty() {
+        return Err(err::Error::Internal(
+            "Can't measure a filter without coefficients".to_string()));
+    }
+    Ok(())
+}
+
 /// Product of two vectors, element by element.
 pub fn product(mut v1: Signal, v2: &Signal) -> Signal {
     if v1.len() != v2.len() {
@@ -189,26 +475,11 @@ pub fn product(mut v1: Signal, v2: &Signal) -> Signal {
 mod tests {
 
     use super::*;
-
-    /// Calculate absolute value of fft
-    fn abs_fft(signal: &Signal) -> Signal {
-        use rustfft::FFTplanner;
-        use rustfft::num_complex::Complex;
-        use rustfft::num_traits::Zero;
-
-        let mut input: Vec<Complex<f32>> = signal.iter()
-            .map(|x| Complex::new(*x, 0.)).collect();
-
-        let mut output: Vec<Complex<f32>> = vec![Complex::zero(); input.len()];
-
-        let mut planner = FFTplanner::new(false); // inverse=false
-        let fft = planner.plan_fft(input.len());
-        fft.process(&mut input, &mut output);
-
-        output.iter().map(|x| x.norm()).collect()
-    }
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    use dsp::abs_fft;
 
     /// Check if two vectors of float are equal given some margin of precision
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
     fn vector_roughly_equal(a: &Vec<f32>, b: &Vec<f32>) -> bool {
         // Iterator with tuples of values to compare.
         // [(a1, b1), (a2, b2), (a3, b3), ...]
@@ -217,23 +488,25 @@ mod tests {
         values.all(|(&a, &b)| ulps_eq!(a, b))
     }
 
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
     #[test]
     fn test_abs_fft() {
         // Checked with GNU Octave
         assert!(vector_roughly_equal(
-            &abs_fft(&vec![1., 2., 3., 4.]),
+            &abs_fft(&vec![1., 2., 3., 4.]).unwrap().into_vec(),
             &vec![10., 2.828427124746190, 2., 2.828427124746190])
         );
         assert!(vector_roughly_equal(
-            &abs_fft(&vec![1., 1., 1., 1., 1., 1., 1.]),
+            &abs_fft(&vec![1., 1., 1., 1., 1., 1., 1.]).unwrap().into_vec(),
             &vec![7., 0., 0., 0., 0., 0., 0.])
             );
         assert!(vector_roughly_equal(
-            &abs_fft(&vec![1., -1., 1., -1.]),
+            &abs_fft(&vec![1., -1., 1., -1.]).unwrap().into_vec(),
             &vec![0., 0., 4., 0.])
         );
     }
 
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
     #[test]
     fn test_lowpass() {
         // cutout, atten and delta_w values
@@ -249,7 +522,7 @@ mod tests {
             let ripple = 10_f32.powf(-atten/20.); // 10^(-atten/20)
 
             let coeff = Lowpass { cutout, atten, delta_w }.design();
-            let mut fft = abs_fft(&coeff);
+            let mut fft = abs_fft(&coeff).unwrap();
 
             println!("cutout: {}, atten: {}, delta_w: {}",
                      cutout.get_pi_rad(), atten, delta_w.get_pi_rad());
@@ -272,6 +545,130 @@ mod tests {
         }
     }
 
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    #[test]
+    fn test_passband_ripple_stopband_attenuation() {
+        // Same parameters as test_lowpass()
+        let test_parameters = [
+            (Freq::pi_rad(1./4.), 20., Freq::pi_rad(1./10.)),
+            (Freq::pi_rad(1./3.), 35., Freq::pi_rad(1./30.)),
+            (Freq::pi_rad(2./5.), 60., Freq::pi_rad(1./20.))
+        ];
+
+        for parameters in test_parameters.iter() {
+            let (cutout, atten, delta_w) = *parameters;
+            let coeff = Lowpass { cutout, atten, delta_w }.design();
+
+            // The Kaiser design formulas are approximate, with a fine
+            // frequency resolution the filters miss the specification by a few
+            // tenths of dB, test_lowpass() doesn't notice because it only
+            // checks coeff.len() frequencies
+            let margin = 0.5; // dB
+
+            let ripple = passband_ripple(&coeff, cutout - delta_w / 2.).unwrap();
+            assert!(ripple < 10_f32.powf(-(atten - margin) / 20.), "Ripple: {}", ripple);
+            assert!(ripple > 0.);
+
+            let attenuation = stopband_attenuation(&coeff, cutout, delta_w).unwrap();
+            assert!(attenuation > atten - margin, "Attenuation: {}", attenuation);
+        }
+
+        // A delta has a flat response and no stopband attenuation
+        let delta: Signal = vec![1.];
+        assert_relative_eq!(passband_ripple(&delta, Freq::pi_rad(0.5)).unwrap(), 0., epsilon = 1e-6);
+        assert_relative_eq!(
+            stopband_attenuation(&delta, Freq::pi_rad(0.5), Freq::pi_rad(0.1)).unwrap(),
+            0., epsilon = 1e-5);
+
+        assert!(passband_ripple(&Signal::new(), Freq::pi_rad(0.5)).is_err());
+        assert!(stopband_attenuation(
+            &Signal::new(), Freq::pi_rad(0.5), Freq::pi_rad(0.1)).is_err());
+    }
+
+    #[test]
+    fn test_length() {
+        let atten = 30.;
+        let delta_w = Freq::pi_rad(0.1);
+        let cutout = Freq::pi_rad(0.5);
+        assert_eq!(NoFilter.length(), NoFilter.design().len());
+        let lowpass = Lowpass { cutout, atten, delta_w };
+        assert_eq!(lowpass.length(), lowpass.design().len());
+        let lowpass = LowpassDcRemoval { cutout, atten, delta_w };
+        assert_eq!(lowpass.length(), lowpass.design().len());
+        let hilbert = Hilbert { atten, delta_w };
+        assert_eq!(hilbert.length(), hilbert.design().len());
+        let notch = Notch { center: cutout, width: delta_w, atten };
+        assert_eq!(notch.length(), notch.design().len());
+        let equalizer = Equalizer {
+            gains: (0..20).map(|i| (Freq::pi_rad(i as f32 / 20.), 1.)).collect(),
+            taps: 8,
+        };
+        assert_eq!(equalizer.length(), equalizer.design().len());
+    }
+
+    #[test]
+    fn test_droop_compensation() {
+        let lowpass = Lowpass {
+            cutout: Freq::pi_rad(0.5),
+            atten: 30.,
+            delta_w: Freq::pi_rad(0.2),
+        }.design();
+        let start = Freq::pi_rad(0.);
+        let end = Freq::pi_rad(0.4);
+        let equalizer = droop_compensation(&lowpass, start, end, 15).design();
+
+        // Same delay as the lowpass
+        assert_eq!(equalizer.len(), 15);
+        assert!((0..7).all(|i| equalizer[i] == equalizer[14 - i]));
+
+        // Maximum deviation from the mean gain on the passband
+        let deviation = |gain: &dyn Fn(Freq) -> f32| {
+            let gains: Vec<f32> = (0..=100)
+                .map(|i| gain(end * (i as f32 / 100.)))
+                .collect();
+            let mean = gains.iter().sum::<f32>() / gains.len() as f32;
+            gains.iter().map(|g| (g - mean).abs()).fold(0., f32::max)
+        };
+        let alone = deviation(&|w| gain_at(&lowpass, w));
+        let compensated = deviation(&|w| gain_at(&lowpass, w) * gain_at(&equalizer, w));
+        assert!(compensated < alone / 2., "Compensated: {}, alone: {}", compensated, alone);
+
+        // Not enough gains to fit
+        let equalizer = Equalizer { gains: vec![(start, 1.)], taps: 5 };
+        assert_eq!(equalizer.design(), vec![1.]);
+
+        assert_relative_eq!(gain_at(&vec![1., 1.], Freq::pi_rad(1.)), 0.);
+        assert_relative_eq!(gain_at(&vec![1., 1.], Freq::pi_rad(0.)), 2.);
+    }
+
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    #[test]
+    fn test_hilbert() {
+        let atten = 40.;
+        let delta_w = Freq::pi_rad(1./10.);
+        let coeff = Hilbert { atten, delta_w }.design();
+        let m = coeff.len();
+
+        // Odd length, antisymmetric and zero on even samples from the center
+        assert_eq!(m % 2, 1);
+        for i in 0..m {
+            assert_relative_eq!(coeff[i], -coeff[m - 1 - i]);
+            if (i as i32 - (m as i32 - 1) / 2) % 2 == 0 {
+                assert_eq!(coeff[i], 0.);
+            }
+        }
+
+        // Flat outside the transition bands
+        let tolerance = 2. * 10_f32.powf(-atten / 20.);
+        for (w, magnitude) in frequency_response(&coeff).unwrap() {
+            if w > delta_w && w < Freq::pi_rad(1.) - delta_w {
+                assert!((magnitude - 1.).abs() < tolerance,
+                    "Magnitude {} on pi*{}rad/s", magnitude, w.get_pi_rad());
+            }
+        }
+    }
+
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
     #[test]
     fn test_lowpass_dc_removal() {
         // cutout, atten and delta_w values
@@ -287,7 +684,7 @@ mod tests {
             let ripple = 10_f32.powf(-atten/20.); // 10^(-atten/20)
 
             let coeff = LowpassDcRemoval { cutout, atten, delta_w }.design();
-            let mut fft = abs_fft(&coeff);
+            let mut fft = abs_fft(&coeff).unwrap();
 
             println!("cutout: {}, atten: {}, delta_w: {}",
                      cutout.get_pi_rad(), atten, delta_w.get_pi_rad());
@@ -374,4 +771,23 @@ mod tests {
 
         assert!(original == resampled);
     }
+
+    #[test]
+    fn test_kaiser_length() {
+        // atten and delta_w values
+        let test_parameters = [
+            (20., Freq::pi_rad(0.1)),
+            (40., Freq::pi_rad(0.05)),
+            (60., Freq::pi_rad(0.01)),
+            (35., Freq::hz(12., Rate::hz(1000))),
+            (80., Freq::hz(500., Rate::hz(11025))),
+        ];
+
+        for parameters in test_parameters.iter() {
+            let (atten, delta_w) = *parameters;
+            let length = kaiser_length(atten, delta_w);
+            assert_eq!(length, kaiser(atten, delta_w).len());
+            assert_eq!(length % 2, 1);
+        }
+    }
 }
@@ -15,6 +15,9 @@
 //! I made them different types so it's harder to confuse them. There are no
 //! direct operators between Freqs and Rates because the result depends on the
 //! units you want to use.
+//!
+//! When a plain `f32` is needed instead of a `Freq`, use `hz_to_norm()` and
+//! `norm_to_hz()` instead of writing the conversion inline.
 
 use std::f32::consts::PI;
 use std::ops::Add;
@@ -27,6 +30,23 @@ use std::ops::MulAssign;
 use std::ops::DivAssign;
 
 
+/// Convert Hertz to a normalized frequency given the sample rate.
+///
+/// Normalized means fractions of pi radians per sample, the unit used by
+/// `Freq::pi_rad()` and by filter parameters like `cutout` and `delta_w`: 0 is
+/// DC and 1 is the Nyquist frequency, `sample_rate / 2`. Negative frequencies
+/// stay negative and nothing is wrapped, so `sample_rate` Hz gives 2.
+pub fn hz_to_norm(hz: f32, sample_rate: Rate) -> f32 {
+    2. * hz / sample_rate.get_hz() as f32
+}
+
+/// Convert a normalized frequency to Hertz given the sample rate.
+///
+/// Inverse of `hz_to_norm()`, 1 gives the Nyquist frequency.
+pub fn norm_to_hz(norm: f32, sample_rate: Rate) -> f32 {
+    norm * sample_rate.get_hz() as f32 / 2.
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub struct Freq {
     pi_rad: f32
@@ -67,7 +87,7 @@ impl Freq {
 
     /// Create frequency struct from Hertz and the sample rate used.
     pub fn hz(f: f32, rate: Rate) -> Self {
-        Self { pi_rad: 2.*f / rate.get_hz() as f32 }
+        Self { pi_rad: hz_to_norm(f, rate) }
     }
 
     /// Get radians per second.
@@ -82,7 +102,7 @@ impl Freq {
 
     /// Get frequency on Hertz given some sample rate.
     pub fn get_hz(self, rate: Rate) -> f32 {
-        self.pi_rad * rate.get_hz() as f32 / 2.
+        norm_to_hz(self.pi_rad, rate)
     }
 }
 
@@ -382,6 +402,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_hz_to_norm() {
+        for &hz in [8000, 11025, 20800, 48000].iter() {
+            let rate = Rate::hz(hz);
+            let nyquist = hz as f32 / 2.;
+
+            assert_eq!(hz_to_norm(0., rate), 0.);
+            assert_eq!(norm_to_hz(0., rate), 0.);
+            assert_roughly_equal(hz_to_norm(nyquist, rate), 1.);
+            assert_roughly_equal(norm_to_hz(1., rate), nyquist);
+            assert_roughly_equal(hz_to_norm(-nyquist, rate), -1.);
+            assert_roughly_equal(norm_to_hz(hz_to_norm(2400., rate), rate), 2400.);
+        }
+    }
+
     #[test]
     fn test_freq_operations() {
         let a: f32 = 12345.;
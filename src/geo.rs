@@ -0,0 +1,331 @@
+//! Geographic calculations for georeferencing images.
+//!
+//! There is no orbit propagation here, the ground track of the satellite is
+//! approximated as a great circle between the positions of the satellite at the
+//! start and at the end of the recording, traversed at constant speed. Also the
+//! scan is considered linear on the ground, in reality pixels on the edges of
+//! the image cover more distance. Good enough for drawing rough coastlines.
+
+use std::f32::consts::PI;
+
+use err;
+use noaa_apt::{FINAL_RATE, PX_PER_ROW};
+#[cfg(feature = "map")]
+use noaa_apt::column_layout;
+
+
+/// Mean radius of the Earth in km.
+const EARTH_RADIUS: f32 = 6371.;
+
+/// Width in km of the area scanned by the satellite (swath).
+const SWATH_WIDTH: f32 = 2900.;
+
+/// Width of the video on each channel.
+const VIDEO_WIDTH: usize = 909;
+
+/// Color used for drawing the map, RGB.
+#[cfg(feature = "map")]
+const MAP_COLOR: [u8; 3] = [255, 255, 0];
+
+/// Positions of the satellite during a pass.
+///
+/// Latitude and longitude in degrees, positive to the north and to the east.
+#[derive(Clone, Debug)]
+pub struct PassGeometry {
+    /// Position of the satellite when receiving the first line.
+    pub start: (f32, f32),
+
+    /// Position of the satellite when receiving the last line.
+    pub end: (f32, f32),
+}
+
+/// Direction of the satellite during a pass.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PassDirection {
+    Northbound,
+    Southbound,
+}
+
+/// Time of each line of the image.
+///
+/// Takes the time when the first line was received, as seconds since the Unix
+/// epoch (UTC). The satellite sends two lines per second.
+pub fn line_timestamps(num_lines: usize, start_time: f64) -> Vec<f64> {
+    let line_duration = f64::from(PX_PER_ROW) / f64::from(FINAL_RATE);
+
+    (0..num_lines).map(|line| start_time + line as f64 * line_duration).collect()
+}
+
+/// Angular distance in radians between two points, using the haversine
+/// formula.
+///
+/// Points are given as latitude and longitude in radians.
+fn distance(p1: (f32, f32), p2: (f32, f32)) -> f32 {
+    let (lat1, lon1) = p1;
+    let (lat2, lon2) = p2;
+
+    let a = ((lat2 - lat1) / 2.).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.).sin().powi(2);
+
+    2. * a.sqrt().min(1.).asin()
+}
+
+/// Initial bearing in radians from one point to another, clockwise from the
+/// north.
+///
+/// Points are given as latitude and longitude in radians.
+fn bearing(p1: (f32, f32), p2: (f32, f32)) -> f32 {
+    let (lat1, lon1) = p1;
+    let (lat2, lon2) = p2;
+
+    (
+        (lon2 - lon1).sin() * lat2.cos()
+    ).atan2(
+        lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * (lon2 - lon1).cos()
+    )
+}
+
+impl PassGeometry {
+
+    /// Whether the satellite moves to the north or to the south, compares the
+    /// latitude of the first and last lines.
+    pub fn direction(&self) -> PassDirection {
+        if self.end.0 > self.start.0 {
+            PassDirection::Northbound
+        } else {
+            PassDirection::Southbound
+        }
+    }
+
+    /// Project a point to the image of a channel.
+    ///
+    /// Takes latitude and longitude in degrees and the height of the image.
+    /// Returns `(column, line)` where column is measured from the start of the
+    /// video on the channel (from 0 to 909), or `None` if the point was not
+    /// scanned.
+    ///
+    /// The line is proportional to the distance traveled along the ground
+    /// track, the column is proportional to the distance from the ground
+    /// track. The video is scanned from the right side of the satellite to the
+    /// left side, that's why images of northbound passes are upside down.
+    pub fn project(&self, lat: f32, lon: f32, height: usize) -> Option<(f32, f32)> {
+
+        let to_rad = |(lat, lon): (f32, f32)| (lat * PI / 180., lon * PI / 180.);
+        let start = to_rad(self.start);
+        let end = to_rad(self.end);
+        let point = to_rad((lat, lon));
+
+        // Angular distances and bearings from the start
+        let track_length = distance(start, end);
+        let d13 = distance(start, point);
+        let angle = bearing(start, point) - bearing(start, end);
+
+        // Cross track distance, positive to the right of the satellite
+        let cross_track = (d13.sin() * angle.sin()).asin();
+
+        // Along track distance, from the right spherical triangle formed by
+        // the start, the point and the closest point on the track
+        let along_track = (d13.sin() * angle.cos()).atan2(d13.cos());
+
+        if track_length == 0. || along_track < 0. || along_track > track_length {
+            return None;
+        }
+
+        let half_width = VIDEO_WIDTH as f32 / 2.;
+        let column = half_width
+            - cross_track * EARTH_RADIUS / (SWATH_WIDTH / 2.) * half_width;
+
+        if column < 0. || column >= VIDEO_WIDTH as f32 {
+            return None;
+        }
+
+        Some((column, along_track / track_length * (height - 1) as f32))
+    }
+}
+
+/// Draw coastlines over an image.
+///
+/// The image should be RGB, three values per pixel, `width` pixels per line
+/// and `height` lines. The lines are drawn on the video of both channels.
+///
+/// Each coastline is a list of points given as latitude and longitude in
+/// degrees, consecutive points are joined by straight lines.
+#[cfg(feature = "map")]
+pub fn overlay_map(
+    image: &mut [u8],
+    width: usize,
+    height: usize,
+    geo: &PassGeometry,
+    coastlines: &[Vec<(f32, f32)>],
+) -> err::Result<()> {
+
+    if image.len() != width * height * 3 {
+        return Err(err::Error::Internal(format!(
+            "Image size does not match dimensions {}x{} for RGB", width, height)));
+    }
+
+    let layout = column_layout(width);
+
+    // In case the image was scaled
+    let scale = layout.video_a.len() as f32 / VIDEO_WIDTH as f32;
+
+    let mut paint = |column: f32, line: f32| {
+        for video in [&layout.video_a, &layout.video_b].iter() {
+            let x = video.start as f32 + column * scale;
+            let (x, y) = (x.round() as usize, line.round() as usize);
+            if x < width && y < height {
+                let i = (y * width + x) * 3;
+                image[i..i + 3].copy_from_slice(&MAP_COLOR);
+            }
+        }
+    };
+
+    for coastline in coastlines {
+        let points: Vec<Option<(f32, f32)>> = coastline.iter()
+            .map(|(lat, lon)| geo.project(*lat, *lon, height)).collect();
+
+        for pair in points.windows(2) {
+            if let (Some((x1, y1)), Some((x2, y2))) = (pair[0], pair[1]) {
+                // Walk the segment one pixel at a time
+                let steps = (x2 - x1).abs().max((y2 - y1).abs()).ceil().max(1.);
+                for step in 0..=steps as usize {
+                    let t = step as f32 / steps;
+                    paint(x1 + (x2 - x1) * t, y1 + (y2 - y1) * t);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Draw coastlines over an image.
+///
+/// Compiled without map support, so fails.
+#[cfg(not(feature = "map"))]
+pub fn overlay_map(
+    _image: &mut [u8],
+    _width: usize,
+    _height: usize,
+    _geo: &PassGeometry,
+    _coastlines: &[Vec<(f32, f32)>],
+) -> err::Result<()> {
+    Err(err::Error::FeatureNotAvailable(vec!["map".to_string()]))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Southbound pass over Argentina, 20 degrees of latitude
+    fn southbound() -> PassGeometry {
+        PassGeometry { start: (-25., -62.), end: (-45., -62.) }
+    }
+
+    #[test]
+    fn test_line_timestamps() {
+        let start = 1_546_300_800.25; // 2019-01-01 00:00:00.25 UTC
+        let timestamps = line_timestamps(1000, start);
+
+        assert_eq!(timestamps.len(), 1000);
+        assert_eq!(timestamps[0], start);
+        for pair in timestamps.windows(2) {
+            assert_eq!(pair[1] - pair[0], 0.5);
+        }
+        assert_eq!(timestamps[999], start + 499.5);
+
+        assert!(line_timestamps(0, start).is_empty());
+    }
+
+    #[test]
+    fn test_direction() {
+        assert_eq!(southbound().direction(), PassDirection::Southbound);
+        let geo = PassGeometry { start: (-45., -62.), end: (-25., -62.) };
+        assert_eq!(geo.direction(), PassDirection::Northbound);
+    }
+
+    #[test]
+    fn test_project_track() {
+        let geo = southbound();
+        let center = VIDEO_WIDTH as f32 / 2.;
+
+        // Points on the ground track are on the center column
+        let (x, y) = geo.project(-25., -62., 1001).unwrap();
+        assert_relative_eq!(x, center, epsilon = 0.1);
+        assert_relative_eq!(y, 0., epsilon = 0.1);
+
+        let (x, y) = geo.project(-35., -62., 1001).unwrap();
+        assert_relative_eq!(x, center, epsilon = 0.1);
+        assert_relative_eq!(y, 500., epsilon = 0.5);
+
+        let (x, y) = geo.project(-45., -62., 1001).unwrap();
+        assert_relative_eq!(x, center, epsilon = 0.1);
+        assert_relative_eq!(y, 1000., epsilon = 0.5);
+
+        // Before and after the pass
+        assert!(geo.project(-24., -62., 1001).is_none());
+        assert!(geo.project(-46., -62., 1001).is_none());
+    }
+
+    #[test]
+    fn test_project_cross_track() {
+        let geo = southbound();
+        let center = VIDEO_WIDTH as f32 / 2.;
+
+        // 5 degrees of longitude at -35 of latitude are about 455km. Going
+        // south, west is on the right of the satellite so it should be on the
+        // left of the image
+        let expected = 455. / (SWATH_WIDTH / 2.) * center;
+
+        let (x, y) = geo.project(-35., -67., 1001).unwrap();
+        assert_relative_eq!(center - x, expected, max_relative = 0.02);
+        assert!((y - 500.).abs() < 10.);
+
+        let (x, _y) = geo.project(-35., -57., 1001).unwrap();
+        assert_relative_eq!(x - center, expected, max_relative = 0.02);
+
+        // Going north everything is mirrored
+        let geo = PassGeometry { start: (-45., -62.), end: (-25., -62.) };
+        let (x, _y) = geo.project(-35., -67., 1001).unwrap();
+        assert_relative_eq!(x - center, expected, max_relative = 0.02);
+
+        // Outside the swath
+        assert!(geo.project(-35., -90., 1001).is_none());
+    }
+
+    #[cfg(feature = "map")]
+    #[test]
+    fn test_overlay_map() {
+        let (width, height) = (PX_PER_ROW as usize, 100);
+        let mut image: Vec<u8> = vec![0; width * height * 3];
+
+        // Coastline along the ground track
+        let coastline = vec![(-25., -62.), (-45., -62.)];
+        overlay_map(&mut image, width, height, &southbound(), &[coastline]).unwrap();
+
+        let center = column_layout(width).video_a.start + VIDEO_WIDTH / 2;
+        for line in 0..height {
+            for x in [center, center + width / 2].iter() {
+                let i = (line * width + x) * 3;
+                let painted = (-1..=1).any(|d|
+                    image[(i as i32 + d * 3) as usize..][..3] == MAP_COLOR);
+                assert!(painted);
+            }
+        }
+
+        // Wrong dimensions
+        assert!(overlay_map(&mut image, width, height + 1, &southbound(), &[]).is_err());
+    }
+
+    #[cfg(not(feature = "map"))]
+    #[test]
+    fn test_overlay_map_not_available() {
+        let mut image: Vec<u8> = vec![0; 30];
+        match overlay_map(&mut image, 5, 2, &southbound(), &[]) {
+            Err(err::Error::FeatureNotAvailable(features)) =>
+                assert_eq!(features, vec!["map".to_string()]),
+            _ => panic!("Expected FeatureNotAvailable"),
+        }
+    }
+}
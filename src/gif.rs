@@ -0,0 +1,141 @@
+//! Animated GIF output.
+//!
+//! Useful for showing how the image builds up line by line while decoding.
+//! With the `gif` feature I write the files using the `gif` crate, otherwise
+//! `write_gif()` fails with `Error::FeatureNotAvailable`. Only grayscale
+//! images, every frame uses the same 256 gray levels.
+
+#[cfg(feature = "gif")]
+use gif_codec;
+
+use err;
+
+
+/// Write grayscale frames as an animated GIF that loops forever.
+///
+/// Each frame has `width * height` pixels and is shown for `delay` hundredths
+/// of a second.
+#[cfg(feature = "gif")]
+pub fn write_gif(
+    filename: &str,
+    frames: &[Vec<u8>],
+    width: usize,
+    height: usize,
+    delay: u16,
+) -> err::Result<()> {
+    if width > usize::from(u16::MAX) || height > usize::from(u16::MAX) {
+        return Err(err::Error::Internal(format!(
+            "Image of {}x{} too big for GIF", width, height)));
+    }
+    if frames.is_empty() {
+        return Err(err::Error::Internal("No frames for GIF".to_string()));
+    }
+    if let Some(frame) = frames.iter().find(|f| f.len() != width * height) {
+        return Err(err::Error::Internal(format!(
+            "Expected frame of {}x{} pixels, got {}", width, height, frame.len())));
+    }
+
+    let (width, height) = (width as u16, height as u16);
+
+    // Global color table with the 256 gray levels, so each pixel value is
+    // also its index on the table
+    let palette: Vec<u8> = (0..=255).flat_map(|gray| vec![gray, gray, gray]).collect();
+
+    let file = std::fs::File::create(filename)?;
+    let mut encoder = gif_codec::Encoder::new(file, width, height, &palette)?;
+    encoder.set_repeat(gif_codec::Repeat::Infinite)?;
+
+    for pixels in frames {
+        let mut frame = gif_codec::Frame::from_indexed_pixels(
+            width, height, &pixels[..], None);
+        frame.delay = delay;
+        encoder.write_frame(&frame)?;
+    }
+
+    Ok(())
+}
+
+/// Write grayscale frames as an animated GIF that loops forever.
+///
+/// Compiled without GIF support, so fails.
+#[cfg(not(feature = "gif"))]
+pub fn write_gif(
+    _filename: &str,
+    _frames: &[Vec<u8>],
+    _width: usize,
+    _height: usize,
+    _delay: u16,
+) -> err::Result<()> {
+    Err(err::Error::FeatureNotAvailable(vec!["gif".to_string()]))
+}
+
+/// Snapshots of an image while it's being decoded.
+///
+/// Returns `count` frames of the whole size, on each one a few more lines are
+/// visible and the rest are black. The last frame is the complete image.
+pub fn growing_frames(image: &[u8], width: usize, count: usize) -> Vec<Vec<u8>> {
+    let height = image.len().checked_div(width).unwrap_or(0);
+
+    (1..=count).map(|i| {
+        let visible = height * i / count * width;
+        let mut frame = image[..visible].to_vec();
+        frame.resize(height * width, 0);
+        frame
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[cfg(feature = "gif")]
+    use test_util::temp_path;
+
+    #[test]
+    fn test_growing_frames() {
+        let (width, height) = (40, 30);
+        let image: Vec<u8> = (0..width * height).map(|i| (i * 13 % 251) as u8).collect();
+        let frames = growing_frames(&image, width, 4);
+
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[3], image);
+        assert!(frames[0][..7 * width] == image[..7 * width]);
+        assert!(frames[0][7 * width..].iter().all(|x| *x == 0));
+    }
+
+    #[cfg(feature = "gif")]
+    #[test]
+    fn test_write_gif() {
+        let filename = &temp_path("test.gif");
+
+        let (width, height) = (40, 30);
+        let image: Vec<u8> = (0..width * height).map(|i| (i * 13 % 251) as u8).collect();
+        let frames = growing_frames(&image, width, 4);
+        write_gif(filename, &frames, width, height, 10).unwrap();
+
+        // Read it back, the pixel values are the indices on the gray palette
+        let mut options = gif_codec::DecodeOptions::new();
+        options.set_color_output(gif_codec::ColorOutput::Indexed);
+        let mut decoder = options.read_info(std::fs::File::open(filename).unwrap()).unwrap();
+        assert_eq!((decoder.width() as usize, decoder.height() as usize), (width, height));
+
+        let mut decoded = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!(frame.delay, 10);
+            decoded.push(frame.buffer.to_vec());
+        }
+        assert_eq!(decoded, frames);
+
+        assert!(write_gif(filename, &frames, width + 1, height, 10).is_err());
+        assert!(write_gif(filename, &[], width, height, 10).is_err());
+
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[cfg(not(feature = "gif"))]
+    #[test]
+    fn test_write_gif_not_available() {
+        assert!(write_gif("unused.gif", &[vec![0]], 1, 1, 10).is_err());
+    }
+}
@@ -29,6 +29,7 @@ use gtk::prelude::*;
 use gtk::Builder;
 use chrono;
 use chrono::prelude::*;
+use png;
 
 use err;
 use noaa_apt::{self, Contrast};
@@ -36,6 +37,7 @@ use context::Context;
 use misc;
 use config;
 use dsp::Rate;
+use telemetry;
 
 
 /// Defined by Cargo.toml
@@ -655,15 +657,40 @@ fn run_noaa_apt(settings: config::GuiSettings, mode: Mode) -> err::Result<()> {
                     let settings = config::DecodeSettings {
                         input_filename,
                         output_filename,
+                        raw_output_filename: None,
+                        histogram_filename: None,
+                        csv_filename: None,
+                        thumbnail_size: None,
+                        output_size: None,
+                        compression: png::Compression::Fast,
+                        line_snr: false,
+                        preview: false,
+                        output_channels: noaa_apt::OutputChannels::Both,
                         sync,
-                        contrast_adjustment,
+                        sync_threshold: f32::NEG_INFINITY,
+                        correct_rate: false,
+                        demod_method: noaa_apt::DemodMethod::TwoSample,
+                        palette: None,
+                        trim: false,
+                        line_range: None,
+                        deterministic: false,
+                        high_precision: false,
+                        compand: None,
+                        deemphasis: None,
+                        channel_a_contrast: contrast_adjustment.clone(),
+                        channel_b_contrast: contrast_adjustment,
+                        telemetry_lines: telemetry::DEFAULT_TELEMETRY_LINES,
+                        fix_swapped_channels: false,
                         export_wav: wav_steps,
                         export_resample_filtered: resample_step,
                         work_rate: settings.work_rate,
                         resample_atten: settings.resample_atten,
                         resample_delta_freq: settings.resample_delta_freq,
                         resample_cutout: settings.resample_cutout,
+                        compensate_droop: false,
                         demodulation_atten: settings.demodulation_atten,
+                        demodulation_cutout: None,
+                        demodulation_delta_freq: None,
                     };
 
                     callback(noaa_apt::decode(
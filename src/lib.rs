@@ -0,0 +1,42 @@
+//! NOAA APT image decoder, as a library.
+//!
+//! The `noaa-apt` executable is built on top of this, it adds the commandline
+//! interface and the GUI. The library can be compiled to WebAssembly with the
+//! `wasm` feature, in that case use `wasm::decode_bytes()` because there is no
+//! filesystem.
+
+extern crate num;
+extern crate hound;
+extern crate png;
+#[macro_use] extern crate log;
+extern crate argparse;
+#[cfg(not(target_arch = "wasm32"))] extern crate reqwest;
+#[cfg(not(target_arch = "wasm32"))] extern crate directories;
+extern crate toml;
+extern crate filetime;
+extern crate serde;
+#[cfg_attr(test, macro_use)] extern crate approx;
+#[cfg(feature = "gzip")] extern crate flate2;
+#[cfg(feature = "fft")] extern crate rustfft;
+// Renamed because the gif module has the same name
+#[cfg(feature = "gif")] extern crate gif as gif_codec;
+
+pub mod noaa_apt;
+pub mod dsp;
+pub mod frequency;
+pub mod wav;
+pub mod raw;
+pub mod misc;
+pub mod err;
+pub mod filters;
+pub mod fft;
+pub mod gif;
+pub mod context;
+pub mod telemetry;
+pub mod config;
+pub mod geo;
+pub mod processing;
+pub mod sink;
+pub mod stream;
+#[cfg(any(test, feature = "wasm"))] pub mod wasm;
+#[cfg(test)] mod test_util;
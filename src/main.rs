@@ -2,45 +2,37 @@
 
 #![cfg_attr(not(feature = "windows_console"), windows_subsystem = "windows")]
 
-extern crate num;
-extern crate hound;
-extern crate rustfft;
-extern crate png;
-#[macro_use] extern crate log;
-extern crate simple_logger;
-extern crate argparse;
-extern crate reqwest;
-extern crate directories;
-extern crate toml;
-extern crate filetime;
+// Renamed because the library has a module with the same name
+extern crate noaa_apt as noaa_apt_lib;
+#[cfg_attr(not(target_arch = "wasm32"), macro_use)] extern crate log;
+#[cfg(not(target_arch = "wasm32"))] extern crate simple_logger;
 extern crate chrono;
-extern crate serde;
-#[cfg_attr(test, macro_use)] extern crate approx;
+#[cfg(feature = "gui")] extern crate png;
 #[cfg(feature = "gui")] extern crate gtk;
 #[cfg(feature = "gui")] extern crate gdk;
 #[cfg(feature = "gui")] extern crate gio;
 #[cfg(feature = "gui")] extern crate glib;
 
-mod noaa_apt;
-mod dsp;
-mod frequency;
-mod wav;
-mod misc;
-mod err;
-mod filters;
-mod context;
-mod telemetry;
-mod config;
 #[cfg(feature = "gui")] mod gui;
 
+// Imported on the root so the modules of this executable can use them the same
+// way as the modules of the library
+#[cfg(not(target_arch = "wasm32"))]
+use noaa_apt_lib::{config, context, dsp, err, misc, noaa_apt};
+#[cfg(feature = "gui")]
+use noaa_apt_lib::telemetry;
+#[cfg(not(target_arch = "wasm32"))]
 use dsp::Rate;
+#[cfg(not(target_arch = "wasm32"))]
 use context::Context;
 
 
 /// Defined by Cargo.toml
+#[cfg(not(target_arch = "wasm32"))]
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// Application entry point
+#[cfg(not(target_arch = "wasm32"))]
 fn main() -> err::Result<()> {
 
     let (check_updates, verbosity, mode) = config::get_config();
@@ -117,3 +109,8 @@ fn main() -> err::Result<()> {
 
     Ok(())
 }
+
+/// There is no commandline on WebAssembly, use `wasm::decode_bytes()` from the
+/// library instead.
+#[cfg(target_arch = "wasm32")]
+fn main() {}
@@ -2,14 +2,14 @@
 
 use std::fs;
 
-use reqwest;
+#[cfg(not(target_arch = "wasm32"))] use reqwest;
 use filetime;
 
 use dsp::{self, Signal};
 use err;
 
 
-/// Lookup table for numbers used in `bessel_i0()`
+/// Lookup table for numbers used in `bessel_i0_series()`
 ///
 /// 1 / (n! * 2^n)^2
 #[allow(clippy::excessive_precision, clippy::unreadable_literal)]
@@ -36,11 +36,28 @@ const BESSEL_TABLE: [f32; 20] = [
     2.458504017633177e-46
 ];
 
+/// Below this argument `bessel_i0()` uses the series, the terms left out are
+/// smaller than the precision of a `f32`.
+const BESSEL_SERIES_LIMIT: f32 = 2.;
+
 /// First Kind modified Bessel function of order zero.
 ///
+/// Uses `bessel_i0_series()` where it converges and `bessel_i0_fast()` for
+/// bigger arguments, see `BESSEL_SERIES_LIMIT`.
+pub fn bessel_i0(x: f32) -> f32 {
+    if x.abs() < BESSEL_SERIES_LIMIT {
+        bessel_i0_series(x)
+    } else {
+        bessel_i0_fast(x)
+    }
+}
+
+/// First Kind modified Bessel function of order zero, summing the power series.
+///
 /// From this
 /// [post](https://dsp.stackexchange.com/questions/37714/kaiser-window-approximation/37715#37715).
-pub fn bessel_i0(x: f32) -> f32 {
+/// Only the first terms are summed, so it loses precision for big arguments.
+pub fn bessel_i0_series(x: f32) -> f32 {
     let mut result: f32 = 0.;
     let limit: usize = 8;
 
@@ -52,13 +69,38 @@ pub fn bessel_i0(x: f32) -> f32 {
     result + 1.
 }
 
+/// Fast approximation of the first kind modified Bessel function of order
+/// zero.
+///
+/// Uses the polynomial approximations from Abramowitz and Stegun (9.8.1 and
+/// 9.8.2), the relative error is smaller than 2e-7. Faster than
+/// `bessel_i0_series()` and still precise for big arguments.
+#[allow(clippy::excessive_precision, clippy::unreadable_literal)]
+pub fn bessel_i0_fast(x: f32) -> f32 {
+    let ax = x.abs();
+
+    if ax < 3.75 {
+        let t = (ax / 3.75).powi(2);
+        1. + t * (3.5156229 + t * (3.0899424 + t * (1.2067492
+            + t * (0.2659732 + t * (0.0360768 + t * 0.0045813)))))
+    } else {
+        let t = 3.75 / ax;
+        (ax.exp() / ax.sqrt()) * (0.39894228 + t * (0.01328592
+            + t * (0.00225319 + t * (-0.00157565 + t * (0.00916281
+            + t * (-0.02057706 + t * (0.02635537 + t * (-0.01647633
+            + t * 0.00392377))))))))
+    }
+}
+
 /// Check if there is an update to this program.
 ///
 /// Takes a `String` with the current version being used.
 ///
 /// Returns a tuple of a `bool` idicating if there are new updates and a
 /// `String` with the latest version. Wrapped in `Option`, returns `None` if
-/// there was a problem retrieving new versions and logs the error.
+/// there was a problem retrieving new versions and logs the error. Not
+/// available on WebAssembly.
+#[cfg(not(target_arch = "wasm32"))]
 pub fn check_updates(current: &str) -> Option<(bool, String)> {
     let addr = format!("https://noaa-apt.mbernardi.com.ar/version_check?{}", current);
 
@@ -185,6 +227,145 @@ pub fn percent(signal: &Signal, percent: f32) -> err::Result<(f32, f32)> {
 
 }
 
+/// Get the narrowest contrast bounds that clip at most `max_clip_fraction` of
+/// the samples.
+///
+/// `percent()` clips the same amount on both ends. Here I try every way of
+/// splitting the clipped samples between the dark and the bright end and keep
+/// the one with the smallest range, so images with a skewed histogram (e.g.
+/// mostly dark sea with a few bright clouds) get more contrast. Returns
+/// `(low, high)`, only samples below `low` or above `high` get clipped.
+pub fn auto_contrast_bounds(image: &[f32], max_clip_fraction: f32) -> err::Result<(f32, f32)> {
+
+    if !(0. ..=1.).contains(&max_clip_fraction) {
+        return Err(err::Error::Internal(
+            "Clip fraction given should be between 0 and 1".to_string()));
+    }
+    if image.is_empty() {
+        return Err(err::Error::Internal(
+            "Can't get contrast bounds of empty image".to_string()));
+    }
+
+    let mut sorted = image.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let last = sorted.len() - 1;
+
+    // Samples I can clip, leaving at least one
+    let clipped = ((max_clip_fraction * sorted.len() as f32).floor() as usize).min(last);
+
+    // Clip `below` samples on the dark end and the rest on the bright end
+    let bounds = |below: usize| (sorted[below], sorted[last - (clipped - below)]);
+    let below = (0..=clipped).min_by(|a, b| {
+        let (range_a, range_b) = (bounds(*a).1 - bounds(*a).0, bounds(*b).1 - bounds(*b).0);
+        range_a.partial_cmp(&range_b).unwrap_or(std::cmp::Ordering::Equal)
+    }).expect("Range not empty");
+
+    Ok(bounds(below))
+}
+
+/// Fit polynomial using least squares.
+///
+/// Returns the coefficients starting from the constant term. Solves the normal
+/// equations, so it's only meant for low degrees. Fails if there are not
+/// enough points for the degree asked.
+pub fn polyfit(x: &[f64], y: &[f64], degree: usize) -> err::Result<Vec<f64>> {
+    let n = degree + 1;
+
+    if x.len() != y.len() || x.len() < n {
+        return Err(err::Error::Internal(format!(
+            "Can't fit polynomial of degree {} to {} points", degree, x.len())));
+    }
+
+    let basis: Vec<Vec<f64>> = x.iter()
+        .map(|xi| (0..n).map(|k| xi.powi(k as i32)).collect())
+        .collect();
+
+    least_squares(&basis, y)
+}
+
+/// Linear least squares.
+///
+/// Each row of `basis` has the value of every basis function on one point, and
+/// `y` the value to fit on each point. Returns the weight of each basis
+/// function. Solves the normal equations, so it's only meant for a few basis
+/// functions.
+pub fn least_squares(basis: &[Vec<f64>], y: &[f64]) -> err::Result<Vec<f64>> {
+    let n = basis.first().map_or(0, |row| row.len());
+
+    if basis.len() != y.len() || basis.len() < n || n == 0 {
+        return Err(err::Error::Internal(format!(
+            "Can't fit {} basis functions to {} points", n, y.len())));
+    }
+
+    // Augmented matrix of the normal equations
+    let mut matrix: Vec<Vec<f64>> = vec![vec![0.; n + 1]; n];
+    for (point, yi) in basis.iter().zip(y.iter()) {
+        for (row, line) in matrix.iter_mut().enumerate() {
+            for (col, value) in line.iter_mut().take(n).enumerate() {
+                *value += point[row] * point[col];
+            }
+            line[n] += yi * point[row];
+        }
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|a, b| matrix[*a][col].abs().partial_cmp(&matrix[*b][col].abs())
+                .unwrap_or(std::cmp::Ordering::Equal))
+            .unwrap();
+        if matrix[pivot][col].abs() < 1e-12 {
+            return Err(err::Error::Internal(
+                "Can't fit, points are degenerate".to_string()));
+        }
+        matrix.swap(col, pivot);
+
+        let (done, remaining) = matrix.split_at_mut(col + 1);
+        let pivot_row = &done[col];
+        for row in remaining {
+            let factor = row[col] / pivot_row[col];
+            for (value, pivot_value) in row.iter_mut().zip(pivot_row.iter()).skip(col) {
+                *value -= factor * pivot_value;
+            }
+        }
+    }
+
+    // Back substitution
+    let mut coeff = vec![0.; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| matrix[row][k] * coeff[k]).sum();
+        coeff[row] = (matrix[row][n] - sum) / matrix[row][row];
+    }
+
+    Ok(coeff)
+}
+
+/// Evaluate polynomial with coefficients starting from the constant term.
+pub fn polyval(coeff: &[f64], x: f64) -> f64 {
+    coeff.iter().rev().fold(0., |accum, c| accum * x + c)
+}
+
+/// Write signal to a CSV file.
+///
+/// One sample per line with its index, after a `sample,value` header. Meant for
+/// plotting on other programs, so the values are written with enough digits
+/// to get the same `f32` back.
+pub fn write_csv(filename: &str, signal: &Signal) -> err::Result<()> {
+    use std::io::Write;
+
+    debug!("Writing CSV to '{}'", filename);
+
+    let mut buffer = std::io::BufWriter::new(fs::File::create(filename)?);
+
+    writeln!(buffer, "sample,value")?;
+    for (i, x) in signal.iter().enumerate() {
+        writeln!(buffer, "{},{:e}", i, x)?;
+    }
+    buffer.flush()?;
+
+    Ok(())
+}
+
 /// Read timestamp from file.
 ///
 /// Returns the timestamp as the amount of seconds from the Unix epoch
@@ -224,15 +405,18 @@ pub fn write_timestamp(timestamp: i64, filename: &str) -> err::Result<()> {
 mod tests {
 
     use super::*;
+    use test_util::temp_path;
 
     #[test]
+    #[allow(clippy::excessive_precision)]
     pub fn test_bessel_i0() {
-        let tolerance = 0.001; // 0.1%
+        let tolerance = 1e-6;
 
         // Compare values with results from GNU Octave
         assert_relative_eq!(bessel_i0(0.),  1.00000000000000, max_relative = tolerance);
         assert_relative_eq!(bessel_i0(0.5), 1.06348337074132, max_relative = tolerance);
         assert_relative_eq!(bessel_i0(1.),  1.26606587775201, max_relative = tolerance);
+        assert_relative_eq!(bessel_i0(-1.), 1.26606587775201, max_relative = tolerance);
         assert_relative_eq!(bessel_i0(1.5), 1.64672318977289, max_relative = tolerance);
         assert_relative_eq!(bessel_i0(2.),  2.27958530233607, max_relative = tolerance);
         assert_relative_eq!(bessel_i0(2.5), 3.28983914405012, max_relative = tolerance);
@@ -247,6 +431,129 @@ mod tests {
         assert_relative_eq!(bessel_i0(7.),  168.593908510290, max_relative = tolerance);
     }
 
+    #[test]
+    fn test_bessel_i0_converged() {
+        // Sum the series on f64 until the terms don't matter anymore
+        let reference = |x: f32| -> f32 {
+            let x = f64::from(x);
+            let mut term = 1.;
+            let mut result = 0.;
+            let mut k = 0.;
+            while term > result * 1e-17 {
+                result += term;
+                k += 1.;
+                term *= (x / 2.).powi(2) / (k * k);
+            }
+            result as f32
+        };
+
+        for i in -100..=100 {
+            let x = i as f32 / 10.;
+            if x.abs() < BESSEL_SERIES_LIMIT {
+                assert_relative_eq!(bessel_i0_series(x), reference(x), max_relative = 1e-7);
+            }
+            assert_relative_eq!(bessel_i0_fast(x), reference(x), max_relative = 2e-7);
+            assert_relative_eq!(bessel_i0(x), reference(x), max_relative = 2e-7);
+        }
+
+        // The series needs more terms for big arguments
+        assert!(reference(7.) - bessel_i0_series(7.) > reference(7.) * 1e-5);
+    }
+
+    #[test]
+    fn test_auto_contrast_bounds() {
+        // Mostly dark with a long bright tail
+        let image: Signal = (0..10000).map(|i| {
+            let x = i as f32 / 10000.;
+            x.powi(4) * 255.
+        }).collect();
+
+        for &fraction in [0., 0.01, 0.02, 0.1].iter() {
+            let (low, high) = auto_contrast_bounds(&image, fraction).unwrap();
+            assert!(low <= high);
+
+            let clipped = image.iter().filter(|x| **x < low || **x > high).count();
+            assert!(clipped as f32 <= fraction * image.len() as f32,
+                "Clipped {} with fraction {}", clipped, fraction);
+
+            // Never wider than clipping the same amount on both ends
+            if fraction > 0. {
+                let (p_low, p_high) = percent(&image, 1. - fraction).unwrap();
+                assert!(high - low <= p_high - p_low);
+            }
+        }
+
+        // Nothing clipped
+        assert_eq!(auto_contrast_bounds(&image, 0.).unwrap(), (0., image[9999]));
+        // Everything clipped but one sample
+        let (low, high) = auto_contrast_bounds(&[3., 1., 2.], 1.).unwrap();
+        assert_eq!(low, high);
+
+        assert!(auto_contrast_bounds(&[], 0.02).is_err());
+        assert!(auto_contrast_bounds(&image, 1.5).is_err());
+    }
+
+    #[test]
+    fn test_percent_empty() {
+        assert!(percent(&Signal::new(), 0.98).is_err());
+
+        // Constant signal, every sample is on the first bucket
+        assert_eq!(percent(&vec![2.; 10], 0.98).unwrap(), (2., 2.));
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let filename = &temp_path("signal.csv");
+
+        let signal: Signal = (0..500).map(|i| (i as f32 * 0.37).sin() * 1e3).collect();
+        write_csv(filename, &signal).unwrap();
+
+        let text = fs::read_to_string(filename).unwrap();
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("sample,value"));
+
+        let parsed: Vec<(usize, f32)> = lines.map(|line| {
+            let mut fields = line.split(',');
+            let index = fields.next().unwrap().parse().unwrap();
+            let value = fields.next().unwrap().parse().unwrap();
+            assert_eq!(fields.next(), None);
+            (index, value)
+        }).collect();
+
+        assert_eq!(parsed.len(), signal.len());
+        for (i, ((index, value), x)) in parsed.iter().zip(signal.iter()).enumerate() {
+            assert_eq!(*index, i);
+            assert_relative_eq!(*value, *x, max_relative = 1e-6);
+        }
+
+        assert!(write_csv("/nonexistent/noaa_apt.csv", &signal).is_err());
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_polyfit() {
+        let x: Vec<f64> = (0..50).map(f64::from).collect();
+
+        let y: Vec<f64> = x.iter().map(|x| 3. - 0.5 * x + 0.02 * x * x).collect();
+        let coeff = polyfit(&x, &y, 2).unwrap();
+        assert_eq!(coeff.len(), 3);
+        assert_relative_eq!(coeff[0], 3., max_relative = 1e-9);
+        assert_relative_eq!(coeff[1], -0.5, max_relative = 1e-9);
+        assert_relative_eq!(coeff[2], 0.02, max_relative = 1e-9);
+        assert_relative_eq!(polyval(&coeff, 10.), 0., epsilon = 1e-9);
+
+        // Best straight line for a parabola symmetric around zero
+        let x = [-1., 0., 1.];
+        let coeff = polyfit(&x, &[1., 0., 1.], 1).unwrap();
+        assert_relative_eq!(coeff[0], 2. / 3., epsilon = 1e-12);
+        assert_relative_eq!(coeff[1], 0., epsilon = 1e-12);
+
+        assert!(polyfit(&x, &[1., 0., 1.], 3).is_err());
+        assert!(polyfit(&[1., 1., 1.], &[1., 0., 1.], 1).is_err());
+        assert_eq!(polyval(&[], 5.), 0.);
+    }
+
     #[test]
     fn test_percent() {
 
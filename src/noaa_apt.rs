@@ -1,5 +1,8 @@
 //! High-level functions for decoding APT.
 
+use std::ops::Range;
+use std::collections::VecDeque;
+
 use hound;
 use png;
 
@@ -7,10 +10,12 @@ use wav;
 use dsp::{self, Signal, Rate, Freq};
 use err;
 use filters;
-use context::{Context, Step};
+use context::{Context, Stage, Step};
 use telemetry;
 use config;
 use misc;
+use processing;
+use sink;
 
 
 /// Final signal sample rate.
@@ -21,9 +26,48 @@ pub const FINAL_RATE: u32 = 4160;
 /// Pixels per image row.
 pub const PX_PER_ROW: u32 = 2080;
 
+/// Height in pixels of the histogram chart written when decoding.
+const HISTOGRAM_HEIGHT: usize = 128;
+
 /// AM carrier frequency in Hz.
 pub const CARRIER_FREQ: u32 = 2400;
 
+/// Lowest sample rate that can hold the APT signal.
+///
+/// The AM signal goes up to `CARRIER_FREQ + FINAL_RATE / 2` Hz, so by the
+/// Nyquist criterion the sample rate must be at least twice that.
+pub const MIN_PROCESSING_RATE: u32 = 2 * (CARRIER_FREQ + FINAL_RATE / 2);
+
+/// Check if a recording has a sample rate high enough for decoding.
+///
+/// Below `MIN_PROCESSING_RATE` part of the AM signal is lost and the image is
+/// garbage, so I fail early with an explanation.
+pub fn check_input_rate(rate: Rate) -> err::Result<()> {
+    if rate.get_hz() < MIN_PROCESSING_RATE {
+        return Err(err::Error::WavOpen(format!(
+            "Sample rate {}Hz too low, need at least {}Hz for the {}Hz carrier \
+            and its sidebands", rate.get_hz(), MIN_PROCESSING_RATE, CARRIER_FREQ)));
+    }
+    Ok(())
+}
+
+/// Choose the processing sample rate that is cheapest to resample to.
+///
+/// When resampling, the filter is designed for the interpolated rate, the
+/// least common multiple of both rates. For a given transition band in Hz the
+/// filter length grows with it, so rates with a small common divisor like
+/// 44099Hz and 11025Hz need huge filters. I choose the candidate with the
+/// smallest least common multiple, on ties the lowest rate. Candidates below
+/// `MIN_PROCESSING_RATE` are ignored, returns `None` if no candidate is left.
+pub fn best_processing_rate(input_rate: u32, candidates: &[u32]) -> Option<u32> {
+    use num::Integer;
+
+    candidates.iter()
+        .filter(|rate| **rate >= MIN_PROCESSING_RATE)
+        .min_by_key(|rate| (u64::from(input_rate).lcm(&u64::from(**rate)), **rate))
+        .cloned()
+}
+
 /// Load and resample WAV file.
 ///
 /// Copy the modification time timestamp too.
@@ -33,15 +77,19 @@ pub fn resample_wav(
 ) -> err::Result<()> {
 
     info!("Reading WAV file");
+    context.set_stage(Stage::Read);
     context.status(0.0, "Reading WAV file".to_string());
 
-    let (input_signal, input_spec) = wav::load_wav(&settings.input_filename)?;
+    let (mut input_signal, input_spec) = wav::load_wav(&settings.input_filename)?;
     let input_rate = Rate::hz(input_spec.sample_rate);
     let timestamp = misc::read_timestamp(&settings.input_filename)?;
 
+    dsp::auto_gain(&mut input_signal);
+
     context.step(Step::signal("input", &input_signal, Some(input_rate)))?;
 
     info!("Resampling");
+    context.set_stage(Stage::Resample);
     context.status(0.2, format!("Resampling to {}", settings.output_rate));
 
     let resampled = dsp::resample(
@@ -68,6 +116,7 @@ pub fn resample_wav(
     };
 
     info!("Writing WAV to '{}'", settings.output_filename);
+    context.set_stage(Stage::Write);
     context.status(0.8, format!("Writing WAV to '{}'", settings.output_filename));
 
     wav::write_wav(&settings.output_filename, &resampled, writer_spec)?;
@@ -84,7 +133,7 @@ pub fn resample_wav(
 ///
 /// Used for cross correlation against the received signal to find the sync
 /// frames positions.
-fn generate_sync_frame(work_rate: Rate) -> err::Result<Vec<i8>> {
+pub fn generate_sync_frame(work_rate: Rate) -> err::Result<Vec<i8>> {
 
     if work_rate.get_hz() % FINAL_RATE != 0 {
         return Err(err::Error::Internal(
@@ -112,14 +161,67 @@ fn generate_sync_frame(work_rate: Rate) -> err::Result<Vec<i8>> {
 }
 
 
+/// Sync frame found on the signal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SyncHit {
+    /// Position on the signal where the sync frame starts.
+    pub index: usize,
+
+    /// Fractional part of the position, from -0.5 to 0.5 samples, estimated by
+    /// parabolic interpolation around the correlation peak. Stored apart from
+    /// `index` because a `f32` can't keep the fraction on long recordings.
+    pub fraction: f32,
+
+    /// Correlation coefficient between the sync frame and the signal at
+    /// `index`, from -1 to 1. Doesn't depend on the amplitude or offset of the
+    /// signal, only on how much it looks like a sync frame.
+    pub confidence: f32,
+}
+
+impl SyncHit {
+    /// Position on the signal where the sync frame starts, with subsample
+    /// precision.
+    pub fn position(&self) -> f64 {
+        self.index as f64 + f64::from(self.fraction)
+    }
+}
+
+/// Cross-correlation between the sync frame and the signal starting on `i`.
+fn sync_correlation(guard: &[i8], signal: &[f32], i: usize) -> f32 {
+    let mut corr: f32 = 0.;
+    for j in 0..guard.len() {
+        match guard[j] {
+            1 => corr += signal[i + j],
+            -1 => corr -= signal[i + j],
+            _ => unreachable!(),
+        }
+    }
+    corr
+}
+
+/// Location of the vertex of the parabola that goes through three equally
+/// spaced points, relative to the middle one.
+///
+/// Used to find the peak of a sampled function between samples. Returns zero
+/// if the points are on a line.
+fn parabolic_peak(before: f32, peak: f32, after: f32) -> f32 {
+    let curvature = before - 2. * peak + after;
+    if curvature == 0. {
+        return 0.;
+    }
+    (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+}
+
 /// Find sync frame positions.
 ///
-/// Returns list of found sync frames positions.
-fn find_sync(
+/// Returns list of found sync frames. Sync frames with a confidence lower than
+/// `threshold` are discarded.
+pub fn find_sync(
     context: &mut Context,
     signal: &Signal,
-    work_rate: Rate
-) -> err::Result<Vec<usize>> {
+    work_rate: Rate,
+    threshold: f32,
+) -> err::Result<Vec<SyncHit>> {
 
     let guard = generate_sync_frame(work_rate)?;
 
@@ -141,15 +243,10 @@ fn find_sync(
         Vec::with_capacity(0) // Not going to be used
     };
 
-    for i in 0 .. signal.len() - guard.len() {
-        let mut corr: f32 = 0.;
-        for j in 0..guard.len() {
-            match guard[j] {
-                1 => corr += signal[i + j],
-                -1 => corr -= signal[i + j],
-                _ => unreachable!(),
-            }
-        }
+    let last = signal.len() - guard.len();
+
+    for i in 0 .. last {
+        let corr = sync_correlation(&guard, signal, i);
 
         if context.export_steps {
             correlation.push(corr);
@@ -173,242 +270,1770 @@ fn find_sync(
         context.step(Step::signal("sync_correlation", &correlation, None))?;
     }
 
-    info!("Found {} sync frames", peaks.len());
-
-    Ok(peaks.iter().map(|(index, _value)| *index).collect())
+    let hits: Vec<SyncHit> = peaks.iter()
+        .map(|(index, value)| SyncHit {
+            index: *index,
+            fraction: if *index > 0 && *index + 1 < last {
+                parabolic_peak(
+                    sync_correlation(&guard, signal, index - 1),
+                    *value,
+                    sync_correlation(&guard, signal, index + 1),
+                )
+            } else {
+                0.
+            },
+            confidence: sync_confidence(&guard, &signal[*index .. *index + guard.len()]),
+        })
+        .filter(|hit| hit.confidence >= threshold)
+        .collect();
+
+    info!("Found {} sync frames, discarded {} with low confidence",
+        hits.len(), peaks.len() - hits.len());
+
+    Ok(hits)
 }
 
-/// Maps float signal values to `u8`.
+/// Times smaller the image decoded on preview mode is, on each direction.
+pub const PREVIEW_SCALE: usize = 2;
+
+/// Settings used when `settings.preview` is set, the rest are not changed.
 ///
-/// `low` becomes 0 and `high` becomes 255. Values are clamped to prevent `u8`
-/// overflow.
-fn map(signal: &Signal, low: f32, high: f32) -> Vec<u8> {
+/// The lowest work rate allowed (see `generate_sync_frame()`), filters with
+/// less attenuation and wider transition bands, so they are much shorter, and
+/// no extra precision. The image is also downscaled by `PREVIEW_SCALE`, that
+/// happens on `decode()`.
+pub fn preview_settings(settings: config::DecodeSettings) -> config::DecodeSettings {
+    if !settings.preview {
+        return settings;
+    }
+    config::DecodeSettings {
+        work_rate: 3 * FINAL_RATE,
+        resample_atten: 20.,
+        resample_delta_freq: 3000.,
+        compensate_droop: false,
+        demodulation_atten: 20.,
+        high_precision: false,
+        ..settings
+    }
+}
 
-    let range = high - low;
-    let signal: Vec<u8> = signal.iter()
-        .map(|x|
-             // Map and clamp between 0 and 255 using min() and max()
-             ((x - low) / range * 255.).max(0.).min(255.).round() as u8
-        ).collect();
+/// Filter used when resampling to the work rate, before demodulating.
+pub fn resample_filter(
+    settings: &config::DecodeSettings,
+    input_rate: Rate,
+) -> filters::LowpassDcRemoval {
+    filters::LowpassDcRemoval {
+        // Cutout frequency of the resampling filter, only the AM spectrum should go
+        // through to avoid noise, 2 times the carrier frequency is enough
+        cutout: Freq::hz(settings.resample_cutout, input_rate),
 
-    signal
-}
+        atten: settings.resample_atten,
 
-/// Available settings for contrast adjustment.
-#[derive(Clone, Debug)]
-pub enum Contrast {
-    /// From telemetry bands, requires syncing to be enabled.
-    Telemetry,
+        // Width of transition band, we are using a DC removal filter that has a
+        // transition band from zero to delta_w. I think that APT signals have
+        // nothing below 500Hz.
+        delta_w: Freq::hz(settings.resample_delta_freq, input_rate),
+    }
+}
 
-    /// Takes only a given percent of the samples, clamping the rest. Something
-    /// like a percentile.
-    Percent(f32),
+/// Filter used after demodulating, on the work rate.
+///
+/// By default keeps everything below `FINAL_RATE / 2`.
+pub fn demodulation_filter(
+    settings: &config::DecodeSettings,
+    work_rate: Rate,
+) -> filters::Lowpass {
+    let cutout = Freq::hz(
+        settings.demodulation_cutout.unwrap_or(FINAL_RATE as f32 / 2.), work_rate);
+    let delta_w = match settings.demodulation_delta_freq {
+        Some(delta_freq) => Freq::hz(delta_freq, work_rate),
+        None => cutout / 5.,
+    };
 
-    /// Don't do anything, map the minimum value to zero and the maximum value
-    /// to 255
-    MinMax,
+    filters::Lowpass {
+        cutout,
+        atten: settings.demodulation_atten,
+        delta_w,
+    }
 }
 
-/// Decode APT image from WAV file.
-pub fn decode(
-    mut context: Context,
-    settings: config::DecodeSettings,
-) -> err::Result<()>{
+/// Get offset of each sync frame from the expected position.
+///
+/// Finds every sync frame and compares its position against a grid with one
+/// line every `PX_PER_ROW` pixels, starting on the first sync frame. Returns
+/// the line number and the difference in samples for each sync frame found,
+/// positive if the frame came late. Lines without a sync frame are skipped.
+/// Useful for plotting the timing jitter and drift of a recording, for example
+/// a constant error on the sample rate gives a linear drift.
+///
+/// Sync frames with confidence lower than `threshold` are ignored, see
+/// `find_sync()`. Takes the `Context` and can fail because `find_sync()` does,
+/// and returns the line numbers too because `processing::estimate_tilt()`
+/// needs them when there are lines without sync frames.
+pub fn sync_offsets(
+    context: &mut Context,
+    signal: &Signal,
+    work_rate: Rate,
+    threshold: f32,
+) -> err::Result<Vec<(u32, i32)>> {
 
-    // --------------------
+    let samples_per_work_row =
+        f64::from(PX_PER_ROW) * f64::from(work_rate.get_hz()) / f64::from(FINAL_RATE);
 
-    context.status(0.0, "Reading WAV file".to_string());
+    let hits = find_sync(context, signal, work_rate, threshold)?;
 
-    let (signal, input_spec) = wav::load_wav(&settings.input_filename)?;
-    let input_rate = Rate::hz(input_spec.sample_rate);
-    let final_rate = Rate::hz(FINAL_RATE);
+    Ok(hit_offsets(&hits, samples_per_work_row).iter()
+        .map(|(line, offset)| (*line as u32, offset.round() as i32))
+        .collect())
+}
 
-    // Samples on each image row when at `WORK_RATE`.
-    let samples_per_work_row: u32 = PX_PER_ROW * settings.work_rate / FINAL_RATE;
+/// Line number and offset in samples of each sync frame.
+///
+/// Same as `sync_offsets()` but keeping the line number, so missing sync frames
+/// don't mess up the timing.
+fn hit_offsets(hits: &[SyncHit], samples_per_work_row: f64) -> Vec<(f64, f64)> {
+    let first = match hits.first() {
+        Some(hit) => hit.position(),
+        None => return Vec::new(),
+    };
 
-    let work_rate = Rate::hz(settings.work_rate);
+    hits.iter().map(|hit| {
+        let distance = hit.position() - first;
+        let line = (distance / samples_per_work_row).round();
+        (line, distance - line * samples_per_work_row)
+    }).collect()
+}
 
-    context.step(Step::signal("input", &signal, Some(input_rate)))?;
+/// Maximum distance between a sync frame and its predicted position, as a
+/// fraction of a line. Farther sync frames are considered corrupt.
+const SYNC_TOLERANCE: f64 = 0.01;
 
-    // --------------------
+/// Amount of recent sync frames used to predict the next one.
+const SYNC_PREDICTION_HITS: usize = 8;
 
-    context.status(0.1, format!("Resampling to {}", work_rate.get_hz()));
+/// Position where each image row starts.
+///
+/// Real passes drop sync frames, so I predict the start of the rows without a
+/// sync frame by fitting a line to the last `SYNC_PREDICTION_HITS` sync frames.
+/// That way the rows below a dropout stay aligned. Sync frames too far from the
+/// predicted position are ignored, unless two of them in a row agree with
+/// each other: then I assume that the recording jumped (e.g. lost samples) and
+/// start predicting from there.
+pub fn row_starts(hits: &[SyncHit], samples_per_work_row: f64) -> Vec<usize> {
+    let first = match hits.first() {
+        Some(hit) => hit.index,
+        None => return Vec::new(),
+    };
 
-    let filter = filters::LowpassDcRemoval {
-        // Cutout frequency of the resampling filter, only the AM spectrum should go
-        // through to avoid noise, 2 times the carrier frequency is enough
-        cutout: Freq::hz(settings.resample_cutout, input_rate),
+    // Line number and position of the last trusted sync frames
+    let mut history: VecDeque<(f64, f64)> = VecDeque::new();
+    history.push_back((0., first as f64));
 
-        atten: settings.resample_atten,
+    let mut starts = vec![first];
 
-        // Width of transition band, we are using a DC removal filter that has a
-        // transition band from zero to delta_w. I think that APT signals have
-        // nothing below 500Hz.
-        delta_w: Freq::hz(settings.resample_delta_freq, input_rate),
+    // Sync frame off the predicted position waiting for another one that
+    // agrees with it
+    let mut candidate: Option<usize> = None;
+
+    let lines_between = |from: f64, to: f64| (to - from) / samples_per_work_row;
+    let on_grid = |lines: f64| {
+        lines.round() >= 1. &&
+        (lines - lines.round()).abs() <= SYNC_TOLERANCE
     };
-    let signal = dsp::resample_with_filter(
-        &mut context, &signal, input_rate, work_rate, filter)?;
 
-    if signal.len() < 10 * samples_per_work_row as usize {
-        return Err(err::Error::Internal(
-            "Got less than 10 rows of samples, audio file is too short".to_string()));
+    for hit in &hits[1..] {
+        let (last_line, last_index) = *history.back().unwrap();
+        let index = hit.index as f64;
+        let line = last_line + lines_between(last_index, index).round();
+
+        let predicted = predict_row_start(&history, line, samples_per_work_row);
+
+        if line > last_line
+            && (index - predicted).abs() <= SYNC_TOLERANCE * samples_per_work_row
+        {
+            for missing in (last_line as usize + 1)..(line as usize) {
+                let start = predict_row_start(
+                    &history, missing as f64, samples_per_work_row);
+                starts.push(start.round() as usize);
+            }
+            starts.push(hit.index);
+            history.push_back((line, index));
+            candidate = None;
+
+        } else if let Some(previous) =
+            candidate.filter(|c| on_grid(lines_between(*c as f64, index)))
+        {
+            debug!("Resyncing on sample {}", previous);
+
+            let previous_line = last_line + lines_between(last_index, previous as f64)
+                .round().max(1.);
+            let line = previous_line + lines_between(previous as f64, index).round();
+
+            history.clear();
+            history.push_back((previous_line, previous as f64));
+            history.push_back((line, index));
+
+            starts.push(previous);
+            for missing in (previous_line as usize + 1)..(line as usize) {
+                let start = predict_row_start(
+                    &history, missing as f64, samples_per_work_row);
+                starts.push(start.round() as usize);
+            }
+            starts.push(hit.index);
+            candidate = None;
+
+        } else {
+            debug!("Ignoring sync frame on sample {}, expected on {}",
+                hit.index, predicted);
+            candidate = Some(hit.index);
+        }
+
+        if history.len() > SYNC_PREDICTION_HITS {
+            history.pop_front();
+        }
     }
 
-    // --------------------
+    starts
+}
 
-    context.status(0.4, "Demodulating".to_string());
+/// Predict where `line` starts from the trusted sync frames in `history`.
+fn predict_row_start(
+    history: &VecDeque<(f64, f64)>,
+    line: f64,
+    samples_per_work_row: f64,
+) -> f64 {
+    let (lines, indices): (Vec<f64>, Vec<f64>) = history.iter().cloned().unzip();
+
+    match misc::polyfit(&lines, &indices, 1) {
+        Ok(coeff) if history.len() >= 2 => misc::polyval(&coeff, line),
+        _ => {
+            let (last_line, last_index) = *history.back().unwrap();
+            last_index + (line - last_line) * samples_per_work_row
+        }
+    }
+}
 
-    let signal = dsp::demodulate(
-        &mut context, &signal, Freq::hz(CARRIER_FREQ as f32, work_rate))?;
+/// Maximum difference between the measured and expected sync spacing, as a
+/// fraction of the expected spacing.
+const SYNC_SPACING_TOLERANCE: f64 = 0.005;
 
-    // --------------------
+/// Check that the sync frames are one line apart.
+///
+/// Takes the median distance between consecutive sync frames, divided by the
+/// lines between them when some are missing, and compares it against
+/// `expected_spacing` in samples. Fails when they differ by more than
+/// `SYNC_SPACING_TOLERANCE`, usually because the sample rate is wrong (try
+/// `--correct-rate`) or the syncing went wrong.
+pub fn verify_sync_spacing(sync_positions: &[usize], expected_spacing: f64) -> err::Result<()> {
+    let positions: Vec<f64> = sync_positions.iter().map(|p| *p as f64).collect();
+    let measured = median_line_spacing(&positions, expected_spacing).ok_or_else(||
+        err::Error::Internal(
+            "Can't verify sync spacing, need at least two sync frames".to_string()))?;
+
+    if (measured - expected_spacing).abs() > SYNC_SPACING_TOLERANCE * expected_spacing {
+        return Err(err::Error::Internal(format!(
+            "Sync frames are {:.1} samples apart, expected {:.1}. The sample \
+            rate is probably wrong", measured, expected_spacing)));
+    }
+
+    Ok(())
+}
+
+/// Median distance in samples between consecutive sync frames, divided by the
+/// lines between them when some are missing.
+///
+/// `expected_spacing` is only used to count the lines between sync frames.
+/// Returns `None` if there are less than two sync frames.
+fn median_line_spacing(sync_positions: &[f64], expected_spacing: f64) -> Option<f64> {
+    let mut spacings: Vec<f32> = sync_positions.windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .filter(|distance| *distance > 0.)
+        .map(|distance| (distance / (distance / expected_spacing).round().max(1.)) as f32)
+        .collect();
+
+    telemetry::median(&mut spacings).map(f64::from)
+}
 
-    context.status(0.42, "Filtering".to_string());
+/// Sync frames with lower confidence are not used by `detect_sample_drops()`.
+const SAMPLE_DROP_MIN_CONFIDENCE: f32 = 0.5;
 
-    let cutout = Freq::pi_rad(FINAL_RATE as f32 / work_rate.get_hz() as f32);
-    let filter = filters::Lowpass {
-        cutout,
-        atten: settings.demodulation_atten,
-        delta_w: cutout / 5.
+/// Find samples dropped while recording.
+///
+/// When the soundcard buffer underruns a sample is lost, and every line after
+/// that one starts a sample earlier. I look for consecutive sync frames that
+/// are closer than the median line spacing by about one sample. The rest of the
+/// sync frames keep their phase, so a slightly wrong sample rate doesn't look
+/// like a drop.
+///
+/// The exact position of the lost sample is unknown, I return the start of the
+/// sync frame found early, once for each lost sample. Inserting a sample there
+/// with `repair_drops()` puts the following lines back in place. `rate` has to
+/// be a multiple of `FINAL_RATE`, like the work rate.
+pub fn detect_sample_drops(
+    context: &mut Context,
+    signal: &Signal,
+    rate: Rate,
+) -> err::Result<Vec<usize>> {
+    let samples_per_row =
+        f64::from(PX_PER_ROW) * f64::from(rate.get_hz()) / f64::from(FINAL_RATE);
+
+    let hits = find_sync(context, signal, rate, SAMPLE_DROP_MIN_CONFIDENCE)?;
+    let positions: Vec<f64> = hits.iter().map(|hit| hit.position()).collect();
+    let period = match median_line_spacing(&positions, samples_per_row) {
+        Some(period) => period,
+        None => return Ok(Vec::new()),
     };
-    // mut because on sync the signal is going to be modified
-    let mut signal = dsp::filter(&mut context, &signal, filter)?;
 
-    // --------------------
+    let mut drops = Vec::new();
+    for (pair, hit) in positions.windows(2).zip(hits.iter().skip(1)) {
+        let distance = pair[1] - pair[0];
+        let lines = (distance / period).round();
+        let missing = (lines * period - distance).round();
+        // Ignore jumps longer than half a line, row_starts() handles those
+        if missing >= 1. && missing < period / 2. {
+            drops.extend(std::iter::repeat_n(hit.index, missing as usize));
+        }
+    }
 
-    if settings.sync {
-        context.status(0.5, "Syncing".to_string());
+    if !drops.is_empty() {
+        info!("Found {} dropped samples", drops.len());
+    }
 
-        // Get list of sync frames positions
-        let sync_pos = find_sync(&mut context, &signal, work_rate)?;
+    Ok(drops)
+}
 
-        if sync_pos.len() < 5 {
-            return Err(err::Error::Internal(
-                "Found less than 5 sync frames, audio file is too short or too \
-                noisy".to_string())
-            );
-        }
+/// Insert a sample on each position given by `detect_sample_drops()`.
+///
+/// Positions are indices on the original signal and can be repeated to insert
+/// more than one sample. The new samples are the mean of their neighbours.
+pub fn repair_drops(signal: &Signal, positions: &[usize]) -> Signal {
+    let mut positions = positions.to_vec();
+    positions.sort_unstable();
+
+    let mut repaired = Vec::with_capacity(signal.len() + positions.len());
+    let mut last = 0;
+    for position in positions {
+        let position = position.min(signal.len());
+        repaired.extend_from_slice(&signal[last..position]);
+        last = position;
+
+        let before = signal.get(position.wrapping_sub(1)).or_else(|| signal.get(position));
+        let after = signal.get(position).or(before);
+        repaired.push(match (before, after) {
+            (Some(before), Some(after)) => (before + after) / 2.,
+            _ => 0.,
+        });
+    }
+    repaired.extend_from_slice(&signal[last..]);
 
-        // Create new "aligned" vector to samples_per_work_row. Each row starts on
-        // a found sync frame position
-        let mut aligned: Signal = Vec::new();
+    repaired
+}
 
-        // For each sync position
-        for i in 0..sync_pos.len()-1 {
-            // Check if there are enough samples left to fill an image row
-            if (sync_pos[i] + samples_per_work_row as usize) < signal.len() {
+/// How much of the distance to a sync frame `LineTracker` moves the phase.
+const LINE_TRACKER_PHASE_GAIN: f64 = 0.1;
 
-                aligned.extend_from_slice(
-                    &signal[sync_pos[i] .. sync_pos[i] + samples_per_work_row as usize]
-                );
-            }
-        }
+/// How much of the distance to a sync frame `LineTracker` adds to the period.
+const LINE_TRACKER_PERIOD_GAIN: f64 = 0.005;
 
-        signal = aligned;
+/// Follows the start of the lines with a phase accumulator.
+///
+/// Keeps an estimate of the line period and of the start of the next line.
+/// Every line the start advances by one period, and the sync frames found
+/// only nudge the phase and the period, so a noisy sync frame moves the image
+/// a little instead of a whole jump. Sync frames farther than
+/// `SYNC_TOLERANCE` from the prediction are ignored, the same as on
+/// `row_starts()`. The period starts as half a second.
+pub struct LineTracker {
+    /// Samples per line.
+    period: f64,
+
+    /// Where the next line should start, `None` until the first sync frame.
+    next_start: Option<f64>,
+}
 
-    } else {
-        context.status(0.5, "Skipping Syncing".to_string());
+impl LineTracker {
+    /// Create tracker for a signal with this sample rate.
+    pub fn new(rate: Rate) -> LineTracker {
+        LineTracker {
+            period: f64::from(rate.get_hz()) * f64::from(PX_PER_ROW)
+                / f64::from(FINAL_RATE),
+            next_start: None,
+        }
+    }
 
-        // If we are not syncing send a dummy correlation step
-        context.step(Step::signal("sync_correlation", &vec![], Some(work_rate)))?;
+    /// Advance one line, with the position of its sync frame if found.
+    pub fn update(&mut self, detected_sync: Option<usize>) {
+        let detected = detected_sync.map(|d| d as f64);
+
+        let start = match (self.next_start, detected) {
+            (None, None) => return,
+            (None, Some(detected)) => detected,
+            (Some(predicted), Some(detected))
+                if (detected - predicted).abs() <= SYNC_TOLERANCE * self.period =>
+            {
+                let error = detected - predicted;
+                self.period += LINE_TRACKER_PERIOD_GAIN * error;
+                predicted + LINE_TRACKER_PHASE_GAIN * error
+            },
+            (Some(predicted), _) => predicted,
+        };
+
+        self.next_start = Some(start + self.period);
+    }
 
-        // Crop signal to multiple of samples_per_work_row
-        let length = signal.len();
-        signal.truncate(length
-            / samples_per_work_row as usize // Integer division
-            * samples_per_work_row as usize
-        );
+    /// Where the next line should start, zero before the first sync frame.
+    pub fn next_line_start(&self) -> usize {
+        self.next_start.map_or(0, |start| start.round().max(0.) as usize)
     }
 
-    context.step(Step::signal("sync_result", &signal, Some(work_rate)))?;
+    /// Current estimate of the samples per line.
+    pub fn period(&self) -> f64 {
+        self.period
+    }
+}
 
-    // --------------------
+/// Correct sample rate drift that changes over time.
+///
+/// A wrong sample rate makes the sync frames drift linearly, but the sample
+/// rate of cheap hardware can also change during the recording, for example
+/// when it heats up. I fit a polynomial of `degree` to the offsets of the sync
+/// frames (see `sync_offsets()`) and resample the signal following it, so the
+/// sync frames end up every `PX_PER_ROW` pixels. Degree 1 is the same as
+/// correcting a constant sample rate error.
+pub fn resample_correct(
+    context: &mut Context,
+    signal: &Signal,
+    work_rate: Rate,
+    threshold: f32,
+    degree: usize,
+) -> err::Result<Signal> {
+
+    let samples_per_work_row =
+        f64::from(PX_PER_ROW) * f64::from(work_rate.get_hz()) / f64::from(FINAL_RATE);
+
+    let hits = find_sync(context, signal, work_rate, threshold)?;
+    let (lines, offsets): (Vec<f64>, Vec<f64>) =
+        hit_offsets(&hits, samples_per_work_row).into_iter().unzip();
+
+    let coeff = misc::polyfit(&lines, &offsets, degree)?;
+    debug!("Sync drift polynomial: {:?}", coeff);
+
+    // The offsets are measured from the first sync frame
+    let first = hits[0].position();
+
+    let mut positions: Vec<f64> = Vec::with_capacity(signal.len());
+    loop {
+        let j = positions.len() as f64;
+        let position = j + misc::polyval(&coeff, (j - first) / samples_per_work_row);
+        if position >= signal.len() as f64 {
+            break;
+        }
+        positions.push(position);
+    }
 
-    context.status(0.90, "Resampling to 4160".to_string());
+    Ok(dsp::resample_arbitrary(signal, &positions))
+}
 
-    // Resample without filter because we already filtered the signal before
-    // syncing
-    let signal = dsp::resample_with_filter(
-        &mut context, &signal, work_rate, final_rate, filters::NoFilter)?;
+/// SNR in dB that gets the best score on `decode_quality()`.
+const QUALITY_MAX_SNR: f32 = 30.;
 
+/// Fraction of clipped pixels that gets the worst score on `decode_quality()`.
+const QUALITY_MAX_CLIPPED: f32 = 0.2;
 
-    let (low, high) = match settings.contrast_adjustment {
-        Contrast::Telemetry => {
-            info!("Adjusting contrast from telemetry");
-            if !settings.sync {
-                warn!("Reading telemetry without syncing, expect horrible results!");
-            }
+/// Summarize the quality of a decoded image on a number from 0 to 1.
+///
+/// Meant for sorting passes, not as an absolute measurement. `signal` is the
+/// signal with one sample per pixel before mapping to `u8`, `image` is the
+/// grayscale image and `sync_hits` are the sync frames found. Combines three
+/// scores from 0 to 1:
+///
+/// - SNR, weight 0.4: the noise is estimated from the differences between
+///   consecutive pixels, using the median so the edges on the image don't
+///   count as noise. 0dB scores 0 and `QUALITY_MAX_SNR` or more scores 1.
+/// - Sync, weight 0.4: mean confidence of the sync frames found, the lines
+///   where no sync frame was found count as zero confidence.
+/// - Clipping, weight 0.2: fraction of pixels that are full black or full
+///   white, zero pixels scores 1 and `QUALITY_MAX_CLIPPED` or more scores 0.
+///
+/// Fails if the image doesn't have a full line or if there are no sync frames,
+/// e.g. when decoding without syncing.
+pub fn decode_quality(
+    signal: &Signal,
+    image: &[u8],
+    sync_hits: &[SyncHit],
+) -> err::Result<f32> {
+    let lines = image.len() / PX_PER_ROW as usize;
+    if signal.len() < 2 || lines == 0 {
+        return Err(err::Error::ImageProcessing(format!(
+            "Can't estimate quality of image with {} pixels, shorter than a line",
+            image.len())));
+    }
+    if sync_hits.is_empty() {
+        return Err(err::Error::ImageProcessing(
+            "Can't estimate quality of image without sync frames".to_string()));
+    }
 
-            let telemetry = telemetry::read_telemetry(&mut context, &signal)?;
+    // SNR
 
-            let low = telemetry.get_wedge_value(9, None);
-            let high = telemetry.get_wedge_value(8, None);
+    // Accumulate on f64, long recordings lose precision on f32
+    let mean = signal.iter().map(|x| f64::from(*x)).sum::<f64>() / signal.len() as f64;
+    let variance = (signal.iter().map(|x| (f64::from(*x) - mean).powi(2)).sum::<f64>()
+        / signal.len() as f64) as f32;
 
-            (low, high)
-        },
-        Contrast::Percent(p) => {
-            info!("Adjusting contrast using {} percent", p * 100.);
-            misc::percent(&signal, p)?
-        },
-        Contrast::MinMax => {
-            info!("Mapping values (no contrast adjustment)");
-            let low: f32 = *dsp::get_min(&signal)?;
-            let high: f32 = *dsp::get_max(&signal)?;
+    // For gaussian noise the median of the absolute difference between
+    // samples is 0.6745 * sqrt(2) times the standard deviation
+    let mut differences: Signal = signal.windows(2).map(|w| (w[1] - w[0]).abs()).collect();
+    differences.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let noise_std = differences[differences.len() / 2] / (0.6745 * 2_f32.sqrt());
 
-            (low, high)
-        }
+    let snr_score = if noise_std > 0. {
+        let snr = 10. * (variance / noise_std.powi(2)).log10();
+        (snr / QUALITY_MAX_SNR).clamp(0., 1.)
+    } else if variance > 0. {
+        1.
+    } else {
+        0.
     };
 
-    let signal = map(&signal, low, high);
+    // Sync
 
-    context.step(Step::signal(
-            "mapped",
-            &signal.iter().map(|x| f32::from(*x)).collect(),
-            Some(final_rate)
-    ))?;
+    let sync_score = (sync_hits.iter().map(|hit| hit.confidence.max(0.)).sum::<f32>()
+        / lines as f32).min(1.);
 
-    // --------------------
+    // Clipping
 
-    context.status(0.95, format!("Writing PNG to '{}'", settings.output_filename));
+    let clipped = image.iter().filter(|x| **x == 0 || **x == 255).count();
+    let clipped_score = 1. - (clipped as f32 / image.len() as f32 / QUALITY_MAX_CLIPPED).min(1.);
 
-    // To use encoder.set()
-    use png::HasParameters;
+    debug!("Quality scores: SNR {}, sync {}, clipping {}",
+        snr_score, sync_score, clipped_score);
 
-    let path = std::path::Path::new(&settings.output_filename);
-    let file = std::fs::File::create(path)?;
-    let buffer = &mut std::io::BufWriter::new(file);
+    Ok(0.4 * snr_score + 0.4 * sync_score + 0.2 * clipped_score)
+}
 
-    let height = signal.len() as u32 / PX_PER_ROW;
+/// Correlation coefficient between the sync frame and part of the signal.
+///
+/// Pearson correlation coefficient, from -1 to 1. Returns 0 if the signal is
+/// constant.
+pub fn sync_confidence(guard: &[i8], window: &[f32]) -> f32 {
+
+    let n = guard.len() as f32;
+
+    let (mut sum_g, mut sum_g2, mut sum_x, mut sum_x2, mut sum_gx) = (0., 0., 0., 0., 0.);
+    for (g, x) in guard.iter().zip(window.iter()) {
+        let g = f32::from(*g);
+        sum_g += g;
+        sum_g2 += g * g;
+        sum_x += x;
+        sum_x2 += x * x;
+        sum_gx += g * x;
+    }
 
-    let mut encoder = png::Encoder::new(buffer, PX_PER_ROW, height);
-    encoder.set(png::ColorType::Grayscale).set(png::BitDepth::Eight);
-    let mut writer = encoder.write_header()?;
+    let denominator = ((n * sum_g2 - sum_g * sum_g) * (n * sum_x2 - sum_x * sum_x)).sqrt();
 
-    writer.write_image_data(&signal[..])?;
+    if denominator > 0. {
+        (n * sum_gx - sum_g * sum_x) / denominator
+    } else {
+        0.
+    }
+}
 
-    // --------------------
+/// Maps float signal values to `u8`.
+///
+/// `low` becomes 0 and `high` becomes 255. Values are clamped to prevent `u8`
+/// overflow.
+pub fn map(signal: &[f32], low: f32, high: f32) -> Vec<u8> {
 
-    context.status(1., "Finished".to_string());
-    Ok(())
+    let range = high - low;
+    let signal: Vec<u8> = signal.iter()
+        .map(|x|
+             // Map and clamp between 0 and 255 using min() and max()
+             ((x - low) / range * 255.).max(0.).min(255.).round() as u8
+        ).collect();
+
+    signal
 }
 
-#[cfg(test)]
-mod tests {
+/// Available settings for contrast adjustment.
+#[derive(Clone, Debug)]
+pub enum Contrast {
+    /// From telemetry bands, requires syncing to be enabled.
+    Telemetry,
 
-    use super::*;
+    /// Takes only a given percent of the samples, clamping the rest. Something
+    /// like a percentile.
+    Percent(f32),
 
-    #[test]
-    fn test_sample_sync_frame() {
+    /// Narrowest range that clamps at most the given fraction of the samples,
+    /// see `misc::auto_contrast_bounds()`.
+    Auto(f32),
 
-        assert_eq!(
-            vec![-1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
-                  1,  1,  1,  1,  1,  1,  1,  1,  1,  1,
-                 -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
-                  1,  1,  1,  1,  1,  1,  1,  1,  1,  1,
-                 -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
-                  1,  1,  1,  1,  1,  1,  1,  1,  1,  1,
-                 -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
-                  1,  1,  1,  1,  1,  1,  1,  1,  1,  1,
+    /// Don't do anything, map the minimum value to zero and the maximum value
+    /// to 255
+    MinMax,
+}
+
+/// Demodulation method.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DemodMethod {
+    /// Use two consecutive samples, see `dsp::demodulate()`.
+    TwoSample,
+
+    /// Square law envelope detector, lower latency but worse on noisy
+    /// signals. See `dsp::square_law_demod()`.
+    SquareLaw,
+}
+
+/// APT channels to save on the output image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputChannels {
+    A,
+    B,
+    Both,
+}
+
+impl OutputChannels {
+
+    /// Width in pixels of the image with the selected channels.
+    pub fn width(self) -> u32 {
+        match self {
+            OutputChannels::A | OutputChannels::B => PX_PER_ROW / 2,
+            OutputChannels::Both => PX_PER_ROW,
+        }
+    }
+
+    /// Keep only the selected channels of an image with `PX_PER_ROW` pixels
+    /// per line.
+    pub fn select<T: Copy>(self, image: &[T]) -> Vec<T> {
+        match self {
+            OutputChannels::A => split_channels(image).0,
+            OutputChannels::B => split_channels(image).1,
+            OutputChannels::Both => image.to_vec(),
+        }
+    }
+}
+
+/// Split each line of the image in the two channels.
+///
+/// Takes an already synced signal with `PX_PER_ROW` values per line. Returns
+/// the channel A and channel B images, each one with `PX_PER_ROW / 2` values
+/// per line. Incomplete lines at the end are dropped.
+pub fn split_channels<T: Copy>(signal: &[T]) -> (Vec<T>, Vec<T>) {
+
+    let half = PX_PER_ROW as usize / 2;
+
+    let mut channel_a: Vec<T> = Vec::with_capacity(signal.len() / 2);
+    let mut channel_b: Vec<T> = Vec::with_capacity(signal.len() / 2);
+
+    for line in signal.chunks_exact(PX_PER_ROW as usize) {
+        channel_a.extend_from_slice(&line[..half]);
+        channel_b.extend_from_slice(&line[half..]);
+    }
+
+    (channel_a, channel_b)
+}
+
+/// Drop the first half line, so each line starts on the other channel.
+///
+/// For images split half a line off, where channel B appears first, see
+/// `telemetry::detect_channel_swap()`. The incomplete line left at the end is
+/// dropped too.
+pub fn shift_half_line(signal: &Signal) -> Signal {
+    let half = PX_PER_ROW as usize / 2;
+    let lines = signal.len().saturating_sub(half) / PX_PER_ROW as usize;
+    signal.iter().skip(half).take(lines * PX_PER_ROW as usize).cloned().collect()
+}
+
+/// Move sync frames the same way as `shift_half_line()` moves the image.
+///
+/// The sync frames are on the signal at the work rate, with
+/// `samples_per_work_row` samples per line. The ones on the half line dropped
+/// are removed, the rest are moved half a line back.
+fn shift_hits_half_line(sync_hits: &[SyncHit], samples_per_work_row: u32) -> Vec<SyncHit> {
+    let half = samples_per_work_row as usize / 2;
+    let start = match sync_hits.first() {
+        Some(hit) => hit.index + half,
+        None => return Vec::new(),
+    };
+
+    sync_hits.iter()
+        .filter(|hit| hit.index >= start)
+        .map(|hit| SyncHit { index: hit.index - half, ..*hit })
+        .collect()
+}
+
+/// Join two channels into a single image.
+///
+/// Inverse of `split_channels()`, each line of the result has the line of
+/// channel A followed by the line of channel B.
+pub fn join_channels<T: Copy>(channel_a: &[T], channel_b: &[T]) -> Vec<T> {
+
+    let half = PX_PER_ROW as usize / 2;
+
+    let mut signal: Vec<T> = Vec::with_capacity(channel_a.len() + channel_b.len());
+
+    for (line_a, line_b) in channel_a.chunks_exact(half).zip(channel_b.chunks_exact(half)) {
+        signal.extend_from_slice(line_a);
+        signal.extend_from_slice(line_b);
+    }
+
+    signal
+}
+
+/// Position of each part of an APT line.
+///
+/// Each field is the range of columns it uses, they are contiguous and in
+/// this order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineLayout {
+    pub sync_a: Range<usize>,
+    pub space_a: Range<usize>,
+    pub video_a: Range<usize>,
+    pub telemetry_a: Range<usize>,
+    pub sync_b: Range<usize>,
+    pub space_b: Range<usize>,
+    pub video_b: Range<usize>,
+    pub telemetry_b: Range<usize>,
+}
+
+/// Get the columns of each part of an APT line.
+///
+/// The standard line has `PX_PER_ROW` (2080) pixels, if `line_width` is
+/// different (e.g. a scaled image) the positions are scaled proportionally.
+pub fn column_layout(line_width: usize) -> LineLayout {
+
+    // Width of each part on a standard line, for each channel: sync, space,
+    // video and telemetry
+    let widths: [usize; 8] = [39, 47, 909, 45, 39, 47, 909, 45];
+
+    // Scale the boundaries instead of the widths, so there are no gaps
+    let mut boundaries: Vec<usize> = vec![0];
+    let mut sum: usize = 0;
+    for width in widths.iter() {
+        sum += width;
+        boundaries.push(
+            (sum as f32 * line_width as f32 / PX_PER_ROW as f32).round() as usize);
+    }
+
+    let range = |i: usize| boundaries[i] .. boundaries[i + 1];
+
+    LineLayout {
+        sync_a: range(0),
+        space_a: range(1),
+        video_a: range(2),
+        telemetry_a: range(3),
+        sync_b: range(4),
+        space_b: range(5),
+        video_b: range(6),
+        telemetry_b: range(7),
+    }
+}
+
+/// Minimum difference between the black and white lines of a minute marker,
+/// as a fraction of the range of the image.
+const MINUTE_MARKER_CONTRAST: f32 = 0.5;
+
+/// Minimum lines between minute markers, they come every 120 lines.
+const MINUTE_MARKER_MIN_DISTANCE: usize = 60;
+
+/// Find the minute markers on an image.
+///
+/// Every minute the space part of both channels has two black lines followed
+/// by two white lines. I average the space columns of each line and look for
+/// that pattern, so it works with both black and white space. Returns the
+/// index of the first black line of each marker. The image can have any
+/// `width`, see `column_layout()`.
+pub fn find_minute_markers(image: &[f32], width: usize) -> Vec<usize> {
+    if width == 0 || image.len() < 4 * width {
+        return Vec::new();
+    }
+
+    let layout = column_layout(width);
+
+    // Mean of the space columns on each line
+    let space: Signal = image.chunks_exact(width).map(|line| {
+        let columns = line[layout.space_a.clone()].iter()
+            .chain(line[layout.space_b.clone()].iter());
+        columns.sum::<f32>() / (layout.space_a.len() + layout.space_b.len()) as f32
+    }).collect();
+
+    // White lines minus black lines
+    let score: Signal = space.windows(4)
+        .map(|lines| (lines[2] + lines[3] - lines[0] - lines[1]) / 2.)
+        .collect();
+
+    let min = image.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = image.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let threshold = MINUTE_MARKER_CONTRAST * (max - min);
+    if threshold <= 0. {
+        return Vec::new();
+    }
+
+    dsp::find_peaks(&score, MINUTE_MARKER_MIN_DISTANCE, threshold)
+}
+
+/// Get values to use for contrast adjustment of a channel.
+///
+/// Returns tuple of `(low, high)`, the values that should be mapped to 0 and
+/// 255 respectively. The telemetry is needed only when using
+/// `Contrast::Telemetry`.
+fn contrast_bounds(
+    channel_signal: &Signal,
+    contrast: &Contrast,
+    telemetry: Option<&telemetry::Telemetry>,
+    channel: telemetry::Channel,
+) -> err::Result<(f32, f32)> {
+
+    match *contrast {
+        Contrast::Telemetry => {
+            info!("Adjusting contrast of channel {:?} from telemetry", channel);
+
+            let telemetry = telemetry.ok_or_else(|| err::Error::Internal(
+                "Telemetry needed for contrast adjustment".to_string()))?;
+
+            let low = telemetry.get_wedge_value(9, Some(channel));
+            let high = telemetry.get_wedge_value(8, Some(channel));
+
+            Ok((low, high))
+        },
+        Contrast::Percent(p) => {
+            info!("Adjusting contrast of channel {:?} using {} percent",
+                channel, p * 100.);
+            misc::percent(channel_signal, p)
+        },
+        Contrast::Auto(fraction) => {
+            info!("Adjusting contrast of channel {:?} clipping at most {} percent",
+                channel, fraction * 100.);
+            misc::auto_contrast_bounds(channel_signal, fraction)
+        },
+        Contrast::MinMax => {
+            info!("Mapping values of channel {:?} (no contrast adjustment)", channel);
+            let low: f32 = *dsp::get_min(channel_signal)?;
+            let high: f32 = *dsp::get_max(channel_signal)?;
+
+            Ok((low, high))
+        }
+    }
+}
+
+/// Write image to file, the format depends on the extension, see
+/// `sink::open()`.
+///
+/// The image can be grayscale or RGB. `compression` is used only for PNG.
+fn write_image(
+    filename: &str,
+    image: &[u8],
+    width: u32,
+    color: png::ColorType,
+    compression: png::Compression,
+) -> err::Result<()> {
+    let mut sink = sink::open(filename, width as usize, color, compression)?;
+    sink::write_image(sink.as_mut(), image, width as usize, color)
+}
+
+/// Filename of a file saved next to an image.
+///
+/// Replaces the extension with `suffix`, so `pass.png` and `_thumb.png` give
+/// `pass_thumb.png`.
+fn sidecar_filename(filename: &str, suffix: &str) -> String {
+    let path = std::path::Path::new(filename);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("output");
+    path.with_file_name(format!("{}{}", stem, suffix))
+        .to_string_lossy().into_owned()
+}
+
+/// First bytes of files written by `write_raw_f32()`.
+const RAW_F32_MAGIC: &[u8; 4] = b"APTF";
+
+/// Save image with full precision, one `f32` per pixel.
+///
+/// The file starts with `RAW_F32_MAGIC`, then the width and height as
+/// little endian `u32`, then every pixel as little endian `f32` row by row.
+/// Useful for scientific processing where the 8 bits of a PNG are not enough.
+pub fn write_raw_f32(filename: &str, image: &Signal, width: usize) -> err::Result<()> {
+    use std::io::Write;
+
+    if width == 0 || !image.chunks_exact(width).remainder().is_empty() {
+        return Err(err::Error::Internal(format!(
+            "Image length {} is not a multiple of width {}", image.len(), width)));
+    }
+
+    let file = std::fs::File::create(filename)?;
+    let mut buffer = std::io::BufWriter::new(file);
+
+    buffer.write_all(RAW_F32_MAGIC)?;
+    buffer.write_all(&(width as u32).to_le_bytes())?;
+    buffer.write_all(&((image.len() / width) as u32).to_le_bytes())?;
+    for pixel in image {
+        buffer.write_all(&pixel.to_le_bytes())?;
+    }
+    buffer.flush()?;
+
+    Ok(())
+}
+
+/// Load image saved with `write_raw_f32()`.
+///
+/// Returns the pixels and the width of the image.
+pub fn read_raw_f32(filename: &str) -> err::Result<(Signal, usize)> {
+    let bytes = std::fs::read(filename)?;
+
+    if bytes.len() < 12 || &bytes[0..4] != RAW_F32_MAGIC {
+        return Err(err::Error::Internal(format!(
+            "{} is not a raw f32 image", filename)));
+    }
+
+    let word_at = |start: usize| {
+        let mut word = [0; 4];
+        word.copy_from_slice(&bytes[start..start + 4]);
+        word
+    };
+    let width = u32::from_le_bytes(word_at(4)) as usize;
+    let height = u32::from_le_bytes(word_at(8)) as usize;
+
+    if bytes.len() != 12 + width * height * 4 {
+        return Err(err::Error::Internal(format!(
+            "Raw f32 image {} has the wrong size for {}x{} pixels",
+            filename, width, height)));
+    }
+
+    let image = (0..width * height)
+        .map(|i| f32::from_le_bytes(word_at(12 + i * 4)))
+        .collect();
+
+    Ok((image, width))
+}
+
+/// Adjust contrast of each channel independently and map values to `u8`.
+///
+/// Takes an already synced signal. Channel A (usually visible) and channel B
+/// (usually infrared) need different treatment, so each one gets its own
+/// contrast adjustment before joining them again.
+fn map_channels(
+    signal: &Signal,
+    channel_a_contrast: &Contrast,
+    channel_b_contrast: &Contrast,
+    telemetry: Option<&telemetry::Telemetry>,
+) -> err::Result<Vec<u8>> {
+
+    let (channel_a, channel_b) = split_channels(signal);
+
+    let (low_a, high_a) = contrast_bounds(
+        &channel_a, channel_a_contrast, telemetry, telemetry::Channel::A)?;
+    let (low_b, high_b) = contrast_bounds(
+        &channel_b, channel_b_contrast, telemetry, telemetry::Channel::B)?;
+
+    Ok(join_channels(
+        &map(&channel_a, low_a, high_a),
+        &map(&channel_b, low_b, high_b),
+    ))
+}
+
+/// Signal read by `read()`, ready to resample.
+pub struct Input {
+    /// Samples with automatic gain applied.
+    pub signal: Signal,
+
+    /// Sample rate, corrected if `settings.correct_rate` is set.
+    pub rate: Rate,
+
+    /// Lines kept before the ones asked on `settings.line_range`, see
+    /// `crop_lines()`. `assemble()` drops them.
+    pub margin_lines: usize,
+}
+
+/// Read the input WAV file, first stage of `decode()`.
+///
+/// Also corrects the sample rate, trims and crops the lines asked if the
+/// settings say so. The lines asked are counted from the start of the
+/// recording, so when cropping the recording is not trimmed.
+pub fn read(
+    context: &mut Context,
+    settings: &config::DecodeSettings,
+) -> err::Result<Input> {
+
+    context.set_stage(Stage::Read);
+    context.status(0.0, "Reading WAV file".to_string());
+
+    let (signal, input_spec) = wav::load_wav(&settings.input_filename)?;
+    read_signal(context, settings, signal, Rate::hz(input_spec.sample_rate))
+}
+
+/// Same as `read()` with the samples already loaded, for example from memory
+/// with `wav::read_wav_from_bytes()`. `settings.input_filename` is ignored.
+pub fn read_signal(
+    context: &mut Context,
+    settings: &config::DecodeSettings,
+    mut signal: Signal,
+    mut input_rate: Rate,
+) -> err::Result<Input> {
+
+    context.set_stage(Stage::Read);
+    check_input_rate(input_rate)?;
+
+    if settings.correct_rate {
+        let offset = dsp::estimate_freq_offset(
+            &signal, input_rate, CARRIER_FREQ as f32)?;
+        input_rate = dsp::correct_rate(input_rate, CARRIER_FREQ as f32, offset);
+        info!("Carrier offset of {}Hz, using sample rate of {}Hz",
+            offset, input_rate.get_hz());
+    }
+
+    // Lines before the ones requested, left on the signal for the filters.
+    // The rate correction doesn't move the samples, only makes the line
+    // positions more accurate, but trimming would, so crop on the original
+    // recording instead
+    let mut margin_lines = 0;
+    if let Some(range) = settings.line_range {
+        if settings.trim {
+            info!("Decoding a range of lines, not trimming the recording");
+        }
+        let (cropped, margin) = crop_lines(&signal, input_rate, range)?;
+        signal = cropped;
+        margin_lines = margin;
+    } else if settings.trim {
+        signal = auto_trim(&signal, input_rate)?;
+    }
+
+    dsp::auto_gain(&mut signal);
+
+    context.step(Step::signal("input", &signal, Some(input_rate)))?;
+
+    Ok(Input { signal, rate: input_rate, margin_lines })
+}
+
+/// Resample the input to `settings.work_rate`, second stage of `decode()`.
+pub fn resample(
+    context: &mut Context,
+    settings: &config::DecodeSettings,
+    input: &Input,
+) -> err::Result<Signal> {
+
+    let work_rate = Rate::hz(settings.work_rate);
+
+    context.set_stage(Stage::Resample);
+    context.status(0.1, format!("Resampling to {}", work_rate.get_hz()));
+
+    let signal = if settings.compensate_droop {
+        // Between both transition bands of the filter
+        let passband = (
+            settings.resample_delta_freq,
+            settings.resample_cutout - settings.resample_delta_freq / 2.,
+        );
+        dsp::resample_compensated(
+            context, &input.signal, input.rate, work_rate,
+            resample_filter(settings, input.rate), passband)?
+    } else {
+        dsp::resample_with_filter(
+            context, &input.signal, input.rate, work_rate,
+            resample_filter(settings, input.rate))?
+    };
+
+    if signal.len() < 10 * samples_per_work_row(settings) as usize {
+        return Err(err::Error::Internal(
+            "Got less than 10 rows of samples, audio file is too short".to_string()));
+    }
+
+    Ok(signal)
+}
+
+/// Demodulate and filter the signal on the work rate, third stage of
+/// `decode()`.
+pub fn demodulate(
+    context: &mut Context,
+    settings: &config::DecodeSettings,
+    signal: &Signal,
+) -> err::Result<Signal> {
+
+    let work_rate = Rate::hz(settings.work_rate);
+
+    context.set_stage(Stage::Demodulate);
+    context.status(0.4, "Demodulating".to_string());
+
+    let filter = demodulation_filter(settings, work_rate);
+
+    // The square law detector has its own lowpass filter, filtering again
+    // would only add delay
+    let mut signal = match settings.demod_method {
+        DemodMethod::TwoSample => {
+            let signal = dsp::demodulate(
+                context, signal, Freq::hz(CARRIER_FREQ as f32, work_rate))?;
+
+            context.status(0.42, "Filtering".to_string());
+            dsp::filter(context, &signal, filter)?
+        },
+        DemodMethod::SquareLaw => dsp::square_law_demod(
+            context, signal, filter.cutout, filter.atten)?,
+    };
+
+    if let Some(tau) = settings.deemphasis {
+        info!("De-emphasizing signal with time constant: {}s", tau);
+        signal = dsp::deemphasis(&signal, work_rate, tau);
+    }
+
+    if let Some(ref csv_filename) = settings.csv_filename {
+        info!("Writing demodulated signal to '{}'", csv_filename);
+        misc::write_csv(csv_filename, &signal)?;
+    }
+
+    Ok(signal)
+}
+
+/// Sync the demodulated signal and resample it to `FINAL_RATE`, last stage
+/// of `decode()` before mapping to pixels.
+///
+/// Returns the image as a signal, `PX_PER_ROW` values per line, and the sync
+/// frames found. `margin_lines` comes from `Input`.
+pub fn assemble(
+    context: &mut Context,
+    settings: &config::DecodeSettings,
+    mut signal: Signal,
+    margin_lines: usize,
+) -> err::Result<(Signal, Vec<SyncHit>)> {
+
+    let work_rate = Rate::hz(settings.work_rate);
+    let final_rate = Rate::hz(FINAL_RATE);
+    let samples_per_work_row = samples_per_work_row(settings);
+
+    let mut sync_hits: Vec<SyncHit> = Vec::new();
+
+    context.set_stage(Stage::Assemble);
+
+    if settings.sync {
+        context.status(0.5, "Syncing".to_string());
+
+        sync_hits = find_sync(context, &signal, work_rate, settings.sync_threshold)?;
+
+        if sync_hits.len() < 5 {
+            return Err(err::Error::Internal(
+                "Found less than 5 sync frames, audio file is too short or too \
+                noisy".to_string())
+            );
+        }
+
+        let positions: Vec<usize> = sync_hits.iter().map(|hit| hit.index).collect();
+        if let Err(e) = verify_sync_spacing(&positions, f64::from(samples_per_work_row)) {
+            warn!("{}", e);
+        }
+
+        // Each row starts on a found sync frame position, or on a predicted one
+        // where the sync frame is missing
+        let sync_pos = row_starts(&sync_hits, f64::from(samples_per_work_row));
+
+        // Drop rows of the margin left by crop_lines()
+        let margin_end = (margin_lines * samples_per_work_row as usize)
+            .saturating_sub(samples_per_work_row as usize / 2);
+        let sync_pos: Vec<usize> = sync_pos.into_iter()
+            .filter(|pos| *pos >= margin_end)
+            .collect();
+
+        // Create new "aligned" vector to samples_per_work_row
+        let mut aligned: Signal = Vec::new();
+
+        // For each sync position
+        for i in 0..sync_pos.len().saturating_sub(1) {
+            // Check if there are enough samples left to fill an image row
+            if (sync_pos[i] + samples_per_work_row as usize) < signal.len() {
+
+                aligned.extend_from_slice(
+                    &signal[sync_pos[i] .. sync_pos[i] + samples_per_work_row as usize]
+                );
+            }
+        }
+
+        signal = aligned;
+
+    } else {
+        context.status(0.5, "Skipping Syncing".to_string());
+
+        // If we are not syncing send a dummy correlation step
+        context.step(Step::signal("sync_correlation", &vec![], Some(work_rate)))?;
+
+        // Crop signal to multiple of samples_per_work_row
+        let length = signal.len();
+        signal.truncate(length
+            / samples_per_work_row as usize // Integer division
+            * samples_per_work_row as usize
+        );
+
+        // Drop rows of the margin left by crop_lines()
+        let margin_len = std::cmp::min(
+            margin_lines * samples_per_work_row as usize, signal.len());
+        signal.drain(..margin_len);
+    }
+
+    if let Some((first, last)) = settings.line_range {
+        signal.truncate((last - first) * samples_per_work_row as usize);
+    }
+
+    context.step(Step::signal("sync_result", &signal, Some(work_rate)))?;
+
+    // --------------------
+
+    context.status(0.90, "Resampling to 4160".to_string());
+
+    // Resample without filter because we already filtered the signal before
+    // syncing
+    let mut signal = dsp::resample_with_filter(
+        context, &signal, work_rate, final_rate, filters::NoFilter)?;
+
+
+    if let Some(mu) = settings.compand {
+        info!("Companding signal with mu: {}", mu);
+        dsp::compand(&mut signal, mu);
+    }
+
+    Ok((signal, sync_hits))
+}
+
+/// Samples on each image row when at `settings.work_rate`.
+fn samples_per_work_row(settings: &config::DecodeSettings) -> u32 {
+    PX_PER_ROW * settings.work_rate / FINAL_RATE
+}
+
+/// Save the signal between stages of `decode()` as a 32 bit float WAV.
+///
+/// For example save the output of `resample()` once and then try different
+/// demodulation settings with `load_intermediate()`. The samples are
+/// normalized like on every WAV written by `wav::write_wav()`, that doesn't
+/// change the image because the contrast is adjusted at the end.
+pub fn save_intermediate(filename: &str, signal: &Signal, rate: Rate) -> err::Result<()> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: rate.get_hz(),
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    wav::write_wav(filename, signal, spec)
+}
+
+/// Load signal saved with `save_intermediate()`, returns it with its sample
+/// rate.
+pub fn load_intermediate(filename: &str) -> err::Result<(Signal, Rate)> {
+    let (signal, spec) = wav::load_wav(filename)?;
+    Ok((signal, Rate::hz(spec.sample_rate)))
+}
+
+/// Decode APT image from WAV file.
+///
+/// Runs `read()`, `resample()`, `demodulate()`, `assemble()` and
+/// `map_image()`, then writes every output asked on the settings.
+pub fn decode(
+    mut context: Context,
+    settings: config::DecodeSettings,
+) -> err::Result<()>{
+
+    // --------------------
+
+    let settings = preview_settings(settings);
+
+    context.deterministic = settings.deterministic;
+    context.high_precision = settings.high_precision;
+
+    let input = read(&mut context, &settings)?;
+
+    if settings.line_snr {
+        let snr_filename = sidecar_filename(&settings.output_filename, "_snr.csv");
+        context.status(0.05, format!("Writing SNR of each line to '{}'", snr_filename));
+        misc::write_csv(&snr_filename, &per_line_snr(&input.signal, input.rate)?)?;
+    }
+
+    let signal = resample(&mut context, &settings, &input)?;
+    let signal = demodulate(&mut context, &settings, &signal)?;
+    let (signal, sync_hits) = assemble(&mut context, &settings, signal, input.margin_lines)?;
+    let (signal, mapped) = map_image(&mut context, &settings, signal, &sync_hits)?;
+
+    // --------------------
+
+    context.set_stage(Stage::Write);
+    context.status(0.95, format!("Writing image to '{}'", settings.output_filename));
+
+    let (output, output_width) = select_output(&settings, &mapped)?;
+    let (image, color) = apply_output_palette(&settings, output.clone());
+    write_image(&settings.output_filename, &image, output_width, color,
+                settings.compression.clone())?;
+
+    if let Some(max_size) = settings.thumbnail_size {
+        let thumbnail_filename = sidecar_filename(&settings.output_filename, "_thumb.png");
+        context.status(0.96, format!("Writing thumbnail to '{}'", thumbnail_filename));
+
+        let height = image.len() / output_width as usize / color.samples();
+        let (width, height) = processing::thumbnail_size(
+            output_width as usize, height, max_size);
+        let thumbnail = processing::resize_image(
+            &image, output_width as usize, color.samples(), width, height)?;
+        write_image(&thumbnail_filename, &thumbnail, width as u32, color,
+                    settings.compression.clone())?;
+    }
+
+    // Branch here so we don't need to decode again for the raw image
+    if let Some(ref raw_filename) = settings.raw_output_filename {
+        context.status(0.97, format!("Writing raw image to '{}'", raw_filename));
+
+        let raw = map_channels(&signal, &Contrast::MinMax, &Contrast::MinMax, None)?;
+        let (raw, _) = select_output(&settings, &raw)?;
+        write_image(raw_filename, &raw, output_width, png::ColorType::Grayscale,
+                    settings.compression.clone())?;
+    }
+
+    if let Some(ref histogram_filename) = settings.histogram_filename {
+        context.status(0.98, format!("Writing histogram to '{}'", histogram_filename));
+
+        let histogram = processing::image_histogram(&output);
+        let chart = processing::histogram_chart(&histogram, HISTOGRAM_HEIGHT);
+        write_image(histogram_filename, &chart, 256, png::ColorType::Grayscale,
+                    settings.compression.clone())?;
+    }
+
+    // --------------------
+
+    context.status(1., "Finished".to_string());
+    Ok(())
+}
+
+/// Read telemetry if needed, fix swapped channels and map the signal to
+/// pixels, the step of `decode()` after `assemble()`.
+///
+/// Returns the signal, moved by half a line if the channels were swapped and
+/// `settings.fix_swapped_channels` is set, and the mapped image with both
+/// channels.
+pub fn map_image(
+    context: &mut Context,
+    settings: &config::DecodeSettings,
+    signal: Signal,
+    sync_hits: &[SyncHit],
+) -> err::Result<(Signal, Vec<u8>)> {
+
+    // Read telemetry only if some channel needs it
+    let telemetry = match (&settings.channel_a_contrast, &settings.channel_b_contrast) {
+        (Contrast::Telemetry, _) | (_, Contrast::Telemetry) => {
+            if !settings.sync {
+                warn!("Reading telemetry without syncing, expect horrible results!");
+            }
+            Some(telemetry::read_telemetry(context, &signal, settings.telemetry_lines)?)
+        },
+        _ => None,
+    };
+
+    let swapped = telemetry.as_ref().is_some_and(telemetry::detect_channel_swap);
+
+    let (signal, telemetry, sync_hits) = if swapped && settings.fix_swapped_channels {
+        warn!("Channels A and B look swapped, moving lines by half a line");
+        let signal = shift_half_line(&signal);
+        let telemetry = telemetry::read_telemetry(
+            context, &signal, settings.telemetry_lines)?;
+        let sync_hits = shift_hits_half_line(sync_hits, samples_per_work_row(settings));
+        (signal, Some(telemetry), sync_hits)
+    } else {
+        if swapped {
+            warn!("Channels A and B look swapped, use --fix-swapped-channels \
+                to move lines by half a line");
+        }
+        (signal, telemetry, sync_hits.to_vec())
+    };
+
+    let mapped = map_channels(
+        &signal,
+        &settings.channel_a_contrast,
+        &settings.channel_b_contrast,
+        telemetry.as_ref(),
+    )?;
+
+    match decode_quality(&signal, &mapped, &sync_hits) {
+        Ok(quality) => info!("Decode quality: {:.2}", quality),
+        Err(e) => info!("Decode quality not available: {}", e),
+    }
+
+    context.step(Step::signal(
+            "mapped",
+            &mapped.iter().map(|x| f32::from(*x)).collect(),
+            Some(Rate::hz(FINAL_RATE))
+    ))?;
+
+    Ok((signal, mapped))
+}
+
+/// Channels of the mapped image asked on the settings, downscaled on preview
+/// mode and fitted on the output size if there is one. Returns the image and
+/// its width.
+pub fn select_output(
+    settings: &config::DecodeSettings,
+    mapped: &[u8],
+) -> err::Result<(Vec<u8>, u32)> {
+    let output = settings.output_channels.select(mapped);
+    let width = settings.output_channels.width();
+
+    let (output, width) = if settings.preview {
+        let (output, width, _) = processing::downscale_nearest(
+            &output, width as usize, 1, PREVIEW_SCALE)?;
+        (output, width as u32)
+    } else {
+        (output, width)
+    };
+
+    match settings.output_size {
+        Some((target_width, target_height)) => {
+            let output = processing::fit_image(
+                &output, width as usize, 1, target_width, target_height, 0)?;
+            Ok((output, target_width as u32))
+        },
+        None => Ok((output, width)),
+    }
+}
+
+/// Color the output image with the palette on the settings, if any.
+///
+/// Returns the image with its color type, grayscale images are returned as
+/// they are.
+pub fn apply_output_palette(
+    settings: &config::DecodeSettings,
+    output: Vec<u8>,
+) -> (Vec<u8>, png::ColorType) {
+    match settings.palette {
+        Some(palette) => (
+            processing::apply_palette(&output, &palette.lut()),
+            png::ColorType::RGB,
+        ),
+        None => (output, png::ColorType::Grayscale),
+    }
+}
+
+/// Decode image with the default settings.
+///
+/// For when you just want an image: uses the same defaults as the commandline
+/// (see `config::DecodeSettings::new()`) and also corrects the sample rate
+/// and removes the noise at the start and end of the recording.
+pub fn decode_auto(input_filename: &str, output_filename: &str) -> err::Result<()> {
+    let settings = config::DecodeSettings {
+        correct_rate: true,
+        trim: true,
+        ..config::DecodeSettings::new(input_filename, output_filename)
+    };
+
+    let context = Context::decode(
+        |_progress, description| info!("{}", description),
+        Rate::hz(settings.work_rate),
+        Rate::hz(FINAL_RATE),
+        false,
+        false,
+    );
+
+    decode(context, settings)
+}
+
+/// Estimate frequency of a tone by counting zero crossings.
+///
+/// Counts rising zero crossings, the position of each one is linearly
+/// interpolated between samples so the result has subsample precision. Returns
+/// `None` if there are less than two crossings.
+fn zero_crossing_freq(signal: &[f32], rate: Rate) -> Option<f32> {
+
+    let mut first: Option<f32> = None;
+    let mut last: f32 = 0.;
+    let mut count: usize = 0;
+
+    for i in 1..signal.len() {
+        if signal[i - 1] < 0. && signal[i] >= 0. {
+            // Interpolated position of the crossing
+            let pos = (i - 1) as f32 + signal[i - 1] / (signal[i - 1] - signal[i]);
+            if first.is_none() {
+                first = Some(pos);
+            }
+            last = pos;
+            count += 1;
+        }
+    }
+
+    match first {
+        Some(first) if count >= 2 && last > first =>
+            Some((count - 1) as f32 / (last - first) * rate.get_hz() as f32),
+        _ => None,
+    }
+}
+
+/// Fraction of the power of a block that should be near the carrier to
+/// consider that there is signal.
+const CARRIER_POWER_THRESHOLD: f32 = 0.2;
+
+/// Fraction of the power of each block of `block_len` samples that is near the
+/// carrier.
+///
+/// Measured from `CARRIER_FREQ - 150Hz` to `CARRIER_FREQ + 150Hz`, wide enough
+/// for the Doppler shift. White noise has a small fraction while the APT
+/// signal has most of its power on the carrier. Usually called with blocks of
+/// one second.
+fn carrier_power(signal: &[f32], rate: Rate, block_len: usize) -> err::Result<Vec<f32>> {
+    let hz_per_bin = rate.get_hz() as f32 / block_len as f32;
+    let low = ((CARRIER_FREQ as f32 - 150.) / hz_per_bin) as usize;
+    let high = ((CARRIER_FREQ as f32 + 150.) / hz_per_bin) as usize;
+
+    signal.chunks_exact(block_len).map(|block| {
+        let spectrum = dsp::abs_fft(&block.to_vec())?;
+        // Only positive frequencies
+        let power: Signal = spectrum[..block_len / 2].iter().map(|x| x.powi(2)).collect();
+        let total: f32 = power.iter().sum();
+        Ok(if total > 0. {
+            power[low..=high.min(power.len() - 1)].iter().sum::<f32>() / total
+        } else {
+            0.
+        })
+    }).collect()
+}
+
+/// SNR in dB of each line of the AM signal.
+///
+/// Compares the power density between `CARRIER_FREQ - FINAL_RATE / 2` and
+/// `CARRIER_FREQ + FINAL_RATE / 2`, where the APT signal is, against the power
+/// density from there to the Nyquist frequency, where there is only noise. Pure
+/// white noise gives around 0dB. Lines are counted from the start of the signal,
+/// so they don't match the image rows exactly unless the signal starts on a
+/// sync frame. Fails if the sample rate leaves no band for measuring the noise.
+pub fn per_line_snr(signal: &Signal, rate: Rate) -> err::Result<Signal> {
+    let samples_per_line = f64::from(rate.get_hz()) * f64::from(PX_PER_ROW) / f64::from(FINAL_RATE);
+    let line_len = samples_per_line as usize;
+    let band_end = (CARRIER_FREQ + FINAL_RATE / 2) as f32;
+    let hz_per_bin = rate.get_hz() as f32 / line_len as f32;
+
+    let in_band = ((CARRIER_FREQ - FINAL_RATE / 2) as f32 / hz_per_bin).ceil() as usize
+        ..(band_end / hz_per_bin) as usize;
+    let out_band = (band_end / hz_per_bin).ceil() as usize + 1 .. line_len / 2;
+    if out_band.len() < 2 {
+        return Err(err::Error::Internal(format!(
+            "Can't measure SNR at {}Hz, there is nothing above {}Hz",
+            rate.get_hz(), band_end)));
+    }
+
+    let lines = (signal.len() as f64 / samples_per_line) as usize;
+    (0..lines).map(|line| {
+        let start = (line as f64 * samples_per_line) as usize;
+        let spectrum = dsp::abs_fft(&signal[start..start + line_len].to_vec())?;
+        let density = |band: &Range<usize>| {
+            spectrum[band.clone()].iter().map(|x| x.powi(2)).sum::<f32>() / band.len() as f32
+        };
+
+        let noise = density(&out_band);
+        Ok(if noise > 0. {
+            10. * (density(&in_band) / noise).log10()
+        } else {
+            f32::INFINITY
+        })
+    }).collect()
+}
+
+/// Find where the signal starts.
+///
+/// Returns the index of the first sample of the first second of the recording
+/// where the carrier is clearly present, or `None` if there is no signal.
+pub fn find_signal_start(signal: &Signal, rate: Rate) -> err::Result<Option<usize>> {
+    Ok(carrier_power(signal, rate, rate.get_hz() as usize)?.iter()
+        .position(|x| *x > CARRIER_POWER_THRESHOLD)
+        .map(|block| block * rate.get_hz() as usize))
+}
+
+/// Find where the signal ends.
+///
+/// Same as `find_signal_start()` but from the back, returns the index after
+/// the last part where the carrier is clearly present.
+///
+/// The pass usually ends with the carrier fading on the noise, so after
+/// finding the last second with carrier I check its second half (one line)
+/// again on its own, that way at most half a second of static is left.
+pub fn find_signal_end(signal: &Signal, rate: Rate) -> err::Result<Option<usize>> {
+    let block_len = rate.get_hz() as usize;
+    let last = match carrier_power(signal, rate, block_len)?.iter()
+        .rposition(|x| *x > CARRIER_POWER_THRESHOLD)
+    {
+        Some(block) => block,
+        None => return Ok(None),
+    };
+
+    let half = block_len / 2;
+    let start = last * block_len;
+    let end = start + block_len;
+    let power = carrier_power(&signal[start + half..end], rate, end - start - half)?;
+    Ok(Some(if power.iter().all(|x| *x > CARRIER_POWER_THRESHOLD) {
+        end
+    } else {
+        start + half
+    }))
+}
+
+/// Find the parts of the recording with carrier.
+///
+/// Returns the ranges of samples where the carrier is clearly present, with a
+/// resolution of one second like `find_signal_start()`. When the signal drops
+/// out in the middle of the pass each part can be decoded on its own image.
+pub fn segment_by_carrier(signal: &Signal, rate: Rate) -> err::Result<Vec<Range<usize>>> {
+    let block_len = rate.get_hz() as usize;
+
+    let mut segments: Vec<Range<usize>> = Vec::new();
+    let mut start: Option<usize> = None;
+
+    let power = carrier_power(signal, rate, block_len)?;
+    for (block, x) in power.iter().enumerate() {
+        match (*x > CARRIER_POWER_THRESHOLD, start) {
+            (true, None) => start = Some(block),
+            (false, Some(first)) => {
+                segments.push(first * block_len .. block * block_len);
+                start = None;
+            },
+            _ => (),
+        }
+    }
+    if let Some(first) = start {
+        segments.push(first * block_len .. power.len() * block_len);
+    }
+
+    Ok(segments)
+}
+
+/// Remove the start and end of the recording where there is no signal.
+///
+/// Passes start and end with the satellite below the horizon, so there is only
+/// noise. Works with a resolution of one second. If no signal is found the
+/// recording is returned as is.
+pub fn auto_trim(signal: &Signal, rate: Rate) -> err::Result<Signal> {
+    Ok(match (find_signal_start(signal, rate)?, find_signal_end(signal, rate)?) {
+        (Some(start), Some(end)) => {
+            info!("Trimming recording from {}s to {}s",
+                start / rate.get_hz() as usize, end / rate.get_hz() as usize);
+            signal[start..end].to_vec()
+        },
+        _ => {
+            warn!("No carrier found for trimming the recording");
+            signal.clone()
+        },
+    })
+}
+
+/// Lines kept before and after the range given to `crop_lines()`, so the
+/// filters have time to settle and the sync frames of the first line can be
+/// found.
+const LINE_RANGE_MARGIN: usize = 1;
+
+/// Keep only the samples of lines `first` to `last` (not included), plus
+/// `LINE_RANGE_MARGIN` lines on each side if available.
+///
+/// Lines are counted from the start of the recording, every half second, so
+/// this should be done before any trimming. Returns the cropped signal and how many margin lines were kept before the
+/// range.
+pub fn crop_lines(
+    signal: &Signal,
+    rate: Rate,
+    (first, last): (usize, usize),
+) -> err::Result<(Signal, usize)> {
+    if first >= last {
+        return Err(err::Error::Internal(format!(
+            "Invalid line range {}-{}, the end should be after the start", first, last)));
+    }
+
+    let samples_per_line = f64::from(rate.get_hz()) * f64::from(PX_PER_ROW)
+        / f64::from(FINAL_RATE);
+    let sample = |line: usize| (line as f64 * samples_per_line).round() as usize;
+
+    let margin = std::cmp::min(first, LINE_RANGE_MARGIN);
+    let start = sample(first - margin);
+    let end = std::cmp::min(sample(last + LINE_RANGE_MARGIN), signal.len());
+
+    if start >= end {
+        return Err(err::Error::Internal(format!(
+            "Invalid line range {}-{}, the recording has only {} lines",
+            first, last, (signal.len() as f64 / samples_per_line) as usize)));
+    }
+
+    info!("Decoding only lines {} to {}", first, last);
+
+    Ok((signal[start..end].to_vec(), margin))
+}
+
+/// Estimate the time of closest approach (TCA) of the satellite.
+///
+/// Returns the amount of seconds from the start of the recording.
+///
+/// The subcarrier frequency is tracked on blocks of one second and should
+/// follow a Doppler curve: higher than usual while the satellite approaches and
+/// lower while it moves away. The TCA is the zero-Doppler crossing, which is
+/// also where the frequency falls faster. I look for the steepest point instead
+/// of comparing against `CARRIER_FREQ` so a constant error on the sample rate
+/// doesn't move the result.
+pub fn estimate_tca(signal: &Signal, rate: Rate) -> err::Result<f32> {
+
+    let block_len = rate.get_hz() as usize;
+
+    // Frequency of each block, the time in seconds of each value is on the
+    // center of the block
+    let freqs: Vec<f32> = signal.chunks_exact(block_len)
+        .map(|block| zero_crossing_freq(block, rate))
+        .collect::<Option<Vec<f32>>>()
+        .ok_or_else(|| err::Error::Internal(
+            "Could not find subcarrier for Doppler estimation".to_string()))?;
+
+    if freqs.len() < 5 {
+        return Err(err::Error::Internal(
+            "Recording too short for Doppler estimation".to_string()));
+    }
+
+    // Frequency decrease between blocks, smoothed by averaging three values.
+    // Value i is located between blocks i+1 and i+2, so on i + 2 seconds
+    let descent: Vec<f32> = freqs.windows(4)
+        .map(|f| (f[0] - f[3]) / 3.)
+        .collect();
+
+    let (i, _) = descent.iter().enumerate()
+        .max_by(|a, b| a.1.partial_cmp(b.1).expect("Can't compare values"))
+        .expect("Empty descent");
+
+    // The peak is wide and the values are a little noisy, so instead of
+    // interpolating with only three values I fit a parabola by least squares
+    // using up to 5 values on each side of the maximum
+    let k = 5.min(i).min(descent.len() - 1 - i);
+    let window = &descent[i - k ..= i + k];
+    let n = window.len() as f32;
+    let (mut sum_y, mut sum_xy, mut sum_x2, mut sum_x2y, mut sum_x4) = (0., 0., 0., 0., 0.);
+    for (j, y) in window.iter().enumerate() {
+        let x = j as f32 - k as f32;
+        sum_y += y;
+        sum_xy += x * y;
+        sum_x2 += x.powi(2);
+        sum_x2y += x.powi(2) * y;
+        sum_x4 += x.powi(4);
+    }
+    // Coefficients of y = c0 + c1 * x + c2 * x^2, the values of x are
+    // symmetric so the fit is simpler
+    let c1 = if sum_x2 > 0. { sum_xy / sum_x2 } else { 0. };
+    let c2 = if k > 0 {
+        (sum_x2y - sum_x2 * sum_y / n) / (sum_x4 - sum_x2.powi(2) / n)
+    } else {
+        0.
+    };
+    // Use the vertex only if it's a maximum and falls near the value found
+    let offset = if c2 < 0. && c1.abs() < -2. * c2 { -c1 / (2. * c2) } else { 0. };
+
+    let tca = i as f32 + offset + 2.;
+
+    info!("Estimated time of closest approach: {}s", tca);
+
+    Ok(tca)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use test_util::{self, temp_path};
+
+    #[test]
+    fn test_sample_sync_frame() {
+
+        assert_eq!(
+            vec![-1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+                  1,  1,  1,  1,  1,  1,  1,  1,  1,  1,
+                 -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+                  1,  1,  1,  1,  1,  1,  1,  1,  1,  1,
+                 -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+                  1,  1,  1,  1,  1,  1,  1,  1,  1,  1,
+                 -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+                  1,  1,  1,  1,  1,  1,  1,  1,  1,  1,
                  -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
                   1,  1,  1,  1,  1,  1,  1,  1,  1,  1,
                  -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
@@ -445,6 +2070,186 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_check_input_rate() {
+        assert_eq!(MIN_PROCESSING_RATE, 8960);
+        assert!(check_input_rate(Rate::hz(8960)).is_ok());
+        assert!(check_input_rate(Rate::hz(11025)).is_ok());
+
+        match check_input_rate(Rate::hz(8000)) {
+            Err(err::Error::WavOpen(msg)) => assert_eq!(msg,
+                "Sample rate 8000Hz too low, need at least 8960Hz for the 2400Hz \
+                carrier and its sidebands"),
+            _ => panic!("Expected WavOpen error"),
+        }
+
+        // Fails when decoding too
+        let filename = &temp_path("8000hz.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let signal: Signal = (0..8000).map(|i| (i as f32).sin()).collect();
+        wav::write_wav(filename, &signal, spec).unwrap();
+
+        let context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                      Rate::hz(FINAL_RATE), false, false);
+        let result = decode(context, test_util::decode_settings(filename, "unused.png"));
+        assert!(matches!(result, Err(err::Error::WavOpen(_))));
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_best_processing_rate() {
+        let profiles = [12480, 16640, 20800];
+
+        // 12480 and 20800 need filters of the same length, the lowest wins
+        assert_eq!(best_processing_rate(11025, &profiles), Some(12480));
+        assert_eq!(best_processing_rate(48000, &profiles), Some(12480));
+
+        // Same rate as the input, no resampling needed
+        assert_eq!(best_processing_rate(11025, &[11024, 11025, 12480]), Some(11025));
+        assert_eq!(best_processing_rate(44099, &[11025, 44099]), Some(44099));
+        assert_eq!(best_processing_rate(44100, &[11025, 11026, 12480]), Some(11025));
+
+        // Too low for the APT signal
+        assert_eq!(best_processing_rate(8000, &[8000, 12480]), Some(12480));
+        assert_eq!(best_processing_rate(8000, &[4160, 8000]), None);
+        assert_eq!(best_processing_rate(8000, &[]), None);
+    }
+
+    #[test]
+    fn test_find_sync() {
+        let work_rate = Rate::hz(FINAL_RATE * 3);
+        let samples_per_work_row = (PX_PER_ROW * 3) as usize;
+        let guard = generate_sync_frame(work_rate).unwrap();
+
+        let mut noise = test_util::noise(1234);
+
+        // Even lines have strong sync frames, odd lines have weak ones
+        let offset = 100;
+        let mut signal: Signal = Vec::new();
+        for line in 0..10 {
+            let amplitude = if line % 2 == 0 { 3. } else { 0.1 };
+            for i in 0..samples_per_work_row {
+                let sync = if i >= offset && i < offset + guard.len() && guard[i - offset] == 1 {
+                    amplitude
+                } else {
+                    0.
+                };
+                signal.push(sync + noise());
+            }
+        }
+
+        let context = &mut Context::resample(|_,_| {}, false, false);
+
+        // Keep everything
+        let hits = find_sync(context, &signal, work_rate, -1.).unwrap();
+        let strong: Vec<&SyncHit> = hits.iter()
+            .filter(|hit| (hit.index - offset) % (2 * samples_per_work_row) == 0).collect();
+        assert_eq!(strong.len(), 5);
+        assert!(strong.iter().all(|hit| hit.confidence > 0.8));
+        assert!(hits.iter().filter(|hit| !strong.contains(hit)).all(|hit| hit.confidence < 0.5));
+
+        // The default threshold is below any confidence, so it keeps
+        // everything too
+        let default = config::DecodeSettings::new("", "").sync_threshold;
+        assert!(default < -1.);
+        assert_eq!(find_sync(context, &signal, work_rate, default).unwrap(), hits);
+
+        // Discard weak sync frames
+        let hits = find_sync(context, &signal, work_rate, 0.6).unwrap();
+        assert_eq!(
+            hits.iter().map(|hit| hit.index).collect::<Vec<usize>>(),
+            (0..5).map(|i| offset + i * 2 * samples_per_work_row).collect::<Vec<usize>>()
+        );
+    }
+
+    #[test]
+    fn test_find_sync_subsample() {
+        let work_rate = Rate::hz(FINAL_RATE * 3);
+        let samples_per_work_row = (PX_PER_ROW * 3) as usize;
+        let guard = generate_sync_frame(work_rate).unwrap();
+
+        // Sync frame with the edges linearly interpolated, so it can start
+        // between samples
+        let frame = |x: f32| {
+            if x < 0. || x >= (guard.len() - 1) as f32 {
+                return 0.;
+            }
+            let i = x.floor() as usize;
+            let f = x - i as f32;
+            f32::from(guard[i]) * (1. - f) + f32::from(guard[i + 1]) * f
+        };
+
+        let fractions: [f32; 8] = [0., 0.2, 0.4, 0.5, 0.7, 0.9, 0.1, 0.3];
+        let starts: Vec<f64> = fractions.iter().enumerate()
+            .map(|(line, f)| (100 + line * samples_per_work_row) as f64 + f64::from(*f))
+            .collect();
+
+        let signal: Signal = (0..fractions.len() * samples_per_work_row).map(|i| {
+            let line = i / samples_per_work_row;
+            frame((i as f64 - starts[line]) as f32)
+        }).collect();
+
+        let context = &mut Context::resample(|_,_| {}, false, false);
+        let hits = find_sync(context, &signal, work_rate, 0.5).unwrap();
+        assert_eq!(hits.len(), fractions.len());
+
+        for (hit, start) in hits.iter().zip(starts.iter()) {
+            assert!(hit.fraction.abs() <= 0.5);
+            assert!((hit.position() - start).abs() < 0.15,
+                "Found {} instead of {}", hit.position(), start);
+        }
+    }
+
+    #[test]
+    fn test_parabolic_peak() {
+        // y = -(x - 0.3)^2 sampled on -1, 0, 1
+        let y = |x: f32| -(x - 0.3).powi(2);
+        assert_relative_eq!(parabolic_peak(y(-1.), y(0.), y(1.)), 0.3, epsilon = 1e-6);
+        assert_eq!(parabolic_peak(1., 1., 1.), 0.);
+        assert_eq!(parabolic_peak(1., 2., 1.), 0.);
+    }
+
+    #[test]
+    fn test_decode_quality() {
+        let lines = 20;
+        let width = PX_PER_ROW as usize;
+
+        let mut noise = test_util::noise(42);
+
+        // Smooth image, with a vertical edge in the middle
+        let clean: Signal = (0..lines * width).map(|i| {
+            let x = i % width;
+            (x as f32 / 300.).sin() + if x > width / 2 { 1. } else { 0. }
+        }).collect();
+        let clean_image = map(&clean, *dsp::get_min(&clean).unwrap(), *dsp::get_max(&clean).unwrap());
+        let clean_hits: Vec<SyncHit> = (0..lines)
+            .map(|i| SyncHit { index: i * width, fraction: 0., confidence: 0.95 })
+            .collect();
+
+        let quality = decode_quality(&clean, &clean_image, &clean_hits).unwrap();
+        assert!(quality > 0.9, "Clean quality: {}", quality);
+
+        // Same image with a lot of noise, few sync frames and bad contrast
+        let noisy: Signal = clean.iter().map(|x| x + 4. * noise()).collect();
+        let noisy_image = map(&noisy, -0.5, 1.5);
+        let noisy_hits: Vec<SyncHit> = (0..lines / 4)
+            .map(|i| SyncHit { index: i * 4 * width, fraction: 0., confidence: 0.4 })
+            .collect();
+
+        let quality = decode_quality(&noisy, &noisy_image, &noisy_hits).unwrap();
+        assert!(quality < 0.3, "Noisy quality: {}", quality);
+
+        assert!(decode_quality(&Signal::new(), &[], &[]).is_err());
+        let short = clean[..width / 2].to_vec();
+        assert!(decode_quality(&short, &clean_image[..width / 2], &clean_hits).is_err());
+        assert!(decode_quality(&clean, &clean_image, &[]).is_err());
+    }
+
     #[test]
     fn test_map() {
         let expected: Vec<u8> = vec![
@@ -462,4 +2267,1100 @@ mod tests {
 
         assert_eq!(expected, map(&shifted_values, low, high));
     }
+
+    #[test]
+    fn test_split_join_channels() {
+        let half = PX_PER_ROW as usize / 2;
+
+        // Three lines, channel A has values equal to the line number and
+        // channel B the line number plus 100. Also some extra values that
+        // don't fill a line
+        let mut signal: Vec<u32> = Vec::new();
+        for line in 0..3 {
+            signal.extend_from_slice(&vec![line; half]);
+            signal.extend_from_slice(&vec![line + 100; half]);
+        }
+        signal.extend_from_slice(&[1000; 10]);
+
+        let (channel_a, channel_b) = split_channels(&signal);
+
+        assert_eq!(channel_a.len(), 3 * half);
+        assert_eq!(channel_b.len(), 3 * half);
+        assert!(channel_a.chunks(half).enumerate()
+            .all(|(i, line)| line.iter().all(|x| *x == i as u32)));
+        assert!(channel_b.chunks(half).enumerate()
+            .all(|(i, line)| line.iter().all(|x| *x == i as u32 + 100)));
+
+        assert_eq!(&join_channels(&channel_a, &channel_b)[..], &signal[..3 * PX_PER_ROW as usize]);
+    }
+
+    #[test]
+    fn test_map_channels() {
+        let half = PX_PER_ROW as usize / 2;
+
+        // Channel A has values from 0 to 100, channel B from 1000 to 2000. If
+        // both channels were mapped together, channel A would be all black
+        let signal: Signal = (0..4)
+            .flat_map(|_line|
+                (0..half).map(|x| x as f32 / half as f32 * 100.)
+                    .chain((0..half).map(|x| 1000. + x as f32 / half as f32 * 1000.))
+            ).collect();
+
+        let mapped = map_channels(
+            &signal, &Contrast::MinMax, &Contrast::MinMax, None).unwrap();
+        let (channel_a, channel_b) = split_channels(&mapped);
+
+        // Each channel uses the full range
+        assert_eq!(*channel_a.iter().min().unwrap(), 0);
+        assert_eq!(*channel_a.iter().max().unwrap(), 255);
+        assert_eq!(*channel_b.iter().min().unwrap(), 0);
+        assert_eq!(*channel_b.iter().max().unwrap(), 255);
+
+        // A different adjustment on each channel, channel B keeps only the
+        // central 50 percent so it saturates more. Channel A should not change
+        let mapped = map_channels(
+            &signal, &Contrast::MinMax, &Contrast::Percent(0.5), None).unwrap();
+        let (new_channel_a, new_channel_b) = split_channels(&mapped);
+
+        assert_eq!(new_channel_a, channel_a);
+        assert!(new_channel_b.iter().filter(|x| **x == 255).count()
+            > channel_b.iter().filter(|x| **x == 255).count() + half / 2);
+
+        // Telemetry is required when asked for
+        assert!(map_channels(
+            &signal, &Contrast::Telemetry, &Contrast::MinMax, None).is_err());
+    }
+
+    #[test]
+    fn test_shift_hits_half_line() {
+        let hit = |index| SyncHit { index, fraction: 0.1, confidence: 0.8 };
+
+        // The first line is dropped, the rest move back
+        let hits = [hit(100), hit(6340), hit(12580), hit(25060)];
+        assert_eq!(shift_hits_half_line(&hits, 6240),
+                   vec![hit(3220), hit(9460), hit(21940)]);
+
+        assert!(shift_hits_half_line(&[], 6240).is_empty());
+    }
+
+    #[test]
+    fn test_sync_offsets() {
+        let work_rate = Rate::hz(FINAL_RATE * 3);
+        let samples_per_work_row = (PX_PER_ROW * 3) as usize;
+        let guard = generate_sync_frame(work_rate).unwrap();
+
+        // Every line is two samples longer than it should, so the sync frames
+        // drift
+        let offset = 100;
+        let mut signal: Signal = Vec::new();
+        for _line in 0..10 {
+            for i in 0..samples_per_work_row + 2 {
+                let sync = if i >= offset && i < offset + guard.len() && guard[i - offset] == 1 {
+                    1.
+                } else {
+                    0.
+                };
+                signal.push(sync);
+            }
+        }
+
+        let context = &mut Context::resample(|_,_| {}, false, false);
+        let offsets = sync_offsets(context, &signal, work_rate, 0.5).unwrap();
+        assert_eq!(offsets, (0..10).map(|line| (line, line as i32 * 2)).collect::<Vec<_>>());
+
+        // No sync frames
+        let silence: Signal = vec![0.; 20000];
+        assert!(sync_offsets(context, &silence, work_rate, 0.5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deskew_from_sync_offsets() {
+        let work_rate = Rate::hz(FINAL_RATE * 3);
+        let samples_per_work_row = (PX_PER_ROW * 3) as usize;
+        let guard = generate_sync_frame(work_rate).unwrap();
+
+        // Every line is three samples (one pixel) longer than it should, with a
+        // bright feature at a fixed distance from the sync frame. Some sync
+        // frames are missing
+        let lines = 20;
+        let mut signal: Signal = Vec::new();
+        for line in 0..lines {
+            let mut row = vec![0.; samples_per_work_row + 3];
+            if line % 7 != 3 {
+                for (i, g) in guard.iter().enumerate() {
+                    if *g == 1 {
+                        row[100 + i] = 1.;
+                    }
+                }
+            }
+            for sample in &mut row[1100..1106] {
+                *sample = 1.;
+            }
+            signal.extend(row);
+        }
+
+        let context = &mut Context::resample(|_,_| {}, false, false);
+        let offsets = sync_offsets(context, &signal, work_rate, 0.5).unwrap();
+        assert!(offsets.len() < lines);
+        let tilt = processing::estimate_tilt(&offsets, work_rate);
+        assert_relative_eq!(tilt, 1., epsilon = 0.01);
+
+        // Image without syncing, one sample out of three
+        let image: Vec<f32> = signal.chunks_exact(samples_per_work_row)
+            .take(lines)
+            .flat_map(|row| row.iter().step_by(3).cloned())
+            .collect();
+
+        let feature = |image: &[f32]| -> Vec<usize> {
+            image.chunks_exact(PX_PER_ROW as usize)
+                .map(|row| row[200..].iter().position(|x| *x == 1.).unwrap() + 200)
+                .collect()
+        };
+
+        // Tilted before, vertical after
+        let before = feature(&image);
+        assert_eq!(before[lines - 1] - before[0], lines - 1);
+        let straight = processing::deskew(&image, tilt, PX_PER_ROW as usize).unwrap();
+        let after = feature(&straight);
+        assert!(after.iter().all(|x| *x == after[0]), "{:?}", after);
+    }
+
+    #[test]
+    fn test_verify_sync_spacing() {
+        // One sync frame missing
+        let positions = [100, 6340, 12580, 25060, 31300];
+        verify_sync_spacing(&positions, 6240.).unwrap();
+        verify_sync_spacing(&[100, 6345, 12578], 6240.).unwrap();
+
+        // Sample rate 2% off
+        let wrong: Vec<usize> = (0..10).map(|i| 100 + i * 6365).collect();
+        match verify_sync_spacing(&wrong, 6240.) {
+            Err(err::Error::Internal(msg)) => {
+                assert!(msg.contains("6365.0") && msg.contains("6240.0"), "{}", msg)
+            },
+            other => panic!("Expected error, got {:?}", other),
+        }
+
+        assert!(verify_sync_spacing(&[100], 6240.).is_err());
+        assert!(verify_sync_spacing(&[], 6240.).is_err());
+    }
+
+    #[test]
+    fn test_sample_drops() {
+        let rate = Rate::hz(12480);
+        let guard = generate_sync_frame(rate).unwrap();
+        let mut noise = test_util::noise(7);
+        let signal: Signal = (0..12).flat_map(|_| {
+            let line: Signal = guard.iter().map(|x| f32::from(*x + 1) / 2.)
+                .chain((guard.len()..6240).map(|_| 0.5 + noise() * 0.2))
+                .collect();
+            line
+        }).collect();
+
+        let mut context = Context::resample(|_,_| {}, false, false);
+        assert!(detect_sample_drops(&mut context, &signal, rate).unwrap().is_empty());
+
+        // Lose a sample in the middle of line 5
+        let mut dropped = signal.clone();
+        dropped.remove(5 * 6240 + 3000);
+        let drops = detect_sample_drops(&mut context, &dropped, rate).unwrap();
+        assert_eq!(drops, vec![6 * 6240 - 1]);
+
+        let repaired = repair_drops(&dropped, &drops);
+        assert_eq!(repaired.len(), signal.len());
+        let hits = find_sync(&mut context, &repaired, rate, 0.5).unwrap();
+        assert_eq!(hits.len(), 12);
+        assert!(hits.iter().enumerate().all(|(line, hit)| hit.index == line * 6240));
+        assert_eq!(&repaired[6 * 6240..], &signal[6 * 6240..]);
+
+        // Inserted samples are interpolated
+        assert_eq!(repair_drops(&vec![1., 3., 5.], &[1, 2, 3]), vec![1., 2., 3., 4., 5., 5.]);
+        assert_eq!(repair_drops(&vec![1., 3.], &[0]), vec![1., 1., 3.]);
+    }
+
+    #[test]
+    fn test_line_tracker() {
+        let mut tracker = LineTracker::new(Rate::hz(12480));
+        assert_eq!(tracker.period(), 6240.);
+        assert_eq!(tracker.next_line_start(), 0);
+        tracker.update(None);
+        assert_eq!(tracker.next_line_start(), 0);
+
+        // Sample rate a little off, so the lines are longer than expected
+        let period = 6243.7;
+
+        // Pseudo random jitter of a few samples
+        let mut noise = test_util::noise(777);
+        let mut jitter = || f64::from(noise()) * 8.;
+
+        for line in 0..600 {
+            let sync = (1000. + line as f64 * period + jitter()).round() as usize;
+            let detected = match line % 10 {
+                3 | 4 => None, // Dropouts
+                7 => Some(sync + 500), // Corrupt sync frame
+                _ => Some(sync),
+            };
+            tracker.update(detected);
+        }
+
+        assert!((tracker.period() - period).abs() < 0.2, "Period: {}", tracker.period());
+        let expected = 1000. + 600. * period;
+        assert!((tracker.next_line_start() as f64 - expected).abs() < 5.,
+                "Next line: {}, expected {}", tracker.next_line_start(), expected);
+    }
+
+    #[test]
+    fn test_row_starts() {
+        let work_rate = Rate::hz(FINAL_RATE * 3);
+        let samples_per_work_row = (PX_PER_ROW * 3) as usize;
+        let guard = generate_sync_frame(work_rate).unwrap();
+
+        let mut noise = test_util::noise(4321);
+
+        // Lines a little longer than expected, like when the sample rate is
+        // wrong, so the sync frames drift
+        let line_len = samples_per_work_row as f64 + 0.4;
+        let expected: Vec<usize> = (0..40).map(|line|
+            100 + (line as f64 * line_len).round() as usize
+        ).collect();
+
+        // Sync frames missing on lines 15 to 21 and 30
+        let missing = |line: usize| (15..22).contains(&line) || line == 30;
+
+        let mut signal: Signal = (0..expected[39] + samples_per_work_row)
+            .map(|_| noise()).collect();
+        for (line, start) in expected.iter().enumerate() {
+            if !missing(line) {
+                for (i, x) in guard.iter().enumerate() {
+                    signal[start + i] += 2. * f32::from(*x);
+                }
+            }
+        }
+
+        // A corrupt sync frame in the middle of line 25
+        for (i, x) in guard.iter().enumerate() {
+            signal[expected[25] + samples_per_work_row / 2 + i] += 4. * f32::from(*x);
+        }
+
+        let context = &mut Context::resample(|_,_| {}, false, false);
+        let hits = find_sync(context, &signal, work_rate, 0.6).unwrap();
+        assert!(hits.len() < expected.len());
+
+        let starts = row_starts(&hits, samples_per_work_row as f64);
+        assert_eq!(starts.len(), expected.len());
+        for (line, (start, expected)) in starts.iter().zip(expected.iter()).enumerate() {
+            assert!(start.abs_diff(*expected) <= 1,
+                "Line {}: {} != {}", line, start, expected);
+        }
+
+        // The recording jumps, two sync frames that agree are enough to resync
+        let hits: Vec<SyncHit> = [0, 1000, 2000, 3000, 3500, 4500, 5500].iter()
+            .map(|index| SyncHit { index: *index, fraction: 0., confidence: 1. }).collect();
+        assert_eq!(row_starts(&hits, 1000.), vec![0, 1000, 2000, 3000, 3500, 4500, 5500]);
+
+        // A single one is ignored
+        let hits: Vec<SyncHit> = [0, 1000, 2000, 2500, 4000].iter()
+            .map(|index| SyncHit { index: *index, fraction: 0., confidence: 1. }).collect();
+        assert_eq!(row_starts(&hits, 1000.), vec![0, 1000, 2000, 3000, 4000]);
+
+        assert!(row_starts(&[], 1000.).is_empty());
+    }
+
+    #[test]
+    fn test_resample_correct() {
+        let work_rate = Rate::hz(FINAL_RATE * 3);
+        let samples_per_work_row = (PX_PER_ROW * 3) as usize;
+        let guard = generate_sync_frame(work_rate).unwrap();
+
+        // Sync frames drift following a parabola, first they come early and
+        // then late
+        let lines = 15;
+        let drift = |line: usize| (0.4 * (line * line) as f32 - 3. * line as f32).round() as i32;
+        let mut signal: Signal = vec![0.; (lines + 1) * samples_per_work_row + 200];
+        for line in 0..lines {
+            let start = (100 + (line * samples_per_work_row) as i32 + drift(line)) as usize;
+            for (i, g) in guard.iter().enumerate() {
+                if *g == 1 {
+                    signal[start + i] = 1.;
+                }
+            }
+        }
+
+        let context = &mut Context::resample(|_,_| {}, false, false);
+        let offsets = sync_offsets(context, &signal, work_rate, 0.5).unwrap();
+        assert_eq!(offsets, (0..lines).map(|line| (line as u32, drift(line))).collect::<Vec<_>>());
+
+        // A straight line can't follow the drift
+        let linear = resample_correct(context, &signal, work_rate, 0.5, 1).unwrap();
+        let offsets = sync_offsets(context, &linear, work_rate, 0.5).unwrap();
+        assert!(offsets.iter().any(|(_line, offset)| offset.abs() > 5));
+
+        let corrected = resample_correct(context, &signal, work_rate, 0.5, 2).unwrap();
+        let offsets = sync_offsets(context, &corrected, work_rate, 0.5).unwrap();
+        assert_eq!(offsets.len(), lines);
+        assert!(offsets.iter().all(|(_line, offset)| offset.abs() <= 1), "{:?}", offsets);
+
+        assert!(resample_correct(context, &signal, work_rate, 0.5, 20).is_err());
+    }
+
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    #[test]
+    fn test_auto_trim() {
+        use std::f32::consts::PI;
+
+        let rate = Rate::hz(11025);
+
+        let mut noise = test_util::noise(4321);
+
+        // 10 seconds of noise, 20 seconds of AM signal with noise and 10 more
+        // seconds of noise
+        let signal: Signal = (0..40 * 11025).map(|i| {
+            let t = i as f32 / 11025.;
+            let apt = if (10. .. 30.).contains(&t) {
+                (1. + 0.5 * (2. * PI * 2. * t).sin()) * (2. * PI * 2400. * t).sin()
+            } else {
+                0.
+            };
+            apt + noise()
+        }).collect();
+
+        assert_eq!(find_signal_start(&signal, rate).unwrap(), Some(10 * 11025));
+        assert_eq!(find_signal_end(&signal, rate).unwrap(), Some(30 * 11025));
+        assert_eq!(auto_trim(&signal, rate).unwrap()[..], signal[10 * 11025 .. 30 * 11025]);
+
+        // Only noise, nothing trimmed
+        let noise: Signal = signal[..10 * 11025].to_vec();
+        assert_eq!(find_signal_start(&noise, rate).unwrap(), None);
+        assert_eq!(auto_trim(&noise, rate).unwrap(), noise);
+    }
+
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    #[test]
+    fn test_find_signal_end() {
+        use std::f32::consts::PI;
+
+        let rate = Rate::hz(11025);
+
+        let mut noise = test_util::noise(1234);
+
+        // Tone until `end` seconds, then only noise up to 30 seconds
+        let mut signal_until = |end: f32| -> Signal {
+            (0..30 * 11025).map(|i| {
+                let t = i as f32 / 11025.;
+                let tone = if t < end { (2. * PI * 2400. * t).sin() } else { 0. };
+                tone + noise()
+            }).collect()
+        };
+
+        let signal = signal_until(20.5);
+        assert_eq!(find_signal_end(&signal, rate).unwrap(), Some(20 * 11025 + 5512));
+        assert_eq!(auto_trim(&signal, rate).unwrap().len(), 20 * 11025 + 5512);
+
+        let signal = signal_until(20.);
+        assert_eq!(find_signal_end(&signal, rate).unwrap(), Some(20 * 11025));
+
+        // Carrier until the end of the recording
+        let signal = signal_until(31.);
+        assert_eq!(find_signal_end(&signal, rate).unwrap(), Some(30 * 11025));
+
+        let signal = signal_until(0.);
+        assert_eq!(find_signal_end(&signal, rate).unwrap(), None);
+    }
+
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    #[test]
+    fn test_segment_by_carrier() {
+        use std::f32::consts::PI;
+
+        let rate = Rate::hz(11025);
+
+        let mut noise = test_util::noise(1111);
+
+        // Signal from 5 to 15 seconds and from 22 to 30, the pass ends with
+        // signal
+        let signal: Signal = (0..30 * 11025).map(|i| {
+            let t = i as f32 / 11025.;
+            let apt = if (5. .. 15.).contains(&t) || t >= 22. {
+                (1. + 0.5 * (2. * PI * 2. * t).sin()) * (2. * PI * 2400. * t).sin()
+            } else {
+                0.
+            };
+            apt + noise()
+        }).collect();
+
+        assert_eq!(segment_by_carrier(&signal, rate).unwrap(),
+                   vec![5 * 11025 .. 15 * 11025, 22 * 11025 .. 30 * 11025]);
+
+        // Only noise
+        assert!(segment_by_carrier(&signal[..5 * 11025].to_vec(), rate).unwrap().is_empty());
+        assert!(segment_by_carrier(&Signal::new(), rate).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_estimate_tca() {
+        use std::f32::consts::PI;
+        use frequency::hz_to_norm;
+
+        let rate = Rate::hz(11025);
+        let tca = 37.3; // Seconds
+        let length = 60; // Seconds
+
+        // Doppler curve like the one of a satellite passing at a distance
+        // proportional to 30 seconds, maximum shift of 40Hz
+        let freq = |t: f32|
+            CARRIER_FREQ as f32 - 40. * (t - tca) / ((t - tca).powi(2) + 30_f32.powi(2)).sqrt();
+
+        // Integrate frequency to get phase, also modulate in amplitude
+        let mut phase: f32 = 0.;
+        let signal: Signal = (0..length * rate.get_hz()).map(|i| {
+            let t = i as f32 / rate.get_hz() as f32;
+            phase = (phase + PI * hz_to_norm(freq(t), rate)) % (2. * PI);
+            (0.6 + 0.4 * (2. * PI * 2. * t).sin()) * phase.sin()
+        }).collect();
+
+        let estimation = estimate_tca(&signal, rate).unwrap();
+        assert!((estimation - tca).abs() < 0.5);
+
+        // Too short
+        assert!(estimate_tca(&vec![0.; 100], rate).is_err());
+    }
+
+    /// Read grayscale PNG, return pixels.
+    fn read_png(filename: &str) -> Vec<u8> {
+        let decoder = png::Decoder::new(std::fs::File::open(filename).unwrap());
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut image = vec![0; info.buffer_size()];
+        reader.next_frame(&mut image).unwrap();
+        image
+    }
+
+    #[test]
+    fn test_raw_f32() {
+        let filename = &temp_path("raw.f32");
+
+        let width = 7;
+        let image: Signal = (0..width * 5).map(|i| (i as f32 * 0.37).sin() / 3.).chain(
+            [f32::MAX, f32::MIN_POSITIVE, -0., 1e-40, 12345.679, -1., 0.1].iter().cloned()
+        ).collect();
+
+        write_raw_f32(filename, &image, width).unwrap();
+        let (loaded, loaded_width) = read_raw_f32(filename).unwrap();
+        assert_eq!(loaded_width, width);
+        assert_eq!(loaded.len(), image.len());
+        for (a, b) in loaded.iter().zip(image.iter()) {
+            assert_eq!(a.to_bits(), b.to_bits());
+        }
+
+        // Truncated file
+        let bytes = std::fs::read(filename).unwrap();
+        std::fs::write(filename, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(read_raw_f32(filename).is_err());
+
+        assert!(write_raw_f32(filename, &image, 5).is_err());
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_find_minute_markers() {
+        let width = 1040;
+        let layout = column_layout(width);
+        let markers = [30, 150, 270];
+
+        // Pseudo random noise between -0.05 and 0.05
+        let mut random = test_util::noise(4321);
+        let mut noise = || random() / 10.;
+
+        // Gray video, space is black or white depending on the channel
+        let mut image: Vec<f32> = Vec::new();
+        for line in 0..300 {
+            let marker = markers.iter()
+                .find(|m| (**m..**m + 4).contains(&line))
+                .map(|m| if line < m + 2 { 0. } else { 1. });
+            for x in 0..width {
+                let value = if layout.space_a.contains(&x) {
+                    marker.unwrap_or(0.)
+                } else if layout.space_b.contains(&x) {
+                    marker.unwrap_or(1.)
+                } else {
+                    0.5
+                };
+                image.push(value + noise());
+            }
+        }
+
+        assert_eq!(find_minute_markers(&image, width), markers.to_vec());
+
+        // Without markers
+        let plain: Vec<f32> = image[..20 * width].to_vec();
+        assert!(find_minute_markers(&plain, width).is_empty());
+        assert!(find_minute_markers(&[], width).is_empty());
+    }
+
+    #[test]
+    fn test_column_layout() {
+        for width in [PX_PER_ROW as usize, 1040, 909, 100, 0].iter() {
+            let layout = column_layout(*width);
+            let fields = [
+                &layout.sync_a, &layout.space_a, &layout.video_a, &layout.telemetry_a,
+                &layout.sync_b, &layout.space_b, &layout.video_b, &layout.telemetry_b,
+            ];
+
+            // Contiguous
+            assert_eq!(fields[0].start, 0);
+            for pair in fields.windows(2) {
+                assert_eq!(pair[0].end, pair[1].start);
+            }
+
+            assert_eq!(fields.iter().map(|x| x.len()).sum::<usize>(), *width);
+            assert_eq!(layout.telemetry_b.end, *width);
+        }
+
+        let layout = column_layout(PX_PER_ROW as usize);
+        assert_eq!(layout.video_a, 86..995);
+        assert_eq!(layout.sync_b.start, PX_PER_ROW as usize / 2);
+        assert_eq!(layout.video_b, 1126..2035);
+    }
+
+    /// Write eight seconds of AM signal to a WAV file, brightness is a ramp
+    /// on each line.
+    fn write_decode_test_wav(filename: &str) {
+        write_decode_test_wav_seconds(filename, 8);
+    }
+
+    /// Same as `write_decode_test_wav()` with a given length.
+    fn write_decode_test_wav_seconds(filename: &str, seconds: usize) {
+        use std::f32::consts::PI;
+
+        let input_rate = 11025.;
+        let signal: Signal = (0..seconds * 11025).map(|i| {
+            let t = i as f32 / input_rate;
+            let brightness = 0.2 + 0.8 * (t * 2.).fract();
+            brightness * (2. * PI * CARRIER_FREQ as f32 * t).sin()
+        }).collect();
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 11025,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        wav::write_wav(filename, &signal, spec).unwrap();
+    }
+
+    #[test]
+    fn test_decode_filters() {
+        use filters::Filter;
+
+        let input_rate = Rate::hz(11025);
+        let work_rate = Rate::hz(12480);
+        let settings = test_util::decode_settings("unused.wav", "unused.png");
+
+        // Defaults
+        let lowpass = demodulation_filter(&settings, work_rate);
+        assert_eq!(lowpass.cutout, Freq::hz(FINAL_RATE as f32 / 2., work_rate));
+        assert_eq!(lowpass.delta_w, lowpass.cutout / 5.);
+
+        // More attenuation needs a longer filter
+        let default_len = resample_filter(&settings, input_rate).design().len();
+        let settings = config::DecodeSettings {
+            resample_atten: 60.,
+            demodulation_cutout: Some(1500.),
+            demodulation_delta_freq: Some(100.),
+            ..settings
+        };
+        assert!(resample_filter(&settings, input_rate).design().len() > default_len);
+
+        let lowpass = demodulation_filter(&settings, work_rate);
+        assert_eq!(lowpass.cutout, Freq::hz(1500., work_rate));
+        assert_eq!(lowpass.delta_w, Freq::hz(100., work_rate));
+    }
+
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    #[test]
+    fn test_decode_auto() {
+        use std::f32::consts::PI;
+
+        // APT-like line: sync A, gradient on video A, flat video B
+        let width = PX_PER_ROW as usize;
+        let layout = column_layout(width);
+        let guard = generate_sync_frame(Rate::hz(FINAL_RATE)).unwrap();
+        let line: Vec<f32> = (0..width).map(|x| {
+            if x < guard.len() {
+                if guard[x] == 1 { 0.9 } else { 0.1 }
+            } else if layout.video_a.contains(&x) {
+                0.2 + 0.6 * (x - layout.video_a.start) as f32 / layout.video_a.len() as f32
+            } else if layout.video_b.contains(&x) {
+                0.6
+            } else {
+                0.4
+            }
+        }).collect();
+
+        // 12 seconds, two lines per second
+        let input_rate = 11025.;
+        let signal: Signal = (0..12 * 11025).map(|i| {
+            let t = i as f32 / input_rate;
+            let pixel = (t * FINAL_RATE as f32) as usize % width;
+            line[pixel] * (2. * PI * CARRIER_FREQ as f32 * t).sin()
+        }).collect();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 11025,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        wav::write_wav(&temp_path("auto.wav"), &signal, spec).unwrap();
+
+        decode_auto(&temp_path("auto.wav"), &temp_path("auto.png")).unwrap();
+
+        let image = read_png(&temp_path("auto.png"));
+        assert!(image.len() / width >= 20, "Only {} rows", image.len() / width);
+
+        // Every row is synced, so the gradient is on the right place
+        let mean = |row: &[u8]| row.iter().map(|x| f32::from(*x)).sum::<f32>() / row.len() as f32;
+        for row in image.chunks_exact(width) {
+            let video_a = &row[layout.video_a.clone()];
+            let quarter = video_a.len() / 4;
+            assert!(mean(&video_a[..quarter]) + 50. < mean(&video_a[3 * quarter..]));
+            assert!(mean(&row[layout.video_b.clone()]) > mean(&video_a[..quarter]));
+        }
+
+        std::fs::remove_file(temp_path("auto.wav")).unwrap();
+        std::fs::remove_file(temp_path("auto.png")).unwrap();
+    }
+
+    #[test]
+    fn test_decode_raw_output() {
+        write_decode_test_wav(&temp_path("raw_input.wav"));
+
+        let settings = config::DecodeSettings {
+            raw_output_filename: Some(temp_path("raw.png")),
+            histogram_filename: Some(temp_path("histogram.png")),
+            csv_filename: Some(temp_path("demodulated.csv")),
+            ..test_util::decode_settings(&temp_path("raw_input.wav"), &temp_path("enhanced.png"))
+        };
+        let context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                      Rate::hz(FINAL_RATE), false, false);
+        decode(context, settings).unwrap();
+
+        let enhanced = read_png(&temp_path("enhanced.png"));
+        let raw = read_png(&temp_path("raw.png"));
+        assert_eq!(enhanced.len(), raw.len());
+        assert_eq!(raw.len() % PX_PER_ROW as usize, 0);
+
+        // The raw image is only mapped from minimum to maximum, so only a few
+        // pixels are saturated. The enhanced one clamps 5% of each side
+        let saturated = |image: &Vec<u8>| image.iter().filter(|x| **x == 0 || **x == 255).count();
+        assert!(saturated(&raw) < raw.len() / 100);
+        assert!(saturated(&enhanced) > enhanced.len() / 20);
+
+        let chart = read_png(&temp_path("histogram.png"));
+        assert_eq!(chart, processing::histogram_chart(
+            &processing::image_histogram(&enhanced), HISTOGRAM_HEIGHT));
+
+        // The demodulated signal is on the work rate, about 8 seconds long
+        // because resampling loses a few samples at the edges
+        let csv = std::fs::read_to_string(temp_path("demodulated.csv")).unwrap();
+        assert_eq!(csv.lines().next(), Some("sample,value"));
+        assert!((csv.lines().count() - 1).abs_diff(8 * 12480) < 12480 / 100);
+
+        for x in ["raw_input.wav", "enhanced.png", "raw.png", "histogram.png",
+                  "demodulated.csv"].iter() {
+            std::fs::remove_file(temp_path(x)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_demodulate_square_law_delay() {
+        let settings = config::DecodeSettings {
+            demod_method: DemodMethod::SquareLaw,
+            ..test_util::decode_settings("unused.wav", "unused.png")
+        };
+        let work_rate = Rate::hz(settings.work_rate);
+
+        // Fast envelope so a lag of one sample is easy to see
+        let envelope_freq = Freq::hz(200., work_rate);
+        let envelope = |i: usize| 1. + 0.5 * (envelope_freq.get_rad() * i as f32).sin();
+        let carrier = Freq::hz(CARRIER_FREQ as f32, work_rate);
+        let signal: Signal = (0..12480)
+            .map(|i| envelope(i) * (carrier.get_rad() * i as f32).cos())
+            .collect();
+
+        let mut context = Context::resample(|_,_| {}, false, false);
+        let demodulated = demodulate(&mut context, &settings, &signal).unwrap();
+
+        let filter = demodulation_filter(&settings, work_rate);
+        let delay = dsp::square_law_delay(filter.cutout, filter.atten);
+
+        // Mean error against the envelope moved by `lag` samples, skipping
+        // the transient
+        let error = |lag: usize| {
+            let range = 4 * delay..demodulated.len() - 20;
+            range.clone().map(|i| (demodulated[i] - envelope(i - lag)).abs())
+                .sum::<f32>() / range.len() as f32
+        };
+        let best = (delay - 10..=delay + 10)
+            .min_by(|a, b| error(*a).partial_cmp(&error(*b)).unwrap())
+            .unwrap();
+        assert_eq!(best, delay);
+        assert!(error(delay) < 0.05, "{}", error(delay));
+    }
+
+    #[test]
+    fn test_intermediate() {
+        write_decode_test_wav(&temp_path("intermediate_input.wav"));
+        let settings = test_util::decode_settings(
+            &temp_path("intermediate_input.wav"), &temp_path("unused.png"));
+        let mut context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                          Rate::hz(FINAL_RATE), false, false);
+
+        let input = read(&mut context, &settings).unwrap();
+        let resampled = resample(&mut context, &settings, &input).unwrap();
+        save_intermediate(
+            &temp_path("resampled.wav"), &resampled, Rate::hz(settings.work_rate)).unwrap();
+
+        let (loaded, rate) = load_intermediate(&temp_path("resampled.wav")).unwrap();
+        assert_eq!(rate, Rate::hz(12480));
+        assert_eq!(loaded.len(), resampled.len());
+
+        // Same image except for the scale
+        let assemble_from = |context: &mut Context, signal: &Signal| {
+            let demodulated = demodulate(context, &settings, signal).unwrap();
+            let (image, _) = assemble(context, &settings, demodulated, 0).unwrap();
+            let max = image.iter().cloned().fold(0., f32::max);
+            image.iter().map(|x| x / max).collect::<Signal>()
+        };
+        let expected = assemble_from(&mut context, &resampled);
+        let image = assemble_from(&mut context, &loaded);
+        assert_eq!(image.len(), expected.len());
+        assert_eq!(image.len() % PX_PER_ROW as usize, 0);
+        for (x, y) in image.iter().zip(expected.iter()) {
+            assert_relative_eq!(x, y, epsilon = 1e-4);
+        }
+
+        // Another demodulation method works from the same file
+        let square_law = config::DecodeSettings {
+            demod_method: DemodMethod::SquareLaw,
+            ..settings.clone()
+        };
+        let demodulated = demodulate(&mut context, &square_law, &loaded).unwrap();
+        assert!(!assemble(&mut context, &square_law, demodulated, 0).unwrap().0.is_empty());
+
+        for x in ["intermediate_input.wav", "resampled.wav"].iter() {
+            std::fs::remove_file(temp_path(x)).unwrap();
+        }
+    }
+
+    #[cfg(any(feature = "fft", feature = "fft_builtin"))]
+    #[test]
+    fn test_per_line_snr() {
+        use std::f32::consts::PI;
+
+        // Four clean lines and four lines of noise
+        let rate = 11025;
+        let mut random = test_util::noise(3);
+        let noise: Signal = (0..8 * rate / 2).map(|_| random()).collect();
+        let signal: Signal = noise.iter().enumerate().map(|(i, n)| {
+            let t = i as f32 / rate as f32;
+            if i < 4 * rate as usize / 2 {
+                let brightness = 0.5 + 0.4 * (2. * PI * 300. * t).sin();
+                brightness * (2. * PI * CARRIER_FREQ as f32 * t).sin() + 0.001 * n
+            } else {
+                *n
+            }
+        }).collect();
+
+        let snr = per_line_snr(&signal, Rate::hz(rate)).unwrap();
+        assert_eq!(snr.len(), 8);
+        assert!(snr[..4].iter().all(|x| *x > 20.), "{:?}", snr);
+        assert!(snr[4..].iter().all(|x| x.abs() < 3.), "{:?}", snr);
+
+        // No band left for the noise
+        assert!(per_line_snr(&signal, Rate::hz(MIN_PROCESSING_RATE)).is_err());
+
+        // Saved next to the image when decoding
+        write_decode_test_wav(&temp_path("snr_input.wav"));
+        let settings = config::DecodeSettings {
+            line_snr: true,
+            ..test_util::decode_settings(&temp_path("snr_input.wav"), &temp_path("snr.png"))
+        };
+        let context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                      Rate::hz(FINAL_RATE), false, false);
+        decode(context, settings).unwrap();
+
+        let csv = std::fs::read_to_string(temp_path("snr_snr.csv")).unwrap();
+        assert_eq!(csv.lines().count(), 1 + 16);
+        assert!(csv.lines().skip(1).all(|line| {
+            line.split(',').nth(1).unwrap().parse::<f32>().unwrap() > 20.
+        }), "{}", csv);
+
+        for x in ["snr_input.wav", "snr.png", "snr_snr.csv"].iter() {
+            std::fs::remove_file(temp_path(x)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_decode_events() {
+        use context::DecodeEvent;
+
+        write_decode_test_wav(&temp_path("events_input.wav"));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                          Rate::hz(FINAL_RATE), false, false);
+        context.set_event_callback(move |event| sender.send(event).unwrap());
+        decode(context, test_util::decode_settings(
+            &temp_path("events_input.wav"), &temp_path("events.png"))).unwrap();
+
+        let events: Vec<DecodeEvent> = receiver.try_iter().collect();
+
+        // Every stage in order, progress never goes back
+        let mut stages: Vec<Stage> = events.iter().map(|e| e.stage).collect();
+        stages.dedup();
+        assert_eq!(stages, vec![Stage::Read, Stage::Resample, Stage::Demodulate,
+                                Stage::Assemble, Stage::Write]);
+        assert!(events.windows(2).all(|w| w[0].progress <= w[1].progress));
+
+        assert_eq!(events[0].message, "Reading WAV file");
+        let last = events.last().unwrap();
+        assert_eq!((last.stage, last.message.as_str(), last.progress),
+                   (Stage::Write, "Finished", 1.));
+
+        for x in ["events_input.wav", "events.png"].iter() {
+            std::fs::remove_file(temp_path(x)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_decode_preview() {
+        use filters::Filter;
+
+        write_decode_test_wav(&temp_path("preview_input.wav"));
+
+        let full = test_util::decode_settings(
+            &temp_path("preview_input.wav"), &temp_path("preview_full.png"));
+        let preview = config::DecodeSettings {
+            preview: true,
+            raw_output_filename: Some(temp_path("preview_raw.png")),
+            ..test_util::decode_settings(&temp_path("preview_input.wav"), &temp_path("preview.png"))
+        };
+
+        // Shorter filters, so less work per sample
+        let input_rate = Rate::hz(11025);
+        let work_rate = Rate::hz(preview_settings(preview.clone()).work_rate);
+        assert!(work_rate.get_hz() <= full.work_rate);
+        assert!(resample_filter(&preview_settings(preview.clone()), input_rate).design().len()
+            < resample_filter(&full, input_rate).design().len());
+        assert!(demodulation_filter(&preview_settings(preview.clone()), work_rate).design().len()
+            < demodulation_filter(&full, work_rate).design().len());
+        assert_eq!(preview_settings(full.clone()).resample_atten, full.resample_atten);
+
+        for settings in [full, preview].iter() {
+            let context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                          Rate::hz(FINAL_RATE), false, false);
+            decode(context, settings.clone()).unwrap();
+        }
+
+        let size = |filename: &str| {
+            let decoder = png::Decoder::new(std::fs::File::open(filename).unwrap());
+            let (info, _) = decoder.read_info().unwrap();
+            (info.width as usize, info.height as usize)
+        };
+
+        // Half the size on each direction, the raw image too
+        let (width, height) = size(&temp_path("preview_full.png"));
+        assert_eq!(width, PX_PER_ROW as usize);
+        assert_eq!(size(&temp_path("preview.png")), (width / 2, height / 2));
+        assert_eq!(size(&temp_path("preview_raw.png")), (width / 2, height / 2));
+
+        for x in ["preview_input.wav", "preview_full.png", "preview.png", "preview_raw.png"].iter() {
+            std::fs::remove_file(temp_path(x)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_decode_thumbnail() {
+        assert_eq!(sidecar_filename(&temp_path("thumb.png"), "_thumb.png"),
+            temp_path("thumb_thumb.png"));
+        assert_eq!(sidecar_filename("pass", "_thumb.png"), "pass_thumb.png");
+
+        write_decode_test_wav(&temp_path("thumb_input.wav"));
+
+        let settings = config::DecodeSettings {
+            thumbnail_size: Some(520),
+            palette: Some(processing::Palette::Thermal),
+            ..test_util::decode_settings(&temp_path("thumb_input.wav"), &temp_path("thumb.png"))
+        };
+        let context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                      Rate::hz(FINAL_RATE), false, false);
+        decode(context, settings).unwrap();
+
+        let size = |filename: &str| {
+            let decoder = png::Decoder::new(std::fs::File::open(filename).unwrap());
+            let (info, _) = decoder.read_info().unwrap();
+            (info.width as usize, info.height as usize, info.color_type)
+        };
+
+        // Fits on 520 pixels with the same aspect ratio, colored like the
+        // full image
+        let (width, height, color) = size(&temp_path("thumb.png"));
+        let (thumb_width, thumb_height, thumb_color) = size(&temp_path("thumb_thumb.png"));
+        assert_eq!(thumb_width, 520);
+        assert_eq!(thumb_height, (height as f32 * 520. / width as f32).round() as usize);
+        assert_eq!(thumb_color, color);
+
+        for x in ["thumb_input.wav", "thumb.png", "thumb_thumb.png"].iter() {
+            std::fs::remove_file(temp_path(x)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_decode_output_size() {
+        write_decode_test_wav(&temp_path("size_input.wav"));
+
+        let settings = config::DecodeSettings {
+            output_size: Some((520, 300)),
+            ..test_util::decode_settings(&temp_path("size_input.wav"), &temp_path("size.png"))
+        };
+        let context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                      Rate::hz(FINAL_RATE), false, false);
+        decode(context, settings).unwrap();
+
+        let decoder = png::Decoder::new(std::fs::File::open(temp_path("size.png")).unwrap());
+        let (info, _) = decoder.read_info().unwrap();
+        assert_eq!((info.width, info.height), (520, 300));
+
+        // Much wider than tall, so the image takes the whole width and a few
+        // lines in the middle
+        let image = read_png(&temp_path("size.png"));
+        let lines: Vec<&[u8]> = image.chunks_exact(520).collect();
+        assert!(lines[..140].iter().all(|line| line.iter().all(|x| *x == 0)));
+        assert!(lines[160..].iter().all(|line| line.iter().all(|x| *x == 0)));
+        assert!(lines[148..152].iter().all(|line| line.iter().any(|x| *x != 0)));
+
+        for x in ["size_input.wav", "size.png"].iter() {
+            std::fs::remove_file(temp_path(x)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_decode_line_range() {
+        // 220 lines
+        write_decode_test_wav_seconds(&temp_path("lines_input.wav"), 110);
+
+        let decode_lines = |range| {
+            let settings = config::DecodeSettings {
+                line_range: Some(range),
+                ..test_util::decode_settings(&temp_path("lines_input.wav"), &temp_path("lines.png"))
+            };
+            let context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                          Rate::hz(FINAL_RATE), false, false);
+            decode(context, settings)
+        };
+
+        decode_lines((100, 200)).unwrap();
+        assert_eq!(read_png(&temp_path("lines.png")).len(), 100 * PX_PER_ROW as usize);
+
+        // Without margin before the first line
+        decode_lines((0, 20)).unwrap();
+        assert_eq!(read_png(&temp_path("lines.png")).len(), 20 * PX_PER_ROW as usize);
+
+        assert!(decode_lines((200, 100)).is_err());
+        assert!(decode_lines((300, 400)).is_err());
+
+        // Lines counted on the original recording even if trimming was asked
+        let settings = config::DecodeSettings {
+            line_range: Some((100, 200)),
+            trim: true,
+            ..test_util::decode_settings(&temp_path("lines_input.wav"), &temp_path("lines.png"))
+        };
+        let mut context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                          Rate::hz(FINAL_RATE), false, false);
+        let input = read(&mut context, &settings).unwrap();
+        let (signal, spec) = wav::load_wav(&temp_path("lines_input.wav")).unwrap();
+        let (cropped, _) = crop_lines(&signal, Rate::hz(spec.sample_rate), (100, 200)).unwrap();
+        assert_eq!(input.signal.len(), cropped.len());
+
+        for x in ["lines_input.wav", "lines.png"].iter() {
+            std::fs::remove_file(temp_path(x)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_crop_lines() {
+        let rate = Rate::hz(100);
+        let signal: Signal = (0..1000).map(|x| x as f32).collect();
+
+        // 50 samples per line, one line of margin on each side
+        let (cropped, margin) = crop_lines(&signal, rate, (3, 5)).unwrap();
+        assert_eq!(margin, 1);
+        assert_eq!(cropped, signal[100..300].to_vec());
+
+        let (cropped, margin) = crop_lines(&signal, rate, (0, 19)).unwrap();
+        assert_eq!(margin, 0);
+        assert_eq!(cropped, signal);
+
+        assert!(crop_lines(&signal, rate, (5, 5)).is_err());
+        assert!(crop_lines(&signal, rate, (25, 30)).is_err());
+    }
+
+    #[test]
+    fn test_decode_output_channels() {
+        write_decode_test_wav(&temp_path("channels_input.wav"));
+
+        let cases = [
+            (OutputChannels::A, "channel_a.png"),
+            (OutputChannels::B, "channel_b.png"),
+            (OutputChannels::Both, "channel_both.png"),
+        ];
+        for (channels, filename) in cases.iter() {
+            let settings = config::DecodeSettings {
+                output_channels: *channels,
+                ..test_util::decode_settings(&temp_path("channels_input.wav"), &temp_path(filename))
+            };
+            let context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                          Rate::hz(FINAL_RATE), false, false);
+            decode(context, settings).unwrap();
+
+            let decoder = png::Decoder::new(std::fs::File::open(temp_path(filename)).unwrap());
+            let (info, _reader) = decoder.read_info().unwrap();
+            assert_eq!(info.width, channels.width());
+        }
+
+        // Each channel is one half of the full image
+        let both = read_png(&temp_path("channel_both.png"));
+        let (a, b) = split_channels(&both);
+        assert_eq!(read_png(&temp_path("channel_a.png")), a);
+        assert_eq!(read_png(&temp_path("channel_b.png")), b);
+
+        std::fs::remove_file(temp_path("channels_input.wav")).unwrap();
+        for (_channels, filename) in cases.iter() {
+            std::fs::remove_file(temp_path(filename)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_decode_deterministic() {
+        write_decode_test_wav(&temp_path("deterministic_input.wav"));
+
+        for filename in ["deterministic_1.png", "deterministic_2.png"].iter() {
+            let settings = config::DecodeSettings {
+                deterministic: true,
+                ..test_util::decode_settings(
+                    &temp_path("deterministic_input.wav"), &temp_path(filename))
+            };
+            let context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                          Rate::hz(FINAL_RATE), false, false);
+            decode(context, settings).unwrap();
+        }
+
+        assert_eq!(
+            std::fs::read(temp_path("deterministic_1.png")).unwrap(),
+            std::fs::read(temp_path("deterministic_2.png")).unwrap()
+        );
+
+        for x in ["deterministic_input.wav", "deterministic_1.png", "deterministic_2.png"].iter() {
+            std::fs::remove_file(temp_path(x)).unwrap();
+        }
+    }
 }
@@ -0,0 +1,1109 @@
+//! Image post-processing.
+//!
+//! Functions that work on already decoded images, one channel at a time.
+
+use dsp;
+use dsp::Signal;
+use err;
+use geo::PassDirection;
+use dsp::Rate;
+use noaa_apt::{column_layout, FINAL_RATE};
+use telemetry::{Channel, Telemetry};
+
+
+/// Color lookup table, one RGB color for each grayscale value.
+pub type Lut = [[u8; 3]; 256];
+
+/// Built-in color palettes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Palette {
+    /// From black to blue, red, yellow and white. Good for thermal images.
+    Thermal,
+
+    /// From blue to cyan, green, yellow and red.
+    Rainbow,
+}
+
+impl Palette {
+
+    /// Parse palette name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "thermal" => Some(Palette::Thermal),
+            "rainbow" => Some(Palette::Rainbow),
+            _ => None,
+        }
+    }
+
+    /// Get lookup table of the palette.
+    pub fn lut(self) -> Lut {
+        match self {
+            Palette::Thermal => gradient(&[
+                [0, 0, 0], [0, 0, 255], [255, 0, 0], [255, 255, 0], [255, 255, 255]]),
+            Palette::Rainbow => gradient(&[
+                [0, 0, 255], [0, 255, 255], [0, 255, 0], [255, 255, 0], [255, 0, 0]]),
+        }
+    }
+}
+
+/// Build lookup table interpolating linearly between equally spaced colors.
+fn gradient(colors: &[[u8; 3]]) -> Lut {
+    let mut lut: Lut = [[0; 3]; 256];
+    let segments = (colors.len() - 1) as f32;
+
+    for (value, color) in lut.iter_mut().enumerate() {
+        let position = value as f32 / 255. * segments;
+        let i = (position as usize).min(colors.len() - 2);
+        let t = position - i as f32;
+        for c in 0..3 {
+            color[c] = (f32::from(colors[i][c]) * (1. - t)
+                + f32::from(colors[i + 1][c]) * t).round() as u8;
+        }
+    }
+
+    lut
+}
+
+/// Color a grayscale image using a lookup table.
+///
+/// Returns an RGB image, three values per pixel.
+pub fn apply_palette(image: &[u8], palette: &Lut) -> Vec<u8> {
+    image.iter().flat_map(|x| palette[*x as usize].iter().cloned()).collect()
+}
+
+/// Resize image using bilinear interpolation.
+///
+/// `channels` is the number of values per pixel, e.g. 3 for RGB. The corners
+/// of the image stay on the corners, so every pixel is sampled from the same
+/// relative position. Not meant for downscaling a lot, each output pixel
+/// only looks at the four nearest input pixels.
+pub fn resize_image(
+    image: &[u8],
+    width: usize,
+    channels: usize,
+    new_width: usize,
+    new_height: usize,
+) -> err::Result<Vec<u8>> {
+    let line_len = width * channels;
+    if line_len == 0 || image.is_empty() || !image.chunks_exact(line_len).remainder().is_empty() {
+        return Err(err::Error::Internal(format!(
+            "Image length {} is not a multiple of line length {}", image.len(), line_len)));
+    }
+    let height = image.len() / line_len;
+
+    // Position on the input of an output coordinate
+    let source = |i: usize, new_len: usize, len: usize| -> (usize, usize, f32) {
+        if new_len <= 1 || len <= 1 {
+            return (0, 0, 0.);
+        }
+        let position = i as f32 * (len - 1) as f32 / (new_len - 1) as f32;
+        let first = (position.floor() as usize).min(len - 1);
+        (first, (first + 1).min(len - 1), position - first as f32)
+    };
+
+    let mut result: Vec<u8> = Vec::with_capacity(new_width * new_height * channels);
+    for y in 0..new_height {
+        let (y0, y1, fy) = source(y, new_height, height);
+        for x in 0..new_width {
+            let (x0, x1, fx) = source(x, new_width, width);
+            for c in 0..channels {
+                let pixel = |x: usize, y: usize| f32::from(image[y * line_len + x * channels + c]);
+                let top = pixel(x0, y0) * (1. - fx) + pixel(x1, y0) * fx;
+                let bottom = pixel(x0, y1) * (1. - fx) + pixel(x1, y1) * fx;
+                result.push((top * (1. - fy) + bottom * fy).round() as u8);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Make image `factor` times smaller keeping one of every `factor` pixels on
+/// each direction.
+///
+/// Nearest neighbor, so much faster than `resize_image()` but with aliasing.
+/// Returns the image with its new width and height, the last pixels are
+/// dropped if the size is not a multiple of `factor`.
+pub fn downscale_nearest(
+    image: &[u8],
+    width: usize,
+    channels: usize,
+    factor: usize,
+) -> err::Result<(Vec<u8>, usize, usize)> {
+    let line_len = width * channels;
+    if factor == 0 || line_len == 0 || !image.chunks_exact(line_len).remainder().is_empty() {
+        return Err(err::Error::Internal(format!(
+            "Can't downscale image of length {} and line length {} by {}",
+            image.len(), line_len, factor)));
+    }
+    let (new_width, new_height) = (width / factor, image.len() / line_len / factor);
+
+    let mut result: Vec<u8> = Vec::with_capacity(new_width * new_height * channels);
+    for line in image.chunks_exact(line_len).step_by(factor).take(new_height) {
+        for pixel in line.chunks_exact(channels).step_by(factor).take(new_width) {
+            result.extend_from_slice(pixel);
+        }
+    }
+
+    Ok((result, new_width, new_height))
+}
+
+/// Size of a thumbnail that fits on a square of `max_size`.
+///
+/// Keeps the aspect ratio and never makes the image bigger. Returns
+/// `(width, height)`, at least one pixel each.
+pub fn thumbnail_size(width: usize, height: usize, max_size: usize) -> (usize, usize) {
+    let biggest = width.max(height);
+    if biggest <= max_size {
+        return (width, height);
+    }
+    let scale = |length: usize| {
+        ((length as f32 * max_size as f32 / biggest as f32).round() as usize).max(1)
+    };
+    (scale(width), scale(height))
+}
+
+/// Scale an image so it fits on `target_width` by `target_height` pixels.
+///
+/// Keeps the aspect ratio using `resize_image()` and centers the result,
+/// filling the rest with `fill` (letterboxing), so the returned image always
+/// has the requested size. `channels` is the number of values per pixel like
+/// on `resize_image()`, every value of the fill gets `fill`.
+pub fn fit_image(
+    image: &[u8],
+    width: usize,
+    channels: usize,
+    target_width: usize,
+    target_height: usize,
+    fill: u8,
+) -> err::Result<Vec<u8>> {
+    let line_len = width * channels;
+    if line_len == 0 || image.is_empty() || target_width == 0 || target_height == 0
+        || !image.chunks_exact(line_len).remainder().is_empty()
+    {
+        return Err(err::Error::Internal(format!(
+            "Can't fit image of length {} and line length {} on {}x{}",
+            image.len(), line_len, target_width, target_height)));
+    }
+    let height = image.len() / line_len;
+
+    let scale = (target_width as f32 / width as f32).min(target_height as f32 / height as f32);
+    let fit = |length: usize, target: usize| {
+        ((length as f32 * scale).round() as usize).max(1).min(target)
+    };
+    let (new_width, new_height) = (fit(width, target_width), fit(height, target_height));
+    let resized = resize_image(image, width, channels, new_width, new_height)?;
+
+    let left = (target_width - new_width) / 2;
+    let top = (target_height - new_height) / 2;
+    let mut result = vec![fill; target_width * target_height * channels];
+    for (y, line) in resized.chunks_exact(new_width * channels).enumerate() {
+        let start = ((top + y) * target_width + left) * channels;
+        result[start..start + line.len()].copy_from_slice(line);
+    }
+
+    Ok(result)
+}
+
+/// Surround a grayscale image with a border of `margin` pixels of `fill`.
+///
+/// Returns the new image with its width and height. Panics if the image is
+/// not `width` by `height` pixels.
+pub fn add_margin(
+    image: &[u8],
+    width: usize,
+    height: usize,
+    margin: usize,
+    fill: u8,
+) -> (Vec<u8>, usize, usize) {
+    assert_eq!(image.len(), width * height, "Image is not {}x{}", width, height);
+
+    let new_width = width + 2 * margin;
+    let new_height = height + 2 * margin;
+
+    let mut result = vec![fill; new_width * new_height];
+    for (y, line) in image.chunks_exact(width.max(1)).enumerate() {
+        let start = (y + margin) * new_width + margin;
+        result[start..start + width].copy_from_slice(line);
+    }
+
+    (result, new_width, new_height)
+}
+
+/// Estimate tilt of the image from the offset of each sync frame.
+///
+/// Takes the line numbers and offsets returned by `noaa_apt::sync_offsets()`,
+/// measured in samples at `work_rate`. Returns the slope of the least squares
+/// line in pixels of the final image per line, so it can be given directly to
+/// `deskew()`. Zero if there are less than two lines.
+pub fn estimate_tilt(sync_offsets: &[(u32, i32)], work_rate: Rate) -> f32 {
+    let n = sync_offsets.len() as f64;
+
+    let mean_x = sync_offsets.iter().map(|(x, _)| f64::from(*x)).sum::<f64>() / n;
+    let mean_y = sync_offsets.iter().map(|(_, y)| f64::from(*y)).sum::<f64>() / n;
+
+    let mut covariance = 0.;
+    let mut variance = 0.;
+    for (x, y) in sync_offsets {
+        covariance += (f64::from(*x) - mean_x) * (f64::from(*y) - mean_y);
+        variance += (f64::from(*x) - mean_x).powi(2);
+    }
+
+    // Also covers having a single line, or none
+    if variance == 0. {
+        return 0.;
+    }
+
+    let samples_per_line = covariance / variance;
+    (samples_per_line * f64::from(FINAL_RATE) / f64::from(work_rate.get_hz())) as f32
+}
+
+/// Correct tilt of an image.
+///
+/// Moves each line to the left by `tilt` pixels more than the previous line,
+/// so a feature that drifts to the right by `tilt` pixels per line ends up
+/// vertical. Empty pixels are filled with the default value (zero).
+pub fn deskew<T: Copy + Default>(image: &[T], tilt: f32, width: usize) -> err::Result<Vec<T>> {
+    if width == 0 || !image.chunks_exact(width).remainder().is_empty() {
+        return Err(err::Error::Internal(format!(
+            "Image length {} is not a multiple of width {}", image.len(), width)));
+    }
+
+    let mut result: Vec<T> = Vec::with_capacity(image.len());
+    for (i, line) in image.chunks_exact(width).enumerate() {
+        let shift = (tilt * i as f32).round() as isize;
+        result.extend((0..width as isize).map(|x| {
+            let source = x + shift;
+            if source >= 0 && source < width as isize {
+                line[source as usize]
+            } else {
+                T::default()
+            }
+        }));
+    }
+
+    Ok(result)
+}
+
+/// Match the brightness of both channels.
+///
+/// Channel A and B image the same scene, but each one comes with its own gain
+/// so one can end up dominating a false color image. I measure the mean and
+/// standard deviation of the video part of each channel and map both to the
+/// average of them, every pixel of the channel gets the same linear mapping.
+/// `width` is the width of a single channel, e.g. `PX_PER_ROW / 2`.
+pub fn balance_channels(a: &mut Signal, b: &mut Signal, width: usize) -> err::Result<()> {
+    if width == 0 || a.len() != b.len()
+        || !a.chunks_exact(width).remainder().is_empty()
+    {
+        return Err(err::Error::ImageProcessing(format!(
+            "Can't balance channels of length {} and {} with width {}",
+            a.len(), b.len(), width)));
+    }
+
+    // Layout of a line with both channels, channel B has the same layout
+    let video = column_layout(2 * width).video_a;
+
+    let statistics = |channel: &Signal| {
+        let values: Vec<f64> = channel.chunks_exact(width)
+            .flat_map(|line| line[video.clone()].iter().map(|x| f64::from(*x)))
+            .collect();
+        let n = values.len().max(1) as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance = values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n;
+        (mean, variance.sqrt())
+    };
+
+    let (mean_a, std_a) = statistics(a);
+    let (mean_b, std_b) = statistics(b);
+    let mean = (mean_a + mean_b) / 2.;
+    let std = (std_a + std_b) / 2.;
+
+    for (channel, channel_mean, channel_std) in [(a, mean_a, std_a), (b, mean_b, std_b)] {
+        // Flat channel, only move the mean
+        let gain = if channel_std > 0. { std / channel_std } else { 1. };
+        for x in channel.iter_mut() {
+            *x = ((f64::from(*x) - channel_mean) * gain + mean) as f32;
+        }
+    }
+
+    Ok(())
+}
+
+/// Rotate the image so the north is up.
+///
+/// The satellite scans from its right to its left, so images of southbound
+/// passes come with the north up and the west on the left, but northbound
+/// passes come upside down and mirrored. Rotating 180 degrees (flipping
+/// vertically and mirroring horizontally) fixes both, the channels also swap
+/// sides. Only for grayscale images, `width` pixels per line.
+pub fn orient_north_up(image: &mut [u8], width: usize, direction: PassDirection) -> err::Result<()> {
+    if width == 0 || !image.chunks_exact(width).remainder().is_empty() {
+        return Err(err::Error::ImageProcessing(format!(
+            "Image length {} is not a multiple of width {}", image.len(), width)));
+    }
+
+    if direction == PassDirection::Northbound {
+        info!("Rotating image of northbound pass");
+        image.reverse();
+    }
+
+    Ok(())
+}
+
+/// Stretch contrast using the telemetry wedges as references.
+///
+/// Wedge 9 is the black reference and wedge 8 the white one, so I map the
+/// first to 0 and the second to 255. Values outside are not clipped, `map()`
+/// does that when converting to `u8`. `channel` selects which band of the
+/// telemetry to read, `None` uses the mean of both like
+/// `Telemetry::get_wedge_value()`.
+pub fn stretch_to_wedges(
+    image: &mut Signal,
+    telemetry: &Telemetry,
+    channel: Option<Channel>,
+) -> err::Result<()> {
+    let black = telemetry.get_wedge_value(9, channel);
+    let white = telemetry.get_wedge_value(8, channel);
+
+    if !(white - black).is_normal() {
+        return Err(err::Error::ImageProcessing(format!(
+            "Can't stretch to wedges, black {} and white {} are too close",
+            black, white)));
+    }
+
+    let gain = 255. / (white - black);
+    for x in image.iter_mut() {
+        *x = (*x - black) * gain;
+    }
+
+    Ok(())
+}
+
+/// Lines that `stack()` is allowed to move an image, up or down.
+const STACK_MAX_OFFSET: usize = 120;
+
+/// Lines to move `image` so it matches `reference`.
+///
+/// Compares the mean of each line, with the mean of the whole image removed,
+/// by cross-correlation. Only offsets where at least half of the lines of the
+/// shortest image overlap are considered. Positive values mean that `image`
+/// started recording later, so its first line matches that line of the
+/// reference.
+fn line_offset(reference: &[f64], image: &[f64]) -> isize {
+    let centered = |means: &[f64]| {
+        let mean = means.iter().sum::<f64>() / means.len().max(1) as f64;
+        means.iter().map(|x| x - mean).collect::<Vec<f64>>()
+    };
+    let (reference, image) = (centered(reference), centered(image));
+    let min_overlap = (reference.len().min(image.len()) / 2).max(1);
+
+    let max = STACK_MAX_OFFSET as isize;
+    let mut best: (isize, f64) = (0, f64::NEG_INFINITY);
+    for offset in -max..=max {
+        let pairs: Vec<(f64, f64)> = image.iter().enumerate()
+            .filter_map(|(i, x)| {
+                let j = i as isize + offset;
+                if j >= 0 && (j as usize) < reference.len() {
+                    Some((reference[j as usize], *x))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        if pairs.len() < min_overlap {
+            continue;
+        }
+        let corr = pairs.iter().map(|(a, b)| a * b).sum::<f64>() / pairs.len() as f64;
+        if corr > best.1 {
+            best = (offset, corr);
+        }
+    }
+
+    best.0
+}
+
+/// Average several recordings of the same pass.
+///
+/// Each receiver starts recording at its own time, so I align every image to
+/// the first one by cross-correlating the mean of each line (see
+/// `line_offset()`), up to `STACK_MAX_OFFSET` lines. The result has the lines
+/// of the first image, each pixel is the mean of the images that cover that
+/// line. Random noise goes down by the square root of the number of images.
+/// The images should have the same contrast, e.g. taken with
+/// `Contrast::MinMax`.
+pub fn stack(images: &[&[f32]], width: usize) -> err::Result<Signal> {
+    if images.is_empty() || width == 0
+        || images.iter().any(|image| !image.chunks_exact(width).remainder().is_empty())
+    {
+        return Err(err::Error::ImageProcessing(format!(
+            "Can't stack {} images of width {}", images.len(), width)));
+    }
+
+    let line_means = |image: &[f32]| -> Vec<f64> {
+        image.chunks_exact(width)
+            .map(|line| line.iter().map(|x| f64::from(*x)).sum::<f64>() / width as f64)
+            .collect()
+    };
+
+    let reference = images[0];
+    let reference_means = line_means(reference);
+
+    let mut sum: Vec<f64> = reference.iter().map(|x| f64::from(*x)).collect();
+    let mut count: Vec<u32> = vec![1; reference_means.len()];
+
+    for image in &images[1..] {
+        let offset = line_offset(&reference_means, &line_means(image));
+        debug!("Stacking image moved {} lines", offset);
+
+        for (i, line) in image.chunks_exact(width).enumerate() {
+            let j = i as isize + offset;
+            if j < 0 || j as usize >= count.len() {
+                continue;
+            }
+            let j = j as usize;
+            for (s, x) in sum[j * width..(j + 1) * width].iter_mut().zip(line) {
+                *s += f64::from(*x);
+            }
+            count[j] += 1;
+        }
+    }
+
+    Ok(sum.chunks_exact(width).zip(count.iter())
+        .flat_map(|(line, n)| line.iter().map(move |x| (x / f64::from(*n)) as f32))
+        .collect())
+}
+
+/// Measure how sharp an image is.
+///
+/// Mean of the squared discrete Laplacian (4 neighbors) over every pixel that
+/// is not on the border, so blurry or flat images score low and
+/// images with fine detail score high. Only meaningful when comparing images
+/// of the same scene with the same contrast, e.g. to rank several decodes of
+/// a pass or to check that a filter didn't make things worse. Fails if the
+/// image has less than three lines or columns, there is nothing to measure.
+pub fn sharpness(image: &[f32], width: usize) -> err::Result<f32> {
+    let height = image.len().checked_div(width).unwrap_or(0);
+    if width < 3 || height < 3 {
+        return Err(err::Error::ImageProcessing(format!(
+            "Can't measure sharpness of image with {} pixels and width {}, \
+            needs at least three lines and columns", image.len(), width)));
+    }
+
+    let mut sum: f64 = 0.;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let i = y * width + x;
+            let laplacian = image[i - width] + image[i + width]
+                + image[i - 1] + image[i + 1] - 4. * image[i];
+            sum += f64::from(laplacian).powi(2);
+        }
+    }
+
+    Ok((sum / ((height - 2) * (width - 2)) as f64) as f32)
+}
+
+/// Enhance edges using unsharp masking.
+///
+/// Blurs a copy of the image with a box of `2 * radius + 1` pixels (moving
+/// averages on lines and then on columns) and adds the difference with the
+/// original times `amount`, so coastlines and cloud edges stand out. The
+/// result is clamped to the range of values the image had, otherwise the
+/// overshoot near strong edges ends up clipped when mapping to `u8` anyway.
+/// An `amount` or `radius` of zero leaves the image as it is, pixels on an
+/// incomplete last line too.
+pub fn unsharp_mask(image: &mut [f32], width: usize, radius: usize, amount: f32) {
+    if width == 0 || radius == 0 || amount == 0. {
+        return;
+    }
+    let height = image.len() / width;
+    let window = 2 * radius + 1;
+
+    let mut blurred: Signal = Vec::with_capacity(height * width);
+    for line in image.chunks_exact(width) {
+        blurred.extend(dsp::moving_average(&line.to_vec(), window));
+    }
+    for x in 0..width {
+        let column: Signal = (0..height).map(|y| blurred[y * width + x]).collect();
+        for (y, value) in dsp::moving_average(&column, window).iter().enumerate() {
+            blurred[y * width + x] = *value;
+        }
+    }
+
+    let min = image.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = image.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    for (pixel, blur) in image.iter_mut().zip(blurred.iter()) {
+        *pixel = (*pixel + amount * (*pixel - blur)).max(min).min(max);
+    }
+}
+
+/// Calculate histogram of a channel.
+///
+/// Divides the range from `min` to `max` on `bins` bins of equal width and
+/// counts the samples on each one. Samples outside the range are counted on
+/// the first or last bin.
+pub fn histogram(channel: &Signal, min: f32, max: f32, bins: usize) -> Vec<u32> {
+    let mut histogram: Vec<u32> = vec![0; bins];
+    if bins == 0 {
+        return histogram;
+    }
+
+    let bin_width = (max - min) / bins as f32;
+    for sample in channel {
+        let bin = ((sample - min) / bin_width).floor();
+        let bin = if bin < 0. {
+            0
+        } else if bin as usize >= bins {
+            bins - 1
+        } else {
+            bin as usize
+        };
+        histogram[bin] += 1;
+    }
+
+    histogram
+}
+
+/// Count pixels of each value on an image.
+pub fn image_histogram(image: &[u8]) -> [u32; 256] {
+    let mut histogram = [0; 256];
+    for pixel in image {
+        histogram[*pixel as usize] += 1;
+    }
+    histogram
+}
+
+/// Draw histogram as a bar chart.
+///
+/// Returns a grayscale image 256 pixels wide and `height` pixels tall, with a
+/// white bar for each value on a black background. The bars are scaled so the
+/// biggest one takes the whole height.
+pub fn histogram_chart(histogram: &[u32; 256], height: usize) -> Vec<u8> {
+    let max = histogram.iter().cloned().max().unwrap_or(0).max(1);
+
+    let bars: Vec<usize> = histogram.iter()
+        .map(|count| (u64::from(*count) * height as u64 / u64::from(max)) as usize)
+        .collect();
+
+    let mut chart = Vec::with_capacity(256 * height);
+    for row in 0..height {
+        // Rows from top to bottom
+        let level = height - row;
+        chart.extend(bars.iter().map(|bar| if *bar >= level { 255 } else { 0 }));
+    }
+    chart
+}
+
+/// Find threshold that separates a histogram on two classes using Otsu's
+/// method.
+///
+/// Looks for the threshold that maximizes the variance between both classes.
+/// The value returned is on bins, samples on bins lower than the threshold
+/// belong to the first class. If several thresholds are equally good (e.g.
+/// when there is a gap between classes) I return the middle one. Fails if the
+/// histogram is empty or has no samples.
+pub fn otsu_threshold(histogram: &[u32]) -> err::Result<f32> {
+    let total: f64 = histogram.iter().map(|&x| f64::from(x)).sum();
+    if total == 0. {
+        return Err(err::Error::Internal(
+            "Can't get threshold of an empty histogram".to_string()));
+    }
+
+    let total_sum: f64 = histogram.iter().enumerate()
+        .map(|(i, &x)| i as f64 * f64::from(x)).sum();
+
+    let mut weight_low: f64 = 0.; // Samples on the first class
+    let mut sum_low: f64 = 0.; // Sum of the samples on the first class
+
+    let mut max_variance: f64 = -1.;
+    let mut first_best: usize = 0;
+    let mut last_best: usize = 0;
+
+    for (i, &count) in histogram.iter().enumerate() {
+        weight_low += f64::from(count);
+        sum_low += i as f64 * f64::from(count);
+
+        let weight_high = total - weight_low;
+        if weight_low == 0. || weight_high == 0. {
+            continue;
+        }
+
+        let mean_low = sum_low / weight_low;
+        let mean_high = (total_sum - sum_low) / weight_high;
+        let variance = weight_low * weight_high * (mean_low - mean_high).powi(2);
+
+        // Compare with some tolerance, otherwise rounding errors choose the
+        // best threshold on plateaus
+        if variance > max_variance * (1. + 1e-9) {
+            max_variance = variance;
+            first_best = i;
+            last_best = i;
+        } else if variance >= max_variance * (1. - 1e-9) {
+            last_best = i;
+        }
+    }
+
+    // The threshold is on the edge after the last bin of the first class
+    Ok((first_best + last_best) as f32 / 2. + 1.)
+}
+
+/// Segment a channel on classes using brightness thresholds.
+///
+/// Returns a class index for each pixel: the number of thresholds smaller or
+/// equal than the pixel value. So for thresholds `[a, b]` pixels lower than
+/// `a` are on class 0, pixels between `a` and `b` on class 1 and the rest on
+/// class 2. Useful for separating clouds, land and sea on the IR channel.
+pub fn segment(channel: &Signal, width: usize, thresholds: &[f32]) -> err::Result<Vec<u8>> {
+    if width == 0 || !channel.chunks_exact(width).remainder().is_empty() {
+        return Err(err::Error::Internal(format!(
+            "Channel length {} is not a multiple of width {}",
+            channel.len(), width)));
+    }
+    if thresholds.len() > 255 {
+        return Err(err::Error::Internal(
+            "Too many thresholds for segmentation".to_string()));
+    }
+
+    let mut thresholds = thresholds.to_vec();
+    thresholds.sort_by(|a, b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal));
+
+    Ok(channel.iter()
+        .map(|sample| thresholds.iter().filter(|t| *t <= sample).count() as u8)
+        .collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use test_util;
+
+    /// Bimodal image with dark pixels from 40 to 60 and bright pixels from 190
+    /// to 210.
+    fn bimodal_image() -> Signal {
+        (0..1000).map(|i| {
+            let offset = (i % 21) as f32;
+            if (i / 7) % 3 == 0 { 190. + offset } else { 40. + offset }
+        }).collect()
+    }
+
+    #[test]
+    fn test_apply_palette() {
+        let ramp: Vec<u8> = (0..=255).collect();
+
+        for palette in [Palette::Thermal, Palette::Rainbow].iter() {
+            let lut = palette.lut();
+            let rgb = apply_palette(&ramp, &lut);
+            assert_eq!(rgb.len(), 256 * 3);
+            for (value, color) in rgb.chunks_exact(3).enumerate() {
+                assert_eq!(color, &lut[value]);
+            }
+        }
+
+        let lut = Palette::Thermal.lut();
+        assert_eq!(lut[0], [0, 0, 0]);
+        assert_eq!(lut[255], [255, 255, 255]);
+        assert_eq!(Palette::Rainbow.lut()[0], [0, 0, 255]);
+        assert_eq!(Palette::Rainbow.lut()[255], [255, 0, 0]);
+
+        assert_eq!(apply_palette(&[255, 0], &lut), vec![255, 255, 255, 0, 0, 0]);
+        assert_eq!(Palette::from_name("thermal"), Some(Palette::Thermal));
+        assert_eq!(Palette::from_name("sepia"), None);
+    }
+
+    #[test]
+    fn test_resize_image() {
+        // Horizontal ramp, RGB with the inverted ramp on the last channel
+        let image: Vec<u8> = (0..4 * 3).flat_map(|i| {
+            let x = (i % 4) as u8 * 60;
+            vec![x, 100, 180 - x]
+        }).collect();
+
+        let resized = resize_image(&image, 4, 3, 7, 2).unwrap();
+        assert_eq!(resized.len(), 7 * 2 * 3);
+        let first_line: Vec<u8> = resized[..7 * 3].chunks(3).map(|p| p[0]).collect();
+        assert_eq!(first_line, vec![0, 30, 60, 90, 120, 150, 180]);
+        assert!(resized.chunks(3).all(|p| p[1] == 100 && p[0] + p[2] == 180));
+
+        assert_eq!(resize_image(&image, 4, 3, 1, 1).unwrap(), vec![0, 100, 180]);
+        assert!(resize_image(&image, 5, 3, 2, 2).is_err());
+        assert!(resize_image(&[], 4, 3, 2, 2).is_err());
+    }
+
+    #[test]
+    fn test_downscale_nearest() {
+        // RGB, each pixel has its coordinates and a constant
+        let image: Vec<u8> = (0..5 * 3).flat_map(|i| vec![i % 5, i / 5, 7]).collect();
+
+        let (small, width, height) = downscale_nearest(&image, 5, 3, 2).unwrap();
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(small, vec![0, 0, 7, 2, 0, 7]);
+
+        assert_eq!(downscale_nearest(&image, 5, 3, 1).unwrap(), (image.clone(), 5, 3));
+        assert!(downscale_nearest(&image, 5, 3, 0).is_err());
+        assert!(downscale_nearest(&image, 4, 3, 2).is_err());
+    }
+
+    #[test]
+    fn test_thumbnail_size() {
+        assert_eq!(thumbnail_size(2080, 1000, 256), (256, 123));
+        assert_eq!(thumbnail_size(1040, 4000, 256), (67, 256));
+        assert_eq!(thumbnail_size(200, 100, 256), (200, 100));
+        assert_eq!(thumbnail_size(5000, 2, 256), (256, 1));
+    }
+
+    #[test]
+    fn test_estimate_tilt() {
+        let rate = Rate::hz(FINAL_RATE);
+        let lines = |offsets: &[i32]| -> Vec<(u32, i32)> {
+            offsets.iter().enumerate().map(|(x, y)| (x as u32, *y)).collect()
+        };
+
+        assert_eq!(estimate_tilt(&[], rate), 0.);
+        assert_eq!(estimate_tilt(&[(3, 5)], rate), 0.);
+        assert_relative_eq!(estimate_tilt(&lines(&[0, 2, 4, 6, 8]), rate), 2.);
+        assert_relative_eq!(estimate_tilt(&lines(&[10, 9, 9, 8, 7, 7, 6]), rate), -0.64285713);
+
+        // Lines without sync frame don't change the slope
+        assert_relative_eq!(estimate_tilt(&[(0, 0), (1, 2), (4, 8), (9, 18)], rate), 2.);
+
+        // Offsets on samples at the work rate, slope on pixels
+        assert_relative_eq!(
+            estimate_tilt(&lines(&[0, 6, 12, 18]), Rate::hz(FINAL_RATE * 3)), 2.);
+    }
+
+    #[test]
+    fn test_deskew() {
+        let (width, height) = (50, 20);
+
+        // Vertical line that moves to the right half a pixel per line
+        let tilted: Vec<u8> = (0..width * height).map(|i| {
+            let (x, y) = (i % width, i / width);
+            if x == 10 + (y as f32 * 0.5).round() as usize { 255 } else { 0 }
+        }).collect();
+
+        let offsets: Vec<(u32, i32)> = (0..height)
+            .map(|y| (y as u32, (y as f32 * 0.5).round() as i32))
+            .collect();
+        let tilt = estimate_tilt(&offsets, Rate::hz(FINAL_RATE));
+        assert_relative_eq!(tilt, 0.5, epsilon = 0.05);
+
+        let straight = deskew(&tilted, 0.5, width).unwrap();
+        assert_eq!(straight.len(), tilted.len());
+        for line in straight.chunks_exact(width) {
+            assert_eq!(line.iter().position(|x| *x == 255), Some(10));
+        }
+
+        assert!(deskew(&tilted, 0.5, 7).is_err());
+    }
+
+    #[test]
+    fn test_stack() {
+        let (width, height) = (50, 300);
+
+        // Every line with its own brightness, so the lines can be aligned
+        let mut noise = test_util::noise(11);
+        let brightness: Signal = (0..height).map(|_| noise() + 0.5).collect();
+        let image: Signal = (0..width * height)
+            .map(|i| brightness[i / width] + 0.2 * ((i % width) as f32 * 0.3).sin())
+            .collect();
+
+        // Two noisy copies, the second one starts 7 lines later
+        let mut noisy = |image: &[f32]| -> Signal {
+            image.iter().map(|x| x + noise()).collect()
+        };
+        let first = noisy(&image);
+        let second = noisy(&image[7 * width..]);
+
+        let variance = |a: &[f32], b: &[f32]| {
+            a.iter().zip(b).map(|(x, y)| (x - y).powi(2)).sum::<f32>() / a.len() as f32
+        };
+
+        let stacked = stack(&[&first, &second], width).unwrap();
+        assert_eq!(stacked.len(), image.len());
+        let before = variance(&first, &image);
+        let after = variance(&stacked[7 * width..], &image[7 * width..]);
+        assert!(after < 0.6 * before, "{} {}", before, after);
+
+        // First lines only on the first image
+        assert_eq!(&stacked[..7 * width], &first[..7 * width]);
+
+        assert_eq!(stack(&[&first], width).unwrap(), first);
+        assert!(stack(&[], width).is_err());
+        assert!(stack(&[&first[1..]], width).is_err());
+    }
+
+    #[test]
+    fn test_fit_image() {
+        // Wide image, black bars on top and bottom
+        let image = vec![100; 4 * 2];
+        let fitted = fit_image(&image, 4, 1, 8, 8, 0).unwrap();
+        assert_eq!(fitted.len(), 8 * 8);
+        for (y, line) in fitted.chunks_exact(8).enumerate() {
+            let expected = if (2..6).contains(&y) { 100 } else { 0 };
+            assert!(line.iter().all(|x| *x == expected), "line {}: {:?}", y, line);
+        }
+
+        // Tall RGB image, bars on the sides
+        let image: Vec<u8> = [10, 20, 30].iter().cycle().take(2 * 6 * 3).cloned().collect();
+        let fitted = fit_image(&image, 2, 3, 5, 3, 255).unwrap();
+        assert_eq!(fitted.len(), 5 * 3 * 3);
+        for line in fitted.chunks_exact(5 * 3) {
+            assert_eq!(&line[..6], &[255; 6]);
+            assert_eq!(&line[6..9], &[10, 20, 30]);
+            assert_eq!(&line[9..], &[255; 6]);
+        }
+
+        // Already the right size
+        let image: Vec<u8> = (0..12).collect();
+        assert_eq!(fit_image(&image, 4, 1, 4, 3, 0).unwrap(), image);
+
+        assert!(fit_image(&image, 5, 1, 4, 3, 0).is_err());
+        assert!(fit_image(&image, 4, 1, 0, 3, 0).is_err());
+    }
+
+    #[test]
+    fn test_add_margin() {
+        let image: Vec<u8> = (1..=6).collect();
+
+        let (result, width, height) = add_margin(&image, 3, 2, 2, 255);
+        assert_eq!((width, height), (7, 6));
+        assert_eq!(result.len(), 7 * 6);
+        for (y, line) in result.chunks(width).enumerate() {
+            if !(2..4).contains(&y) {
+                assert!(line.iter().all(|x| *x == 255));
+            } else {
+                assert_eq!(&line[..2], &[255, 255]);
+                assert_eq!(&line[2..5], &image[(y - 2) * 3..(y - 1) * 3]);
+                assert_eq!(&line[5..], &[255, 255]);
+            }
+        }
+
+        assert_eq!(add_margin(&image, 3, 2, 0, 0), (image.clone(), 3, 2));
+        assert_eq!(add_margin(&[], 0, 0, 1, 7), (vec![7; 4], 2, 2));
+    }
+
+    #[test]
+    fn test_orient_north_up() {
+        let image: Vec<u8> = vec![
+            1, 2, 3,
+            4, 5, 6,
+        ];
+
+        let mut southbound = image.clone();
+        orient_north_up(&mut southbound, 3, PassDirection::Southbound).unwrap();
+        assert_eq!(southbound, image);
+
+        // Last line first and mirrored
+        let mut northbound = image.clone();
+        orient_north_up(&mut northbound, 3, PassDirection::Northbound).unwrap();
+        assert_eq!(northbound, vec![
+            6, 5, 4,
+            3, 2, 1,
+        ]);
+
+        assert!(orient_north_up(&mut northbound, 4, PassDirection::Northbound).is_err());
+        assert!(orient_north_up(&mut northbound, 0, PassDirection::Southbound).is_err());
+    }
+
+    /// Telemetry where wedge `n` of channel A has value `n * scale_a`, and the
+    /// same for B. Wedge 9 is zero, like on a real frame.
+    fn synthetic_telemetry(scale_a: f32, scale_b: f32) -> Telemetry {
+        let band = |scale: f32| -> Signal {
+            (0..2 * 16 * 8).map(|row| {
+                let wedge = row / 8 % 16 + 1;
+                if wedge == 9 { 0. } else { wedge as f32 * scale }
+            }).collect()
+        };
+        Telemetry::from_bands(&band(scale_a), &band(scale_b), 0).unwrap()
+    }
+
+    #[test]
+    fn test_stretch_to_wedges() {
+        let telemetry = synthetic_telemetry(10., 20.);
+
+        // White wedge is 80 on channel A
+        let mut image: Signal = vec![0., 80., 40., -8., 88.];
+        stretch_to_wedges(&mut image, &telemetry, Some(Channel::A)).unwrap();
+        for (x, y) in image.iter().zip([0., 255., 127.5, -25.5, 280.5].iter()) {
+            assert_relative_eq!(x, y, epsilon = 1e-3);
+        }
+
+        // 160 on channel B, 120 on average
+        let mut image: Signal = vec![0., 160., 120.];
+        stretch_to_wedges(&mut image, &telemetry, Some(Channel::B)).unwrap();
+        assert_relative_eq!(image[1], 255., epsilon = 1e-3);
+        let mut image: Signal = vec![0., 160., 120.];
+        stretch_to_wedges(&mut image, &telemetry, None).unwrap();
+        assert_relative_eq!(image[2], 255., epsilon = 1e-3);
+
+        // No contrast between references
+        let flat = synthetic_telemetry(0., 0.);
+        assert!(stretch_to_wedges(&mut image, &flat, None).is_err());
+    }
+
+    #[test]
+    fn test_balance_channels() {
+        let width = 1040;
+
+        // Same scene, channel B with three times the gain and an offset
+        let scene: Signal = (0..width * 20)
+            .map(|i| ((i % width) as f32 * 0.01).sin() + (i / width) as f32 * 0.05)
+            .collect();
+        let mut a = scene.clone();
+        let mut b: Signal = scene.iter().map(|x| 3. * x + 1.).collect();
+
+        balance_channels(&mut a, &mut b, width).unwrap();
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert_relative_eq!(x, y, epsilon = 1e-4);
+        }
+
+        // Flat channel gets only the mean moved
+        let mut flat: Signal = vec![2.; width * 2];
+        let mut other: Signal = (0..width * 2).map(|i| (i / width) as f32 * 2.).collect();
+        balance_channels(&mut flat, &mut other, width).unwrap();
+        assert!(flat.iter().all(|x| (x - 1.5).abs() < 1e-4));
+
+        assert!(balance_channels(&mut a, &mut vec![0.; 5], width).is_err());
+        assert!(balance_channels(&mut vec![0.; 5], &mut vec![0.; 5], width).is_err());
+    }
+
+    #[test]
+    fn test_sharpness() {
+        let width = 40;
+        let image: Signal = (0..width * 30)
+            .map(|i| if (i % width / 4 + i / width / 4) % 2 == 0 { 200. } else { 50. })
+            .collect();
+
+        // 3x3 box blur, borders left as they are
+        let mut blurred = image.clone();
+        for y in 1..29 {
+            for x in 1..width - 1 {
+                let mut sum = 0.;
+                for dy in 0..3 {
+                    for dx in 0..3 {
+                        sum += image[(y + dy - 1) * width + x + dx - 1];
+                    }
+                }
+                blurred[y * width + x] = sum / 9.;
+            }
+        }
+
+        assert!(sharpness(&image, width).unwrap() > 2. * sharpness(&blurred, width).unwrap());
+        assert!(sharpness(&blurred, width).unwrap() > 0.);
+        assert_eq!(sharpness(&vec![100.; width * 30], width).unwrap(), 0.);
+        assert!(sharpness(&image[..width * 2], width).is_err());
+        assert!(sharpness(&image, 0).is_err());
+        assert!(sharpness(&[], width).is_err());
+    }
+
+    #[test]
+    fn test_unsharp_mask() {
+        let width = 40;
+        let image: Signal = (0..width * 30)
+            .map(|i| {
+                let x = (i % width) as f32;
+                // Soft vertical edge from 50 to 200
+                50. + 150. / (1. + (-(x - 20.) / 3.).exp())
+            })
+            .collect();
+
+        let mut unchanged = image.clone();
+        unsharp_mask(&mut unchanged, width, 2, 0.);
+        assert_eq!(unchanged, image);
+        unsharp_mask(&mut unchanged, width, 0, 1.);
+        assert_eq!(unchanged, image);
+
+        let mut sharpened = image.clone();
+        unsharp_mask(&mut sharpened, width, 2, 1.5);
+        assert!(sharpness(&sharpened, width).unwrap() > sharpness(&image, width).unwrap());
+
+        let min = image.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = image.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        assert!(sharpened.iter().all(|x| *x >= min && *x <= max));
+
+        // Flat areas stay the same
+        assert_relative_eq!(sharpened[0], image[0], epsilon = 1e-3);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let channel: Signal = vec![-5., 0., 0.9, 1., 2.5, 3.99, 4., 100.];
+        assert_eq!(histogram(&channel, 0., 4., 4), vec![3, 1, 1, 3]);
+        assert_eq!(histogram(&channel, 0., 4., 0), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_image_histogram() {
+        let image: Vec<u8> = (0..1000).map(|i| (i % 10) as u8 * 20).chain(vec![255; 5]).collect();
+        let histogram = image_histogram(&image);
+        for (value, count) in histogram.iter().enumerate() {
+            let expected = match value {
+                255 => 5,
+                v if v % 20 == 0 && v < 200 => 100,
+                _ => 0,
+            };
+            assert_eq!(*count, expected, "Value {}", value);
+        }
+        assert_eq!(image_histogram(&[]).iter().sum::<u32>(), 0);
+    }
+
+    #[test]
+    fn test_histogram_chart() {
+        let mut histogram = [0; 256];
+        histogram[0] = 10;
+        histogram[1] = 5;
+        histogram[255] = 1;
+
+        let chart = histogram_chart(&histogram, 10);
+        assert_eq!(chart.len(), 256 * 10);
+
+        // Height of each bar
+        let column = |x: usize| chart.iter().skip(x).step_by(256).filter(|p| **p == 255).count();
+        assert_eq!(column(0), 10);
+        assert_eq!(column(1), 5);
+        assert_eq!(column(2), 0);
+        assert_eq!(column(255), 1);
+
+        // Bars grow from the bottom
+        assert_eq!(chart[256 * 9 + 255], 255);
+        assert_eq!(chart[256 * 8 + 255], 0);
+
+        assert!(histogram_chart(&[0; 256], 10).iter().all(|p| *p == 0));
+    }
+
+    #[test]
+    fn test_otsu_threshold() {
+        let hist = histogram(&bimodal_image(), 0., 256., 256);
+        let threshold = otsu_threshold(&hist).unwrap();
+
+        // Middle of the gap between 60 and 190
+        assert!((threshold - 125.5).abs() <= 1.);
+
+        // Uniform histogram, split in half
+        let threshold = otsu_threshold(&[10; 100]).unwrap();
+        assert!((threshold - 50.).abs() <= 1.);
+
+        assert!(otsu_threshold(&[]).is_err());
+        assert!(otsu_threshold(&[0; 10]).is_err());
+    }
+
+    #[test]
+    fn test_segment() {
+        let channel: Signal = vec![0., 10., 20., 30., 40., 50.];
+        assert_eq!(segment(&channel, 3, &[35., 15.]).unwrap(),
+                   vec![0, 0, 1, 1, 2, 2]);
+        assert_eq!(segment(&channel, 2, &[]).unwrap(), vec![0; 6]);
+        assert!(segment(&channel, 4, &[10.]).is_err());
+        assert!(segment(&channel, 0, &[10.]).is_err());
+
+        // Segment using Otsu's threshold
+        let image = bimodal_image();
+        let threshold = otsu_threshold(&histogram(&image, 0., 256., 256)).unwrap();
+        let classes = segment(&image, 10, &[threshold]).unwrap();
+        for (class, sample) in classes.iter().zip(image.iter()) {
+            assert_eq!(*class, if *sample > 100. { 1 } else { 0 });
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Functions for loading headerless sample files.
+//!
+//! Tools like `rtl_sdr`, `sox` or GQRX can save samples without any header, so
+//! the user has to tell me how they are stored with a `RawFormat`.
+
+use dsp;
+use dsp::Signal;
+use err;
+
+
+/// Byte order of each sample.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/// How each sample is stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleType {
+    /// Unsigned 8 bit centered on 128, like `rtl_sdr` output.
+    U8,
+    /// Signed 8 bit, like `hackrf_transfer` output.
+    I8,
+    /// Signed 16 bit.
+    I16,
+    /// 32 bit float.
+    F32,
+}
+
+impl SampleType {
+    /// Bytes used by each sample.
+    pub fn size(self) -> usize {
+        match self {
+            SampleType::U8 | SampleType::I8 => 1,
+            SampleType::I16 => 2,
+            SampleType::F32 => 4,
+        }
+    }
+}
+
+/// Description of a raw file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RawFormat {
+    pub endian: Endian,
+
+    /// Bytes to skip at the start of the file.
+    pub header_bytes: usize,
+
+    pub sample_type: SampleType,
+}
+
+impl Default for RawFormat {
+    /// Little endian 32 bit float without header, what GNU Radio file sinks
+    /// write on most computers.
+    fn default() -> Self {
+        RawFormat {
+            endian: Endian::Little,
+            header_bytes: 0,
+            sample_type: SampleType::F32,
+        }
+    }
+}
+
+/// Load every sample of a raw file as a real signal.
+///
+/// Integer samples keep their value like on `wav::load_wav()`, only `U8` is
+/// moved so it's centered on zero.
+pub fn read_raw(filename: &str, format: &RawFormat) -> err::Result<Signal> {
+    debug!("Loading raw file: {} as {:?}", filename, format);
+    let bytes = std::fs::read(filename)?;
+    decode_samples(&bytes, format)
+}
+
+/// Load a raw file with interleaved I/Q samples.
+///
+/// Fails if there is an I sample without its Q.
+pub fn read_raw_iq(filename: &str, format: &RawFormat) -> err::Result<Vec<(f32, f32)>> {
+    let samples = read_raw(filename, format)?;
+
+    let pairs = samples.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(err::Error::Deserialize(format!(
+            "Raw I/Q file has an odd number of samples: {}", samples.len())));
+    }
+
+    Ok(pairs.map(|pair| (pair[0], pair[1])).collect())
+}
+
+/// Convert bytes to samples, skipping the header first.
+fn decode_samples(bytes: &[u8], format: &RawFormat) -> err::Result<Signal> {
+    let data = bytes.get(format.header_bytes..).ok_or_else(||
+        err::Error::Deserialize(format!(
+            "Raw file has {} bytes, shorter than the {} bytes header",
+            bytes.len(), format.header_bytes)))?;
+
+    let size = format.sample_type.size();
+    let chunks = data.chunks_exact(size);
+    if !chunks.remainder().is_empty() {
+        return Err(err::Error::Deserialize(format!(
+            "Raw file has {} bytes after the header, not a multiple of the \
+            sample size {}", data.len(), size)));
+    }
+
+    let mut samples: Signal = chunks.map(|chunk| {
+        let mut sample = [0; 4];
+        sample[..size].copy_from_slice(chunk);
+        match (format.sample_type, format.endian) {
+            (SampleType::U8, _) => f32::from(chunk[0]) - 128.,
+            (SampleType::I8, _) => f32::from(chunk[0] as i8),
+            (SampleType::I16, Endian::Little) => f32::from(i16::from_le_bytes([sample[0], sample[1]])),
+            (SampleType::I16, Endian::Big) => f32::from(i16::from_be_bytes([sample[0], sample[1]])),
+            (SampleType::F32, Endian::Little) => f32::from_le_bytes(sample),
+            (SampleType::F32, Endian::Big) => f32::from_be_bytes(sample),
+        }
+    }).collect();
+
+    if format.sample_type == SampleType::F32 {
+        let invalid = dsp::sanitize(&mut samples);
+        if invalid > 0 {
+            warn!("Replaced {} NaN or infinite samples with zero", invalid);
+        }
+    }
+
+    Ok(samples)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use test_util::temp_path;
+
+    #[test]
+    fn test_read_raw() {
+        let filename = &temp_path("raw.bin");
+
+        // Big endian float with a 6 bytes header
+        let values: Signal = vec![1.5, -2.25, 0., 1000.];
+        let mut bytes = b"HEADER".to_vec();
+        for x in values.iter() {
+            bytes.extend_from_slice(&x.to_be_bytes());
+        }
+        std::fs::write(filename, &bytes).unwrap();
+
+        let format = RawFormat {
+            endian: Endian::Big,
+            header_bytes: 6,
+            sample_type: SampleType::F32,
+        };
+        assert_eq!(read_raw(filename, &format).unwrap(), values);
+        assert_eq!(read_raw_iq(filename, &format).unwrap(),
+                   vec![(1.5, -2.25), (0., 1000.)]);
+
+        // Wrong header length leaves bytes of a partial sample
+        let wrong = RawFormat { header_bytes: 5, ..format };
+        assert!(read_raw(filename, &wrong).is_err());
+        let wrong = RawFormat { header_bytes: 100, ..format };
+        assert!(read_raw(filename, &wrong).is_err());
+
+        std::fs::remove_file(filename).unwrap();
+
+        // Integer types
+        let bytes = [0x01, 0x02, 0xff, 0x80];
+        let format = |endian, sample_type| RawFormat { endian, header_bytes: 0, sample_type };
+        assert_eq!(decode_samples(&bytes, &format(Endian::Big, SampleType::I16)).unwrap(),
+                   vec![258., -128.]);
+        assert_eq!(decode_samples(&bytes, &format(Endian::Little, SampleType::I16)).unwrap(),
+                   vec![513., -32513.]);
+        assert_eq!(decode_samples(&bytes, &format(Endian::Little, SampleType::U8)).unwrap(),
+                   vec![-127., -126., 127., 0.]);
+        assert_eq!(decode_samples(&bytes, &format(Endian::Little, SampleType::I8)).unwrap(),
+                   vec![1., 2., -1., -128.]);
+        assert!(decode_samples(&bytes[..3], &format(Endian::Little, SampleType::I16)).is_err());
+        assert_eq!(RawFormat::default().sample_type.size(), 4);
+    }
+}
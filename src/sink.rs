@@ -0,0 +1,541 @@
+//! Destinations for decoded images.
+//!
+//! The decoder writes images line by line to an `ImageSink`, so adding a new
+//! format means implementing that trait and adding it to `open()`.
+
+use std::io::{Seek, SeekFrom, Write};
+
+use png;
+
+use err;
+
+
+/// Receives an image line by line.
+pub trait ImageSink {
+    /// Append one line of pixels.
+    fn write_line(&mut self, row: &[u8]) -> err::Result<()>;
+
+    /// Called after the last line, nothing should be written after this.
+    fn finish(&mut self) -> err::Result<()>;
+}
+
+/// Open a sink for writing an image to a file, the format depends on the
+/// extension.
+///
+/// `.pgm` and `.ppm` give a `PgmWriter`, anything else a `PngWriter` with the
+/// given `compression`.
+pub fn open(
+    filename: &str,
+    width: usize,
+    color: png::ColorType,
+    compression: png::Compression,
+) -> err::Result<Box<dyn ImageSink>> {
+    let extension = std::path::Path::new(filename).extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+
+    match extension.as_deref() {
+        Some("pgm") | Some("ppm") => Ok(Box::new(PgmWriter::new(filename, width, color)?)),
+        _ => {
+            let mut writer = PngWriter::new(filename, width, color);
+            writer.set_compression(compression);
+            Ok(Box::new(writer))
+        },
+    }
+}
+
+/// Write a whole image to a sink and finish it.
+pub fn write_image(
+    sink: &mut dyn ImageSink,
+    image: &[u8],
+    width: usize,
+    color: png::ColorType,
+) -> err::Result<()> {
+    let line_len = width * color.samples();
+    if line_len == 0 || !image.chunks_exact(line_len).remainder().is_empty() {
+        return Err(err::Error::Internal(format!(
+            "Image length {} is not a multiple of line length {}",
+            image.len(), line_len)));
+    }
+
+    for row in image.chunks_exact(line_len) {
+        sink.write_line(row)?;
+    }
+    sink.finish()
+}
+
+/// Fail if `row` doesn't have `width` pixels of `color`.
+fn check_line(row: &[u8], width: usize, color: png::ColorType) -> err::Result<()> {
+    let line_len = width * color.samples();
+    if row.len() != line_len {
+        return Err(err::Error::Internal(format!(
+            "Expected line of length {}, got {}", line_len, row.len())));
+    }
+    Ok(())
+}
+
+/// Writes a PNG file.
+///
+/// The `png` crate I'm using can't write image data in pieces, so I keep every
+/// line in memory and write the file on `finish()`. See `StreamingPngWriter`
+/// if you want to see the image while it's being decoded.
+pub struct PngWriter {
+    filename: String,
+    width: usize,
+    color: png::ColorType,
+    image: Vec<u8>,
+    compression: png::Compression,
+}
+
+impl PngWriter {
+    /// Start writing a PNG with lines of `width` pixels.
+    pub fn new(filename: &str, width: usize, color: png::ColorType) -> PngWriter {
+        PngWriter {
+            filename: filename.to_string(),
+            width,
+            color,
+            image: Vec::new(),
+            compression: png::Compression::Fast,
+        }
+    }
+
+    /// Choose between smaller files and faster encoding.
+    ///
+    /// `png::Compression::Fast` by default, `Best` is useful for archiving.
+    /// The pixels are the same with every level.
+    pub fn set_compression(&mut self, compression: png::Compression) {
+        self.compression = compression;
+    }
+}
+
+impl ImageSink for PngWriter {
+    fn write_line(&mut self, row: &[u8]) -> err::Result<()> {
+        check_line(row, self.width, self.color)?;
+        self.image.extend_from_slice(row);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> err::Result<()> {
+        // To use encoder.set()
+        use png::HasParameters;
+
+        let file = std::fs::File::create(&self.filename)?;
+        let buffer = &mut std::io::BufWriter::new(file);
+
+        let height = self.image.len() / self.width / self.color.samples();
+
+        let mut encoder = png::Encoder::new(buffer, self.width as u32, height as u32);
+        encoder.set(self.color).set(png::BitDepth::Eight)
+            .set(self.compression.clone());
+        let mut writer = encoder.write_header()?;
+
+        writer.write_image_data(&self.image)?;
+
+        Ok(())
+    }
+}
+
+/// How many lines I wait before rewriting the file on `StreamingPngWriter`.
+const STREAMING_FLUSH_LINES: usize = 32;
+
+/// Writes a PNG while lines are being decoded.
+///
+/// The `png` crate I'm using can't write image data in pieces, so I keep the
+/// lines in memory and rewrite the whole file every `STREAMING_FLUSH_LINES`
+/// lines. That way an image viewer or the GUI can show the image growing
+/// while the pass is still going.
+///
+/// Each time I write a temporary file next to the output and then rename it,
+/// so whoever is reading the file never finds it half written.
+pub struct StreamingPngWriter {
+    filename: String,
+    width: usize,
+    color: png::ColorType,
+    image: Vec<u8>,
+    pending_lines: usize,
+    compression: png::Compression,
+}
+
+impl StreamingPngWriter {
+    /// Start writing a PNG with lines of `width` pixels.
+    pub fn new(filename: &str, width: usize, color: png::ColorType) -> StreamingPngWriter {
+        StreamingPngWriter {
+            filename: filename.to_string(),
+            width,
+            color,
+            image: Vec::new(),
+            pending_lines: 0,
+            compression: png::Compression::Fast,
+        }
+    }
+
+    /// Set compression level, like `PngWriter::set_compression()`.
+    ///
+    /// The file is rewritten a lot while decoding, so better keep the default
+    /// `Fast` unless the image is short.
+    pub fn set_compression(&mut self, compression: png::Compression) {
+        self.compression = compression;
+    }
+
+    /// Append one line, must have `width` pixels.
+    pub fn write_line(&mut self, line: &[u8]) -> err::Result<()> {
+        check_line(line, self.width, self.color)?;
+
+        self.image.extend_from_slice(line);
+        self.pending_lines += 1;
+
+        if self.pending_lines >= STREAMING_FLUSH_LINES {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Number of lines written so far.
+    pub fn lines(&self) -> usize {
+        self.image.len() / (self.width * self.color.samples())
+    }
+
+    /// Write every line received until now to the file.
+    ///
+    /// Does nothing if there are no new lines since the last time, so the
+    /// file is not created until there is at least one line.
+    pub fn flush(&mut self) -> err::Result<()> {
+        // To use encoder.set()
+        use png::HasParameters;
+
+        if self.pending_lines == 0 {
+            return Ok(());
+        }
+
+        let temp_filename = format!("{}.tmp", self.filename);
+        {
+            let file = std::fs::File::create(&temp_filename)?;
+            let buffer = &mut std::io::BufWriter::new(file);
+
+            let mut encoder = png::Encoder::new(
+                buffer, self.width as u32, self.lines() as u32);
+            encoder.set(self.color).set(png::BitDepth::Eight)
+                .set(self.compression.clone());
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&self.image)?;
+        }
+        std::fs::rename(&temp_filename, &self.filename)?;
+
+        self.pending_lines = 0;
+        Ok(())
+    }
+
+    /// Write the remaining lines and close the file.
+    pub fn finish(mut self) -> err::Result<()> {
+        self.flush()
+    }
+}
+
+impl ImageSink for StreamingPngWriter {
+    fn write_line(&mut self, row: &[u8]) -> err::Result<()> {
+        StreamingPngWriter::write_line(self, row)
+    }
+
+    fn finish(&mut self) -> err::Result<()> {
+        self.flush()
+    }
+}
+
+/// Digits reserved for the height on the header written by `PgmWriter`.
+const PGM_HEIGHT_DIGITS: usize = 10;
+
+/// Writes a binary PGM file, or PPM if the image is RGB.
+///
+/// The format is so simple that I can write each line as soon as I get it.
+/// The height is not known until the end, so I leave space for it on the
+/// header and go back to write it on `finish()`.
+pub struct PgmWriter {
+    file: std::io::BufWriter<std::fs::File>,
+    width: usize,
+    color: png::ColorType,
+    lines: usize,
+
+    /// Position of the height on the header.
+    height_position: u64,
+}
+
+impl PgmWriter {
+    /// Create file for an image with lines of `width` pixels, only grayscale
+    /// and RGB are supported.
+    pub fn new(filename: &str, width: usize, color: png::ColorType) -> err::Result<PgmWriter> {
+        let magic = match color {
+            png::ColorType::Grayscale => "P5",
+            png::ColorType::RGB => "P6",
+            _ => return Err(err::Error::Internal(format!(
+                "Can't write {:?} images to PGM", color))),
+        };
+
+        let mut file = std::io::BufWriter::new(std::fs::File::create(filename)?);
+
+        let start = format!("{}\n{} ", magic, width);
+        file.write_all(start.as_bytes())?;
+        write!(file, "{:>1$}\n255\n", 0, PGM_HEIGHT_DIGITS)?;
+
+        Ok(PgmWriter {
+            file,
+            width,
+            color,
+            lines: 0,
+            height_position: start.len() as u64,
+        })
+    }
+}
+
+impl ImageSink for PgmWriter {
+    fn write_line(&mut self, row: &[u8]) -> err::Result<()> {
+        check_line(row, self.width, self.color)?;
+        self.file.write_all(row)?;
+        self.lines += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> err::Result<()> {
+        self.file.seek(SeekFrom::Start(self.height_position))?;
+        write!(self.file, "{:>1$}", self.lines, PGM_HEIGHT_DIGITS)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Keeps the image in memory.
+///
+/// Useful when using the decoder as a library and the image is going to be
+/// processed afterwards instead of saved.
+pub struct ImageBuffer {
+    width: usize,
+    color: png::ColorType,
+    image: Vec<u8>,
+    finished: bool,
+}
+
+impl ImageBuffer {
+    /// Create empty buffer for lines of `width` pixels.
+    pub fn new(width: usize, color: png::ColorType) -> ImageBuffer {
+        ImageBuffer {
+            width,
+            color,
+            image: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Pixels received until now, row by row.
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    /// Whether `finish()` was called.
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+}
+
+impl ImageSink for ImageBuffer {
+    fn write_line(&mut self, row: &[u8]) -> err::Result<()> {
+        check_line(row, self.width, self.color)?;
+        self.image.extend_from_slice(row);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> err::Result<()> {
+        self.finished = true;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use test_util::temp_path;
+
+    /// Saves every call received.
+    #[derive(Default)]
+    struct MockSink {
+        rows: Vec<Vec<u8>>,
+        finish_calls: usize,
+    }
+
+    impl ImageSink for MockSink {
+        fn write_line(&mut self, row: &[u8]) -> err::Result<()> {
+            self.rows.push(row.to_vec());
+            Ok(())
+        }
+
+        fn finish(&mut self) -> err::Result<()> {
+            self.finish_calls += 1;
+            Ok(())
+        }
+    }
+
+    fn test_image(width: usize, height: usize) -> Vec<u8> {
+        (0..width * height).map(|i| (i * 7) as u8).collect()
+    }
+
+    /// Size and pixels of a PNG file.
+    fn read_png(filename: &str) -> ((u32, u32), Vec<u8>) {
+        let decoder = png::Decoder::new(std::fs::File::open(filename).unwrap());
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut decoded = vec![0; info.buffer_size()];
+        reader.next_frame(&mut decoded).unwrap();
+        ((info.width, info.height), decoded)
+    }
+
+    #[test]
+    fn test_write_image() {
+        let image = test_image(5, 4);
+
+        let mut sink = MockSink::default();
+        write_image(&mut sink, &image, 5, png::ColorType::Grayscale).unwrap();
+        assert_eq!(sink.rows, image.chunks(5).map(|r| r.to_vec()).collect::<Vec<_>>());
+        assert_eq!(sink.finish_calls, 1);
+
+        // RGB lines are three times longer
+        let mut sink = MockSink::default();
+        write_image(&mut sink, &image, 2, png::ColorType::RGB).unwrap_err();
+        write_image(&mut sink, &image[..18], 2, png::ColorType::RGB).unwrap();
+        assert_eq!(sink.rows.len(), 3);
+        assert!(sink.rows.iter().all(|r| r.len() == 6));
+
+        let mut sink = MockSink::default();
+        assert!(write_image(&mut sink, &image, 0, png::ColorType::Grayscale).is_err());
+        assert_eq!(sink.finish_calls, 0);
+    }
+
+    #[test]
+    fn test_image_buffer() {
+        let image = test_image(5, 4);
+
+        let mut buffer = ImageBuffer::new(5, png::ColorType::Grayscale);
+        assert!(buffer.write_line(&[0; 4]).is_err());
+        write_image(&mut buffer, &image, 5, png::ColorType::Grayscale).unwrap();
+        assert_eq!(buffer.image(), &image[..]);
+        assert!(buffer.finished());
+    }
+
+    #[test]
+    fn test_pgm_writer() {
+        let filename = &temp_path("sink.pgm");
+        let image = test_image(5, 4);
+
+        let mut sink = open(
+            filename, 5, png::ColorType::Grayscale, png::Compression::Fast).unwrap();
+        write_image(sink.as_mut(), &image, 5, png::ColorType::Grayscale).unwrap();
+        drop(sink);
+
+        let bytes = std::fs::read(filename).unwrap();
+        let header = format!("P5\n5 {:>10}\n255\n", 4);
+        assert_eq!(&bytes[..header.len()], header.as_bytes());
+        assert_eq!(&bytes[header.len()..], &image[..]);
+
+        assert!(PgmWriter::new(filename, 5, png::ColorType::GrayscaleAlpha).is_err());
+
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_png_writer() {
+        let filename = &temp_path("sink.png");
+        let image = test_image(5, 4);
+
+        let mut sink = open(
+            filename, 5, png::ColorType::Grayscale, png::Compression::Fast).unwrap();
+        write_image(sink.as_mut(), &image, 5, png::ColorType::Grayscale).unwrap();
+
+        let decoder = png::Decoder::new(std::fs::File::open(filename).unwrap());
+        let (info, mut reader) = decoder.read_info().unwrap();
+        assert_eq!((info.width, info.height), (5, 4));
+        let mut decoded = vec![0; info.buffer_size()];
+        reader.next_frame(&mut decoded).unwrap();
+        assert_eq!(decoded, image);
+
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_png_compression() {
+        let filename = |level: &str| temp_path(&format!("compression_{}.png", level));
+
+        // Smooth gradient with some noise, compresses but not trivially
+        let width = 200;
+        let image: Vec<u8> = (0..width * 150)
+            .map(|i| ((i % width + i / width) / 2 + (i * 31) % 7) as u8)
+            .collect();
+
+        let mut decoded_images = Vec::new();
+        let mut sizes = Vec::new();
+        for (level, compression) in [
+            ("fast", png::Compression::Fast),
+            ("best", png::Compression::Best),
+        ].iter() {
+            let filename = filename(level);
+            let mut sink = open(
+                &filename, width, png::ColorType::Grayscale, compression.clone()).unwrap();
+            write_image(sink.as_mut(), &image, width, png::ColorType::Grayscale).unwrap();
+
+            let decoder = png::Decoder::new(std::fs::File::open(&filename).unwrap());
+            let (info, mut reader) = decoder.read_info().unwrap();
+            let mut decoded = vec![0; info.buffer_size()];
+            reader.next_frame(&mut decoded).unwrap();
+            decoded_images.push(decoded);
+            sizes.push(std::fs::metadata(&filename).unwrap().len());
+
+            std::fs::remove_file(&filename).unwrap();
+        }
+
+        assert_eq!(decoded_images[0], image);
+        assert_eq!(decoded_images[1], image);
+        assert!(sizes[1] <= sizes[0]);
+    }
+
+    #[test]
+    fn test_streaming_png_writer() {
+        let filename = &temp_path("streaming.png");
+        let width = 40;
+        let height = STREAMING_FLUSH_LINES + 10;
+
+        let mut writer = StreamingPngWriter::new(filename, width, png::ColorType::Grayscale);
+        assert!(writer.write_line(&[0; 3]).is_err());
+
+        // Nothing to write yet, no empty PNG
+        writer.flush().unwrap();
+        assert!(!std::path::Path::new(filename).exists());
+
+        for y in 0..height {
+            let line: Vec<u8> = (0..width).map(|x| (x + y) as u8).collect();
+            writer.write_line(&line).unwrap();
+
+            // The file should be readable after the first flush, with every
+            // line written until then, and the temporary file renamed
+            if y == STREAMING_FLUSH_LINES - 1 {
+                let (size, image) = read_png(filename);
+                assert_eq!(size, (width as u32, STREAMING_FLUSH_LINES as u32));
+                assert_eq!(image.len(), width * STREAMING_FLUSH_LINES);
+                assert!(!std::path::Path::new(&format!("{}.tmp", filename)).exists());
+            }
+        }
+        assert_eq!(writer.lines(), height);
+        writer.finish().unwrap();
+
+        let (size, image) = read_png(filename);
+        assert_eq!(size, (width as u32, height as u32));
+        for (y, line) in image.chunks_exact(width).enumerate() {
+            assert_eq!(line[0], y as u8);
+            assert_eq!(line[width - 1], (width - 1 + y) as u8);
+        }
+
+        std::fs::remove_file(filename).unwrap();
+
+        // Finishing without lines doesn't create the file either
+        let writer = StreamingPngWriter::new(filename, width, png::ColorType::Grayscale);
+        writer.finish().unwrap();
+        assert!(!std::path::Path::new(filename).exists());
+    }
+}
@@ -0,0 +1,404 @@
+//! Decoding while the samples arrive.
+//!
+//! Same steps as `noaa_apt::decode()` but on blocks of samples, so lines can be
+//! shown while the satellite is still passing. Everything that needs the whole
+//! signal is left out: there is no automatic gain and the contrast is fixed
+//! beforehand.
+
+use dsp::{self, Signal, Rate, Freq};
+use err;
+use config;
+use context::Context;
+use noaa_apt::{self, FINAL_RATE, PX_PER_ROW, CARRIER_FREQ};
+
+
+/// Input samples processed at a time.
+const BLOCK_LEN: usize = 4096;
+
+/// How far from the expected position I look for the next sync frame, in
+/// pixels. Enough for the drift of a wrong sample rate or a missing sample.
+const SYNC_SEARCH: usize = 20;
+
+/// Decoder that yields one image line at a time.
+///
+/// Takes the samples from any iterator, for example a WAV file being read or
+/// a sound card. The lines have `PX_PER_ROW` pixels, the value `levels.0`
+/// becomes black and `levels.1` becomes white, the levels are on the scale of
+/// the input samples.
+///
+/// The first line starts on the first sync frame found with a confidence of at
+/// least `sync_threshold` (see `noaa_apt::find_sync()`). After that each line
+/// starts on the best sync frame near the expected position, or on the
+/// expected position if the sync frame there has low confidence.
+pub struct DecodeIter<I: Iterator<Item = err::Result<f32>>> {
+    samples: I,
+
+    /// Resampler to the work rate, also removes the noise outside the AM
+    /// signal.
+    resampler: dsp::Resampler,
+
+    demodulator: dsp::Demodulator,
+
+    /// Mean output of the demodulator for a carrier with amplitude one.
+    demodulator_gain: f32,
+
+    /// Resampler from the work rate to `FINAL_RATE`, its filter removes the
+    /// carrier that is left after demodulating.
+    final_resampler: dsp::Resampler,
+
+    /// Sync frame at `FINAL_RATE`.
+    guard: Vec<i8>,
+
+    sync_threshold: f32,
+    levels: (f32, f32),
+
+    /// Demodulated samples at `FINAL_RATE` that are not on a line yet.
+    buffer: Signal,
+
+    /// Start of the next line on the buffer, `None` before finding the first
+    /// sync frame.
+    line_start: Option<usize>,
+
+    /// Whether every input sample was received.
+    input_finished: bool,
+}
+
+impl<I: Iterator<Item = err::Result<f32>>> DecodeIter<I> {
+
+    /// Create decoder. Uses the work rate, sync threshold and filter
+    /// parameters from `settings`, ignores the rest. The resamplers sum the
+    /// products as `context.dot_mode()` says and fail if the filters are
+    /// longer than `context.max_resample_taps`.
+    pub fn new(
+        context: &Context,
+        samples: I,
+        input_rate: Rate,
+        settings: &config::DecodeSettings,
+        levels: (f32, f32),
+    ) -> err::Result<Self> {
+
+        let work_rate = Rate::hz(settings.work_rate);
+        let final_rate = Rate::hz(FINAL_RATE);
+
+        let resample_filter = noaa_apt::resample_filter(settings, input_rate);
+        let final_filter = noaa_apt::demodulation_filter(settings, work_rate);
+
+        // The output of the demodulator for a constant carrier has some ripple
+        // that the final filter removes, the mean depends on the carrier
+        // frequency. Measure it so the levels can be on the scale of the input
+        let carrier = Freq::hz(CARRIER_FREQ as f32, work_rate);
+        let tone: Signal = (0..1000).map(|i| (i as f32 * carrier.get_rad()).cos()).collect();
+        let mut demodulated_tone = Signal::new();
+        dsp::Demodulator::new(carrier).process_into(&tone, &mut demodulated_tone);
+        let demodulator_gain = demodulated_tone[1..].iter().sum::<f32>() / (tone.len() - 1) as f32;
+
+        let mut resampler = dsp::Resampler::new(
+            input_rate, work_rate, resample_filter, context.max_resample_taps)?;
+        resampler.set_dot_mode(context.dot_mode());
+        let mut final_resampler = dsp::Resampler::new(
+            work_rate, final_rate, final_filter, context.max_resample_taps)?;
+        final_resampler.set_dot_mode(context.dot_mode());
+
+        Ok(Self {
+            samples,
+            resampler,
+            demodulator: dsp::Demodulator::new(carrier),
+            demodulator_gain,
+            final_resampler,
+            guard: noaa_apt::generate_sync_frame(final_rate)?,
+            sync_threshold: settings.sync_threshold,
+            levels,
+            buffer: Vec::new(),
+            line_start: None,
+            input_finished: false,
+        })
+    }
+
+    /// Read the next block of input samples and process them. Returns false
+    /// if there were no samples left.
+    fn process_block(&mut self) -> err::Result<bool> {
+        let block: Signal = self.samples.by_ref().take(BLOCK_LEN)
+            .collect::<err::Result<Signal>>()?;
+
+        let resampled = if block.is_empty() {
+            self.resampler.finish()
+        } else {
+            self.resampler.process(&block)
+        };
+
+        let mut demodulated = Signal::with_capacity(resampled.len());
+        self.demodulator.process_into(&resampled, &mut demodulated);
+        for x in demodulated.iter_mut() {
+            *x /= self.demodulator_gain;
+        }
+
+        let mut output = self.final_resampler.process(&demodulated);
+        if block.is_empty() {
+            output.extend(self.final_resampler.finish());
+        }
+        self.buffer.extend(output);
+
+        Ok(!block.is_empty())
+    }
+
+    /// Find the strongest sync frame starting between `start` and `end` on the
+    /// buffer. Returns the position and the confidence.
+    fn best_sync(&self, start: usize, end: usize) -> (usize, f32) {
+        let correlation = |i: usize| -> f32 {
+            self.guard.iter().zip(&self.buffer[i..])
+                .map(|(g, x)| f32::from(*g) * x).sum()
+        };
+
+        let mut best = start;
+        let mut best_correlation = correlation(start);
+        for i in start + 1 .. end {
+            let c = correlation(i);
+            if c > best_correlation {
+                best = i;
+                best_correlation = c;
+            }
+        }
+
+        let guard_len = self.guard.len();
+        (best, noaa_apt::sync_confidence(&self.guard, &self.buffer[best..best + guard_len]))
+    }
+
+    /// Take the next line from the buffer if there are enough samples.
+    fn take_line(&mut self) -> Option<Vec<u8>> {
+        let row = PX_PER_ROW as usize;
+        let guard_len = self.guard.len();
+        let (low, high) = self.levels;
+
+        // Look for the first sync frame
+        while self.line_start.is_none() {
+            if self.buffer.len() < row + guard_len {
+                return None;
+            }
+
+            let (position, confidence) = self.best_sync(0, row);
+            if confidence >= self.sync_threshold {
+                self.line_start = Some(position);
+            } else {
+                self.buffer.drain(..row);
+            }
+        }
+
+        let start = self.line_start.unwrap();
+
+        // Wait until the next sync frame can be found, unless there is nothing
+        // left to receive
+        let search_end = start + row + SYNC_SEARCH;
+        if self.buffer.len() < search_end + guard_len {
+            if !self.input_finished || self.buffer.len() < start + row {
+                return None;
+            }
+            // Last line
+            let line = noaa_apt::map(&self.buffer[start..start + row], low, high);
+            self.buffer.drain(..start + row);
+            self.line_start = Some(0);
+            return Some(line);
+        }
+
+        let expected = start + row;
+        let (position, confidence) = self.best_sync(expected - SYNC_SEARCH, search_end);
+        let next = if confidence >= self.sync_threshold { position } else { expected };
+
+        let line = noaa_apt::map(&self.buffer[start..start + row], low, high);
+
+        // Keep the samples from the next line
+        self.buffer.drain(..next);
+        self.line_start = Some(0);
+
+        Some(line)
+    }
+}
+
+impl<I: Iterator<Item = err::Result<f32>>> Iterator for DecodeIter<I> {
+    type Item = err::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(line) = self.take_line() {
+                return Some(Ok(line));
+            }
+            if self.input_finished {
+                return None;
+            }
+            match self.process_block() {
+                Ok(true) => {},
+                Ok(false) => self.input_finished = true,
+                Err(e) => {
+                    // Don't try to read again after an error
+                    self.input_finished = true;
+                    self.buffer.clear();
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use test_util::{self, temp_path};
+    use noaa_apt::Contrast;
+    use wav;
+
+    /// Generate APT-like signal with `lines` lines, starting somewhere in the
+    /// middle of a line. Has sync frames and a different gradient on each
+    /// line, brightness from 0 to 1.
+    fn generate_apt(lines: usize, input_rate: u32) -> Signal {
+        let guard = noaa_apt::generate_sync_frame(Rate::hz(FINAL_RATE)).unwrap();
+        let row = PX_PER_ROW as usize;
+
+        let pixels: Signal = (0..lines * row + 500).map(|i| {
+            let (line, x) = (i / row, (i + row - 500) % row);
+            if x < guard.len() {
+                if guard[x] == 1 { 1. } else { 0. }
+            } else {
+                ((x + 20 * line) % 400) as f32 / 400.
+            }
+        }).collect();
+
+        // AM modulate, interpolating the pixels linearly
+        let duration = pixels.len() as f32 / FINAL_RATE as f32;
+        (0..(duration * input_rate as f32) as usize).map(|i| {
+            let t = i as f32 / input_rate as f32;
+            let position = t * FINAL_RATE as f32;
+            let j = position as usize;
+            let fraction = position - j as f32;
+            let brightness = pixels[j] * (1. - fraction)
+                + pixels.get(j + 1).unwrap_or(&0.) * fraction;
+            brightness * (2. * std::f32::consts::PI * CARRIER_FREQ as f32 * t).sin()
+        }).collect()
+    }
+
+    /// Settings for syncing the signal from `generate_apt()`, with the
+    /// contrast of the whole range so the lines can be compared.
+    fn test_settings(input_filename: &str, output_filename: &str) -> config::DecodeSettings {
+        config::DecodeSettings {
+            sync: true,
+            sync_threshold: 0.5,
+            channel_a_contrast: Contrast::MinMax,
+            channel_b_contrast: Contrast::MinMax,
+            ..test_util::decode_settings(input_filename, output_filename)
+        }
+    }
+
+    #[test]
+    fn test_decode_iter() {
+        let signal = generate_apt(20, 11025);
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 11025,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        wav::write_wav(&temp_path("stream_input.wav"), &signal, spec).unwrap();
+        let settings = config::DecodeSettings {
+            deterministic: true,
+            ..test_settings(&temp_path("stream_input.wav"), &temp_path("stream_output.png"))
+        };
+
+        // Decode at once
+        let context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                      Rate::hz(FINAL_RATE), false, false);
+        noaa_apt::decode(context, settings.clone()).unwrap();
+
+        let file = std::fs::File::open(temp_path("stream_output.png")).unwrap();
+        let decoder = png::Decoder::new(file);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut image = vec![0; info.buffer_size()];
+        reader.next_frame(&mut image).unwrap();
+
+        // Decode by lines
+        let (samples, _spec) = wav::load_wav(&temp_path("stream_input.wav")).unwrap();
+        let mut context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                          Rate::hz(FINAL_RATE), false, false);
+        context.deterministic = settings.deterministic;
+        let iter = DecodeIter::new(
+            &context, samples.into_iter().map(Ok), Rate::hz(11025), &settings, (0., 1.)
+        ).unwrap();
+        assert_eq!(iter.resampler.dot_mode(), dsp::DotMode::Deterministic);
+        assert_eq!(iter.final_resampler.dot_mode(), dsp::DotMode::Deterministic);
+        let lines: Vec<Vec<u8>> = iter.collect::<err::Result<_>>().unwrap();
+
+        assert!(lines.iter().all(|line| line.len() == PX_PER_ROW as usize));
+        assert!(lines.len() >= 19 && lines.len() <= 20);
+        let rows = image.len() / PX_PER_ROW as usize;
+        assert!(rows + 2 >= lines.len() && rows <= lines.len());
+
+        // Both start on the first sync frame, but the filters and the contrast
+        // are a little different, so I compare the correlation of the video
+        // of each line
+        let video = noaa_apt::column_layout(PX_PER_ROW as usize).video_a;
+        for (line, expected) in lines.iter().zip(image.chunks_exact(PX_PER_ROW as usize)) {
+            let (line, expected) = (&line[video.clone()], &expected[video.clone()]);
+            let mean = |x: &[u8]| x.iter().map(|v| f32::from(*v)).sum::<f32>() / x.len() as f32;
+            let (mean_a, mean_b) = (mean(line), mean(expected));
+            let (mut ab, mut aa, mut bb) = (0., 0., 0.);
+            for (a, b) in line.iter().zip(expected.iter()) {
+                let (a, b) = (f32::from(*a) - mean_a, f32::from(*b) - mean_b);
+                ab += a * b;
+                aa += a * a;
+                bb += b * b;
+            }
+            let correlation = ab / (aa * bb).sqrt();
+            assert!(correlation > 0.99, "Correlation: {}", correlation);
+        }
+
+        // The sync frames are black and white
+        let guard = noaa_apt::generate_sync_frame(Rate::hz(FINAL_RATE)).unwrap();
+        for line in lines.iter() {
+            for (g, x) in guard.iter().zip(line.iter()).skip(2).take(24) {
+                assert!(if *g == 1 { *x > 200 } else { *x < 55 });
+            }
+        }
+
+        std::fs::remove_file(temp_path("stream_input.wav")).unwrap();
+        std::fs::remove_file(temp_path("stream_output.png")).unwrap();
+    }
+
+    #[test]
+    fn test_decode_iter_high_precision() {
+        let signal = generate_apt(5, 11025);
+        let settings = test_settings("", "");
+
+        let decode = |high_precision: bool| -> Vec<Vec<u8>> {
+            let mut context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                              Rate::hz(FINAL_RATE), false, false);
+            context.high_precision = high_precision;
+            let iter = DecodeIter::new(
+                &context, signal.iter().cloned().map(Ok), Rate::hz(11025), &settings, (0., 1.)
+            ).unwrap();
+            let mode = if high_precision { dsp::DotMode::HighPrecision } else { dsp::DotMode::Fast };
+            assert_eq!(iter.resampler.dot_mode(), mode);
+            assert_eq!(iter.final_resampler.dot_mode(), mode);
+            iter.collect::<err::Result<_>>().unwrap()
+        };
+
+        // Only the rounding changes
+        let fast = decode(false);
+        let precise = decode(true);
+        assert!(!fast.is_empty());
+        assert_eq!(fast.len(), precise.len());
+        for (a, b) in fast.iter().flatten().zip(precise.iter().flatten()) {
+            assert!((i16::from(*a) - i16::from(*b)).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn test_decode_iter_error() {
+        let settings = test_settings("", "");
+        let samples = vec![Ok(0.), Err(err::Error::Internal("Broken".to_string()))];
+        let context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                      Rate::hz(FINAL_RATE), false, false);
+        let mut iter = DecodeIter::new(
+            &context, samples.into_iter(), Rate::hz(11025), &settings, (0., 1.)).unwrap();
+
+        assert!(matches!(iter.next(), Some(Err(err::Error::Internal(_)))));
+        assert!(iter.next().is_none());
+    }
+}
@@ -7,11 +7,29 @@ use context::{Context, Step};
 
 
 /// Determines if working channel A or B.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Channel {
     A,
     B,
 }
 
+/// Whether the AVHRR channel name is thermal infrared: 3b, 4 or 5.
+fn is_ir(name: &str) -> bool {
+    name == "3b" || name == "4" || name == "5"
+}
+
+/// Check if channels A and B look swapped according to the channel
+/// identification wedges.
+///
+/// The NOAA satellites send a visible or near infrared channel on A and a
+/// thermal infrared one on B, so an infrared channel on A next to a visible
+/// one on B means the lines were split half a line off. Fix it with
+/// `noaa_apt::shift_half_line()` and read the telemetry again.
+pub fn detect_channel_swap(telemetry: &Telemetry) -> bool {
+    is_ir(telemetry.get_channel_name(Channel::A))
+        && !is_ir(telemetry.get_channel_name(Channel::B))
+}
+
 /// Contains the telemetry data.
 ///
 /// Also methods to extract values from it.
@@ -27,40 +45,56 @@ impl Telemetry {
     ///
     /// Takes two signals containing the horizontal averages of each band (8
     /// values per wedge), also takes the row where to start reading the frame.
-    pub fn from_bands(means_a: &Signal, means_b: &Signal, row: usize) -> Self {
+    ///
+    /// The value of each wedge is the median of its rows instead of the mean,
+    /// so a few lines ruined by noise don't change the result. Fails if the
+    /// bands end before the contrast wedges of the next frame.
+    pub fn from_bands(means_a: &Signal, means_b: &Signal, row: usize) -> err::Result<Self> {
+        Self::from_frames(means_a, means_b, &[row])
+    }
 
-        // Calculate the mean of contiguous 8 values, starting from the given
-        // one until wedge 9 of the next frame
-        // As a result we have the average of wedges 1-16 and 1-9
-        let means_a: Signal = means_a[row..]
-            .chunks_exact(8).map(|x| x.iter().sum::<f32>() / 8.).take(16 + 9).collect();
-        let means_b: Signal = means_b[row..]
-            .chunks_exact(8).map(|x| x.iter().sum::<f32>() / 8.).take(16 + 9).collect();
+    /// Read telemetry from several frames, like `from_bands()`.
+    ///
+    /// `rows` has the row where each frame starts, the median of each wedge is
+    /// taken over the rows of every frame. Fails if there are no frames.
+    pub fn from_frames(means_a: &Signal, means_b: &Signal, rows: &[usize]) -> err::Result<Self> {
+
+        // Contrast wedges are read on two frames of 16 wedges, 8 rows each
+        let needed = match rows.iter().max() {
+            Some(row) => row + FRAME_READ_LINES,
+            None => return Err(err::Error::Internal(
+                "No telemetry frames to read".to_string())),
+        };
+        if means_a.len() < needed || means_b.len() < needed {
+            return Err(err::Error::Internal(format!(
+                "Telemetry bands too short, need {} rows, got {} and {}",
+                needed, means_a.len(), means_b.len())));
+        }
 
-        let telemetry = Self {
-            values_a: (1..=16).map(|wedge|
-                // Contrast wedges 1-9 are averaged to the ones on the next frame
+        // Rows of a wedge on every frame, contrast wedges 1-9 are joined with
+        // the ones on the next frame
+        let wedge_value = |means: &Signal, wedge: usize| {
+            let mut values: Signal = Vec::new();
+            for row in rows {
+                let start = row + (wedge - 1) * 8;
+                values.extend_from_slice(&means[start..start + 8]);
                 if wedge <= 9 {
-                    (means_a[wedge - 1] + means_a[wedge + 16 - 1]) / 2.
-                } else {
-                    means_a[wedge - 1]
+                    let next_start = start + FRAME_LINES;
+                    values.extend_from_slice(&means[next_start..next_start + 8]);
                 }
-            ).collect(),
+            }
+            median(&mut values).expect("Wedges have rows")
+        };
 
-            values_b: (1..=16).map(|wedge|
-                // Contrast wedges 1-9 are averaged to the ones on the next frame
-                if wedge <= 9 {
-                    (means_b[wedge - 1] + means_b[wedge + 16 - 1]) / 2.
-                } else {
-                    means_b[wedge - 1]
-                }
-            ).collect(),
+        let telemetry = Self {
+            values_a: (1..=16).map(|wedge| wedge_value(means_a, wedge)).collect(),
+            values_b: (1..=16).map(|wedge| wedge_value(means_b, wedge)).collect(),
         };
 
         debug!("Telemetry wedges_a: {:?}, wedges_b: {:?}",
             telemetry.values_a, telemetry.values_b);
 
-        telemetry
+        Ok(telemetry)
     }
 
     /// Get value of wedge.
@@ -104,12 +138,180 @@ impl Telemetry {
     }
 }
 
+/// Median of some values, sorts them in place.
+///
+/// With an even number of values returns the mean of the two middle ones,
+/// `None` if there are no values.
+pub fn median(values: &mut [f32]) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let middle = values.len() / 2;
+    if middle * 2 == values.len() {
+        Some((values[middle - 1] + values[middle]) / 2.)
+    } else {
+        Some(values[middle])
+    }
+}
+
+/// Lines on each telemetry frame, 16 wedges of 8 lines.
+pub const FRAME_LINES: usize = 16 * 8;
+
+/// Lines needed to read a frame, the contrast wedges of the next frame are
+/// read too.
+const FRAME_READ_LINES: usize = FRAME_LINES + 9 * 8;
+
+/// Lines averaged by default on `read_telemetry()`, a single frame.
+pub const DEFAULT_TELEMETRY_LINES: usize = FRAME_LINES;
+
+/// First column of the telemetry band of channel A and B read by
+/// `read_telemetry()`, when the image is perfectly aligned.
+const TELEMETRY_START: [usize; 2] = [994, 2034];
+
+/// Columns averaged on each telemetry band.
+const TELEMETRY_WIDTH: usize = 44;
+
+/// Maximum columns that `telemetry_shift()` moves the telemetry bands.
+const TELEMETRY_MAX_SHIFT: i64 = 20;
+
+/// Values of a telemetry band on a line, `shift` columns to the right.
+///
+/// The signal is read as a whole, so the band can continue on the next line.
+/// Gets cut at the ends of the signal.
+fn band(signal: &Signal, line: usize, start: usize, shift: i64) -> &[f32] {
+    let first = (line * PX_PER_ROW as usize + start) as i64 + shift;
+    let first = first.clamp(0, signal.len() as i64) as usize;
+    let last = (first + TELEMETRY_WIDTH).min(signal.len());
+    &signal[first..last]
+}
+
+/// Find how many columns the telemetry bands are moved to the right.
+///
+/// If the syncing is a little off the fixed columns end up mixing video and
+/// telemetry. Along a line the telemetry band is flat, while over the lines
+/// it changes a lot because of the wedges. So I try every shift up to
+/// `TELEMETRY_MAX_SHIFT` and keep the one where the bands have the least
+/// horizontal variance compared to the variance of their means. On ties the
+/// smallest shift wins.
+pub fn telemetry_shift(signal: &Signal) -> i64 {
+    let lines = signal.len() / PX_PER_ROW as usize;
+    if lines < 2 {
+        return 0;
+    }
+
+    let score = |shift: i64| -> f64 {
+        let mut horizontal = 0.;
+        let mut means: Vec<f64> = Vec::with_capacity(2 * lines);
+        for line in 0..lines {
+            for start in TELEMETRY_START.iter() {
+                let values = band(signal, line, *start, shift);
+                if values.is_empty() {
+                    continue;
+                }
+                let n = values.len() as f64;
+                let mean = values.iter().map(|x| f64::from(*x)).sum::<f64>() / n;
+                horizontal += values.iter()
+                    .map(|x| (f64::from(*x) - mean).powi(2)).sum::<f64>() / n;
+                means.push(mean);
+            }
+        }
+
+        let n = means.len().max(1) as f64;
+        let mean = means.iter().sum::<f64>() / n;
+        let vertical = means.iter().map(|x| (x - mean).powi(2)).sum::<f64>();
+        if vertical > 0. { horizontal / vertical } else { f64::INFINITY }
+    };
+
+    let mut best: (i64, f64) = (0, score(0));
+    for shift in (1..=TELEMETRY_MAX_SHIFT).flat_map(|s| vec![-s, s]) {
+        let s = score(shift);
+        if s < best.1 {
+            best = (shift, s);
+        }
+    }
+
+    if best.0 != 0 {
+        info!("Telemetry bands moved {} columns", best.0);
+    }
+    best.0
+}
+
 /// Read telemetry from aligned signal.
 ///
 /// Takes already synced signal, it's a Vec where the first `PX_PER_ROW` values
 /// represent the first line of the image, the next `PX_PER_ROW` represent the
-/// next line, etc.
-pub fn read_telemetry(context: &mut Context, signal: &Signal) -> err::Result<Telemetry> {
+/// next line, etc. The columns of the telemetry bands are found with
+/// `telemetry_shift()`, so the wedges are read right even if the syncing is
+/// a few pixels off.
+///
+/// Uses the frames that start less than `lines / 2` lines away from the
+/// frame with the best quality, always at least that one. More lines give a
+/// more stable result if the wedges don't change during the pass, see
+/// `read_telemetry_windowed()` otherwise.
+pub fn read_telemetry(
+    context: &mut Context,
+    signal: &Signal,
+    lines: usize,
+) -> err::Result<Telemetry> {
+
+    let (mean_a, mean_b, best) = telemetry_bands(context, signal)?;
+
+    let frames: Vec<usize> = frame_starts(best, mean_a.len())
+        .filter(|row| *row == best || row.abs_diff(best) < lines / 2)
+        .collect();
+
+    let telemetry = Telemetry::from_frames(&mean_a, &mean_b, &frames)?;
+    info!("Channel A: {}, Channel B: {}",
+        telemetry.get_channel_name(Channel::A), telemetry.get_channel_name(Channel::B));
+
+    Ok(telemetry)
+}
+
+/// Read telemetry on each block of `lines` lines of an aligned signal.
+///
+/// For long passes where the wedges change, e.g. the temperature of the
+/// blackbody. Each block uses the frames that start on it and are complete,
+/// blocks without frames are skipped. Returns the first line of each block
+/// with its telemetry.
+pub fn read_telemetry_windowed(
+    context: &mut Context,
+    signal: &Signal,
+    lines: usize,
+) -> err::Result<Vec<(usize, Telemetry)>> {
+
+    if lines == 0 {
+        return Err(err::Error::Internal(
+            "Can't read telemetry on blocks of 0 lines".to_string()));
+    }
+
+    let (mean_a, mean_b, best) = telemetry_bands(context, signal)?;
+
+    let mut result = Vec::new();
+    for block in (0..mean_a.len()).step_by(lines) {
+        let frames: Vec<usize> = frame_starts(best, mean_a.len())
+            .filter(|row| (block..block + lines).contains(row))
+            .collect();
+        if !frames.is_empty() {
+            result.push((block, Telemetry::from_frames(&mean_a, &mean_b, &frames)?));
+        }
+    }
+
+    Ok(result)
+}
+
+/// Rows where complete telemetry frames start, given the start of one of them
+/// and the amount of rows.
+fn frame_starts(best: usize, rows: usize) -> impl Iterator<Item = usize> {
+    (best % FRAME_LINES..rows.saturating_sub(FRAME_READ_LINES - 1)).step_by(FRAME_LINES)
+}
+
+/// Horizontal averages of each telemetry band and the row where the frame
+/// with the best quality starts.
+fn telemetry_bands(
+    context: &mut Context,
+    signal: &Signal,
+) -> err::Result<(Signal, Signal, usize)> {
 
     // Sample of telemetry band used for correlation. Only contrast wedges
     // (1 to 9) are given. Each value is repeated 8 times because the height of
@@ -128,23 +330,26 @@ pub fn read_telemetry(context: &mut Context, signal: &Signal) -> err::Result<Tel
     // Horizontal variance of both telemetry bands, indicates if there is noise
     let mut variance: Signal = Vec::with_capacity(signal.len() / PX_PER_ROW as usize);
 
+    let shift = telemetry_shift(signal);
+
     // Iterate a row at a time (each row is one pixel high)
-    for line in signal.chunks_exact(PX_PER_ROW as usize) {
+    for line in 0 .. signal.len() / PX_PER_ROW as usize {
 
-        // Values on each band
-        let a_values = &line[994..(994+44)];
-        let b_values = &line[2034..(2034+44)];
+        // Values on each band, can be shorter on the last line
+        let a_values = band(signal, line, TELEMETRY_START[0], shift);
+        let b_values = band(signal, line, TELEMETRY_START[1], shift);
+        let (len_a, len_b) = (a_values.len().max(1) as f32, b_values.len().max(1) as f32);
 
         // Horizontal average
-        let curr_mean_a: f32 = a_values.iter().sum::<f32>() / 44.;
-        let curr_mean_b: f32 = b_values.iter().sum::<f32>() / 44.;
+        let curr_mean_a: f32 = a_values.iter().sum::<f32>() / len_a;
+        let curr_mean_b: f32 = b_values.iter().sum::<f32>() / len_b;
         mean_a.push(curr_mean_a);
         mean_b.push(curr_mean_b);
 
         // Horizontal variance
         variance.push(
             (a_values.iter().map(|x| (x - curr_mean_a).powi(2)).sum::<f32>() +
-             b_values.iter().map(|x| (x - curr_mean_b).powi(2)).sum::<f32>()) / 88.
+             b_values.iter().map(|x| (x - curr_mean_b).powi(2)).sum::<f32>()) / (len_a + len_b)
         );
     }
 
@@ -197,23 +402,20 @@ pub fn read_telemetry(context: &mut Context, signal: &Signal) -> err::Result<Tel
         }
     }
 
-    let telemetry = Telemetry::from_bands(&mean_a, &mean_b, best.0);
-    info!("Channel A: {}, Channel B: {}",
-        telemetry.get_channel_name(Channel::A), telemetry.get_channel_name(Channel::B));
-
     context.step(Step::signal("telemetry_a", &mean_a, None))?;
     context.step(Step::signal("telemetry_b", &mean_b, None))?;
     context.step(Step::signal("telemetry_correlation", &corr, None))?;
     context.step(Step::signal("telemetry_variance", &variance, None))?;
     context.step(Step::signal("telemetry_quality", &quality, None))?;
 
-    Ok(telemetry)
+    Ok((mean_a, mean_b, best.0))
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use test_util;
 
     /// Check if two floats are equal given some margin of precision
     fn assert_roughly_equal(a: f32, b: f32) {
@@ -261,7 +463,7 @@ mod tests {
         // if channel A is being averaged to channel B
         let means_b = means_a.iter().map(|x| x + 1.).collect();
 
-        let telemetry = Telemetry::from_bands(&means_a, &means_b, index_row);
+        let telemetry = Telemetry::from_bands(&means_a, &means_b, index_row).unwrap();
 
         for wedge in 1..=16 {
             assert_roughly_equal(
@@ -279,6 +481,190 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_telemetry_from_bands_outliers() {
+        let mut noise = test_util::noise(99);
+
+        // Two frames with wedges 1 to 16, 8 rows each, with a little noise
+        let mut means: Signal = (0..2 * 16 * 8)
+            .map(|i| ((i / 8) % 16 + 1) as f32 + 0.05 * noise()).collect();
+
+        // Some rows are garbage, up to three of the eight rows of a wedge
+        for (i, row) in [3, 8, 9, 10, 40, 41, 47, 100, 127, 130, 200].iter().enumerate() {
+            means[*row] = if i % 2 == 0 { 1000. } else { -300. };
+        }
+
+        let telemetry = Telemetry::from_bands(&means, &means, 0).unwrap();
+
+        // Missing the contrast wedges of the next frame
+        let short: Signal = means[..(16 + 9) * 8 - 1].to_vec();
+        assert!(Telemetry::from_bands(&short, &short, 0).is_err());
+        assert!(Telemetry::from_bands(&Signal::new(), &Signal::new(), 0).is_err());
+        for wedge in 1..=16 {
+            assert_abs_diff_eq!(
+                telemetry.get_wedge_value(wedge, Some(Channel::A)),
+                wedge as f32,
+                epsilon = 0.05
+            );
+        }
+    }
+
+    /// Synced signal with three telemetry frames on both channels, video is
+    /// noise. Wedges 1 to 8 go from 31 to 255, wedge 9 is zero, wedge 16 has
+    /// the given channel identification values and the rest are 100.
+    fn synthetic_image(id_a: f32, id_b: f32) -> Signal {
+        use noaa_apt::column_layout;
+
+        let layout = column_layout(PX_PER_ROW as usize);
+        let mut noise = test_util::noise(5);
+        let wedge_value = |line: usize, id: f32| match line / 8 % 16 + 1 {
+            wedge if wedge <= 8 => 32. * wedge as f32 - 1.,
+            9 => 0.,
+            16 => id,
+            _ => 100.,
+        };
+
+        (0..3 * 16 * 8).flat_map(|line| {
+            (0..PX_PER_ROW as usize).map(|column| {
+                let random = noise();
+                if layout.telemetry_a.contains(&column) {
+                    wedge_value(line, id_a)
+                } else if layout.telemetry_b.contains(&column) {
+                    wedge_value(line, id_b)
+                } else if layout.sync_a.contains(&column) || layout.sync_b.contains(&column) {
+                    if column / 4 % 2 == 0 { 0. } else { 255. }
+                } else {
+                    (random + 0.5) * 255.
+                }
+            }).collect::<Signal>()
+        }).collect()
+    }
+
+    #[test]
+    fn test_telemetry_shift() {
+        let image = synthetic_image(100., 100.);
+        let mut context = Context::resample(|_,_| {}, false, false);
+
+        let check = |telemetry: Telemetry| {
+            for wedge in 1..=8 {
+                assert_relative_eq!(telemetry.get_wedge_value(wedge, None),
+                                    32. * wedge as f32 - 1., epsilon = 0.01);
+            }
+            assert_relative_eq!(telemetry.get_wedge_value(9, None), 0., epsilon = 0.01);
+        };
+
+        // The fixed columns start one pixel before the band
+        assert!(telemetry_shift(&image).abs() <= 2);
+        check(read_telemetry(&mut context, &image, DEFAULT_TELEMETRY_LINES).unwrap());
+
+        // Moved to the right and to the left, the fixed columns would mix
+        // video with the wedges
+        for &offset in [12, -9].iter() {
+            let shifted: Signal = if offset > 0 {
+                std::iter::repeat_n(0., offset as usize)
+                    .chain(image[..image.len() - offset as usize].iter().cloned()).collect()
+            } else {
+                image[(-offset) as usize..].iter().cloned()
+                    .chain(std::iter::repeat_n(0., (-offset) as usize)).collect()
+            };
+            assert!((telemetry_shift(&shifted) - offset).abs() <= 2);
+            check(read_telemetry(&mut context, &shifted, DEFAULT_TELEMETRY_LINES).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_detect_channel_swap() {
+        use noaa_apt::shift_half_line;
+
+        assert!(!detect_channel_swap(&telemetry_with_ids(2., 4.)));
+        assert!(detect_channel_swap(&telemetry_with_ids(4., 2.)));
+        // Both infrared at night, 3b and 4
+        assert!(!detect_channel_swap(&telemetry_with_ids(6., 4.)));
+
+        // Channel 2 on A and 4 on B, wedges 2 and 4 have those values
+        let image = synthetic_image(63., 127.);
+        let mut context = Context::resample(|_,_| {}, false, false);
+        let telemetry = read_telemetry(&mut context, &image, DEFAULT_TELEMETRY_LINES).unwrap();
+        assert!(!detect_channel_swap(&telemetry));
+
+        // Lines starting on channel B
+        let half = PX_PER_ROW as usize / 2;
+        let swapped: Signal = image[half..image.len() - half].to_vec();
+        let telemetry = read_telemetry(&mut context, &swapped, DEFAULT_TELEMETRY_LINES).unwrap();
+        assert_eq!(telemetry.get_channel_name(Channel::A), "4");
+        assert!(detect_channel_swap(&telemetry));
+
+        let fixed = shift_half_line(&swapped);
+        assert_eq!(fixed.len(), swapped.len() - PX_PER_ROW as usize);
+        let telemetry = read_telemetry(&mut context, &fixed, DEFAULT_TELEMETRY_LINES).unwrap();
+        assert!(!detect_channel_swap(&telemetry));
+    }
+
+    #[test]
+    fn test_map_image_channel_swap() {
+        use config;
+        use noaa_apt::{self, map_image, Contrast, SyncHit};
+
+        let half = PX_PER_ROW as usize / 2;
+        let image = synthetic_image(63., 127.);
+        let swapped: Signal = image[half..image.len() - half].to_vec();
+
+        let settings = config::DecodeSettings {
+            channel_a_contrast: Contrast::Telemetry,
+            channel_b_contrast: Contrast::Telemetry,
+            ..test_util::decode_settings("", "")
+        };
+        let samples_per_work_row = (PX_PER_ROW * settings.work_rate / noaa_apt::FINAL_RATE) as usize;
+        let sync_hits: Vec<SyncHit> = (0..swapped.len() / PX_PER_ROW as usize)
+            .map(|i| SyncHit { index: i * samples_per_work_row, fraction: 0., confidence: 0.9 })
+            .collect();
+        let mut context = Context::resample(|_,_| {}, false, false);
+
+        // Left as it is unless asked
+        let (signal, _mapped) = map_image(
+            &mut context, &settings, swapped.clone(), &sync_hits).unwrap();
+        assert_eq!(signal, swapped);
+
+        let settings = config::DecodeSettings { fix_swapped_channels: true, ..settings };
+        let (signal, _mapped) = map_image(
+            &mut context, &settings, swapped.clone(), &sync_hits).unwrap();
+        assert_eq!(signal, noaa_apt::shift_half_line(&swapped));
+    }
+
+    #[test]
+    fn test_read_telemetry_windowed() {
+        // Channel B changes from 4 to 3b after three frames
+        let mut image = synthetic_image(63., 127.);
+        image.extend(synthetic_image(63., 191.));
+        let mut context = Context::resample(|_,_| {}, false, false);
+
+        let blocks = read_telemetry_windowed(&mut context, &image, 3 * FRAME_LINES).unwrap();
+        let names: Vec<(usize, &str)> = blocks.iter()
+            .map(|(line, t)| (*line, t.get_channel_name(Channel::B))).collect();
+        assert_eq!(names, vec![(0, "4"), (3 * FRAME_LINES, "3b")]);
+
+        // Every frame, the change is in the middle
+        let blocks = read_telemetry_windowed(&mut context, &image, FRAME_LINES).unwrap();
+        assert_eq!(blocks.len(), 5);
+        assert_eq!(blocks[2].1.get_channel_name(Channel::B), "4");
+        assert_eq!(blocks[3].1.get_channel_name(Channel::B), "3b");
+
+        // Everything averaged, most frames say 4
+        let telemetry = read_telemetry(&mut context, &image, image.len() / PX_PER_ROW as usize).unwrap();
+        assert_eq!(telemetry.get_channel_name(Channel::B), "4");
+        assert_eq!(telemetry.get_channel_name(Channel::A), "2");
+
+        assert!(read_telemetry_windowed(&mut context, &image, 0).is_err());
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&mut [3., 1., 2.]), Some(2.));
+        assert_eq!(median(&mut [4., 1., 3., 2.]), Some(2.5));
+        assert_eq!(median(&mut [5.]), Some(5.));
+        assert_eq!(median(&mut []), None);
+    }
+
     #[test]
     fn test_telemetry_get_channel() {
         // Means for wedges 1 to 15
@@ -316,4 +702,14 @@ mod tests {
             assert_eq!(telemetry.get_channel_name(Channel::B), case.2);
         }
     }
+
+    /// Telemetry with contrast wedges from 1 to 9 and the given values on
+    /// the channel identification wedges.
+    fn telemetry_with_ids(id_a: f32, id_b: f32) -> Telemetry {
+        let mut values_a: Vec<f32> = (1..=16).map(|x| x as f32).collect();
+        let mut values_b = values_a.clone();
+        values_a[15] = id_a;
+        values_b[15] = id_b;
+        Telemetry { values_a, values_b }
+    }
 }
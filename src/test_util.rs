@@ -0,0 +1,45 @@
+//! Helpers shared by the tests of every module.
+
+use config;
+use noaa_apt::Contrast;
+
+
+/// Pseudo random noise between -0.5 and 0.5.
+///
+/// A linear congruential generator, gives always the same numbers for the same
+/// `seed` so the tests don't change between runs.
+pub fn noise(seed: u32) -> impl FnMut() -> f32 {
+    let mut seed = seed;
+    move || {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (seed >> 16) as f32 / 65536. - 0.5
+    }
+}
+
+/// Path for a temporary file, with the process id so tests running at the
+/// same time on other processes don't use the same file.
+pub fn temp_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("noaa_apt_{}_{}", std::process::id(), name))
+        .to_str().unwrap().to_string()
+}
+
+/// Settings for decoding short test recordings quickly.
+///
+/// Low quality filters, no syncing and fixed contrast, so the result only
+/// depends on the signal.
+pub fn decode_settings(input_filename: &str, output_filename: &str) -> config::DecodeSettings {
+    config::DecodeSettings {
+        sync: false,
+        sync_threshold: 0.,
+        channel_a_contrast: Contrast::Percent(0.9),
+        channel_b_contrast: Contrast::Percent(0.9),
+        work_rate: 12480,
+        resample_atten: 30.,
+        resample_delta_freq: 1000.,
+        resample_cutout: 4800.,
+        compensate_droop: false,
+        demodulation_atten: 25.,
+        ..config::DecodeSettings::new(input_filename, output_filename)
+    }
+}
@@ -0,0 +1,141 @@
+//! Decoding from memory, for running on the browser.
+//!
+//! WebAssembly has no filesystem and no threads, so `decode_bytes()` takes
+//! the WAV file as bytes and returns the image as pixels, nothing else is read
+//! or written. Only slices and numbers go in and out so it's easy to wrap
+//! with `wasm-bindgen`. Compiled with the `wasm` feature, which also enables
+//! `fft_builtin` so there are no dependencies on native code.
+
+use config;
+use context::Context;
+use dsp::Rate;
+use err;
+use noaa_apt;
+use sink;
+use wav;
+
+
+/// Decode APT image from the bytes of a WAV file.
+///
+/// Runs the same stages as `noaa_apt::decode()`. Settings for extra files
+/// (raw image, histogram, CSV, thumbnail, SNR, exporting steps) are ignored.
+/// Returns the pixels with the width and height of the image, grayscale or
+/// RGB (three values per pixel) if `settings.palette` is set.
+pub fn decode_bytes(
+    wav: &[u8],
+    settings: &config::DecodeSettings,
+) -> err::Result<(Vec<u8>, usize, usize)> {
+
+    let settings = noaa_apt::preview_settings(config::DecodeSettings {
+        csv_filename: None,
+        export_wav: false,
+        export_resample_filtered: false,
+        ..settings.clone()
+    });
+
+    let mut context = Context::decode(
+        |_progress, description| debug!("{}", description),
+        Rate::hz(settings.work_rate),
+        Rate::hz(noaa_apt::FINAL_RATE),
+        false,
+        false,
+    );
+
+    context.deterministic = settings.deterministic;
+    context.high_precision = settings.high_precision;
+
+    let (signal, rate) = wav::read_wav_from_bytes(wav)?;
+    let input = noaa_apt::read_signal(&mut context, &settings, signal, Rate::hz(rate))?;
+    let signal = noaa_apt::resample(&mut context, &settings, &input)?;
+    let signal = noaa_apt::demodulate(&mut context, &settings, &signal)?;
+    let (signal, sync_hits) = noaa_apt::assemble(
+        &mut context, &settings, signal, input.margin_lines)?;
+    let (_signal, mapped) = noaa_apt::map_image(&mut context, &settings, signal, &sync_hits)?;
+
+    let (output, width) = noaa_apt::select_output(&settings, &mapped)?;
+    let width = width as usize;
+    let (image, color) = noaa_apt::apply_output_palette(&settings, output);
+
+    let mut buffer = sink::ImageBuffer::new(width, color);
+    sink::write_image(&mut buffer, &image, width, color)?;
+    let height = buffer.image().len() / (width * color.samples());
+
+    Ok((buffer.image().to_vec(), width, height))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use test_util::{self, temp_path};
+    use processing::Palette;
+
+    /// Bytes of a WAV file with an APT carrier whose brightness goes up on
+    /// each line.
+    fn test_wav(seconds: usize) -> Vec<u8> {
+        use std::f32::consts::PI;
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 11025,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        {
+            let mut writer = hound::WavWriter::new(&mut cursor, spec).unwrap();
+            for i in 0..seconds * 11025 {
+                let t = i as f32 / 11025.;
+                let brightness = 0.2 + 0.8 * (t * 2.).fract();
+                let sample = brightness * (2. * PI * noaa_apt::CARRIER_FREQ as f32 * t).sin();
+                writer.write_sample((sample * 20000.) as i16).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+        cursor.into_inner()
+    }
+
+    #[test]
+    fn test_decode_bytes() {
+        let wav = test_wav(8);
+        let settings = test_util::decode_settings("unused.wav", "unused.png");
+        let (image, width, height) = decode_bytes(&wav, &settings).unwrap();
+        assert_eq!(width, noaa_apt::PX_PER_ROW as usize);
+        // 8 seconds are 16 lines, the filters take a bit of the last one
+        assert_eq!(height, 15);
+        assert_eq!(image.len(), width * height);
+
+        // Same image as decoding the file
+        std::fs::write(temp_path("wasm_input.wav"), &wav).unwrap();
+        let settings = test_util::decode_settings(
+            &temp_path("wasm_input.wav"), &temp_path("wasm.png"));
+        let context = Context::decode(|_,_| {}, Rate::hz(12480),
+                                      Rate::hz(noaa_apt::FINAL_RATE), false, false);
+        noaa_apt::decode(context, settings).unwrap();
+
+        let decoder = png::Decoder::new(std::fs::File::open(temp_path("wasm.png")).unwrap());
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut decoded = vec![0; info.buffer_size()];
+        reader.next_frame(&mut decoded).unwrap();
+        assert_eq!((info.width as usize, info.height as usize), (width, height));
+        assert_eq!(decoded, image);
+
+        for x in ["wasm_input.wav", "wasm.png"].iter() {
+            std::fs::remove_file(temp_path(x)).unwrap();
+        }
+
+        // Nothing written when asking for extra files
+        let settings = config::DecodeSettings {
+            palette: Some(Palette::Thermal),
+            csv_filename: Some(temp_path("wasm.csv")),
+            ..test_util::decode_settings("unused.wav", &temp_path("wasm.png"))
+        };
+        let (image, width, height) = decode_bytes(&wav, &settings).unwrap();
+        assert_eq!(image.len(), width * height * 3);
+        assert!(!std::path::Path::new(&temp_path("wasm.csv")).exists());
+        assert!(!std::path::Path::new(&temp_path("wasm.png")).exists());
+
+        assert!(decode_bytes(&[1, 2, 3], &settings).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 //! Functions for loading and saving WAV files.
 
+use std::io::Read;
+
 use hound;
 
 use dsp;
@@ -8,11 +10,186 @@ use err;
 
 
 /// Load wav file, return `Signal` and specs.
+///
+/// If the file is compressed with gzip (the filename ends with `.gz` or the
+/// file starts with the gzip magic bytes) it's decompressed on the fly, only
+/// available when compiled with the `gzip` feature.
 pub fn load_wav(filename: &str) -> err::Result<(Signal, hound::WavSpec)> {
 
     debug!("Loading WAV: {}", filename);
 
-    let mut reader = hound::WavReader::open(filename)?;
+    if is_gzip(filename) {
+        load_gzip_wav(filename)
+    } else {
+        let reader = hound::WavReader::open(filename)?;
+        check_truncated(filename)?;
+        read_wav(reader)
+    }
+}
+
+/// Load WAV file already in memory, return `Signal` and sample rate.
+///
+/// For data that didn't come from the filesystem, like a recording received
+/// over the network. Fails if the data is shorter than the header says, like
+/// `load_wav()`. Compressed data is not supported.
+pub fn read_wav_from_bytes(data: &[u8]) -> err::Result<(Signal, u32)> {
+    debug!("Loading WAV from {} bytes in memory", data.len());
+
+    check_truncated_reader(&mut std::io::Cursor::new(data), data.len() as u64)?;
+    let (signal, spec) = read_wav(hound::WavReader::new(std::io::Cursor::new(data))?)?;
+    Ok((signal, spec.sample_rate))
+}
+
+/// Information from a WAV header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WavInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits: u16,
+    pub duration_secs: f32,
+}
+
+/// Read WAV header without loading the samples.
+///
+/// Cheap, meant for showing the file details before decoding. Compressed
+/// files are supported like on `load_wav()`, only the start of the file is
+/// decompressed.
+pub fn wav_info(filename: &str) -> err::Result<WavInfo> {
+    if is_gzip(filename) {
+        gzip_wav_info(filename)
+    } else {
+        Ok(reader_info(&hound::WavReader::open(filename)?))
+    }
+}
+
+/// Read WAV header of gzip compressed file.
+#[cfg(feature = "gzip")]
+fn gzip_wav_info(filename: &str) -> err::Result<WavInfo> {
+    use flate2::read::GzDecoder;
+
+    let file = std::fs::File::open(filename)?;
+    let decoder = GzDecoder::new(std::io::BufReader::new(file));
+
+    Ok(reader_info(&hound::WavReader::new(decoder)?))
+}
+
+/// Read WAV header of gzip compressed file.
+///
+/// Compiled without gzip support, so fails.
+#[cfg(not(feature = "gzip"))]
+fn gzip_wav_info(_filename: &str) -> err::Result<WavInfo> {
+    Err(err::Error::FeatureNotAvailable(vec!["gzip".to_string()]))
+}
+
+/// Get `WavInfo` from a `hound::WavReader` that didn't read any samples.
+fn reader_info<R: Read>(reader: &hound::WavReader<R>) -> WavInfo {
+    let spec = reader.spec();
+    WavInfo {
+        sample_rate: spec.sample_rate,
+        channels: spec.channels,
+        bits: spec.bits_per_sample,
+        // Samples per channel
+        duration_secs: reader.duration() as f32 / spec.sample_rate as f32,
+    }
+}
+
+/// Fail if the file is shorter than what the WAV header says.
+///
+/// Happens with incomplete downloads, `hound` would give an error in the middle
+/// of the samples or silently return less samples. Files that are not
+/// RIFF/WAVE pass, `hound` gives a better error for those. Recorders that
+/// didn't finish writing the file leave the data length as zero or
+/// `0xFFFFFFFF`, I can't check those.
+fn check_truncated(filename: &str) -> err::Result<()> {
+    let mut file = std::fs::File::open(filename)?;
+    let file_len = file.metadata()?.len();
+    check_truncated_reader(&mut file, file_len)
+}
+
+/// Same as `check_truncated()` for any reader, `file_len` is the total length
+/// of the data.
+fn check_truncated_reader<R: Read + std::io::Seek>(file: &mut R, file_len: u64) -> err::Result<()> {
+    use std::io::SeekFrom;
+
+    let mut header: [u8; 12] = [0; 12];
+    if file.read_exact(&mut header).is_err()
+        || &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE"
+    {
+        return Ok(());
+    }
+
+    // Look for the data chunk, every chunk has an ID and a length
+    let mut position: u64 = 12;
+    let mut chunk: [u8; 8] = [0; 8];
+    while file.read_exact(&mut chunk).is_ok() {
+        let mut length: [u8; 4] = [0; 4];
+        length.copy_from_slice(&chunk[4..8]);
+        let length = u64::from(u32::from_le_bytes(length));
+        position += 8;
+
+        if &chunk[0..4] == b"data" {
+            if length == 0 || length == u64::from(u32::MAX) {
+                return Ok(());
+            }
+
+            let expected = position + length;
+            if expected > file_len {
+                return Err(err::Error::WavOpen(format!(
+                    "Truncated WAV, expected {} bytes but the file has {}, \
+                    looks like an incomplete download", expected, file_len)));
+            }
+            return Ok(());
+        }
+
+        // Chunks are padded to an even length
+        position += length + length % 2;
+        file.seek(SeekFrom::Start(position))?;
+    }
+
+    Ok(())
+}
+
+/// Check if file is compressed with gzip.
+///
+/// Looks at the extension and at the first two bytes of the file.
+fn is_gzip(filename: &str) -> bool {
+    if filename.ends_with(".gz") {
+        return true;
+    }
+
+    // If the file can't be opened or is too short it's not gzip, hound will
+    // complain later with the usual error
+    let mut magic: [u8; 2] = [0; 2];
+    std::fs::File::open(filename)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok() && magic == [0x1f, 0x8b]
+}
+
+/// Load gzip compressed WAV file.
+#[cfg(feature = "gzip")]
+fn load_gzip_wav(filename: &str) -> err::Result<(Signal, hound::WavSpec)> {
+
+    use flate2::read::GzDecoder;
+
+    debug!("Decompressing gzip WAV: {}", filename);
+
+    let file = std::fs::File::open(filename)?;
+    let decoder = GzDecoder::new(std::io::BufReader::new(file));
+
+    read_wav(hound::WavReader::new(decoder)?)
+}
+
+/// Load gzip compressed WAV file.
+///
+/// Compiled without gzip support, so fails.
+#[cfg(not(feature = "gzip"))]
+fn load_gzip_wav(_filename: &str) -> err::Result<(Signal, hound::WavSpec)> {
+    Err(err::Error::FeatureNotAvailable(vec!["gzip".to_string()]))
+}
+
+/// Read samples from a `hound::WavReader`, return `Signal` and specs.
+fn read_wav<R: Read>(mut reader: hound::WavReader<R>) -> err::Result<(Signal, hound::WavSpec)> {
+
     let spec = reader.spec();
 
     if spec.channels != 1 {
@@ -40,7 +217,7 @@ pub fn load_wav(filename: &str) -> err::Result<(Signal, hound::WavSpec)> {
                 .collect()
         }
         hound::SampleFormat::Float => {
-            reader.samples::<f32>()
+            let mut samples: Signal = reader.samples::<f32>()
                 .collect::<Result<Vec<f32>, hound::Error>>()?
                 .iter()
                 .enumerate()
@@ -50,7 +227,16 @@ pub fn load_wav(filename: &str) -> err::Result<(Signal, hound::WavSpec)> {
                         _ => None,
                     }
                 )
-                .collect()
+                .collect();
+
+            // Integer samples are always valid, but float ones can be
+            // corrupted
+            let invalid = dsp::sanitize(&mut samples);
+            if invalid > 0 {
+                warn!("Replaced {} NaN or infinite samples with zero", invalid);
+            }
+
+            samples
         }
     };
 
@@ -103,3 +289,213 @@ pub fn write_wav(filename: &str, signal: &Signal, spec: hound::WavSpec) -> err::
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use test_util::temp_path;
+
+    /// Write a short 16 bit WAV to a file on the temporary folder, return the
+    /// path and the samples written.
+    fn write_test_wav(name: &str) -> (std::path::PathBuf, Vec<i16>) {
+        let path = std::path::PathBuf::from(temp_path(name));
+
+        let samples: Vec<i16> = (0..1000).map(|x| (x * 13 % 2000 - 1000) as i16).collect();
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 11025,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in samples.iter() {
+            writer.write_sample(*sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        (path, samples)
+    }
+
+    #[test]
+    fn test_wav_info() {
+        let (path, _) = write_test_wav("info.wav");
+        let info = wav_info(path.to_str().unwrap()).unwrap();
+        assert_eq!((info.sample_rate, info.channels, info.bits), (11025, 1, 16));
+        assert_relative_eq!(info.duration_secs, 1000. / 11025.);
+        std::fs::remove_file(&path).unwrap();
+
+        // The duration counts samples of each channel
+        let path = std::path::PathBuf::from(temp_path("info_stereo.wav"));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..2 * 12000 {
+            writer.write_sample(0.5_f32).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let info = wav_info(path.to_str().unwrap()).unwrap();
+        assert_eq!(info, WavInfo {
+            sample_rate: 48000, channels: 2, bits: 32, duration_secs: 0.25 });
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(wav_info("/nonexistent/noaa_apt.wav").is_err());
+    }
+
+    #[test]
+    fn test_read_wav_from_bytes() {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 20800,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let samples: Vec<i16> = (0..500).map(|x| (x * 37 % 3000 - 1500) as i16).collect();
+
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        {
+            let mut writer = hound::WavWriter::new(&mut cursor, spec).unwrap();
+            for sample in samples.iter() {
+                writer.write_sample(*sample).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+        let bytes = cursor.into_inner();
+
+        let (signal, rate) = read_wav_from_bytes(&bytes).unwrap();
+        assert_eq!(rate, 20800);
+        assert_eq!(signal, samples.iter().map(|x| f32::from(*x)).collect::<Signal>());
+
+        assert!(read_wav_from_bytes(&bytes[..bytes.len() - 10]).is_err());
+        assert!(read_wav_from_bytes(b"not a wav file").is_err());
+        assert!(read_wav_from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn test_load_truncated_wav() {
+        let (path, samples) = write_test_wav("truncated.wav");
+        let filename = path.to_str().unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        // Complete file
+        assert_eq!(load_wav(filename).unwrap().0.len(), samples.len());
+
+        // Cut in the middle of the samples
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 100).unwrap();
+
+        match load_wav(filename) {
+            Err(err::Error::WavOpen(msg)) => assert_eq!(msg, format!(
+                "Truncated WAV, expected {} bytes but the file has {}, looks \
+                like an incomplete download", len, len - 100)),
+            _ => panic!("Expected WavOpen error"),
+        }
+
+        // Data length never written by the recorder, can't check
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[40..44].copy_from_slice(&[0xFF; 4]);
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(check_truncated(filename).is_ok());
+
+        // Not a WAV, let hound complain
+        std::fs::write(&path, b"RIFF").unwrap();
+        assert!(check_truncated(filename).is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing_wav() {
+        // Same error as opening with hound, not a gzip check failing first
+        let filename = "/nonexistent/noaa_apt.wav";
+        let expected = match hound::WavReader::open(filename) {
+            Err(hound::Error::IoError(e)) => e.kind(),
+            _ => panic!("Expected hound IoError"),
+        };
+
+        match load_wav(filename) {
+            Err(err::Error::Io(got)) => assert_eq!(got.kind(), expected),
+            _ => panic!("Expected Io error"),
+        }
+        match wav_info(filename) {
+            Err(err::Error::Io(_)) => (),
+            _ => panic!("Expected Io error"),
+        }
+    }
+
+    #[test]
+    fn test_load_float_wav_with_nan() {
+        let path = std::path::PathBuf::from(temp_path("nan.wav"));
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 11025,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in [0.5, f32::NAN, -0.25, f32::INFINITY].iter() {
+            writer.write_sample(*sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let (signal, _spec) = load_wav(path.to_str().unwrap()).unwrap();
+        assert_eq!(signal, vec![0.5, 0., -0.25, 0.]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_load_gzip_wav() {
+        use std::io::Write;
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let (path, samples) = write_test_wav("gzip.wav");
+
+        // Compress the WAV, once with the .gz extension and once without
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&std::fs::read(&path).unwrap()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let gz_path = path.with_extension("wav.gz");
+        let no_extension_path = path.with_extension("compressed");
+        std::fs::write(&gz_path, &compressed).unwrap();
+        std::fs::write(&no_extension_path, &compressed).unwrap();
+
+        for p in [gz_path, no_extension_path].iter() {
+            let (signal, spec) = load_wav(p.to_str().unwrap()).unwrap();
+            assert_eq!(spec.sample_rate, 11025);
+            assert_eq!(signal, samples.iter().map(|x| *x as f32).collect::<Signal>());
+            std::fs::remove_file(p).unwrap();
+        }
+
+        // Uncompressed files still work
+        let (signal, _spec) = load_wav(path.to_str().unwrap()).unwrap();
+        assert_eq!(signal.len(), samples.len());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    #[test]
+    fn test_load_gzip_wav_not_available() {
+        let (path, _samples) = write_test_wav("gzip_not_available.wav");
+        let gz_path = path.with_extension("wav.gz");
+        std::fs::rename(&path, &gz_path).unwrap();
+
+        match load_wav(gz_path.to_str().unwrap()) {
+            Err(err::Error::FeatureNotAvailable(features)) =>
+                assert_eq!(features, vec!["gzip".to_string()]),
+            _ => panic!("Expected FeatureNotAvailable"),
+        }
+
+        std::fs::remove_file(&gz_path).unwrap();
+    }
+}